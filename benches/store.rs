@@ -0,0 +1,90 @@
+#![feature(test)]
+extern crate test;
+
+use packman::VecPack;
+use std::path::PathBuf;
+use test::Bencher;
+use upl_microservice::store::UplStore;
+use upl_microservice::upl::*;
+
+// Number of UPLs in the benchmark store
+const UPL_COUNT: u32 = 5_000;
+
+// Create a store with UPL_COUNT UPLs
+// spread over 500 SKUs and 10 stocks
+fn store() -> UplStore {
+  let path = std::env::temp_dir().join("upl_store_bench");
+  if path.exists() {
+    std::fs::remove_dir_all(&path).unwrap();
+  }
+  let mut store = UplStore::new(VecPack::load_or_init(PathBuf::from(&path)).unwrap());
+  for i in 0..UPL_COUNT {
+    store
+      .insert(Upl {
+        id: i.to_string(),
+        kind: Kind::Sku { sku: i % 500 },
+        location: Location::Stock(i % 10),
+        ..Upl::default()
+      })
+      .unwrap();
+  }
+  store
+}
+
+#[bench]
+fn bench_by_sku_scan(b: &mut Bencher) {
+  let store = store();
+  b.iter(|| {
+    store
+      .iter()
+      .filter(|upl| upl.unpack().get_sku() == 250)
+      .count()
+  });
+}
+
+#[bench]
+fn bench_by_sku_index(b: &mut Bencher) {
+  let store = store();
+  b.iter(|| store.find_by_sku(250).count());
+}
+
+#[bench]
+fn bench_by_location_scan(b: &mut Bencher) {
+  let store = store();
+  b.iter(|| {
+    store
+      .iter()
+      .filter(|upl| upl.unpack().get_location() == &Location::Stock(5))
+      .count()
+  });
+}
+
+#[bench]
+fn bench_by_location_index(b: &mut Bencher) {
+  let store = store();
+  b.iter(|| store.find_by_location(&Location::Stock(5)).count());
+}
+
+#[bench]
+fn bench_find_id_scan(b: &mut Bencher) {
+  let store = store();
+  b.iter(|| store.iter().position(|upl| upl.unpack().id == "4999"));
+}
+
+#[bench]
+fn bench_find_id_index(b: &mut Bencher) {
+  let store = store();
+  b.iter(|| store.find_id("4999").is_ok());
+}
+
+#[bench]
+fn bench_remove_pack(b: &mut Bencher) {
+  let mut store = store();
+  // Remove the first UPL, the last UPL is moved into its place,
+  // then insert it back to keep the store size
+  // It measures the file removal and the file write of the insert
+  b.iter(|| {
+    let upl = store.remove_pack("0").unwrap();
+    store.insert(upl).unwrap();
+  });
+}
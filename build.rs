@@ -0,0 +1,6 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  tonic_build::configure()
+    .extern_path(".upl", "::gzlib::proto::upl")
+    .compile(&["proto/upl_ext.proto", "proto/health.proto"], &["proto"])?;
+  Ok(())
+}
@@ -1,8 +1,11 @@
-use crate::Upl;
+use crate::eventlog::{EventLog, LogEntry};
+use crate::upl::*;
 use chrono::prelude::*;
+use gzlib::id::LuhnCheck;
 use serde::{Deserialize, Serialize};
 use std::fs::create_dir_all;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 // Determine UPL index path parts from UPL
 // This kind of partitioning enable us to store safely
@@ -11,7 +14,7 @@ use std::path::PathBuf;
 // per folder.
 // returns (million value, thousand value, hundreds value)
 #[inline]
-fn get_path(u: u32) -> (u32, u32, u32) {
+fn get_path(u: u64) -> (u64, u64, u64) {
   (u / 1_000_000, u % 1_000_000 / 1000, u % 1000)
 }
 
@@ -19,168 +22,92 @@ fn get_path(u: u32) -> (u32, u32, u32) {
 pub enum ArchiveError {
   InternalError(String),
   AlreadyExist(String),
+  NotFound(String),
+  WrongId(String),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl std::fmt::Display for ArchiveError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ArchiveError::InternalError(msg) => write!(f, "{}", msg),
+      ArchiveError::AlreadyExist(msg) => write!(f, "{}", msg),
+      ArchiveError::NotFound(msg) => write!(f, "{}", msg),
+      ArchiveError::WrongId(msg) => write!(f, "{}", msg),
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum ArchiveReason {
   // When the UPL has successfully sold
   Sold,
-  //
+  // When the UPL is missing
+  // and was discarded
   Missing,
+  // When a derived UPL is merged back into its parent
+  // It cannot be restored, as its amount lives in the parent
+  Merged,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub enum Location {
-  Stock(u32),
-  Cart(u32),
+/// Archive object
+/// Archived UPL with its archive details
+/// stored in a single .uarch file
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ArchiveObject {
+  // Why the UPL was archived
+  reason: ArchiveReason,
+  // When the UPL was archived
+  archived_at: DateTime<Utc>,
+  // The archived UPL itself
+  // with its full history
+  upl: Upl,
 }
 
-impl Default for Location {
-  fn default() -> Self {
-    Self::Stock(0)
+impl ArchiveObject {
+  pub fn new(reason: ArchiveReason, upl: Upl) -> Self {
+    Self::new_at(reason, upl, Utc::now())
   }
-}
-
-/// UPL Kind
-/// Represents the UPL phisical appearance
-/// Can be
-///   SKU => UPL is an un-opened SKU
-///   BulkSku => UPL is a bulk of un-opened SKUs
-///   OpenedSku =>  UPL represents a SKU that has opened and some of its quantity
-///                 has already taken out.
-///   DerivedProduct => its an opened SKU, but the moved out part, and its moved to another
-///                     package. Based on its appearance we cannot tell which SKU its related
-///                     but we can tell, which product it is.
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub enum Kind {
-  // UPL representing a single SKU
-  // Has its own UPL ID
-  Sku {
-    product_id: u32,
-    sku: u32,
-  },
-  // Muliple un-opened SKU in a bulk package
-  // sub UPLs cannot have a UPL ID yet, but we all of them
-  // share the same UPL attributes. So when we split this bulk
-  // package we create the UPLs by cloning its attributes.
-  BulkSku {
-    product_id: u32,
-    sku: u32,
-  },
-  // An opened sku
-  // An original sku has expanded,
-  // and a part of it is already out of it.
-  OpenedSku {
-    product_id: u32,
-    sku: u32,
-    amount: u32,
-  },
-  // Piece of product that
-  // derives from an opened sku
-  DerivedProduct {
-    // Related product ID
-    product_id: u32,
-    // Derived SKU
-    // Can be only Sku, or OpenedSku
-    derived_from: u32,
-    // Amount in the products unit
-    amount: u32,
-  },
-}
-
-impl Default for Kind {
-  fn default() -> Self {
-    Self::Sku {
-      product_id: 0,
-      sku: 0,
+  /// Archive object of a UPL to be archived
+  /// It sets the UplHistoryEvent::Archived event
+  pub fn archived(mut upl: Upl, reason: ArchiveReason, created_by: CreatedBy) -> Self {
+    upl.set_history(UplHistoryItem::new(created_by, UplHistoryEvent::Archived));
+    Self::new(reason, upl)
+  }
+  /// Archive object with a given archive time
+  /// e.g. when it is rebuilt from the event log
+  pub fn new_at(reason: ArchiveReason, upl: Upl, archived_at: DateTime<Utc>) -> Self {
+    Self {
+      reason,
+      archived_at,
+      upl,
     }
   }
+  /// Get archive reason ref
+  pub fn get_reason(&self) -> &ArchiveReason {
+    &self.reason
+  }
+  /// Get archive time
+  pub fn get_archived_at(&self) -> DateTime<Utc> {
+    self.archived_at
+  }
+  /// Get archived UPL ref
+  pub fn get_upl(&self) -> &Upl {
+    &self.upl
+  }
 }
 
-/// Lock kinds
-/// Cart lock means the given UPL is locked to a specific Cart
-/// so it cannot move away, as its under a sales process.
-/// None means there is no lock, so the UPL can be moved away.
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub enum Lock {
-  // Using when a UPL is in a Cart
-  Cart(u32),
-  // Using when UPL is under an inventory process
-  // and missing
-  // todo!: Should have a better naming
-  Inventory(u32),
-  // UPL has no lock
-  // it can be updated and moved freely
-  None,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct ArchiveObject {
-  reason: ArchiveReason,
-  upl_id: u32,
-  // UPL Kind
-  // Single or Bulk(u32)
-  // Single means its a single UPL,
-  // Bulk means its a collection of UPLs under a single UPL ID
-  // e.g. a pallet flower soil (50)
-  kind: Kind,
-  // * Procurement
-  procurement_id: u32,
-  // Net wholesale price in which
-  // this item was purchased by us
-  procurement_net_price: f32,
-  // Current UPL location
-  location: Location, // todo? this way?
-  // todo! Not NOW!
-  // todo! Implement => location_history: Vec<Location>,
-  // --
-  // If the product is injured
-  // it should be scraped. This field
-  // contains the related scrap id
-  scrap_id: Option<i32>, // TODO: scrap_price_log?
-  // Related scrap comment
-  // if there any
-  // From the sku scrap comment from the
-  // related scrap record
-  scrap_comment: Option<String>,
-  // Related scrap price
-  // if there any.
-  // Can set if there is related scrap_id
-  scrap_retail_net_price: Option<f32>,
-  // Best before date
-  // Only for perishable goods.
-  // Optional, but when we have one, we use
-  // DateTime<Utc>
-  best_before: Option<DateTime<Utc>>,
-  // Product quantity
-  // It contains Simple or Complex quantity
-  // Or when a Simple product - wich is divisible -
-  // is divided, it contains the remained quantity.
-  // Inherited from Product(service), but after
-  // bacome Partial(u32), it's going to be managed
-  // here without responding the related Product changes.
-  // --
-  // Only some, if Sku can be devided, and its unopened.
-  // Once its opened, this amount will be none, and its
-  // value is moved to its kind component
-  // This value represents the SKU original divisible quantity
-  divisible_amount: Option<u32>,
-  // Lock enum
-  // When a UPL is locked by any reason,
-  // that UPL cannot be updated.
-  // ~ Only ~ the lock owner can unlock
-  lock: Lock,
-  // Userid who created
-  created_by: String,
-  // Utc datetime when this object
-  // created
-  date_created: DateTime<Utc>,
-}
-
-impl ArchiveObject {}
-
+/// Archive Store
+/// On disk store of the archived UPLs.
+/// Each UPL is stored in its own file, partitioned by its base ID:
+/// {path}/{million}/{thousand}/{upl_id}.uarch
 pub struct ArchiveStore {
   path: PathBuf,
+  // Event log to write archive changes into
+  log: Option<EventLog>,
+  // Number of archived UPLs
+  // Counted once at init, then kept up to date by put and remove,
+  // so the status does not need to walk the archive
+  count: AtomicUsize,
 }
 
 impl ArchiveStore {
@@ -189,17 +116,157 @@ impl ArchiveStore {
     if !path.exists() {
       // 2. Create path if does not exist
       // we use expect as its error should stop the program at the beginning
-      create_dir_all(&path).expect("Error while creating UplIndex path tree! (It did not exist");
+      create_dir_all(&path)
+        .expect("Error while creating ArchiveStore path tree! (It did not exist");
     }
-    Self { path }
+    let store = Self {
+      path,
+      log: None,
+      count: AtomicUsize::new(0),
+    };
+    let count = store
+      .get_upl_ids()
+      .expect("Error while counting archived UPLs")
+      .len();
+    store.count.store(count, Ordering::SeqCst);
+    store
   }
-  // Only when a UPL has any Lock
-  pub fn add(&self, upl: Upl) -> Result<(), ArchiveError> {
-    // 1. Generate UPL Archive object path
-    let base = upl.id / 100;
+
+  /// Number of archived UPLs
+  pub fn count(&self) -> usize {
+    self.count.load(Ordering::SeqCst)
+  }
+
+  // Remove an archive file and count it out
+  fn remove_file(&self, file_path: &Path) -> std::io::Result<()> {
+    std::fs::remove_file(file_path)?;
+    self.count.fetch_sub(1, Ordering::SeqCst);
+    Ok(())
+  }
+
+  /// Write archive changes into an event log
+  pub fn set_log(&mut self, log: EventLog) {
+    self.log = Some(log);
+  }
+
+  fn log(&self, entry: LogEntry) -> Result<(), ArchiveError> {
+    match &self.log {
+      Some(log) => log
+        .append(entry)
+        .map(|_| ())
+        .map_err(|e| ArchiveError::InternalError(e.to_string())),
+      None => Ok(()),
+    }
+  }
+
+  // Generate UPL Archive object folder and file path
+  fn get_file_path(&self, upl_id: &str) -> Result<(PathBuf, PathBuf), ArchiveError> {
+    // Base ID is the ID without its two checksum digits
+    let base = upl_id
+      .to_string()
+      .to_luhn_object()
+      .map_err(|_| ArchiveError::WrongId(format!("A megadott UPL ID nem valid! {}", upl_id)))?
+      .get_base();
     let (parent, child, _) = get_path(base);
     let folder_path = self.path.join(parent.to_string()).join(child.to_string());
-    let file_path = folder_path.join(format!("{}.uarch", upl.id));
+    let file_path = folder_path.join(format!("{}.uarch", upl_id));
+    Ok((folder_path, file_path))
+  }
+
+  // Load archive object from its file
+  fn load(&self, upl_id: &str) -> Result<(PathBuf, ArchiveObject), ArchiveError> {
+    let (_, file_path) = self.get_file_path(upl_id)?;
+
+    // If archive file does not exist
+    // return error
+    if !file_path.exists() {
+      return Err(ArchiveError::NotFound(format!(
+        "A megadott UPL nincs archiválva! {}",
+        upl_id
+      )));
+    }
+
+    // Read file content into file_str
+    let file_str = std::fs::read_to_string(&file_path)
+      .map_err(|e| ArchiveError::InternalError(e.to_string()))?;
+
+    // Try deserialize archive object
+    let archive_object = serde_yaml::from_str::<ArchiveObject>(&file_str)
+      .map_err(|e| ArchiveError::InternalError(e.to_string()))?;
+
+    Ok((file_path, archive_object))
+  }
+
+  /// Check whether a UPL is archived
+  pub fn contains(&self, upl_id: &str) -> bool {
+    match self.get_file_path(upl_id) {
+      Ok((_, file_path)) => file_path.exists(),
+      Err(_) => false,
+    }
+  }
+
+  /// Get all the archived UPL IDs
+  /// It walks the whole archive tree, so use it only
+  /// for maintenance tasks
+  pub fn get_upl_ids(&self) -> Result<Vec<String>, ArchiveError> {
+    let mut res: Vec<String> = Vec::new();
+    let mut folders: Vec<PathBuf> = vec![self.path.clone()];
+    while let Some(folder) = folders.pop() {
+      for entry in
+        std::fs::read_dir(&folder).map_err(|e| ArchiveError::InternalError(e.to_string()))?
+      {
+        let path = entry
+          .map_err(|e| ArchiveError::InternalError(e.to_string()))?
+          .path();
+        if path.is_dir() {
+          folders.push(path);
+        } else if path.extension().and_then(|e| e.to_str()) == Some("uarch") {
+          if let Some(upl_id) = path.file_stem().and_then(|s| s.to_str()) {
+            res.push(upl_id.to_string());
+          }
+        }
+      }
+    }
+    Ok(res)
+  }
+
+  /// Add UPL to the archive
+  /// It sets the UplHistoryEvent::Archived event
+  /// UPLs leaving the active store are archived through
+  /// the store transaction instead, see put_committed
+  pub fn add(
+    &self,
+    upl: Upl,
+    reason: ArchiveReason,
+    created_by: CreatedBy,
+  ) -> Result<(), ArchiveError> {
+    // Convert UPL to ArchiveObject and save it
+    let archive_object = ArchiveObject::archived(upl, reason, created_by);
+    self.put(&archive_object)?;
+
+    // If it cannot be logged, it is not archived
+    let logged = self.log(LogEntry::Archived {
+      reason: archive_object.reason.clone(),
+      archived_at: archive_object.archived_at,
+      upl: archive_object.upl.clone(),
+    });
+    if let Err(e) = logged {
+      let (_, file_path) = self.get_file_path(archive_object.upl.get_upl_id())?;
+      self
+        .remove_file(&file_path)
+        .map_err(|e| ArchiveError::InternalError(e.to_string()))?;
+      return Err(e);
+    }
+
+    Ok(())
+  }
+
+  /// Save an archive object as it is
+  /// e.g. when the archive is rebuilt from the event log
+  /// It does not write the event log
+  pub fn put(&self, archive_object: &ArchiveObject) -> Result<(), ArchiveError> {
+    // 1. Generate UPL Archive object path
+    let (folder_path, file_path) = self.get_file_path(archive_object.upl.get_upl_id())?;
 
     // Create folder path all
     // if not yet exist
@@ -213,31 +280,135 @@ impl ArchiveStore {
     if file_path.exists() {
       return Err(ArchiveError::AlreadyExist(format!(
         "A megadott ID már archiválva van! {}",
-        upl.id
+        archive_object.upl.id
       )));
     }
 
-    // 3. Set Archive history event
+    // 3. Serialize ArchiveObject
+    let content = serde_yaml::to_string(archive_object)
+      .map_err(|e| ArchiveError::InternalError(e.to_string()))?;
+
+    // 4. Save object to Archive Object file
+    // We write a temp file first and rename it, so we never
+    // leave a half written archive file behind.
+    let tmp_path = file_path.with_extension("uarch.tmp");
+    std::fs::write(&tmp_path, content).map_err(|e| ArchiveError::InternalError(e.to_string()))?;
+    std::fs::rename(&tmp_path, &file_path)
+      .map_err(|e| ArchiveError::InternalError(e.to_string()))?;
+    self.count.fetch_add(1, Ordering::SeqCst);
+
+    Ok(())
+  }
 
-    // 3. Create Archive Object file in FS
-    // 4. Convert UPL to ArchiveObject
-    // 5. Serialize ArchiveObject
-    // 6. Save object to Archive Object file
-    todo!();
+  /// Save the archive object of a committed Archived log record
+  /// The event log snapshot writes the missing archive files as well,
+  /// so an existing file is not an error.
+  pub fn put_committed(&self, archive_object: &ArchiveObject) -> Result<(), ArchiveError> {
+    match self.put(archive_object) {
+      Ok(_) | Err(ArchiveError::AlreadyExist(_)) => Ok(()),
+      Err(e) => Err(e),
+    }
+  }
+
+  /// Remove the archive file of a committed Unarchived log record
+  pub fn remove_committed(&self, upl_id: &str) -> Result<(), ArchiveError> {
+    let (_, file_path) = self.get_file_path(upl_id)?;
+    match self.remove_file(&file_path) {
+      Ok(_) => Ok(()),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+      Err(e) => Err(ArchiveError::InternalError(e.to_string())),
+    }
   }
+
+  /// Get archived UPL by ID
+  pub fn get(&self, upl_id: &str) -> Result<Upl, ArchiveError> {
+    Ok(self.load(upl_id)?.1.upl)
+  }
+
+  /// Get archive object by ID
+  pub fn get_object(&self, upl_id: &str) -> Result<ArchiveObject, ArchiveError> {
+    Ok(self.load(upl_id)?.1)
+  }
+
   // Restore UPL
   // Only a RECEIVER LOCK PROVIDER CAN request a restore process
   // e.g.:  - When a PURCHASE has rolled back, and we take back a product,
   //          then that UPL might be restored into that active and opened Cart
   //        - When a missing UPL was found, we can create a NEW INVENTORY LOG,
   //          and add a FOUND UPL. Then the UPL is going to be restored there.
-  pub fn restore(&self, upl_id: u32) -> Result<Upl, ArchiveError> {
+  // The caller is responsible to set the new Location or Lock
+  // and to store the UPL in the active UPL store.
+  pub fn restore(&self, upl_id: &str) -> Result<Upl, ArchiveError> {
     // 1. Check archive file exist
     // 2. Try load
-    // 3. Try create UPL Object
-    // 4. Set the new Lock type and ID
-    // 5. Remove Archive object file from FS
-    // 6. Return UPL
-    todo!();
+    let (file_path, archive_object) = self.load(upl_id)?;
+    // 3. Remove Archive object file from FS
+    self
+      .remove_file(&file_path)
+      .map_err(|e| ArchiveError::InternalError(e.to_string()))?;
+    // If it cannot be logged, it stays archived
+    if let Err(e) = self.log(LogEntry::Unarchived {
+      upl_id: upl_id.to_string(),
+    }) {
+      self.put(&archive_object)?;
+      return Err(e);
+    }
+    // 4. Return UPL
+    Ok(archive_object.upl)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use gzlib::id::{generate_id, HexHelper, IdKind};
+
+  fn store() -> (tempfile::TempDir, ArchiveStore) {
+    let dir = tempfile::tempdir().unwrap();
+    let store = ArchiveStore::init(dir.path().to_path_buf());
+    (dir, store)
+  }
+
+  fn upl(base: u64) -> Upl {
+    Upl {
+      id: generate_id(base, IdKind::LuhnTwo).to_hex(),
+      ..Upl::default()
+    }
+  }
+
+  #[test]
+  fn test_add_get_restore() {
+    let (_dir, store) = store();
+    let upl = upl(10159851);
+
+    assert!(store
+      .add(upl.clone(), ArchiveReason::Sold, CreatedBy::Technical)
+      .is_ok());
+    assert!(store.contains(&upl.id));
+    // Cannot archive twice
+    assert!(store
+      .add(upl.clone(), ArchiveReason::Sold, CreatedBy::Technical)
+      .is_err());
+    assert_eq!(store.count(), 1);
+    // Counted again at init
+    assert_eq!(ArchiveStore::init(store.path.clone()).count(), 1);
+
+    let archived = store.get_object(&upl.id).unwrap();
+    assert_eq!(archived.get_reason(), &ArchiveReason::Sold);
+    assert_eq!(archived.get_upl().get_history().len(), 1);
+
+    let restored = store.restore(&upl.id).unwrap();
+    assert_eq!(restored.id, upl.id);
+    assert!(!store.contains(&upl.id));
+    assert_eq!(store.count(), 0);
+  }
+
+  #[test]
+  fn test_wrong_id() {
+    let (_dir, store) = store();
+    assert!(store
+      .add(Upl::default(), ArchiveReason::Sold, CreatedBy::Technical)
+      .is_err());
+    assert!(store.get("not_a_luhn_id").is_err());
   }
 }
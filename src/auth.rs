@@ -0,0 +1,520 @@
+use crate::config::AuthConfig;
+use crate::proto::upl_ext::*;
+use crate::upl::CreatedBy;
+use chrono::{DateTime, Utc};
+use gzlib::proto::upl::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::transport::NamedService;
+use tonic::{metadata::MetadataMap, Request, Status};
+
+/// Verified caller UID header
+/// Set by the auth interceptor after a successful authentication,
+/// the same header from the client is always dropped.
+pub const CALLER_UID_HEADER: &str = "x-upl-caller-uid";
+
+/// Permissions of a caller role
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+  // Queries, reports and the change feed
+  Read,
+  // Create, split, divide, open, close and merge UPLs
+  Create,
+  // Set SKU prices
+  Price,
+  // Depreciation and best before corrections
+  Depreciate,
+  // Cart and delivery locks, reservations
+  Lock,
+  // Close carts
+  CloseCart,
+  // Inventory sessions
+  Inventory,
+  // Release an inventory lock outside of closing the session
+  ForceUnlock,
+  // Restore, backup and service status
+  Admin,
+}
+
+/// Required permission of an RPC by its path
+/// Unknown methods require Admin
+pub fn required_permission(path: &str) -> Permission {
+  let method = path.rsplit('/').next().unwrap_or_default();
+  match method {
+    "GetBulk"
+    | "GetById"
+    | "GetByIdArchive"
+    | "GetBySku"
+    | "GetByProduct"
+    | "GetBySkuAndLocation"
+    | "GetByLocation"
+    | "GetLocationInfo"
+    | "GetLocationInfoBulk"
+    | "GetInventory"
+    | "GetIdInfo"
+    | "GetReservations"
+    | "GetHistory"
+    | "SuggestPick"
+    | "GetExpiringReport"
+    | "GetStockValuation"
+    | "Watch" => Permission::Read,
+    "CreateNew"
+    | "CreateNewBulk"
+    | "CreateNewBulkChecked"
+    | "Split"
+    | "Divide"
+    | "OpenUpl"
+    | "CloseUpl"
+    | "MergeBack"
+    | "SetProductUnit"
+    | "SetSkuDivisible" => Permission::Create,
+    "SetSkuPrice" => Permission::Price,
+    "SetBestBefore"
+    | "SetDepreciation"
+    | "RemoveDepreciation"
+    | "SetDepreciationPrice"
+    | "RemoveDeprecationPrice" => Permission::Depreciate,
+    "LockToCart"
+    | "ReleaseLockFromCart"
+    | "LockToDelivery"
+    | "ReleaseLockFromDelivery"
+    | "DispatchDelivery"
+    | "ReceiveDelivery"
+    | "Reserve"
+    | "ReleaseReservations" => Permission::Lock,
+    "CloseCart" => Permission::CloseCart,
+    "OpenInventory" | "ScanInventory" | "CloseInventory" | "DiscardInventoryUpl" => {
+      Permission::Inventory
+    }
+    "ReleaseLockFromInventory" => Permission::ForceUnlock,
+    _ => Permission::Admin,
+  }
+}
+
+/// Verified caller
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+  pub name: String,
+  // UID recorded into the UPL history
+  pub uid: u32,
+  pub permissions: BTreeSet<Permission>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+  Unauthenticated,
+  PermissionDenied(String),
+}
+
+impl From<AuthError> for Status {
+  fn from(error: AuthError) -> Self {
+    match error {
+      AuthError::Unauthenticated => Status::unauthenticated("Hiányzó vagy hibás azonosító token!"),
+      AuthError::PermissionDenied(msg) => Status::permission_denied(msg),
+    }
+  }
+}
+
+// Compare the given signature with the expected one in constant time
+// so the response time does not leak the matching prefix.
+// It walks the given signature only, and wraps around the expected one,
+// so the time does not depend on the expected length either.
+fn token_eq(expected: &[u8], given: &[u8]) -> bool {
+  if expected.is_empty() {
+    return false;
+  }
+  let diff = given
+    .iter()
+    .enumerate()
+    .fold(0u8, |acc, (i, y)| acc | (expected[i % expected.len()] ^ y));
+  diff == 0 && expected.len() == given.len()
+}
+
+// Hex HMAC-SHA256 signature of a token payload
+fn signature(secret: &[u8], payload: &str) -> String {
+  hmac_sha256::HMAC::mac(payload.as_bytes(), secret)
+    .iter()
+    .map(|b| format!("{:02x}", b))
+    .collect()
+}
+
+/// Issue a caller token
+/// Format: CALLER.EXPIRES.SIGNATURE, where EXPIRES is a unix timestamp
+/// and SIGNATURE is the hex HMAC-SHA256 of "CALLER.EXPIRES" by the secret
+pub fn sign(secret: &str, caller: &str, expires_at: DateTime<Utc>) -> String {
+  let payload = format!("{}.{}", caller, expires_at.timestamp());
+  let signature = signature(secret.as_bytes(), &payload);
+  format!("{}.{}", payload, signature)
+}
+
+/// Caller authentication and authorization
+/// Callers send their signed token as "authorization: Bearer TOKEN"
+/// metadata, tokens are issued by the token tool and expire.
+///
+/// A caller is revoked by removing it from the config, all tokens
+/// are revoked by changing the secret.
+pub struct Auth {
+  enabled: bool,
+  secret: Vec<u8>,
+  callers: BTreeMap<String, Identity>,
+}
+
+impl Auth {
+  /// Build from config
+  /// Config must be validated before
+  pub fn new(config: &AuthConfig) -> Self {
+    let callers = config
+      .callers
+      .iter()
+      .map(|(name, caller)| {
+        let permissions = caller
+          .roles
+          .iter()
+          .filter_map(|role| config.roles.get(role))
+          .flatten()
+          .copied()
+          .collect();
+        (
+          name.clone(),
+          Identity {
+            name: name.clone(),
+            uid: caller.uid,
+            permissions,
+          },
+        )
+      })
+      .collect();
+    Self {
+      enabled: config.enabled,
+      secret: config.secret.as_bytes().to_vec(),
+      callers,
+    }
+  }
+
+  /// Returns true if callers must authenticate
+  pub fn is_enabled(&self) -> bool {
+    self.enabled
+  }
+
+  /// Authenticate caller by its bearer token at the given time
+  pub fn authenticate(
+    &self,
+    authorization: Option<&str>,
+    now: DateTime<Utc>,
+  ) -> Result<&Identity, AuthError> {
+    let token = authorization
+      .and_then(|value| value.strip_prefix("Bearer "))
+      .ok_or(AuthError::Unauthenticated)?;
+    let (payload, given) = token.rsplit_once('.').ok_or(AuthError::Unauthenticated)?;
+    if self.secret.is_empty()
+      || !token_eq(
+        signature(&self.secret, payload).as_bytes(),
+        given.as_bytes(),
+      )
+    {
+      return Err(AuthError::Unauthenticated);
+    }
+    let (caller, expires) = payload.split_once('.').ok_or(AuthError::Unauthenticated)?;
+    let expires = expires
+      .parse::<i64>()
+      .map_err(|_| AuthError::Unauthenticated)?;
+    if now.timestamp() >= expires {
+      return Err(AuthError::Unauthenticated);
+    }
+    // Removed callers are revoked even with a valid token
+    self.callers.get(caller).ok_or(AuthError::Unauthenticated)
+  }
+
+  /// Authenticate caller and check its permission for an RPC
+  pub fn authorize(&self, authorization: Option<&str>, path: &str) -> Result<&Identity, AuthError> {
+    self.authorize_at(authorization, path, Utc::now())
+  }
+
+  fn authorize_at(
+    &self,
+    authorization: Option<&str>,
+    path: &str,
+    now: DateTime<Utc>,
+  ) -> Result<&Identity, AuthError> {
+    let identity = self.authenticate(authorization, now)?;
+    let permission = required_permission(path);
+    if !identity.permissions.contains(&permission) {
+      return Err(AuthError::PermissionDenied(format!(
+        "A(z) {} hívónak nincs jogosultsága ehhez: {}",
+        identity.name, path
+      )));
+    }
+    Ok(identity)
+  }
+}
+
+/// Auth interceptor
+/// Wraps a tonic service, authenticates and authorizes every call
+/// by its method, and passes the verified caller UID to the handlers
+/// in the CALLER_UID_HEADER metadata.
+/// When auth is disabled, calls are passed through without a verified UID.
+#[derive(Clone)]
+pub struct Authorized<S> {
+  inner: S,
+  auth: Arc<Auth>,
+}
+
+impl<S> Authorized<S> {
+  pub fn new(inner: S, auth: Arc<Auth>) -> Self {
+    Self { inner, auth }
+  }
+}
+
+impl<S: NamedService> NamedService for Authorized<S> {
+  const NAME: &'static str = S::NAME;
+}
+
+impl<S> tower_service::Service<http::Request<hyper::Body>> for Authorized<S>
+where
+  S: tower_service::Service<http::Request<hyper::Body>, Response = http::Response<BoxBody>>,
+  S::Future: Send + 'static,
+{
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = std::pin::Pin<
+    Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>,
+  >;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, mut req: http::Request<hyper::Body>) -> Self::Future {
+    // Never trust a caller UID sent by the client
+    req.headers_mut().remove(CALLER_UID_HEADER);
+    if self.auth.is_enabled() {
+      let authorization = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+      match self.auth.authorize(authorization, req.uri().path()) {
+        Ok(identity) => {
+          req
+            .headers_mut()
+            .insert(CALLER_UID_HEADER, http::HeaderValue::from(identity.uid));
+        }
+        Err(e) => {
+          let response = Status::from(e).to_http();
+          return Box::pin(async move { Ok(response) });
+        }
+      }
+    }
+    Box::pin(self.inner.call(req))
+  }
+}
+
+/// Get the verified caller UID
+/// None if auth is disabled
+pub fn caller_uid(metadata: &MetadataMap) -> Option<u32> {
+  metadata
+    .get(CALLER_UID_HEADER)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.parse::<u32>().ok())
+}
+
+/// Requests with a created_by field
+pub trait CreatedByField {
+  fn set_created_by(&mut self, uid: u32);
+}
+
+macro_rules! created_by_field {
+  ($($request:ty),*) => {
+    $(
+      impl CreatedByField for $request {
+        fn set_created_by(&mut self, uid: u32) {
+          self.created_by = uid;
+        }
+      }
+    )*
+  };
+}
+
+created_by_field!(
+  UplNew,
+  MergeRequest,
+  SetBestBeforeRequest,
+  SplitRequest,
+  DivideRequest,
+  DepreciationRequest,
+  DepreciationRemoveRequest,
+  DepreciationPriceRequest,
+  RemoveDeprecationPriceRequest,
+  InventoryUnlockRequest,
+  CartLockRequest,
+  CartUnlockRequest,
+  CloseCartRequest,
+  CloseInventoryRequest,
+  DeliveryLockRequest,
+  DeliveryUnlockRequest,
+  DispatchDeliveryRequest,
+  ReceiveDeliveryRequest,
+  OpenInventoryRequest,
+  ScanInventoryRequest,
+  DiscardInventoryUplRequest,
+  RestoreRequest,
+  ReserveRequest
+);
+
+impl CreatedByField for CreateNewBulkRequest {
+  fn set_created_by(&mut self, uid: u32) {
+    for upl in &mut self.upls {
+      upl.created_by = uid;
+    }
+  }
+}
+
+/// Get request message with the verified caller UID
+/// as its created_by, so the history records the verified caller
+/// and not the client supplied field
+pub fn verified<T: CreatedByField>(request: Request<T>) -> T {
+  let uid = caller_uid(request.metadata());
+  let mut message = request.into_inner();
+  if let Some(uid) = uid {
+    message.set_created_by(uid);
+  }
+  message
+}
+
+/// Get request message without a created_by field
+/// with the verified caller to record into the history
+/// CreatedBy::Technical if auth is disabled
+pub fn with_caller<T>(request: Request<T>) -> (T, CreatedBy) {
+  let created_by = match caller_uid(request.metadata()) {
+    Some(uid) => CreatedBy::Uid(uid),
+    None => CreatedBy::Technical,
+  };
+  (request.into_inner(), created_by)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::CallerConfig;
+  use chrono::Duration;
+
+  const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+  fn auth() -> Auth {
+    let mut config = AuthConfig {
+      secret: SECRET.to_string(),
+      ..AuthConfig::default()
+    };
+    config
+      .roles
+      .insert("till".to_string(), vec![Permission::Read, Permission::Lock]);
+    config.callers.insert(
+      "till-1".to_string(),
+      CallerConfig {
+        uid: 42,
+        roles: vec!["till".to_string()],
+      },
+    );
+    Auth::new(&config)
+  }
+
+  #[test]
+  fn test_token_eq() {
+    assert!(token_eq(b"secret", b"secret"));
+    assert!(!token_eq(b"secret", b"secre"));
+    assert!(!token_eq(b"secret", b"secretsecret"));
+    assert!(!token_eq(b"secret", b"secreT"));
+    assert!(!token_eq(b"secret", b""));
+    assert!(!token_eq(b"", b""));
+  }
+
+  #[test]
+  fn test_authorize() {
+    let auth = auth();
+    let now = Utc::now();
+    let token = format!("Bearer {}", sign(SECRET, "till-1", now + Duration::days(1)));
+    let till = Some(token.as_str());
+    assert_eq!(
+      auth
+        .authorize_at(till, "/upl.Upl/LockToCart", now)
+        .unwrap()
+        .uid,
+      42
+    );
+    assert!(matches!(
+      auth.authorize_at(till, "/upl.Upl/SetDepreciation", now),
+      Err(AuthError::PermissionDenied(_))
+    ));
+    assert!(matches!(
+      auth.authorize_at(till, "/upl_ext.UplExt/Unknown", now),
+      Err(AuthError::PermissionDenied(_))
+    ));
+    assert_eq!(
+      auth.authorize_at(None, "/upl.Upl/GetById", now),
+      Err(AuthError::Unauthenticated)
+    );
+  }
+
+  #[test]
+  fn test_authenticate() {
+    let auth = auth();
+    let now = Utc::now();
+    let expires_at = now + Duration::days(1);
+    let bearer = |token: String| format!("Bearer {}", token);
+
+    let valid = bearer(sign(SECRET, "till-1", expires_at));
+    assert_eq!(auth.authenticate(Some(&valid), now).unwrap().uid, 42);
+    assert!(auth.authenticate(Some(&valid[7..]), now).is_err());
+    // Expired
+    assert_eq!(
+      auth.authenticate(Some(&valid), expires_at),
+      Err(AuthError::Unauthenticated)
+    );
+    // Tampered expiry
+    let tampered = valid.replace(
+      &expires_at.timestamp().to_string(),
+      &(expires_at + Duration::days(1)).timestamp().to_string(),
+    );
+    assert!(auth.authenticate(Some(&tampered), now).is_err());
+    // Unknown caller
+    let unknown = bearer(sign(SECRET, "till-2", expires_at));
+    assert!(auth.authenticate(Some(&unknown), now).is_err());
+    // Wrong secret
+    let wrong = bearer(sign("another-secret", "till-1", expires_at));
+    assert!(auth.authenticate(Some(&wrong), now).is_err());
+    assert!(auth.authenticate(Some("Bearer till-1"), now).is_err());
+  }
+
+  #[test]
+  fn test_verified_created_by() {
+    let mut request = Request::new(SetBestBeforeRequest {
+      created_by: 7,
+      ..SetBestBeforeRequest::default()
+    });
+    assert_eq!(verified(request).created_by, 7);
+
+    request = Request::new(SetBestBeforeRequest {
+      created_by: 7,
+      ..SetBestBeforeRequest::default()
+    });
+    request
+      .metadata_mut()
+      .insert(CALLER_UID_HEADER, "42".parse().unwrap());
+    assert_eq!(verified(request).created_by, 42);
+  }
+
+  #[test]
+  fn test_with_caller() {
+    let request = Request::new(OpenUplRequest::default());
+    assert!(matches!(with_caller(request).1, CreatedBy::Technical));
+
+    let mut request = Request::new(OpenUplRequest::default());
+    request
+      .metadata_mut()
+      .insert(CALLER_UID_HEADER, "42".parse().unwrap());
+    assert!(matches!(with_caller(request).1, CreatedBy::Uid(42)));
+  }
+}
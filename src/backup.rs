@@ -0,0 +1,420 @@
+use crate::archive::{ArchiveError, ArchiveObject, ArchiveStore};
+use crate::index::{IndexError, IndexObject, UplIndex};
+use crate::inventory::InventorySession;
+use crate::migration::lock_lease;
+use crate::reservation::Reservation;
+use crate::upl::*;
+use chrono::prelude::*;
+use packman::VecPack;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::Path;
+
+// Backup file header
+// magic (8 bytes), version (u32 LE), crc32 of the payload (u32 LE),
+// payload length (u64 LE), then the bincode payload
+const MAGIC: &[u8; 8] = b"UPLBAK\0\0";
+const HEADER_LEN: usize = 24;
+
+/// Current backup format version
+/// 1: active UPLs and archive
+/// 2: inventories, reservations and the UPL index as well
+/// 3: UPLs with their lock lease
+pub const BACKUP_VERSION: u32 = 3;
+
+#[derive(Debug)]
+pub enum BackupError {
+  IoError(String),
+  InvalidFormat(String),
+  UnsupportedVersion(u32),
+  ChecksumMismatch,
+  AlreadyExist(String),
+  InternalError(String),
+}
+
+impl std::fmt::Display for BackupError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      BackupError::IoError(msg) => write!(f, "Mentés IO hiba: {}", msg),
+      BackupError::InvalidFormat(msg) => write!(f, "Hibás mentés formátum: {}", msg),
+      BackupError::UnsupportedVersion(version) => {
+        write!(f, "Nem támogatott mentés verzió: {}", version)
+      }
+      BackupError::ChecksumMismatch => write!(f, "A mentés ellenőrző összege hibás!"),
+      BackupError::AlreadyExist(msg) => write!(f, "{}", msg),
+      BackupError::InternalError(msg) => write!(f, "{}", msg),
+    }
+  }
+}
+
+impl From<std::io::Error> for BackupError {
+  fn from(error: std::io::Error) -> Self {
+    BackupError::IoError(error.to_string())
+  }
+}
+
+impl From<ArchiveError> for BackupError {
+  fn from(error: ArchiveError) -> Self {
+    BackupError::InternalError(error.to_string())
+  }
+}
+
+impl From<IndexError> for BackupError {
+  fn from(error: IndexError) -> Self {
+    BackupError::InternalError(error.to_string())
+  }
+}
+
+/// Backup content
+/// Every store taken at the same time
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+  pub created_at: DateTime<Utc>,
+  pub upls: Vec<Upl>,
+  pub archived: Vec<ArchiveObject>,
+  pub inventories: Vec<InventorySession>,
+  pub reservations: Vec<Reservation>,
+  pub index: Vec<IndexObject>,
+}
+
+// Version 1 backup content
+#[derive(Deserialize)]
+struct BackupV1 {
+  created_at: DateTime<Utc>,
+  upls: Vec<lock_lease::Upl>,
+  archived: Vec<lock_lease::ArchiveObject>,
+}
+
+impl From<BackupV1> for Backup {
+  fn from(backup: BackupV1) -> Self {
+    // The UPL index is rebuilt from the UPLs when the service starts
+    Self::from(BackupV2 {
+      created_at: backup.created_at,
+      upls: backup.upls,
+      archived: backup.archived,
+      inventories: Vec::new(),
+      reservations: Vec::new(),
+      index: Vec::new(),
+    })
+  }
+}
+
+// Version 2 backup content
+#[derive(Deserialize)]
+struct BackupV2 {
+  created_at: DateTime<Utc>,
+  upls: Vec<lock_lease::Upl>,
+  archived: Vec<lock_lease::ArchiveObject>,
+  inventories: Vec<InventorySession>,
+  reservations: Vec<Reservation>,
+  index: Vec<IndexObject>,
+}
+
+impl From<BackupV2> for Backup {
+  fn from(backup: BackupV2) -> Self {
+    // The lock TTL was not stored, so restored locks never expire
+    Self {
+      created_at: backup.created_at,
+      upls: backup
+        .upls
+        .into_iter()
+        .map(|upl| upl.migrate(None))
+        .collect(),
+      archived: backup
+        .archived
+        .into_iter()
+        .map(|archive_object| archive_object.migrate())
+        .collect(),
+      inventories: backup.inventories,
+      reservations: backup.reservations,
+      index: backup.index,
+    }
+  }
+}
+
+/// Backup file details
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackupInfo {
+  pub version: u32,
+  pub checksum: u32,
+  pub created_at: DateTime<Utc>,
+  pub active_upls: usize,
+  pub archived_upls: usize,
+  pub inventories: usize,
+  pub reservations: usize,
+  pub indexed_upls: usize,
+}
+
+impl Backup {
+  /// Collect backup from copies of the active UPLs, the inventories
+  /// and the reservations, and from the archive and the UPL index
+  /// The caller must make sure the archive and the index
+  /// do not change meanwhile
+  pub fn collect(
+    upls: Vec<Upl>,
+    inventories: Vec<InventorySession>,
+    reservations: Vec<Reservation>,
+    archive: &ArchiveStore,
+    index: &UplIndex,
+  ) -> Result<Self, BackupError> {
+    let mut archived = Vec::new();
+    for upl_id in archive.get_upl_ids()? {
+      archived.push(archive.get_object(&upl_id)?);
+    }
+    Ok(Self {
+      created_at: Utc::now(),
+      upls,
+      archived,
+      inventories,
+      reservations,
+      index: index.get_all()?,
+    })
+  }
+
+  fn info(&self, version: u32, checksum: u32) -> BackupInfo {
+    BackupInfo {
+      version,
+      checksum,
+      created_at: self.created_at,
+      active_upls: self.upls.len(),
+      archived_upls: self.archived.len(),
+      inventories: self.inventories.len(),
+      reservations: self.reservations.len(),
+      indexed_upls: self.index.len(),
+    }
+  }
+
+  /// Write backup into a file
+  pub fn write(&self, path: &Path) -> Result<BackupInfo, BackupError> {
+    if path.exists() {
+      return Err(BackupError::AlreadyExist(format!(
+        "A mentés fájl már létezik! {:?}",
+        path
+      )));
+    }
+    if let Some(parent) = path.parent() {
+      std::fs::create_dir_all(parent)?;
+    }
+
+    let payload =
+      bincode::serialize(self).map_err(|e| BackupError::InternalError(e.to_string()))?;
+    let checksum = crc32fast::hash(&payload);
+
+    // Write a temp file first and rename it,
+    // so we never leave a half written backup behind
+    let tmp_path = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(MAGIC)?;
+    file.write_all(&BACKUP_VERSION.to_le_bytes())?;
+    file.write_all(&checksum.to_le_bytes())?;
+    file.write_all(&(payload.len() as u64).to_le_bytes())?;
+    file.write_all(&payload)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+
+    Ok(self.info(BACKUP_VERSION, checksum))
+  }
+
+  /// Read and verify backup file
+  pub fn read(path: &Path) -> Result<(Self, BackupInfo), BackupError> {
+    let mut file = std::fs::File::open(path)?;
+    let mut header = [0u8; HEADER_LEN];
+    file
+      .read_exact(&mut header)
+      .map_err(|_| BackupError::InvalidFormat("Hiányos fejléc".to_string()))?;
+    if &header[0..8] != MAGIC {
+      return Err(BackupError::InvalidFormat(
+        "Nem UPL mentés fájl".to_string(),
+      ));
+    }
+    let version = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    if version == 0 || version > BACKUP_VERSION {
+      return Err(BackupError::UnsupportedVersion(version));
+    }
+    let checksum = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+    let mut len = [0u8; 8];
+    len.copy_from_slice(&header[16..24]);
+    let len = u64::from_le_bytes(len) as usize;
+
+    let mut payload = Vec::new();
+    file.read_to_end(&mut payload)?;
+    if payload.len() != len {
+      return Err(BackupError::InvalidFormat("Hiányos tartalom".to_string()));
+    }
+    if crc32fast::hash(&payload) != checksum {
+      return Err(BackupError::ChecksumMismatch);
+    }
+    let backup: Backup = match version {
+      1 => bincode::deserialize::<BackupV1>(&payload).map(|backup| backup.into()),
+      2 => bincode::deserialize::<BackupV2>(&payload).map(|backup| backup.into()),
+      _ => bincode::deserialize(&payload),
+    }
+    .map_err(|e| BackupError::InvalidFormat(e.to_string()))?;
+    let info = backup.info(version, checksum);
+    Ok((backup, info))
+  }
+
+  /// Restore backup into a fresh data folder
+  /// It creates {data}/upls, {data}/archive, {data}/inventories,
+  /// {data}/reservations and {data}/upl_index.
+  /// The event log is rebuilt from them when the service starts.
+  pub fn restore(&self, data: &Path) -> Result<(), BackupError> {
+    if data.exists() && data.read_dir()?.next().is_some() {
+      return Err(BackupError::AlreadyExist(format!(
+        "A cél mappa nem üres! {:?}",
+        data
+      )));
+    }
+
+    let mut upls: VecPack<Upl> = VecPack::load_or_init(data.join("upls"))
+      .map_err(|e| BackupError::InternalError(e.to_string()))?;
+    for upl in &self.upls {
+      upls
+        .insert(upl.clone())
+        .map_err(|e| BackupError::InternalError(format!("{} {}", e, upl.id)))?;
+    }
+
+    let archive = ArchiveStore::init(data.join("archive"));
+    for archive_object in &self.archived {
+      archive.put(archive_object)?;
+    }
+
+    let mut inventories: VecPack<InventorySession> =
+      VecPack::load_or_init(data.join("inventories"))
+        .map_err(|e| BackupError::InternalError(e.to_string()))?;
+    for inventory in &self.inventories {
+      inventories
+        .insert(inventory.clone())
+        .map_err(|e| BackupError::InternalError(e.to_string()))?;
+    }
+
+    let mut reservations: VecPack<Reservation> =
+      VecPack::load_or_init(data.join("reservations"))
+        .map_err(|e| BackupError::InternalError(e.to_string()))?;
+    for reservation in &self.reservations {
+      reservations
+        .insert(reservation.clone())
+        .map_err(|e| BackupError::InternalError(e.to_string()))?;
+    }
+
+    // A version 1 backup has no index, so it is rebuilt
+    // from the UPLs when the service starts
+    if !self.index.is_empty() {
+      let index = UplIndex::init(data.join("upl_index"));
+      for index_object in &self.index {
+        index.put(index_object)?;
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::inventory::InventoryMethods;
+  use crate::reservation::{ReservationMethods, Scope, Subject};
+  use gzlib::id::{generate_id, HexHelper, IdKind};
+
+  fn upl(base: u64) -> Upl {
+    Upl {
+      id: generate_id(base, IdKind::LuhnTwo).to_hex(),
+      ..Upl::default()
+    }
+  }
+
+  #[test]
+  fn test_write_read_restore() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let archive = ArchiveStore::init(dir.join("archive"));
+    archive
+      .add(
+        upl(2),
+        crate::archive::ArchiveReason::Sold,
+        CreatedBy::Technical,
+      )
+      .unwrap();
+
+    let index = UplIndex::init(dir.join("upl_index"));
+    index.add(&upl(1)).unwrap();
+    index.add(&upl(2)).unwrap();
+
+    let backup = Backup::collect(
+      vec![upl(1)],
+      vec![InventorySession::new(1, 1, vec![upl(1).id], 0)],
+      vec![Reservation::new(
+        "r".to_string(),
+        "cart".to_string(),
+        Subject::Sku(1),
+        Scope::Global,
+        1,
+        0,
+      )],
+      &archive,
+      &index,
+    )
+    .unwrap();
+    let path = dir.join("backup.bak");
+    let info = backup.write(&path).unwrap();
+    assert_eq!(info.active_upls, 1);
+    assert_eq!(info.archived_upls, 1);
+    assert_eq!(info.inventories, 1);
+    assert_eq!(info.reservations, 1);
+    assert_eq!(info.indexed_upls, 2);
+    assert!(backup.write(&path).is_err());
+
+    let (restored, restored_info) = Backup::read(&path).unwrap();
+    assert_eq!(restored_info, info);
+    restored.restore(&dir.join("restored")).unwrap();
+    let restored_archive = ArchiveStore::init(dir.join("restored/archive"));
+    assert!(restored_archive.contains(&upl(2).id));
+    let restored_index = UplIndex::init(dir.join("restored/upl_index"));
+    assert!(restored_index.contains(&upl(1).id) && restored_index.contains(&upl(2).id));
+    let reservations: VecPack<Reservation> =
+      VecPack::load_or_init(dir.join("restored/reservations")).unwrap();
+    assert_eq!(reservations.len(), 1);
+    let inventories: VecPack<InventorySession> =
+      VecPack::load_or_init(dir.join("restored/inventories")).unwrap();
+    assert_eq!(inventories.len(), 1);
+    // Target must be fresh
+    assert!(restored.restore(&dir.join("restored")).is_err());
+
+    // Version 1 backup with UPLs before the lock lease is still readable
+    let old_upls = backup
+      .upls
+      .iter()
+      .map(|upl| lock_lease::Upl::from(upl.clone()))
+      .collect::<Vec<_>>();
+    let old_archived = backup
+      .archived
+      .iter()
+      .map(lock_lease::ArchiveObject::from)
+      .collect::<Vec<_>>();
+    let v1 = bincode::serialize(&(backup.created_at, &old_upls, &old_archived)).unwrap();
+    let v1_path = dir.join("backup_v1.bak");
+    let mut file = std::fs::File::create(&v1_path).unwrap();
+    file.write_all(MAGIC).unwrap();
+    file.write_all(&1u32.to_le_bytes()).unwrap();
+    file.write_all(&crc32fast::hash(&v1).to_le_bytes()).unwrap();
+    file.write_all(&(v1.len() as u64).to_le_bytes()).unwrap();
+    file.write_all(&v1).unwrap();
+    drop(file);
+    let (v1_backup, v1_info) = Backup::read(&v1_path).unwrap();
+    assert_eq!(v1_info.version, 1);
+    assert_eq!(v1_info.active_upls, 1);
+    assert_eq!(v1_info.archived_upls, 1);
+    assert!(v1_backup.index.is_empty());
+
+    // Corrupted backup
+    let mut content = std::fs::read(&path).unwrap();
+    let last = content.len() - 1;
+    content[last] ^= 0xff;
+    std::fs::write(&path, content).unwrap();
+    assert!(matches!(
+      Backup::read(&path),
+      Err(BackupError::ChecksumMismatch)
+    ));
+  }
+}
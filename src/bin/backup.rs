@@ -0,0 +1,83 @@
+use packman::*;
+use std::path::PathBuf;
+use upl_microservice::{archive, backup, config, index, inventory, reservation, upl};
+
+// UPL data backup tool
+//
+// Usage:
+//   backup create BACKUP_FILE
+//     Create a backup from the configured data folder.
+//     Only when the service is stopped, otherwise use the CreateBackup RPC.
+//   backup verify BACKUP_FILE
+//     Check the backup version and checksum
+//   backup restore BACKUP_FILE TARGET_FOLDER
+//     Restore a backup into a fresh data folder
+fn main() {
+  let args = std::env::args().collect::<Vec<String>>();
+  match (args.get(1).map(|a| a.as_str()), args.get(2), args.get(3)) {
+    (Some("create"), Some(file), None) => create(PathBuf::from(file)),
+    (Some("verify"), Some(file), None) => {
+      let (_, info) = read(PathBuf::from(file));
+      print_info(&info);
+    }
+    (Some("restore"), Some(file), Some(target)) => {
+      let (backup, info) = read(PathBuf::from(file));
+      print_info(&info);
+      backup
+        .restore(&PathBuf::from(target))
+        .unwrap_or_else(|e| exit(&format!("Error while restoring backup: {}", e)));
+      println!("Backup restored into {}", target);
+    }
+    _ => exit(
+      "Usage: backup create BACKUP_FILE | verify BACKUP_FILE | restore BACKUP_FILE TARGET_FOLDER",
+    ),
+  }
+}
+
+fn exit(msg: &str) -> ! {
+  println!("{}", msg);
+  std::process::exit(1);
+}
+
+fn read(file: PathBuf) -> (backup::Backup, backup::BackupInfo) {
+  backup::Backup::read(&file).unwrap_or_else(|e| exit(&format!("Invalid backup: {}", e)))
+}
+
+fn print_info(info: &backup::BackupInfo) {
+  println!(
+    "Backup version {}, checksum {:08x}, created at {}, {} active and {} archived UPL(s), \
+     {} inventory session(s), {} reservation(s), {} indexed UPL ID(s)",
+    info.version,
+    info.checksum,
+    info.created_at.to_rfc3339(),
+    info.active_upls,
+    info.archived_upls,
+    info.inventories,
+    info.reservations,
+    info.indexed_upls
+  );
+}
+
+fn create(file: PathBuf) {
+  let config = config::Config::load().unwrap_or_else(|e| exit(&e.to_string()));
+  let upl_db: VecPack<upl::Upl> = VecPack::load_or_init(config.paths.upls())
+    .unwrap_or_else(|e| exit(&format!("Error while loading UPL database: {}", e)));
+  let inventory_db: VecPack<inventory::InventorySession> =
+    VecPack::load_or_init(config.paths.inventories())
+      .unwrap_or_else(|e| exit(&format!("Error while loading inventory database: {}", e)));
+  let reservation_db: VecPack<reservation::Reservation> =
+    VecPack::load_or_init(config.paths.reservations())
+      .unwrap_or_else(|e| exit(&format!("Error while loading reservation database: {}", e)));
+  let archive_db = archive::ArchiveStore::init(config.paths.archive());
+  let index_db = index::UplIndex::init(config.paths.upl_index());
+  let info = backup::Backup::collect(
+    upl_db.iter().map(|upl| upl.unpack().clone()).collect(),
+    inventory_db.iter().map(|i| i.unpack().clone()).collect(),
+    reservation_db.iter().map(|r| r.unpack().clone()).collect(),
+    &archive_db,
+    &index_db,
+  )
+  .and_then(|backup| backup.write(&file))
+  .unwrap_or_else(|e| exit(&format!("Error while creating backup: {}", e)));
+  print_info(&info);
+}
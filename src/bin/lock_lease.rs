@@ -0,0 +1,58 @@
+use packman::*;
+use std::path::PathBuf;
+use upl_microservice::{config, migration::lock_lease, upl};
+
+// Migrate the active UPL store to UPLs with their lock lease
+//
+// Usage: lock_lease TARGET_FOLDER
+//
+// Held locks get the TTL of the current config, acquired at their last
+// lock event. The result is written into TARGET_FOLDER/upls, so the live
+// data is never overwritten. TARGET_FOLDER must not exist.
+// The archive store needs no migration, its YAML files read the missing
+// lease as None. The legacy upl_archive store is read by the old scheme
+// and migrated when the service moves it into the archive store. The event log of the old UPLs cannot be
+// replayed, so move it away as well, the service writes a new baseline
+// into an empty event log when it starts.
+fn main() {
+  let args = std::env::args().collect::<Vec<String>>();
+  if args.len() < 2 {
+    println!("Usage: lock_lease TARGET_FOLDER");
+    std::process::exit(1);
+  }
+
+  let target = PathBuf::from(&args[1]);
+  if target.exists() {
+    println!("Target folder already exists: {:?}", target);
+    std::process::exit(1);
+  }
+
+  let config = config::Config::load().unwrap_or_else(|e| panic!("{}", e));
+
+  // Load old UPLs
+  let old_upl_db: VecPack<lock_lease::Upl> =
+    VecPack::load_or_init(config.paths.upls()).expect("Error while loading old UPLs db");
+
+  // Convert old UPLs into the new UPL DB
+  let mut upl_db: VecPack<upl::Upl> =
+    VecPack::load_or_init(target.join("upls")).expect("Error while creating UPL database");
+  let mut leases = 0;
+  for old_upl in old_upl_db.iter() {
+    let old_upl = old_upl.unpack().clone();
+    let ttl_minutes = config.lock_timeouts.ttl_minutes(&old_upl.lock);
+    let upl = old_upl.migrate(ttl_minutes);
+    if upl.lock_lease.is_some() {
+      leases += 1;
+    }
+    let upl_id = upl.id.clone();
+    upl_db
+      .insert(upl)
+      .unwrap_or_else(|_| panic!("Error while inserting UPL: {}", upl_id));
+  }
+
+  println!(
+    "Migrated {} UPL(s), {} with a lock lease",
+    upl_db.len(),
+    leases
+  );
+}
@@ -84,10 +84,10 @@ fn main() {
         },
         sku.get_divisible_amount(),
         sku.can_divide,
-        price.net_retail_price,
+        price.net_retail_price.into(),
         upl_microservice::upl::VAT::from_str(&price.vat.to_string()).expect("Error converting VAT"),
         ou.procurement_id,
-        ou.procurement_net_price,
+        ou.procurement_net_price.into(),
         match &ou.location {
           migration::upl_old::Location::Stock(id) => upl_microservice::upl::Location::Stock(*id),
           migration::upl_old::Location::Delivery(id) => {
@@ -113,6 +113,8 @@ fn main() {
           } => true,
           _ => false,
         },
+        // The legacy data was priced by the default VAT rates
+        &upl_microservice::upl::VatRates::default(),
         ou.created_by,
       )
       .expect(&format!("UPL nem hozható létre! ID: {}", ou.id));
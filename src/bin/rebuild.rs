@@ -0,0 +1,76 @@
+use chrono::{DateTime, Utc};
+use packman::*;
+use std::path::PathBuf;
+use upl_microservice::{archive, config, eventlog, upl};
+
+// Rebuild the active UPL store and the archive from the event log
+//
+// Usage: rebuild TARGET_FOLDER [UNTIL]
+//
+// UNTIL is an RFC3339 time for a point in time reconstruction,
+// without it the latest state is rebuilt. It cannot be earlier than
+// the oldest kept snapshot, as the log is compacted to it.
+// The log keeps only the IDs of the archived UPLs, so they are copied
+// from the replayed records, or else from the live archive.
+// The result is written into TARGET_FOLDER/upls and TARGET_FOLDER/archive,
+// so the live data is never overwritten. TARGET_FOLDER must not exist.
+fn main() {
+  let args = std::env::args().collect::<Vec<String>>();
+  if args.len() < 2 {
+    println!("Usage: rebuild TARGET_FOLDER [UNTIL]");
+    std::process::exit(1);
+  }
+
+  let target = PathBuf::from(&args[1]);
+  if target.exists() {
+    println!("Target folder already exists: {:?}", target);
+    std::process::exit(1);
+  }
+
+  let until: Option<DateTime<Utc>> = args.get(2).map(|until| {
+    DateTime::parse_from_rfc3339(until)
+      .expect("Error while parsing UNTIL as RFC3339")
+      .with_timezone(&Utc)
+  });
+
+  // Replay event log
+  let config = config::Config::load().unwrap_or_else(|e| panic!("{}", e));
+  let state =
+    eventlog::replay(&config.paths.upl_log(), until).expect("Error while replaying event log");
+
+  // Rebuild active UPLs
+  let mut upl_db: VecPack<upl::Upl> =
+    VecPack::load_or_init(target.join("upls")).expect("Error while creating UPL database");
+  for (upl_id, upl) in state.active {
+    upl_db
+      .insert(upl)
+      .unwrap_or_else(|_| panic!("Error while inserting UPL: {}", upl_id));
+  }
+
+  // Rebuild archive
+  let live_archive_db = archive::ArchiveStore::init(config.paths.archive());
+  let archive_db = archive::ArchiveStore::init(target.join("archive"));
+  let mut missing = 0;
+  for upl_id in &state.archived {
+    let put = match state.recent_archive.get(upl_id) {
+      Some(archive_object) => archive_db.put(archive_object),
+      None => match live_archive_db.get_object(upl_id) {
+        Ok(archive_object) => archive_db.put(&archive_object),
+        Err(_) => {
+          println!("Archived UPL is missing from the live archive: {}", upl_id);
+          missing += 1;
+          continue;
+        }
+      },
+    };
+    put.unwrap_or_else(|_| panic!("Error while archiving UPL: {}", upl_id));
+  }
+
+  println!(
+    "Rebuilt from event log until sequence number {}: {} active and {} archived UPL(s), {} missing",
+    state.seq,
+    upl_db.len(),
+    state.archived.len() - missing,
+    missing
+  );
+}
@@ -0,0 +1,38 @@
+use chrono::{Duration, Utc};
+use upl_microservice::{auth, config};
+
+// Issue a signed caller token
+//
+// Usage: token CALLER DAYS
+//
+// CALLER must be configured under auth.callers, and the token is signed
+// by auth.secret (or UPL_AUTH_SECRET), so it is only valid for services
+// with the same secret. The token expires after DAYS days.
+fn main() {
+  let args = std::env::args().collect::<Vec<String>>();
+  if args.len() < 3 {
+    println!("Usage: token CALLER DAYS");
+    std::process::exit(1);
+  }
+
+  let caller = &args[1];
+  let days: i64 = args[2]
+    .parse()
+    .ok()
+    .filter(|days| *days > 0)
+    .expect("DAYS must be a positive number");
+
+  let config = config::Config::load().unwrap_or_else(|e| panic!("{}", e));
+  if !config.auth.callers.contains_key(caller) {
+    println!("Unknown caller: {}", caller);
+    std::process::exit(1);
+  }
+  if config.auth.secret.len() < 32 {
+    println!("auth.secret must be at least 32 characters");
+    std::process::exit(1);
+  }
+
+  let expires_at = Utc::now() + Duration::days(days);
+  println!("{}", auth::sign(&config.auth.secret, caller, expires_at));
+  println!("Expires at: {}", expires_at.to_rfc3339());
+}
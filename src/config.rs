@@ -0,0 +1,586 @@
+use crate::auth::Permission;
+use crate::markdown::MarkdownRule;
+use crate::upl::{Lock, VatRates, VAT};
+use chrono::NaiveTime;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// Default config file path
+/// It can be changed by the UPL_CONFIG env var
+pub const DEFAULT_CONFIG_PATH: &str = "upl.yaml";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError(pub String);
+
+impl std::fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+/// Data paths
+/// Unset paths are inside the data folder
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Paths {
+  pub data: PathBuf,
+  pub upls: Option<PathBuf>,
+  pub archive: Option<PathBuf>,
+  // Legacy VecPack archive, moved into the archive at startup
+  pub legacy_archive: Option<PathBuf>,
+  pub inventories: Option<PathBuf>,
+  pub upl_index: Option<PathBuf>,
+  pub reservations: Option<PathBuf>,
+  pub upl_log: Option<PathBuf>,
+  pub backups: Option<PathBuf>,
+}
+
+impl Default for Paths {
+  fn default() -> Self {
+    Self {
+      data: PathBuf::from("data"),
+      upls: None,
+      archive: None,
+      legacy_archive: None,
+      inventories: None,
+      upl_index: None,
+      reservations: None,
+      upl_log: None,
+      backups: None,
+    }
+  }
+}
+
+impl Paths {
+  fn get(&self, path: &Option<PathBuf>, name: &str) -> PathBuf {
+    path.clone().unwrap_or_else(|| self.data.join(name))
+  }
+  pub fn upls(&self) -> PathBuf {
+    self.get(&self.upls, "upls")
+  }
+  pub fn archive(&self) -> PathBuf {
+    self.get(&self.archive, "archive")
+  }
+  pub fn legacy_archive(&self) -> PathBuf {
+    self.get(&self.legacy_archive, "upl_archive")
+  }
+  pub fn inventories(&self) -> PathBuf {
+    self.get(&self.inventories, "inventories")
+  }
+  pub fn upl_index(&self) -> PathBuf {
+    self.get(&self.upl_index, "upl_index")
+  }
+  pub fn reservations(&self) -> PathBuf {
+    self.get(&self.reservations, "reservations")
+  }
+  pub fn upl_log(&self) -> PathBuf {
+    self.get(&self.upl_log, "upl_log")
+  }
+  pub fn backups(&self) -> PathBuf {
+    self.get(&self.backups, "backups")
+  }
+}
+
+/// Automatic markdown settings
+/// The job has no default rules, they must be set when it is enabled.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarkdownConfig {
+  // Rules like "3:30,0:50" (days left:percent off)
+  pub rules: String,
+  // Depreciation ID of the automatic markdowns
+  pub depreciation_id: u32,
+  // Local time of the daily run, like "02:00"
+  pub run_at: String,
+}
+
+impl Default for MarkdownConfig {
+  fn default() -> Self {
+    Self {
+      rules: String::new(),
+      depreciation_id: 0,
+      run_at: "02:00".to_string(),
+    }
+  }
+}
+
+/// Event log settings
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventLogConfig {
+  pub snapshot_minutes: u64,
+}
+
+impl Default for EventLogConfig {
+  fn default() -> Self {
+    Self {
+      snapshot_minutes: 60,
+    }
+  }
+}
+
+/// Lock timeouts in minutes
+/// The TTL a new lock is acquired with, 0 means the lock never expires.
+/// Each lock keeps its own TTL, so a change applies to new locks only.
+/// Expired cart and delivery locks are released by the lock reaper,
+/// inventory locks never expire.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockTimeouts {
+  pub cart_minutes: u64,
+  pub delivery_minutes: u64,
+  // Lock reaper interval
+  pub reaper_minutes: u64,
+}
+
+impl Default for LockTimeouts {
+  fn default() -> Self {
+    Self {
+      cart_minutes: 0,
+      delivery_minutes: 0,
+      reaper_minutes: 1,
+    }
+  }
+}
+
+impl LockTimeouts {
+  /// Get the TTL of a new lock in minutes
+  /// None if it never expires
+  pub fn ttl_minutes(&self, lock: &Lock) -> Option<u64> {
+    let minutes = match lock {
+      Lock::Cart(_) => self.cart_minutes,
+      Lock::Delivery(_) => self.delivery_minutes,
+      Lock::Inventory(_) | Lock::None => 0,
+    };
+    match minutes {
+      0 => None,
+      minutes => Some(minutes),
+    }
+  }
+}
+
+/// Caller identity
+/// Its tokens are issued by the token tool
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CallerConfig {
+  // UID recorded into the UPL history
+  pub uid: u32,
+  pub roles: Vec<String>,
+}
+
+/// Caller authentication and authorization
+/// Enabled by default, a dev config can disable it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+  pub enabled: bool,
+  // Token signing secret
+  pub secret: String,
+  // Role name -> permissions
+  pub roles: BTreeMap<String, Vec<Permission>>,
+  // Caller name -> identity
+  pub callers: BTreeMap<String, CallerConfig>,
+}
+
+impl Default for AuthConfig {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      secret: String::new(),
+      roles: BTreeMap::new(),
+      callers: BTreeMap::new(),
+    }
+  }
+}
+
+impl AuthConfig {
+  fn validate(&self, errors: &mut Vec<String>) {
+    if !self.enabled {
+      return;
+    }
+    if self.secret.len() < 32 {
+      errors.push("auth.secret must be at least 32 characters".to_string());
+    }
+    if self.callers.is_empty() {
+      errors.push("auth.callers must not be empty when auth is enabled".to_string());
+    }
+    for (name, caller) in &self.callers {
+      // The token holds the caller name before its dot separated parts
+      if name.is_empty() || name.contains('.') {
+        errors.push(format!(
+          "auth.callers.{} name must not be empty or contain a dot",
+          name
+        ));
+      }
+      for role in &caller.roles {
+        if !self.roles.contains_key(role) {
+          errors.push(format!(
+            "auth.callers.{} has an unknown role: {}",
+            name, role
+          ));
+        }
+      }
+    }
+  }
+}
+
+/// Feature toggles
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+  // Daily automatic markdown job
+  pub markdown_job: bool,
+  // Prometheus metrics endpoint
+  pub metrics: bool,
+}
+
+impl Default for Features {
+  fn default() -> Self {
+    Self {
+      markdown_job: false,
+      metrics: true,
+    }
+  }
+}
+
+/// Service config
+/// Loaded from a YAML file, then overridden by env vars.
+/// Every field is optional in the file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  // gRPC listen address
+  pub listen_addr: String,
+  // Metrics HTTP listen address
+  pub metrics_addr: String,
+  pub paths: Paths,
+  // Buffer size of the gRPC response streams
+  pub stream_buffer: usize,
+  // Number of change events kept for resuming watchers
+  pub feed_capacity: usize,
+  // VAT rates in percent by VAT code, e.g. "27": 27
+  // Missing VAT codes keep their default rate
+  pub vat: BTreeMap<String, u32>,
+  pub markdown: MarkdownConfig,
+  pub event_log: EventLogConfig,
+  pub lock_timeouts: LockTimeouts,
+  pub auth: AuthConfig,
+  pub features: Features,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      listen_addr: "[::1]:50064".to_string(),
+      metrics_addr: "[::1]:50065".to_string(),
+      paths: Paths::default(),
+      stream_buffer: 100,
+      feed_capacity: 10_000,
+      vat: BTreeMap::new(),
+      markdown: MarkdownConfig::default(),
+      event_log: EventLogConfig::default(),
+      lock_timeouts: LockTimeouts::default(),
+      auth: AuthConfig::default(),
+      features: Features::default(),
+    }
+  }
+}
+
+// Parse env var value
+fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, ConfigError> {
+  value
+    .trim()
+    .parse::<T>()
+    .map_err(|_| ConfigError(format!("Invalid value for {}: {}", key, value)))
+}
+
+impl Config {
+  /// Load config
+  /// From the UPL_CONFIG file or from upl.yaml if it exists,
+  /// then apply the env var overrides and validate it.
+  pub fn load() -> Result<Self, ConfigError> {
+    let mut config = match std::env::var("UPL_CONFIG") {
+      Ok(path) => Self::from_file(Path::new(&path))?,
+      Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+        Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+      }
+      Err(_) => Self::default(),
+    };
+    config.apply_env(|key| std::env::var(key).ok())?;
+    config.validate()?;
+    Ok(config)
+  }
+
+  /// Load config from a YAML file
+  pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+    let content = std::fs::read_to_string(path)
+      .map_err(|e| ConfigError(format!("Cannot read config file {:?}: {}", path, e)))?;
+    serde_yaml::from_str(&content)
+      .map_err(|e| ConfigError(format!("Invalid config file {:?}: {}", path, e)))
+  }
+
+  /// Apply env var overrides
+  ///   SERVICE_ADDR_UPL, METRICS_ADDR_UPL, UPL_DATA_DIR, UPL_STREAM_BUFFER, UPL_FEED_CAPACITY,
+  ///   UPL_VAT (e.g. "5:5,18:18,27:27"), MARKDOWN_RULES, MARKDOWN_DEPRECIATION_ID,
+  ///   MARKDOWN_RUN_AT, EVENT_LOG_SNAPSHOT_MINUTES, UPL_LOCK_TIMEOUT_CART_MINUTES,
+  ///   UPL_LOCK_TIMEOUT_DELIVERY_MINUTES, UPL_LOCK_REAPER_MINUTES,
+  ///   UPL_AUTH_ENABLED, UPL_AUTH_SECRET,
+  ///   UPL_FEATURE_MARKDOWN_JOB, UPL_FEATURE_METRICS
+  pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+    if let Some(v) = var("SERVICE_ADDR_UPL") {
+      self.listen_addr = v;
+    }
+    if let Some(v) = var("METRICS_ADDR_UPL") {
+      self.metrics_addr = v;
+    }
+    if let Some(v) = var("UPL_DATA_DIR") {
+      self.paths.data = PathBuf::from(v);
+    }
+    if let Some(v) = var("UPL_STREAM_BUFFER") {
+      self.stream_buffer = parse("UPL_STREAM_BUFFER", &v)?;
+    }
+    if let Some(v) = var("UPL_FEED_CAPACITY") {
+      self.feed_capacity = parse("UPL_FEED_CAPACITY", &v)?;
+    }
+    if let Some(v) = var("UPL_VAT") {
+      for rate in v.split(',').filter(|r| !r.trim().is_empty()) {
+        let mut parts = rate.split(':');
+        match (parts.next(), parts.next(), parts.next()) {
+          (Some(code), Some(percent), None) => {
+            self
+              .vat
+              .insert(code.trim().to_string(), parse("UPL_VAT", percent)?);
+          }
+          _ => return Err(ConfigError(format!("Invalid value for UPL_VAT: {}", v))),
+        }
+      }
+    }
+    if let Some(v) = var("MARKDOWN_RULES") {
+      self.markdown.rules = v;
+    }
+    if let Some(v) = var("MARKDOWN_DEPRECIATION_ID") {
+      self.markdown.depreciation_id = parse("MARKDOWN_DEPRECIATION_ID", &v)?;
+    }
+    if let Some(v) = var("MARKDOWN_RUN_AT") {
+      self.markdown.run_at = v;
+    }
+    if let Some(v) = var("EVENT_LOG_SNAPSHOT_MINUTES") {
+      self.event_log.snapshot_minutes = parse("EVENT_LOG_SNAPSHOT_MINUTES", &v)?;
+    }
+    if let Some(v) = var("UPL_LOCK_TIMEOUT_CART_MINUTES") {
+      self.lock_timeouts.cart_minutes = parse("UPL_LOCK_TIMEOUT_CART_MINUTES", &v)?;
+    }
+    if let Some(v) = var("UPL_LOCK_TIMEOUT_DELIVERY_MINUTES") {
+      self.lock_timeouts.delivery_minutes = parse("UPL_LOCK_TIMEOUT_DELIVERY_MINUTES", &v)?;
+    }
+    if let Some(v) = var("UPL_LOCK_REAPER_MINUTES") {
+      self.lock_timeouts.reaper_minutes = parse("UPL_LOCK_REAPER_MINUTES", &v)?;
+    }
+    if let Some(v) = var("UPL_AUTH_ENABLED") {
+      self.auth.enabled = parse("UPL_AUTH_ENABLED", &v)?;
+    }
+    // The secret can be kept out of the config file
+    if let Some(v) = var("UPL_AUTH_SECRET") {
+      self.auth.secret = v;
+    }
+    if let Some(v) = var("UPL_FEATURE_MARKDOWN_JOB") {
+      self.features.markdown_job = parse("UPL_FEATURE_MARKDOWN_JOB", &v)?;
+    }
+    if let Some(v) = var("UPL_FEATURE_METRICS") {
+      self.features.metrics = parse("UPL_FEATURE_METRICS", &v)?;
+    }
+    Ok(())
+  }
+
+  /// Validate config
+  /// Returns all the problems in one error
+  pub fn validate(&self) -> Result<(), ConfigError> {
+    let mut errors: Vec<String> = Vec::new();
+    if self.listen_addr.parse::<SocketAddr>().is_err() {
+      errors.push(format!(
+        "listen_addr is not a valid address: {}",
+        self.listen_addr
+      ));
+    }
+    if self.metrics_addr.parse::<SocketAddr>().is_err() {
+      errors.push(format!(
+        "metrics_addr is not a valid address: {}",
+        self.metrics_addr
+      ));
+    }
+    if self.stream_buffer == 0 {
+      errors.push("stream_buffer must be greater than 0".to_string());
+    }
+    if self.feed_capacity == 0 {
+      errors.push("feed_capacity must be greater than 0".to_string());
+    }
+    if let Err(e) = self.vat_rates() {
+      errors.push(e.0);
+    }
+    match self.markdown_rules() {
+      Ok(rules) if rules.is_empty() && self.features.markdown_job => {
+        errors.push("markdown.rules must be set for the markdown job".to_string())
+      }
+      Ok(_) => (),
+      Err(e) => errors.push(e.0),
+    }
+    if self.markdown.depreciation_id == 0 && self.features.markdown_job {
+      errors.push("markdown.depreciation_id must be set for the markdown job".to_string());
+    }
+    if let Err(e) = self.markdown_run_at() {
+      errors.push(e.0);
+    }
+    if self.event_log.snapshot_minutes == 0 {
+      errors.push("event_log.snapshot_minutes must be greater than 0".to_string());
+    }
+    if self.lock_timeouts.reaper_minutes == 0 {
+      errors.push("lock_timeouts.reaper_minutes must be greater than 0".to_string());
+    }
+    self.auth.validate(&mut errors);
+    match errors.is_empty() {
+      true => Ok(()),
+      false => Err(ConfigError(format!(
+        "Invalid config:\n  - {}",
+        errors.join("\n  - ")
+      ))),
+    }
+  }
+
+  /// Get listen address
+  pub fn listen_addr(&self) -> Result<SocketAddr, ConfigError> {
+    self.listen_addr.parse().map_err(|_| {
+      ConfigError(format!(
+        "listen_addr is not a valid address: {}",
+        self.listen_addr
+      ))
+    })
+  }
+
+  /// Get metrics listen address
+  pub fn metrics_addr(&self) -> Result<SocketAddr, ConfigError> {
+    self.metrics_addr.parse().map_err(|_| {
+      ConfigError(format!(
+        "metrics_addr is not a valid address: {}",
+        self.metrics_addr
+      ))
+    })
+  }
+
+  /// Get VAT rates table
+  pub fn vat_rates(&self) -> Result<VatRates, ConfigError> {
+    let mut rates = HashMap::new();
+    for (code, rate) in &self.vat {
+      let vat = VAT::from_str(code)
+        .map_err(|_| ConfigError(format!("vat has an unknown VAT code: {}", code)))?;
+      if *rate > 100 {
+        return Err(ConfigError(format!(
+          "vat rate must be at most 100: {}: {}",
+          code, rate
+        )));
+      }
+      rates.insert(vat, *rate);
+    }
+    Ok(VatRates::new(rates))
+  }
+
+  /// Get markdown rules
+  pub fn markdown_rules(&self) -> Result<Vec<MarkdownRule>, ConfigError> {
+    MarkdownRule::parse_list(&self.markdown.rules)
+      .map_err(|e| ConfigError(format!("markdown.rules is invalid: {}", e)))
+  }
+
+  /// Get local time of the daily markdown run
+  pub fn markdown_run_at(&self) -> Result<NaiveTime, ConfigError> {
+    NaiveTime::parse_from_str(&self.markdown.run_at, "%H:%M").map_err(|_| {
+      ConfigError(format!(
+        "markdown.run_at is not a valid time: {}",
+        self.markdown.run_at
+      ))
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_file_env_and_validation() {
+    let mut config: Config = serde_yaml::from_str(
+      "
+listen_addr: 0.0.0.0:50064
+paths:
+  data: /var/upl
+  archive: /mnt/archive
+vat:
+  \"27\": 27
+features:
+  markdown_job: false
+auth:
+  enabled: false
+",
+    )
+    .unwrap();
+    assert_eq!(config.paths.upls(), PathBuf::from("/var/upl/upls"));
+    assert_eq!(config.paths.archive(), PathBuf::from("/mnt/archive"));
+    assert_eq!(config.stream_buffer, 100);
+    assert!(!config.features.markdown_job);
+    assert!(config.validate().is_ok());
+
+    // Unknown fields are errors
+    assert!(serde_yaml::from_str::<Config>("listen: x").is_err());
+
+    let env = |key: &str| match key {
+      "UPL_STREAM_BUFFER" => Some("10".to_string()),
+      "UPL_VAT" => Some("5:5, 27:25".to_string()),
+      _ => None,
+    };
+    config.apply_env(env).unwrap();
+    assert_eq!(config.stream_buffer, 10);
+    assert_eq!(config.vat_rates().unwrap().rate(VAT::_27), 25);
+
+    let env = |key: &str| match key {
+      "UPL_STREAM_BUFFER" => Some("x".to_string()),
+      _ => None,
+    };
+    assert!(config.apply_env(env).is_err());
+
+    // All the problems are reported
+    config.listen_addr = "x".to_string();
+    config.markdown.rules = "x".to_string();
+    config.vat.insert("99".to_string(), 1);
+    let error = config.validate().unwrap_err().0;
+    assert!(error.contains("listen_addr"));
+    assert!(error.contains("markdown.rules"));
+    assert!(error.contains("vat"));
+
+    // The markdown job needs its rules and depreciation ID
+    let mut config = Config::default();
+    config.auth.enabled = false;
+    config.features.markdown_job = true;
+    let error = config.validate().unwrap_err().0;
+    assert!(error.contains("markdown.rules"));
+    assert!(error.contains("markdown.depreciation_id"));
+    config.markdown.rules = "3:30".to_string();
+    config.markdown.depreciation_id = 9;
+    assert!(config.validate().is_ok());
+    config.markdown.run_at = "25:00".to_string();
+    assert!(config.validate().unwrap_err().0.contains("markdown.run_at"));
+  }
+
+  #[test]
+  fn test_example_config() {
+    let mut config: Config = serde_yaml::from_str(include_str!("../upl.example.yaml")).unwrap();
+    // Auth is enabled, its secret is set by env
+    assert!(config.validate().unwrap_err().0.contains("auth.secret"));
+    let env = |key: &str| match key {
+      "UPL_AUTH_SECRET" => Some("0123456789abcdef0123456789abcdef".to_string()),
+      _ => None,
+    };
+    config.apply_env(env).unwrap();
+    assert!(config.validate().is_ok());
+  }
+}
@@ -0,0 +1,766 @@
+//! Append-only event log of the UPL changes
+//!
+//! The log is the source of truth of the active store and the archive,
+//! they can be rebuilt by replaying it from the latest snapshot.
+//! A change record does not hold only its history event, but the
+//! UPL state after it as well, without its history. Not every change
+//! has a history event, and the events do not carry every field
+//! they change, so the state cannot be derived from the events alone.
+//! Replay takes the stored state and rebuilds the history from the
+//! events, so the history is still exact and every point in time
+//! can be reconstructed.
+
+use crate::archive::{ArchiveError, ArchiveObject, ArchiveReason, ArchiveStore};
+use crate::upl::*;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Number of snapshots we keep
+// The log is compacted to the oldest one
+const KEEP_SNAPSHOTS: usize = 3;
+
+#[derive(Debug)]
+pub enum EventLogError {
+  IoError(String),
+  SerializeError(String),
+  DeserializeError(String),
+  // Offset of a bad record that is followed by more data
+  CorruptRecord(u64),
+  // Offset before the start of the compacted log
+  Compacted(u64),
+}
+
+impl std::fmt::Display for EventLogError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      EventLogError::IoError(msg) => write!(f, "Event log IO hiba: {}", msg),
+      EventLogError::SerializeError(msg) => write!(f, "Event log mentési hiba: {}", msg),
+      EventLogError::DeserializeError(msg) => write!(f, "Event log olvasási hiba: {}", msg),
+      EventLogError::CorruptRecord(offset) => write!(
+        f,
+        "Event log sérült rekord a(z) {} pozíción, utána további adat van",
+        offset
+      ),
+      EventLogError::Compacted(offset) => write!(
+        f,
+        "Event log a(z) {} pozíció előtt tömörítve van, ez az időpont nem állítható vissza",
+        offset
+      ),
+    }
+  }
+}
+
+impl From<std::io::Error> for EventLogError {
+  fn from(error: std::io::Error) -> Self {
+    EventLogError::IoError(error.to_string())
+  }
+}
+
+/// Event log entry
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum LogEntry {
+  // UPL entered the active store with its full history
+  Inserted {
+    upl: Upl,
+  },
+  // UPL changed in the active store
+  // Its state is stored without history, replay rebuilds
+  // the history from the events. No event means a change
+  // without any history event.
+  Changed {
+    event: Option<UplHistoryItem>,
+    upl: Upl,
+  },
+  // UPL left the active store
+  Removed {
+    upl_id: String,
+  },
+  // UPL added to the archive with its final state
+  // The archive file is written after the record is committed,
+  // the state keeps only the ID
+  Archived {
+    reason: ArchiveReason,
+    archived_at: DateTime<Utc>,
+    upl: Upl,
+  },
+  // UPL removed from the archive to be restored
+  Unarchived {
+    upl_id: String,
+  },
+}
+
+impl LogEntry {
+  /// Changed entry from a UPL state
+  /// It strips the UPL history
+  pub fn changed(event: Option<UplHistoryItem>, upl: &Upl) -> Self {
+    let mut upl = upl.clone();
+    upl.history = Vec::new();
+    LogEntry::Changed { event, upl }
+  }
+}
+
+/// Event log record
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogRecord {
+  pub seq: u64,
+  pub created_at: DateTime<Utc>,
+  pub entry: LogEntry,
+}
+
+/// State built by replaying the event log
+#[derive(Serialize, Deserialize, Default)]
+pub struct ReplayState {
+  // Last applied sequence number
+  pub seq: u64,
+  // Log file offset after the last applied record
+  pub offset: u64,
+  // Time of the last applied record
+  pub last_at: Option<DateTime<Utc>>,
+  // Active UPLs
+  pub active: BTreeMap<String, Upl>,
+  // Archived UPL IDs
+  // The archived UPLs themselves live in the archive files
+  pub archived: BTreeSet<String>,
+  // Archive objects of the records replayed since the snapshot
+  // They can be missing from the archive files after a crash.
+  // Not part of the snapshot, as the snapshot is taken only
+  // when their archive files are written.
+  #[serde(skip)]
+  pub recent_archive: BTreeMap<String, ArchiveObject>,
+}
+
+impl ReplayState {
+  /// Write the archive files missing for the recent archive records
+  /// The service writes the archive file right after the
+  /// log commit, so it can be written meanwhile
+  pub fn write_recent_archive(&self, archive: &ArchiveStore) -> Result<(), EventLogError> {
+    for (upl_id, archive_object) in &self.recent_archive {
+      if !archive.contains(upl_id) {
+        match archive.put(archive_object) {
+          Ok(_) | Err(ArchiveError::AlreadyExist(_)) => (),
+          Err(e) => return Err(EventLogError::IoError(e.to_string())),
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Apply a record
+  pub fn apply(&mut self, record: LogRecord, offset: u64) {
+    match record.entry {
+      LogEntry::Inserted { upl } => {
+        self.active.insert(upl.id.clone(), upl);
+      }
+      LogEntry::Changed { event, mut upl } => {
+        upl.history = self
+          .active
+          .remove(&upl.id)
+          .map(|old| old.history)
+          .unwrap_or_default();
+        if let Some(event) = event {
+          upl.history.push(event);
+        }
+        self.active.insert(upl.id.clone(), upl);
+      }
+      LogEntry::Removed { upl_id } => {
+        self.active.remove(&upl_id);
+      }
+      LogEntry::Archived {
+        reason,
+        archived_at,
+        upl,
+      } => {
+        self.archived.insert(upl.id.clone());
+        self.recent_archive.insert(
+          upl.id.clone(),
+          ArchiveObject::new_at(reason, upl, archived_at),
+        );
+      }
+      LogEntry::Unarchived { upl_id } => {
+        self.archived.remove(&upl_id);
+        self.recent_archive.remove(&upl_id);
+      }
+    }
+    self.seq = record.seq;
+    self.offset = offset;
+    self.last_at = Some(record.created_at);
+  }
+}
+
+// Log file name
+// events-{base offset}.log, where the base offset is the
+// log offset of its first byte, so offsets stay valid after
+// the log is compacted.
+fn log_name(base: u64) -> String {
+  format!("events-{:020}.log", base)
+}
+
+// Get log files as (base offset, path) ordered by base offset
+// Compaction writes the new file before it removes the old one,
+// so the last one is the current log.
+fn log_files(dir: &Path) -> Result<Vec<(u64, PathBuf)>, EventLogError> {
+  let mut res = Vec::new();
+  if !dir.exists() {
+    return Ok(res);
+  }
+  for entry in std::fs::read_dir(dir)? {
+    let path = entry?.path();
+    let base = path
+      .file_name()
+      .and_then(|n| n.to_str())
+      .and_then(|n| n.strip_prefix("events-"))
+      .and_then(|n| n.strip_suffix(".log"))
+      .and_then(|n| n.parse::<u64>().ok());
+    if let Some(base) = base {
+      res.push((base, path));
+    }
+  }
+  res.sort_by_key(|(base, _)| *base);
+  Ok(res)
+}
+
+// Open the current log file for reading as (base offset, file)
+// None if nothing was ever logged.
+// If it is compacted meanwhile, we open the new one.
+fn open_log(dir: &Path) -> Result<Option<(u64, File)>, EventLogError> {
+  loop {
+    let (base, path) = match log_files(dir)?.pop() {
+      Some(log) => log,
+      None => return Ok(None),
+    };
+    match File::open(&path) {
+      Ok(file) => return Ok(Some((base, file))),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+      Err(e) => return Err(e.into()),
+    }
+  }
+}
+
+// Read records of a log file from an offset
+// Record format: length (u32 LE), crc32 (u32 LE), bincode payload
+// It stops at the end of the file, at a bad last record
+// (e.g. a torn write), or when f returns false.
+// A bad record followed by more data is a corrupt log, not a torn
+// write, so it returns an error instead of skipping the rest.
+// Returns the offset after the last read record.
+fn read_records(
+  file: File,
+  base: u64,
+  offset: u64,
+  mut f: impl FnMut(LogRecord, u64) -> bool,
+) -> Result<u64, EventLogError> {
+  if offset < base {
+    return Err(EventLogError::Compacted(offset));
+  }
+  let file_len = base + file.metadata()?.len();
+  let mut file = file;
+  file.seek(SeekFrom::Start(offset - base))?;
+  let mut reader = BufReader::new(file);
+  let mut offset = offset;
+  let mut header = [0u8; 8];
+  loop {
+    if reader.read_exact(&mut header).is_err() {
+      break;
+    }
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let next_offset = offset + 8 + len as u64;
+    // A length past the end of the file is a torn or corrupt header,
+    // we never allocate for it
+    let record = if next_offset > file_len {
+      None
+    } else {
+      let mut payload = vec![0u8; len];
+      match reader.read_exact(&mut payload) {
+        Ok(_) if crc32fast::hash(&payload) == crc => {
+          bincode::deserialize::<LogRecord>(&payload).ok()
+        }
+        _ => None,
+      }
+    };
+    let record = match record {
+      Some(record) => record,
+      None if next_offset >= file_len => break,
+      None => return Err(EventLogError::CorruptRecord(offset)),
+    };
+    if !f(record, next_offset) {
+      break;
+    }
+    offset = next_offset;
+  }
+  Ok(offset)
+}
+
+// Snapshot file name
+// snapshot-{seq}-{last record timestamp in nanos}.bin
+fn snapshot_name(state: &ReplayState) -> String {
+  format!(
+    "snapshot-{:020}-{}.bin",
+    state.seq,
+    state.last_at.map(timestamp_nanos).unwrap_or(0)
+  )
+}
+
+fn timestamp_nanos(time: DateTime<Utc>) -> i64 {
+  time.timestamp_nanos_opt().unwrap_or(i64::MAX)
+}
+
+// Get snapshot files as (seq, last record timestamp in nanos, path)
+// ordered by seq
+fn snapshots(dir: &Path) -> Result<Vec<(u64, i64, PathBuf)>, EventLogError> {
+  let mut res = Vec::new();
+  if !dir.exists() {
+    return Ok(res);
+  }
+  for entry in std::fs::read_dir(dir)? {
+    let path = entry?.path();
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+      Some(name) => name,
+      None => continue,
+    };
+    if let Some(parts) = name
+      .strip_prefix("snapshot-")
+      .and_then(|n| n.strip_suffix(".bin"))
+    {
+      let mut parts = parts.split('-');
+      if let (Some(Ok(seq)), Some(Ok(ts))) = (
+        parts.next().map(|p| p.parse::<u64>()),
+        parts.next().map(|p| p.parse::<i64>()),
+      ) {
+        res.push((seq, ts, path));
+      }
+    }
+  }
+  res.sort_by_key(|(seq, _, _)| *seq);
+  Ok(res)
+}
+
+fn load_snapshot(path: &Path) -> Result<ReplayState, EventLogError> {
+  let reader = BufReader::new(File::open(path)?);
+  bincode::deserialize_from(reader).map_err(|e| EventLogError::DeserializeError(e.to_string()))
+}
+
+/// Rebuild state from a log folder
+/// It starts from the latest snapshot taken before until,
+/// and replays the log records created before until.
+/// None until means the latest state.
+/// Time before the oldest kept snapshot is compacted away,
+/// so it returns an error for it.
+/// It only reads the log, so it is safe to use while
+/// the service is writing it.
+pub fn replay(dir: &Path, until: Option<DateTime<Utc>>) -> Result<ReplayState, EventLogError> {
+  let snapshot = snapshots(dir)?.into_iter().rev().find(|(_, ts, _)| {
+    until
+      .map(|until| *ts <= timestamp_nanos(until))
+      .unwrap_or(true)
+  });
+  let mut state = match snapshot {
+    Some((_, _, path)) => load_snapshot(&path)?,
+    None => ReplayState::default(),
+  };
+  let (base, file) = match open_log(dir)? {
+    Some(log) => log,
+    None => return Ok(state),
+  };
+  let offset = state.offset;
+  read_records(file, base, offset, |record, offset| {
+    if let Some(until) = until {
+      if record.created_at > until {
+        return false;
+      }
+    }
+    state.apply(record, offset);
+    true
+  })?;
+  Ok(state)
+}
+
+struct LogFile {
+  dir: PathBuf,
+  file: File,
+  // Log offset of the first byte of the file
+  base: u64,
+  // Log offset after the complete records
+  len: u64,
+  next_seq: u64,
+}
+
+/// Event log
+/// Append only log of every UPL change
+/// in the active store and in the archive.
+/// It is the source of truth, the active store and the archive IDs
+/// can be rebuilt from it at any point in time since the oldest
+/// kept snapshot.
+#[derive(Clone)]
+pub struct EventLog {
+  inner: Arc<Mutex<LogFile>>,
+}
+
+impl EventLog {
+  /// Open or create event log in a folder
+  /// A bad last record (e.g. a torn write after a crash) is truncated.
+  /// A bad record followed by more data is an error,
+  /// as truncating it would lose the records after it.
+  pub fn open(dir: PathBuf) -> Result<Self, EventLogError> {
+    create_dir_all(&dir)?;
+
+    // Remove the files left behind by a compaction
+    // that stopped before it finished
+    let mut log_files = log_files(&dir)?;
+    let (base, path) = log_files.pop().unwrap_or((0, dir.join(log_name(0))));
+    for (_, old_path) in log_files {
+      std::fs::remove_file(old_path)?;
+    }
+    let tmp_path = path.with_extension("log.tmp");
+    if tmp_path.exists() {
+      std::fs::remove_file(tmp_path)?;
+    }
+
+    let file = OpenOptions::new()
+      .create(true)
+      .truncate(false)
+      .read(true)
+      .write(true)
+      .open(&path)?;
+
+    // Find the last record from the latest snapshot
+    let (mut next_seq, offset) = match snapshots(&dir)?.last() {
+      Some((seq, _, path)) => (seq + 1, load_snapshot(path)?.offset),
+      None => (1, base),
+    };
+    let end = read_records(file.try_clone()?, base, offset, |record, _| {
+      next_seq = record.seq + 1;
+      true
+    })?;
+
+    // Remove incomplete tail
+    file.set_len(end - base)?;
+    let mut file = file;
+    file.seek(SeekFrom::End(0))?;
+
+    Ok(Self {
+      inner: Arc::new(Mutex::new(LogFile {
+        dir,
+        file,
+        base,
+        len: end,
+        next_seq,
+      })),
+    })
+  }
+
+  /// Returns true if nothing was ever logged
+  pub fn is_empty(&self) -> bool {
+    self.inner.lock().unwrap().next_seq == 1
+  }
+
+  /// Append an entry
+  /// Returns its sequence number
+  pub fn append(&self, entry: LogEntry) -> Result<u64, EventLogError> {
+    self.append_all(vec![entry])
+  }
+
+  /// Append entries as one write
+  /// It returns only after they are synced to disk.
+  /// If it fails, none of them is appended.
+  /// Returns the sequence number of the last one.
+  pub fn append_all(&self, entries: Vec<LogEntry>) -> Result<u64, EventLogError> {
+    let mut log = self.inner.lock().unwrap();
+    let created_at = Utc::now();
+    let mut seq = log.next_seq;
+    let mut buf = Vec::new();
+    for entry in entries {
+      let record = LogRecord {
+        seq,
+        created_at,
+        entry,
+      };
+      let payload =
+        bincode::serialize(&record).map_err(|e| EventLogError::SerializeError(e.to_string()))?;
+      buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+      buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+      buf.extend_from_slice(&payload);
+      seq += 1;
+    }
+    if let Err(e) = log.file.write_all(&buf).and_then(|_| log.file.sync_data()) {
+      // Remove the partly written records, so the next
+      // append does not leave them in the middle of the log
+      let len = log.len - log.base;
+      let _ = log.file.set_len(len);
+      let _ = log.file.seek(SeekFrom::Start(len));
+      return Err(e.into());
+    }
+    log.len += buf.len() as u64;
+    log.next_seq = seq;
+    Ok(seq - 1)
+  }
+
+  /// Take a snapshot
+  /// It replays the log since the latest snapshot,
+  /// so it does not need the active store.
+  /// Archive files missing for the replayed records are written
+  /// into the archive first, so the snapshot can hold only their IDs.
+  /// Then the log is compacted to the oldest kept snapshot.
+  /// Returns the sequence number of the snapshot.
+  /// The service takes these steps one by one, to write the
+  /// archive files under its archive lock.
+  pub fn snapshot(&self, archive: &ArchiveStore) -> Result<u64, EventLogError> {
+    let state = self.snapshot_state()?;
+    state.write_recent_archive(archive)?;
+    self.write_snapshot(&state)
+  }
+
+  /// Replay the log for a snapshot
+  pub fn snapshot_state(&self) -> Result<ReplayState, EventLogError> {
+    let dir = self.inner.lock().unwrap().dir.clone();
+    replay(&dir, None)
+  }
+
+  /// Write the snapshot of a replayed state
+  /// The archive files of its recent archive records
+  /// must be written before, see write_recent_archive
+  pub fn write_snapshot(&self, state: &ReplayState) -> Result<u64, EventLogError> {
+    let dir = self.inner.lock().unwrap().dir.clone();
+
+    // Write a temp file first and rename it,
+    // so we never leave a half written snapshot behind
+    let path = dir.join(snapshot_name(state));
+    let tmp_path = path.with_extension("bin.tmp");
+    let file = File::create(&tmp_path)?;
+    bincode::serialize_into(std::io::BufWriter::new(file), state)
+      .map_err(|e| EventLogError::SerializeError(e.to_string()))?;
+    std::fs::rename(&tmp_path, &path)?;
+
+    // Remove old snapshots
+    let snapshots = snapshots(&dir)?;
+    let removed = snapshots.len().saturating_sub(KEEP_SNAPSHOTS);
+    for (_, _, path) in &snapshots[..removed] {
+      std::fs::remove_file(path)?;
+    }
+
+    // Compact the log to the oldest kept snapshot
+    // We can still replay any point in time since then
+    if let Some((_, _, oldest)) = snapshots.get(removed) {
+      self.compact(load_snapshot(oldest)?.offset)?;
+    }
+
+    Ok(state.seq)
+  }
+
+  // Drop the log records before offset
+  // The records after it are copied into a new log file,
+  // and the old one is removed only after the new one is in place.
+  fn compact(&self, offset: u64) -> Result<(), EventLogError> {
+    let mut log = self.inner.lock().unwrap();
+    if offset <= log.base {
+      return Ok(());
+    }
+    let path = log.dir.join(log_name(offset));
+    let tmp_path = path.with_extension("log.tmp");
+    let old_path = log.dir.join(log_name(log.base));
+
+    let mut tail = File::open(&old_path)?;
+    tail.seek(SeekFrom::Start(offset - log.base))?;
+    let mut tmp = File::create(&tmp_path)?;
+    std::io::copy(&mut tail.take(log.len - offset), &mut tmp)?;
+    tmp.sync_all()?;
+    std::fs::rename(&tmp_path, &path)?;
+
+    let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+    file.seek(SeekFrom::End(0))?;
+    log.file = file;
+    log.base = offset;
+    std::fs::remove_file(&old_path)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use gzlib::id::{generate_id, HexHelper, IdKind};
+
+  fn current_log(dir: &Path) -> PathBuf {
+    log_files(dir).unwrap().pop().unwrap().1
+  }
+
+  fn upl(id: &str) -> Upl {
+    Upl {
+      id: id.to_string(),
+      location: Location::Stock(1),
+      history: vec![UplHistoryItem::new(
+        CreatedBy::Technical,
+        UplHistoryEvent::Created,
+      )],
+      ..Upl::default()
+    }
+  }
+
+  #[test]
+  fn test_replay_snapshot_and_truncate() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let archive_dir = tempfile::tempdir().unwrap();
+    let archive = ArchiveStore::init(archive_dir.path().to_path_buf());
+    let log = EventLog::open(dir.clone()).unwrap();
+    assert!(log.is_empty());
+
+    let mut a = upl("a");
+    log.append(LogEntry::Inserted { upl: a.clone() }).unwrap();
+    log.append(LogEntry::Inserted { upl: upl("b") }).unwrap();
+    a.move_upl(Location::Stock(2), 1).unwrap();
+    log
+      .append(LogEntry::changed(a.history.last().cloned(), &a))
+      .unwrap();
+    assert_eq!(log.snapshot(&archive).unwrap(), 3);
+
+    let middle = Utc::now();
+    log
+      .append(LogEntry::Removed {
+        upl_id: "b".to_string(),
+      })
+      .unwrap();
+    log
+      .append(LogEntry::Archived {
+        reason: ArchiveReason::Sold,
+        archived_at: Utc::now(),
+        upl: upl("b"),
+      })
+      .unwrap();
+
+    let state = replay(&dir, None).unwrap();
+    assert_eq!(state.seq, 5);
+    assert_eq!(state.active.len(), 1);
+    assert_eq!(state.active["a"].get_location(), &Location::Stock(2));
+    assert_eq!(state.active["a"].history.len(), 2);
+    assert!(state.archived.contains("b"));
+
+    // Point in time
+    let state = replay(&dir, Some(middle)).unwrap();
+    assert_eq!(state.seq, 3);
+    assert!(state.active.contains_key("b"));
+
+    // Torn write at the end is truncated on open
+    drop(log);
+    let mut file = OpenOptions::new()
+      .append(true)
+      .open(current_log(&dir))
+      .unwrap();
+    file.write_all(&[100, 0, 0, 0, 1]).unwrap();
+    drop(file);
+    let log = EventLog::open(dir.clone()).unwrap();
+    drop(log);
+
+    // Torn header with a huge length is a torn write as well
+    let mut file = OpenOptions::new()
+      .append(true)
+      .open(current_log(&dir))
+      .unwrap();
+    file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+    file.write_all(&[0, 0, 0, 0]).unwrap();
+    drop(file);
+    let log = EventLog::open(dir.clone()).unwrap();
+    assert_eq!(
+      log
+        .append(LogEntry::Unarchived {
+          upl_id: "b".to_string()
+        })
+        .unwrap(),
+      6
+    );
+    assert!(replay(&dir, None).unwrap().archived.is_empty());
+  }
+
+  #[test]
+  fn test_snapshot_archive_and_compact() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let archive_dir = tempfile::tempdir().unwrap();
+    let archive = ArchiveStore::init(archive_dir.path().to_path_buf());
+    let log = EventLog::open(dir.clone()).unwrap();
+
+    // Archived record whose archive file was never written,
+    // e.g. the service stopped right after the log commit
+    let archived = upl(&generate_id(10159851, IdKind::LuhnTwo).to_hex());
+    log.append(LogEntry::Inserted { upl: upl("a") }).unwrap();
+    log
+      .append(LogEntry::Archived {
+        reason: ArchiveReason::Sold,
+        archived_at: Utc::now(),
+        upl: archived.clone(),
+      })
+      .unwrap();
+    let state = replay(&dir, None).unwrap();
+    assert!(state.recent_archive.contains_key(&archived.id));
+    assert!(!archive.contains(&archived.id));
+
+    // Snapshot writes the archive file and keeps only the ID
+    log.snapshot(&archive).unwrap();
+    assert!(archive.contains(&archived.id));
+    let state = replay(&dir, None).unwrap();
+    assert!(state.archived.contains(&archived.id));
+    assert!(state.recent_archive.is_empty());
+
+    // Log is compacted to the oldest kept snapshot
+    let before_compaction = Utc::now();
+    for _ in 0..KEEP_SNAPSHOTS {
+      log
+        .append(LogEntry::Removed {
+          upl_id: "a".to_string(),
+        })
+        .unwrap();
+      log.snapshot(&archive).unwrap();
+    }
+    let log_files = log_files(&dir).unwrap();
+    assert_eq!(log_files.len(), 1);
+    assert!(log_files[0].0 > 0);
+    assert!(matches!(
+      replay(&dir, Some(before_compaction)),
+      Err(EventLogError::Compacted(_))
+    ));
+
+    // Appends go into the compacted log, and survive a reopen
+    log.append(LogEntry::Inserted { upl: upl("c") }).unwrap();
+    drop(log);
+    let log = EventLog::open(dir.clone()).unwrap();
+    assert_eq!(log.append(LogEntry::Inserted { upl: upl("d") }).unwrap(), 7);
+    let state = replay(&dir, None).unwrap();
+    assert_eq!(state.seq, 7);
+    assert_eq!(state.active.keys().collect::<Vec<_>>(), vec!["c", "d"]);
+    assert!(state.archived.contains(&archived.id));
+  }
+
+  #[test]
+  fn test_corrupt_record_in_the_middle() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let log = EventLog::open(dir.clone()).unwrap();
+    log
+      .append_all(vec![
+        LogEntry::Inserted { upl: upl("a") },
+        LogEntry::Inserted { upl: upl("b") },
+      ])
+      .unwrap();
+    drop(log);
+
+    // Flip the last payload byte of the first record
+    let path = dir.join(log_name(0));
+    let mut bytes = std::fs::read(&path).unwrap();
+    let len = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    bytes[8 + len - 1] ^= 0xff;
+    std::fs::write(&path, &bytes).unwrap();
+
+    // The second record is not truncated away
+    assert!(matches!(
+      EventLog::open(dir.clone()),
+      Err(EventLogError::CorruptRecord(0))
+    ));
+    assert!(replay(&dir, None).is_err());
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+  }
+}
@@ -0,0 +1,217 @@
+use crate::upl::*;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// UPL change event
+#[derive(Debug, Clone)]
+pub struct ChangeEvent {
+  // Sequence number, starts from 1
+  // Sequence numbers are kept in memory only,
+  // so they restart after a service restart
+  pub seq: u64,
+  pub upl_id: String,
+  pub event: UplHistoryItem,
+  // UPL state after the event
+  pub upl: Upl,
+}
+
+/// Change event filter
+/// Empty lists mean no filter
+#[derive(Debug, Clone, Default)]
+pub struct ChangeFilter {
+  pub stock_ids: Vec<u32>,
+  pub skus: Vec<u32>,
+  pub event_kinds: Vec<String>,
+}
+
+impl ChangeFilter {
+  /// Check if an event matches the filter
+  /// A UPL moved out from a stock matches that stock as well
+  pub fn matches(&self, event: &ChangeEvent) -> bool {
+    let in_stock = |location: &Location| match location {
+      Location::Stock(stock_id) => self.stock_ids.contains(stock_id),
+      _ => false,
+    };
+    let stock_ok = self.stock_ids.is_empty()
+      || in_stock(event.upl.get_location())
+      || match event.event.get_event() {
+        UplHistoryEvent::Moved { from, .. } => in_stock(from),
+        _ => false,
+      };
+    let sku_ok = self.skus.is_empty() || self.skus.contains(&event.upl.get_sku());
+    let kind_ok = self.event_kinds.is_empty()
+      || self
+        .event_kinds
+        .iter()
+        .any(|kind| kind == event.event.get_event().get_kind());
+    stock_ok && sku_ok && kind_ok
+  }
+}
+
+struct FeedState {
+  next_seq: u64,
+  // Latest events to resume from
+  buffer: VecDeque<ChangeEvent>,
+  capacity: usize,
+}
+
+/// UPL change feed
+/// Publishes change events to the subscribers and
+/// keeps the latest events, so a subscriber can resume
+/// from a sequence number
+#[derive(Clone)]
+pub struct ChangeFeed {
+  state: Arc<Mutex<FeedState>>,
+  sender: broadcast::Sender<ChangeEvent>,
+}
+
+impl ChangeFeed {
+  /// Create a feed keeping the latest capacity events
+  pub fn new(capacity: usize) -> Self {
+    let (sender, _) = broadcast::channel(capacity.max(1));
+    Self {
+      state: Arc::new(Mutex::new(FeedState {
+        next_seq: 1,
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
+      })),
+      sender,
+    }
+  }
+
+  /// Publish a change event
+  pub fn publish(&self, event: UplHistoryItem, upl: Upl) {
+    let mut state = self.state.lock().unwrap();
+    let change = ChangeEvent {
+      seq: state.next_seq,
+      upl_id: upl.id.clone(),
+      event,
+      upl,
+    };
+    state.next_seq += 1;
+    if state.buffer.len() >= state.capacity {
+      state.buffer.pop_front();
+    }
+    state.buffer.push_back(change.clone());
+    // It is not an error if nobody is listening
+    let _ = self.sender.send(change);
+  }
+
+  /// Last published sequence number
+  /// 0 if nothing has been published yet
+  pub fn last_seq(&self) -> u64 {
+    self.state.lock().unwrap().next_seq - 1
+  }
+
+  // Kept events after a sequence number
+  fn events_after(state: &FeedState, after_seq: u64) -> Result<Vec<ChangeEvent>, String> {
+    // Unknown sequence number, e.g. after a service restart
+    if after_seq >= state.next_seq {
+      return Err(format!("Ismeretlen esemény sorszám! {}", after_seq));
+    }
+    let oldest = state
+      .buffer
+      .front()
+      .map(|e| e.seq)
+      .unwrap_or(state.next_seq);
+    if after_seq + 1 < oldest {
+      return Err(format!(
+        "A kért sorszám után következő események már nem elérhetőek! {}",
+        after_seq
+      ));
+    }
+    Ok(
+      state
+        .buffer
+        .iter()
+        .filter(|e| e.seq > after_seq)
+        .cloned()
+        .collect(),
+    )
+  }
+
+  /// Get kept events after a sequence number
+  pub fn since(&self, after_seq: u64) -> Result<Vec<ChangeEvent>, String> {
+    Self::events_after(&self.state.lock().unwrap(), after_seq)
+  }
+
+  /// Subscribe to the feed
+  /// If after_seq is given, it returns the kept events after it as well.
+  /// The receiver gets only the events after the returned ones.
+  pub fn subscribe(&self, after_seq: Option<u64>) -> Result<Subscription, String> {
+    let state = self.state.lock().unwrap();
+    let (last_seq, backlog) = match after_seq {
+      Some(after_seq) => (after_seq, Self::events_after(&state, after_seq)?),
+      None => (state.next_seq - 1, Vec::new()),
+    };
+    Ok(Subscription {
+      last_seq,
+      backlog,
+      receiver: self.sender.subscribe(),
+    })
+  }
+}
+
+/// Change feed subscription
+pub struct Subscription {
+  // Sequence number the subscription starts after
+  pub last_seq: u64,
+  // Kept events after last_seq
+  pub backlog: Vec<ChangeEvent>,
+  // New events
+  pub receiver: broadcast::Receiver<ChangeEvent>,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn upl(id: &str, stock_id: u32) -> Upl {
+    Upl {
+      id: id.to_string(),
+      kind: Kind::Sku { sku: 1 },
+      location: Location::Stock(stock_id),
+      ..Upl::default()
+    }
+  }
+
+  fn created() -> UplHistoryItem {
+    UplHistoryItem::new(CreatedBy::Technical, UplHistoryEvent::Created)
+  }
+
+  #[test]
+  fn test_resume_and_filter() {
+    let feed = ChangeFeed::new(2);
+    feed.publish(created(), upl("a", 1));
+    let mut subscription = feed.subscribe(Some(0)).unwrap();
+    assert_eq!(subscription.backlog.len(), 1);
+
+    feed.publish(created(), upl("b", 2));
+    feed.publish(created(), upl("c", 1));
+    assert_eq!(subscription.receiver.try_recv().unwrap().seq, 2);
+    assert_eq!(feed.last_seq(), 3);
+
+    // Event 1 is not kept anymore
+    assert!(feed.since(0).is_err());
+    assert!(feed.since(4).is_err());
+    let events = feed.since(1).unwrap();
+    assert_eq!(
+      events.iter().map(|e| e.seq).collect::<Vec<u64>>(),
+      vec![2, 3]
+    );
+
+    let filter = ChangeFilter {
+      stock_ids: vec![1],
+      ..ChangeFilter::default()
+    };
+    assert!(!filter.matches(&events[0]));
+    assert!(filter.matches(&events[1]));
+
+    let filter = ChangeFilter {
+      event_kinds: vec!["moved".to_string()],
+      ..ChangeFilter::default()
+    };
+    assert!(!filter.matches(&events[0]));
+  }
+}
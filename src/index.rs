@@ -1,5 +1,5 @@
 use crate::upl::*;
-use chrono::prelude::*;
+use gzlib::id::LuhnCheck;
 use serde::{Deserialize, Serialize};
 use std::{fs::create_dir_all, path::PathBuf};
 
@@ -14,6 +14,24 @@ pub enum IndexError {
   InternalError(String),
 }
 
+impl std::fmt::Display for IndexError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      IndexError::NotFound => write!(f, "A megadott UPL ID nincs regisztrálva!"),
+      IndexError::WrongId => write!(f, "A megadott UPL ID nem valid!"),
+      IndexError::FileReadError => write!(f, "Hiba az index fájl olvasása közben!"),
+      IndexError::FileDeserializeError => write!(f, "Hiba az index fájl dekódolása közben!"),
+      IndexError::FileSerializeError => write!(f, "Hiba az index fájl mentése közben!"),
+      IndexError::AlreadyExist => write!(f, "A megadott UPL ID már ki lett adva!"),
+      IndexError::InternalError(msg) => write!(f, "{}", msg),
+    }
+  }
+}
+
+/// UPL Index
+/// Global registry of every UPL ID ever issued.
+/// Each ID is recorded exactly once, so an ID cannot be re-used
+/// even if its UPL is already archived.
 pub struct UplIndex {
   path: PathBuf,
 }
@@ -24,32 +42,32 @@ pub struct UplIndex {
 // Maximum 1_000 folder per folder and maximum 1_000 index file
 // per folder.
 // returns (million value, thousand value, hunders value)
-fn get_path(u: u32) -> (u32, u32, u32) {
+fn get_path(u: u64) -> (u64, u64, u64) {
   (u / 1_000_000, u % 1_000_000 / 1000, u % 1000)
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct IndexObject {
   // Base ID
-  pub base_id: u32,
+  pub base_id: u64,
   // ID with checksum characters
-  pub upl: u32,
+  pub upl: String,
   // Related product ID
   pub product: u32,
   // Related SKU ID
-  pub sku: Option<u32>,
+  pub sku: u32,
   // Created at
   pub created_at_unix_ts_utc: i64, // unix epoch
 }
 
 impl IndexObject {
-  pub fn new(base_id: u32, upl: u32, product: u32, sku: Option<u32>) -> Self {
+  pub fn new(base_id: u64, upl: String, product: u32, sku: u32, created_at: i64) -> Self {
     Self {
       base_id,
       upl,
       product,
       sku,
-      created_at_unix_ts_utc: Utc::now().timestamp(),
+      created_at_unix_ts_utc: created_at,
     }
   }
 }
@@ -65,22 +83,34 @@ impl UplIndex {
     Self { path }
   }
 
-  /// Get UPLIndex object
-  fn get(&self, id: u32) -> Result<IndexObject, IndexError> {
+  // Get index folder and file path by UPL ID
+  fn get_file_path(&self, id: &str) -> Result<(u64, PathBuf, PathBuf), IndexError> {
     // 1. Check ID checksum (Validate it)
-    // todo! Implement ID checksum validation or do we have it before?
-    ();
     // 2. Get base ID
     // This means we cut the last two characters
     // This means divide by 100
-    let base = id / 100;
+    let base = id
+      .to_string()
+      .to_luhn_object()
+      .map_err(|_| IndexError::WrongId)?
+      .get_base();
     let (parent, child, _) = get_path(base);
+    let folder_path = self.path.join(parent.to_string()).join(child.to_string());
+    let file_path = folder_path.join(format!("{}.IndexObject", id));
+    Ok((base, folder_path, file_path))
+  }
+
+  /// Check whether a UPL ID is already issued
+  pub fn contains(&self, id: &str) -> bool {
+    match self.get_file_path(id) {
+      Ok((_, _, file_path)) => file_path.exists(),
+      Err(_) => false,
+    }
+  }
 
-    let file_path = self
-      .path
-      .join(parent.to_string())
-      .join(child.to_string())
-      .join(format!("{}.IndexObject", id));
+  /// Get UPLIndex object
+  pub fn get(&self, id: &str) -> Result<IndexObject, IndexError> {
+    let (_, _, file_path) = self.get_file_path(id)?;
 
     // If index file does not exist
     // return error
@@ -94,87 +124,128 @@ impl UplIndex {
 
     // 4. Try deserialize index file
     //    and return the index file or error
-    Ok(
-      serde_yaml::from_str::<IndexObject>(&file_str)
-        .map_err(|_| IndexError::FileDeserializeError)?,
-    )
+    serde_yaml::from_str::<IndexObject>(&file_str).map_err(|_| IndexError::FileDeserializeError)
   }
 
   /// Add UPL as a UPL Index
-  fn add(&self, upl: &Upl) -> Result<(), IndexError> {
+  pub fn add(&self, upl: &Upl) -> Result<(), IndexError> {
     // 1. Get base ID from UplId
-    let base = upl.id / 100;
+    let (base, _, _) = self.get_file_path(upl.get_upl_id())?;
 
-    // 2. Create index file path object
-    let (parent, child, _) = get_path(base);
-    let folder_path = self.path.join(parent.to_string()).join(child.to_string());
-    let file_path = folder_path.join(format!("{}.IndexObject", upl.id));
+    // 2. Create index object from the given UPL
+    let index_object = IndexObject::new(
+      base,
+      upl.id.clone(),
+      upl.get_product_id(),
+      upl.get_sku(),
+      upl.get_created_at().timestamp(),
+    );
 
-    // 3. Check if the index file already exist
+    // 3. Save it
+    self.put(&index_object)
+  }
+
+  /// Save an index object as it is
+  /// e.g. when the index is restored from a backup
+  pub fn put(&self, index_object: &IndexObject) -> Result<(), IndexError> {
+    // 1. Create index file path object
+    let (_, folder_path, file_path) = self.get_file_path(&index_object.upl)?;
+
+    // 2. Check if the index file already exist
     if file_path.exists() {
       return Err(IndexError::AlreadyExist);
     }
 
-    // 4. Check if folder path exist
+    // 3. Check if folder path exist
     //    and create it all if does not
     if !folder_path.exists() {
       std::fs::create_dir_all(&folder_path).map_err(|_| {
         IndexError::InternalError(format!(
           "A megadott path-t nem lehet létrehozni! {:?}",
-          &folder_path
+          folder_path
         ))
       })?;
     }
 
-    // 3. Create index object from the given UPL
-    let index_object =
-      IndexObject::new(base, upl.id, *upl.get_product_id(), upl.get_sku().cloned());
-
     // 4. Create index file
     let mut index_file = std::fs::File::create(&file_path).map_err(|_| {
-      IndexError::InternalError(format!("Error while creating index file: {:?}", &file_path))
+      IndexError::InternalError(format!("Error while creating index file: {:?}", file_path))
     })?;
 
     // 5. Try serialize index object and try save it
     //    into the index file
-    serde_yaml::to_writer(&mut index_file, &index_object)
+    serde_yaml::to_writer(&mut index_file, index_object)
       .map_err(|_| IndexError::FileSerializeError)?;
 
     Ok(())
   }
+
+  /// Get all the index objects
+  /// It walks the whole index tree, so use it only
+  /// for maintenance tasks
+  pub fn get_all(&self) -> Result<Vec<IndexObject>, IndexError> {
+    let mut res: Vec<IndexObject> = Vec::new();
+    let mut folders: Vec<PathBuf> = vec![self.path.clone()];
+    while let Some(folder) = folders.pop() {
+      for entry in std::fs::read_dir(&folder).map_err(|_| IndexError::FileReadError)? {
+        let path = entry.map_err(|_| IndexError::FileReadError)?.path();
+        if path.is_dir() {
+          folders.push(path);
+        } else if path.extension().and_then(|e| e.to_str()) == Some("IndexObject") {
+          if let Some(id) = path.file_stem().and_then(|s| s.to_str()) {
+            res.push(self.get(id)?);
+          }
+        }
+      }
+    }
+    Ok(res)
+  }
+
+  /// Remove UPL index
+  /// Only to roll back an index that was added
+  /// during a failed UPL creation
+  pub fn remove(&self, id: &str) -> Result<(), IndexError> {
+    let (_, _, file_path) = self.get_file_path(id)?;
+    if !file_path.exists() {
+      return Err(IndexError::NotFound);
+    }
+    std::fs::remove_file(&file_path).map_err(|e| IndexError::InternalError(e.to_string()))
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use gzlib::id::{generate_id, HexHelper, IdKind};
 
-  use std::sync::Once;
-
-  static INIT: Once = Once::new();
-
-  fn clean() {
-    INIT.call_once(|| {
-      let path = PathBuf::from("data/test/index");
-      std::fs::remove_dir_all(path).expect("Failed to clean up test index directory");
-    });
+  fn upl(base: u64) -> Upl {
+    Upl {
+      id: generate_id(base, IdKind::LuhnTwo).to_hex(),
+      ..Upl::default()
+    }
   }
 
   #[test]
   fn test_create() {
-    clean();
-    let mut upl = Upl::default();
-    upl.id = 101598512;
+    let upl = upl(1015985);
 
-    let index = UplIndex::init(PathBuf::from("data/test/index"));
-    assert_eq!(index.add(&upl).is_ok(), true);
+    let dir = tempfile::tempdir().unwrap();
+    let index = UplIndex::init(dir.path().to_path_buf());
+    assert!(index.add(&upl).is_ok());
+    assert!(index.contains(&upl.id));
+    // ID can be issued only once
+    assert!(index.add(&upl).is_err());
   }
 
   #[test]
   fn test_get() {
-    let id = 101598512;
-    let index = UplIndex::init(PathBuf::from("data/test/index"));
-    let i = index.get(id);
-    assert_eq!(i.is_ok(), true, "loaded file has success deser {}", id);
+    let upl = upl(1015986);
+    let id = upl.id.clone();
+    let dir = tempfile::tempdir().unwrap();
+    let index = UplIndex::init(dir.path().to_path_buf());
+    index.add(&upl).unwrap();
+    let i = index.get(&id);
+    assert!(i.is_ok(), "loaded file has success deser {}", id);
     assert_eq!(
       i.unwrap().upl,
       id,
@@ -182,4 +253,32 @@ mod tests {
       id
     );
   }
+
+  #[test]
+  fn test_get_all_and_put() {
+    let dir = tempfile::tempdir().unwrap();
+    let index = UplIndex::init(dir.path().to_path_buf());
+    index.add(&upl(1)).unwrap();
+    index.add(&upl(2_000_001)).unwrap();
+    let mut all = index.get_all().unwrap();
+    all.sort_by_key(|i| i.base_id);
+    assert_eq!(all.len(), 2);
+    assert_eq!(all[1].upl, upl(2_000_001).id);
+
+    let copy_dir = tempfile::tempdir().unwrap();
+    let copy = UplIndex::init(copy_dir.path().to_path_buf());
+    copy.put(&all[0]).unwrap();
+    assert!(copy.contains(&upl(1).id));
+    assert!(copy.put(&all[0]).is_err());
+  }
+
+  #[test]
+  fn test_remove() {
+    let upl = upl(1015987);
+    let dir = tempfile::tempdir().unwrap();
+    let index = UplIndex::init(dir.path().to_path_buf());
+    index.add(&upl).unwrap();
+    assert!(index.remove(&upl.id).is_ok());
+    assert!(!index.contains(&upl.id));
+  }
 }
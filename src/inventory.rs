@@ -0,0 +1,216 @@
+use chrono::prelude::*;
+use packman::VecPackMember;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Inventory session method declarations
+pub trait InventoryMethods
+where
+  Self: Sized,
+{
+  /// Open a new inventory session for a given stock
+  /// expected contains the UPL IDs that were in the stock
+  /// at the time the session was opened
+  fn new(id: u32, stock_id: u32, expected: Vec<String>, created_by: u32) -> Self;
+  /// Get inventory session ID
+  fn get_inventory_id(&self) -> u32;
+  /// Get related stock ID
+  fn get_stock_id(&self) -> u32;
+  /// Get expected UPL IDs ref
+  fn get_expected(&self) -> &BTreeSet<String>;
+  /// Get scanned UPL IDs ref
+  fn get_scanned(&self) -> &BTreeSet<String>;
+  /// Get UPL IDs that were expected but not scanned
+  fn get_missing(&self) -> Vec<String>;
+  /// Get UPL IDs that were scanned but not expected
+  fn get_unexpected(&self) -> Vec<String>;
+  /// Get UPL IDs that were locked during closing
+  fn get_locked(&self) -> &Vec<String>;
+  /// Register a scanned UPL ID
+  /// Only while the session is open
+  fn scan(&mut self, upl_id: String) -> Result<&Self, String>;
+  /// Returns true if the session is still open
+  fn is_open(&self) -> bool;
+  /// Close inventory session
+  /// locked contains the missing UPL IDs that got Lock::Inventory(ID)
+  fn close(&mut self, locked: Vec<String>, closed_by: u32) -> Result<&Self, String>;
+  /// Get closing time if there is any
+  fn get_closed_at(&self) -> Option<DateTime<Utc>>;
+  /// Get closed by if there is any
+  fn get_closed_by(&self) -> Option<u32>;
+  /// Get session creation time
+  fn get_created_at(&self) -> DateTime<Utc>;
+  /// Get session created by (user id)
+  fn get_created_by(&self) -> u32;
+}
+
+/// Inventory (stocktake) session
+/// We snapshot the UPLs of a stock when the session opens,
+/// collect the scanned UPL IDs, and the difference of the two
+/// is going to be the list of missing UPLs.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InventorySession {
+  // Inventory session ID
+  // We use this ID as the Lock::Inventory(ID)
+  // and Location::Discard(ID) value
+  id: u32,
+  // Related stock ID
+  stock_id: u32,
+  // UPL IDs in the stock when the session was opened
+  expected: BTreeSet<String>,
+  // Scanned UPL IDs
+  scanned: BTreeSet<String>,
+  // Missing UPL IDs that were locked
+  // with Lock::Inventory(ID) during closing
+  locked: Vec<String>,
+  // Closing time, None while the session is open
+  closed_at: Option<DateTime<Utc>>,
+  // User who closed the session
+  closed_by: Option<u32>,
+  // Session creation time
+  created_at: DateTime<Utc>,
+  // Session created by (user id)
+  created_by: u32,
+}
+
+impl Default for InventorySession {
+  fn default() -> Self {
+    Self {
+      id: 0,
+      stock_id: 0,
+      expected: BTreeSet::new(),
+      scanned: BTreeSet::new(),
+      locked: Vec::new(),
+      closed_at: None,
+      closed_by: None,
+      created_at: Utc::now(),
+      created_by: 0,
+    }
+  }
+}
+
+impl InventoryMethods for InventorySession {
+  fn new(id: u32, stock_id: u32, expected: Vec<String>, created_by: u32) -> Self {
+    Self {
+      id,
+      stock_id,
+      expected: expected.into_iter().collect(),
+      scanned: BTreeSet::new(),
+      locked: Vec::new(),
+      closed_at: None,
+      closed_by: None,
+      created_at: Utc::now(),
+      created_by,
+    }
+  }
+
+  fn get_inventory_id(&self) -> u32 {
+    self.id
+  }
+
+  fn get_stock_id(&self) -> u32 {
+    self.stock_id
+  }
+
+  fn get_expected(&self) -> &BTreeSet<String> {
+    &self.expected
+  }
+
+  fn get_scanned(&self) -> &BTreeSet<String> {
+    &self.scanned
+  }
+
+  fn get_missing(&self) -> Vec<String> {
+    self.expected.difference(&self.scanned).cloned().collect()
+  }
+
+  fn get_unexpected(&self) -> Vec<String> {
+    self.scanned.difference(&self.expected).cloned().collect()
+  }
+
+  fn get_locked(&self) -> &Vec<String> {
+    &self.locked
+  }
+
+  fn scan(&mut self, upl_id: String) -> Result<&Self, String> {
+    if !self.is_open() {
+      return Err("A leltár már lezárva, nem lehet több UPL-t rögzíteni!".to_string());
+    }
+    // Scanning the same UPL twice is not an error
+    self.scanned.insert(upl_id);
+    Ok(self)
+  }
+
+  fn is_open(&self) -> bool {
+    self.closed_at.is_none()
+  }
+
+  fn close(&mut self, locked: Vec<String>, closed_by: u32) -> Result<&Self, String> {
+    if !self.is_open() {
+      return Err("A leltár már le van zárva!".to_string());
+    }
+    self.locked = locked;
+    self.closed_at = Some(Utc::now());
+    self.closed_by = Some(closed_by);
+    Ok(self)
+  }
+
+  fn get_closed_at(&self) -> Option<DateTime<Utc>> {
+    self.closed_at
+  }
+
+  fn get_closed_by(&self) -> Option<u32> {
+    self.closed_by
+  }
+
+  fn get_created_at(&self) -> DateTime<Utc> {
+    self.created_at
+  }
+
+  fn get_created_by(&self) -> u32 {
+    self.created_by
+  }
+}
+
+impl VecPackMember for InventorySession {
+  type Out = u32;
+
+  fn get_id(&self) -> &Self::Out {
+    &self.id
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn session() -> InventorySession {
+    InventorySession::new(
+      1,
+      2,
+      vec!["a".to_string(), "b".to_string(), "c".to_string()],
+      0,
+    )
+  }
+
+  #[test]
+  fn test_missing_and_unexpected() {
+    let mut s = session();
+    s.scan("a".to_string()).unwrap();
+    s.scan("a".to_string()).unwrap();
+    s.scan("x".to_string()).unwrap();
+    assert_eq!(s.get_missing(), vec!["b".to_string(), "c".to_string()]);
+    assert_eq!(s.get_unexpected(), vec!["x".to_string()]);
+  }
+
+  #[test]
+  fn test_close() {
+    let mut s = session();
+    assert!(s.close(vec!["b".to_string()], 3).is_ok());
+    assert!(!s.is_open());
+    assert_eq!(s.get_closed_by(), Some(3));
+    // Cannot scan or close a closed session
+    assert!(s.scan("b".to_string()).is_err());
+    assert!(s.close(Vec::new(), 3).is_err());
+  }
+}
@@ -1,3 +1,31 @@
+pub mod archive;
+pub mod auth;
+pub mod backup;
+pub mod config;
+pub mod eventlog;
+pub mod feed;
+pub mod index;
+pub mod inventory;
+pub mod locks;
+pub mod markdown;
+pub mod metrics;
+pub mod migration;
+pub mod money;
+pub mod pick;
 pub mod prelude;
+pub mod report;
+pub mod reservation;
+pub mod status;
+pub mod store;
 pub mod upl;
-pub mod migration;
\ No newline at end of file
+
+pub mod proto {
+  pub mod upl_ext {
+    tonic::include_proto!("upl_ext");
+  }
+  pub mod health {
+    pub mod v1 {
+      tonic::include_proto!("grpc.health.v1");
+    }
+  }
+}
@@ -0,0 +1,447 @@
+use crate::upl::*;
+use chrono::prelude::*;
+use std::collections::BTreeMap;
+
+/// Lock state machine violations
+#[derive(Debug, Clone, PartialEq)]
+pub enum LockError {
+  // Lock::None cannot be acquired
+  EmptyLock,
+  // UPL is already locked, even if by the same holder
+  AlreadyLocked(Lock),
+  // UPL has no lock to release
+  NotLocked,
+  // Lock can be released only by its holder
+  NotOwner { held: Lock, requested: Lock },
+  // UPL cannot move to the location with its current lock
+  ForeignLock { held: Lock, to: Location },
+  // UPL is in transit with a delivery, it must be received first
+  InTransit(u32),
+}
+
+// Lock holder in human readable form
+fn holder(lock: &Lock) -> String {
+  match lock {
+    Lock::Cart(cart_id) => format!("kosár ({})", cart_id),
+    Lock::Delivery(delivery_id) => format!("szállítás ({})", delivery_id),
+    Lock::Inventory(inventory_id) => format!("leltár ({})", inventory_id),
+    Lock::None => "nincs".to_string(),
+  }
+}
+
+impl std::fmt::Display for LockError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      LockError::EmptyLock => write!(f, "Üres zárolás nem adható meg!"),
+      LockError::AlreadyLocked(held) => write!(
+        f,
+        "A UPL már zárolva van! Zároló: {}. Előbb fel kell oldani.",
+        holder(held)
+      ),
+      LockError::NotLocked => write!(f, "A UPL nincs zárolva!"),
+      LockError::NotOwner { held, requested } => write!(
+        f,
+        "A UPL zárolása nem oldható fel! Zároló: {}, feloldó: {}",
+        holder(held),
+        holder(requested)
+      ),
+      LockError::ForeignLock { held, to } => write!(
+        f,
+        "A UPL nem mozgatható ide: {:?}! Zároló: {}",
+        to,
+        holder(held)
+      ),
+      LockError::InTransit(delivery_id) => write!(
+        f,
+        "A UPL szállítás alatt van! Szállítás: {}. Előbb be kell érkeznie.",
+        delivery_id
+      ),
+    }
+  }
+}
+
+/// Check whether a lock can be acquired
+/// Only an unlocked UPL can be locked, a held lock is never replaced
+/// by another one, it must be released first by its holder.
+pub fn check_acquire(held: &Lock, next: &Lock) -> Result<(), LockError> {
+  match (held, next) {
+    (_, Lock::None) => Err(LockError::EmptyLock),
+    (Lock::None, _) => Ok(()),
+    (held, _) => Err(LockError::AlreadyLocked(held.clone())),
+  }
+}
+
+/// Check whether a lock can be released by the given holder
+pub fn check_release(held: &Lock, by: &Lock) -> Result<(), LockError> {
+  match held {
+    Lock::None => Err(LockError::NotLocked),
+    held if held == by => Ok(()),
+    held => Err(LockError::NotOwner {
+      held: held.clone(),
+      requested: by.clone(),
+    }),
+  }
+}
+
+/// Check whether a UPL with its held lock can move to a location
+/// An unlocked UPL can move anywhere but Discard. A locked UPL can
+/// move only to its own holder, a cart to its cart, a delivery to its
+/// delivery and an inventory to its discard; the move releases that lock.
+/// Any other lock is foreign and blocks the move.
+pub fn check_move(held: &Lock, to: &Location) -> Result<(), LockError> {
+  let allowed = match (held, to) {
+    (Lock::None, Location::Discard(_)) => return Err(LockError::NotLocked),
+    (Lock::None, _) => true,
+    (Lock::Cart(cart_id), Location::Cart(to_id)) => cart_id == to_id,
+    (Lock::Delivery(delivery_id), Location::Delivery(to_id)) => delivery_id == to_id,
+    (Lock::Inventory(inventory_id), Location::Discard(to_id)) => inventory_id == to_id,
+    _ => false,
+  };
+  match allowed {
+    true => Ok(()),
+    false => Err(LockError::ForeignLock {
+      held: held.clone(),
+      to: to.clone(),
+    }),
+  }
+}
+
+/// Check whether a UPL can be locked at its location
+/// A dispatched UPL has no lock, but it belongs to its delivery
+/// until it is received, so it cannot be locked in transit.
+pub fn check_lock_at(at: &Location) -> Result<(), LockError> {
+  match at {
+    Location::Delivery(delivery_id) => Err(LockError::InTransit(*delivery_id)),
+    _ => Ok(()),
+  }
+}
+
+/// Check whether a UPL can move away from its location
+/// A UPL in transit can only be received into a stock.
+pub fn check_move_from(from: &Location, to: &Location) -> Result<(), LockError> {
+  match (from, to) {
+    (Location::Delivery(_), Location::Stock(_)) => Ok(()),
+    (Location::Delivery(delivery_id), _) => Err(LockError::InTransit(*delivery_id)),
+    _ => Ok(()),
+  }
+}
+
+/// Check whether the lock of a UPL has expired
+/// by the TTL of its own lease
+pub fn is_expired(upl: &Upl, now: DateTime<Utc>) -> bool {
+  match upl.get_lock_lease().and_then(|lease| lease.expires_at()) {
+    Some(expires_at) => upl.has_lock() && expires_at <= now,
+    None => false,
+  }
+}
+
+/// Get the IDs of the UPLs with an expired lock
+pub fn expired<'a>(upls: impl Iterator<Item = &'a Upl>, now: DateTime<Utc>) -> Vec<String> {
+  upls
+    .filter(|upl| is_expired(upl, now))
+    .map(|upl| upl.id.clone())
+    .collect()
+}
+
+/// Lock released by the lock reaper
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReapedLock {
+  pub upl_id: String,
+  pub lock: Lock,
+  pub acquired_at: DateTime<Utc>,
+}
+
+/// Lock reaper report
+#[derive(Debug, Default)]
+pub struct ReapReport {
+  pub released: Vec<ReapedLock>,
+  // UPL ID and error message
+  pub failed: Vec<(String, String)>,
+}
+
+/// Lock holder
+/// A cart, a delivery or an inventory session with its locked UPLs
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockHolder {
+  pub lock: Lock,
+  pub upl_ids: Vec<String>,
+  pub oldest_acquired_at: DateTime<Utc>,
+}
+
+/// Get locks acquired before a given time grouped by their holder
+/// Oldest holder first
+pub fn stale<'a>(
+  upls: impl Iterator<Item = &'a Upl>,
+  acquired_before: DateTime<Utc>,
+) -> Vec<LockHolder> {
+  let mut holders: BTreeMap<String, LockHolder> = BTreeMap::new();
+  for upl in upls {
+    let acquired_at = match upl.get_lock_lease() {
+      Some(lease) if upl.has_lock() && lease.acquired_at < acquired_before => lease.acquired_at,
+      _ => continue,
+    };
+    let holder = holders
+      .entry(format!("{:?}", upl.get_lock()))
+      .or_insert_with(|| LockHolder {
+        lock: upl.get_lock().clone(),
+        upl_ids: Vec::new(),
+        oldest_acquired_at: acquired_at,
+      });
+    holder.upl_ids.push(upl.id.clone());
+    holder.oldest_acquired_at = holder.oldest_acquired_at.min(acquired_at);
+  }
+  let mut holders = holders.into_values().collect::<Vec<_>>();
+  holders.sort_by_key(|holder| holder.oldest_acquired_at);
+  holders
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Locked UPL with its lease
+  fn locked(id: &str, lock: Lock, acquired_at: DateTime<Utc>, ttl_minutes: Option<u64>) -> Upl {
+    Upl {
+      id: id.to_string(),
+      lock,
+      lock_lease: Some(LockLease::new(acquired_at, ttl_minutes)),
+      ..Upl::default()
+    }
+  }
+
+  #[test]
+  fn test_expired_and_stale() {
+    let now = Utc.with_ymd_and_hms(2021, 6, 10, 12, 0, 0).unwrap();
+    let upls = [
+      locked(
+        "a",
+        Lock::Cart("c1".to_string()),
+        now - chrono::Duration::minutes(31),
+        Some(30),
+      ),
+      locked(
+        "b",
+        Lock::Cart("c1".to_string()),
+        now - chrono::Duration::minutes(5),
+        Some(30),
+      ),
+      // Lock without a TTL never expires
+      locked(
+        "c",
+        Lock::Delivery(1),
+        now - chrono::Duration::days(3),
+        None,
+      ),
+      Upl {
+        id: "d".to_string(),
+        ..Upl::default()
+      },
+    ];
+    assert_eq!(expired(upls.iter(), now), vec!["a".to_string()]);
+
+    let holders = stale(upls.iter(), now - chrono::Duration::minutes(1));
+    assert_eq!(holders.len(), 2);
+    assert_eq!(holders[0].lock, Lock::Delivery(1));
+    assert_eq!(holders[1].upl_ids, vec!["a".to_string(), "b".to_string()]);
+    assert_eq!(
+      holders[1].oldest_acquired_at,
+      now - chrono::Duration::minutes(31)
+    );
+
+    // The lease is taken when the lock is acquired
+    // and dropped when it is released
+    let mut upl = Upl::default();
+    upl
+      .lock_with_ttl(Lock::Cart("c2".to_string()), Some(30), 1)
+      .unwrap();
+    let lease = upl.get_lock_lease().unwrap().clone();
+    assert_eq!(lease.ttl_minutes, Some(30));
+    assert_eq!(
+      lease.expires_at(),
+      Some(lease.acquired_at + chrono::Duration::minutes(30))
+    );
+    assert!(!is_expired(&upl, lease.acquired_at));
+    assert!(is_expired(
+      &upl,
+      lease.acquired_at + chrono::Duration::minutes(30)
+    ));
+    upl.unlock(Lock::Cart("c2".to_string()), 1).unwrap();
+    assert!(upl.get_lock_lease().is_none());
+    upl.lock(Lock::Inventory(1), 1).unwrap();
+    assert_eq!(upl.get_lock_lease().unwrap().ttl_minutes, None);
+
+    let mut upl = upls[0].clone();
+    upl.expire_lock().unwrap();
+    assert!(!upl.has_lock());
+    assert!(upl.get_lock_lease().is_none());
+    match upl.get_history().last().unwrap().get_event() {
+      UplHistoryEvent::LockExpired { acquired_at, .. } => {
+        assert_eq!(*acquired_at, now - chrono::Duration::minutes(31))
+      }
+      _ => panic!("lock_expired event expected"),
+    }
+    assert_eq!(upl.expire_lock().unwrap_err(), LockError::NotLocked);
+  }
+
+  #[test]
+  fn test_acquire() {
+    let cart = Lock::Cart("c1".to_string());
+    let locks = [cart.clone(), Lock::Delivery(1), Lock::Inventory(1)];
+    // Every lock can be acquired on an unlocked UPL
+    for next in &locks {
+      assert_eq!(check_acquire(&Lock::None, next), Ok(()));
+    }
+    assert_eq!(
+      check_acquire(&Lock::None, &Lock::None),
+      Err(LockError::EmptyLock)
+    );
+    // No lock replaces a held one, not even the same or another cart
+    let others = [
+      cart.clone(),
+      Lock::Cart("c2".to_string()),
+      Lock::Delivery(1),
+      Lock::Delivery(2),
+      Lock::Inventory(1),
+      Lock::Inventory(2),
+    ];
+    for held in &locks {
+      for next in &others {
+        assert_eq!(
+          check_acquire(held, next),
+          Err(LockError::AlreadyLocked(held.clone()))
+        );
+      }
+      assert_eq!(check_acquire(held, &Lock::None), Err(LockError::EmptyLock));
+    }
+
+    let mut upl = Upl::default();
+    upl.lock(cart.clone(), 1).unwrap();
+    assert!(!upl.can_lock());
+    assert_eq!(
+      upl.lock(Lock::Inventory(1), 1).unwrap_err(),
+      LockError::AlreadyLocked(cart.clone())
+    );
+    assert_eq!(upl.get_lock(), &cart);
+  }
+
+  #[test]
+  fn test_release() {
+    let cart = Lock::Cart("c1".to_string());
+    for held in &[cart.clone(), Lock::Delivery(1), Lock::Inventory(1)] {
+      // Only the holder can release
+      assert_eq!(check_release(held, held), Ok(()));
+      for by in &[
+        Lock::Cart("c2".to_string()),
+        Lock::Delivery(2),
+        Lock::Inventory(2),
+        Lock::None,
+      ] {
+        assert_eq!(
+          check_release(held, by),
+          Err(LockError::NotOwner {
+            held: held.clone(),
+            requested: by.clone(),
+          })
+        );
+      }
+    }
+    assert_eq!(
+      check_release(&Lock::None, &Lock::None),
+      Err(LockError::NotLocked)
+    );
+    assert_eq!(check_release(&Lock::None, &cart), Err(LockError::NotLocked));
+
+    let mut upl = Upl::default();
+    upl.lock(cart.clone(), 1).unwrap();
+    assert!(upl.unlock(Lock::Cart("c2".to_string()), 1).is_err());
+    assert!(upl.has_lock());
+    upl.unlock(cart.clone(), 1).unwrap();
+    assert!(!upl.has_lock());
+    assert_eq!(upl.unlock(cart, 1).unwrap_err(), LockError::NotLocked);
+  }
+
+  #[test]
+  fn test_move() {
+    let cart = Lock::Cart("c1".to_string());
+    let targets = [
+      Location::Stock(1),
+      Location::Delivery(1),
+      Location::Cart("c1".to_string()),
+      Location::Discard(1),
+    ];
+    // Unlocked UPL moves anywhere but Discard
+    for to in &targets[..3] {
+      assert_eq!(check_move(&Lock::None, to), Ok(()));
+    }
+    assert_eq!(
+      check_move(&Lock::None, &Location::Discard(1)),
+      Err(LockError::NotLocked)
+    );
+    // Locked UPL moves only to its own holder
+    let own = [
+      (cart.clone(), Location::Cart("c1".to_string())),
+      (Lock::Delivery(1), Location::Delivery(1)),
+      (Lock::Inventory(1), Location::Discard(1)),
+    ];
+    for (held, own_location) in &own {
+      for to in targets.iter().chain(&[
+        Location::Delivery(2),
+        Location::Cart("c2".to_string()),
+        Location::Discard(2),
+      ]) {
+        let res = check_move(held, to);
+        match to == own_location {
+          true => assert_eq!(res, Ok(())),
+          false => assert_eq!(
+            res,
+            Err(LockError::ForeignLock {
+              held: held.clone(),
+              to: to.clone(),
+            })
+          ),
+        }
+      }
+    }
+
+    // The move releases the own lock
+    let mut upl = Upl::default();
+    upl.lock(cart.clone(), 1).unwrap();
+    assert!(upl.move_upl(Location::Stock(2), 1).is_err());
+    assert_eq!(upl.get_location(), &Location::Stock(0));
+    upl.move_upl(Location::Cart("c1".to_string()), 1).unwrap();
+    assert!(!upl.has_lock());
+    assert_eq!(
+      upl.get_history().last().unwrap().get_event().get_kind(),
+      "unlocked"
+    );
+
+    // No unlock event without a lock
+    let mut upl = Upl::default();
+    upl.move_upl(Location::Stock(2), 1).unwrap();
+    assert_eq!(
+      upl.get_history().last().unwrap().get_event().get_kind(),
+      "moved"
+    );
+  }
+
+  #[test]
+  fn test_in_transit() {
+    let mut upl = Upl::default();
+    upl.move_upl(Location::Delivery(1), 1).unwrap();
+    // Dispatched UPL cannot be locked
+    assert_eq!(
+      upl.lock(Lock::Cart("c1".to_string()), 1).unwrap_err(),
+      LockError::InTransit(1)
+    );
+    assert!(!upl.has_lock());
+    // and it can be received only into a stock
+    for to in &[Location::Delivery(2), Location::Cart("c1".to_string())] {
+      assert_eq!(
+        upl.move_upl(to.clone(), 1).unwrap_err(),
+        LockError::InTransit(1)
+      );
+    }
+    upl.move_upl(Location::Stock(2), 1).unwrap();
+    upl.lock(Lock::Cart("c1".to_string()), 1).unwrap();
+  }
+}
@@ -1,33 +1,128 @@
-use async_stream::stream;
-use chrono::{DateTime, Utc};
-use futures::pin_mut;
+use chrono::{DateTime, Local, TimeZone, Utc};
 use futures_util::stream::StreamExt;
 use gzlib::proto::upl::upl_server::*;
 use gzlib::proto::upl::*;
 use packman::*;
-use std::{collections::HashMap, env, path::PathBuf};
-use tokio::sync::{oneshot, Mutex};
+use std::{
+  collections::{BTreeSet, HashMap},
+  path::PathBuf,
+  sync::Arc,
+};
+use tokio::sync::oneshot;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{transport::Server, Request, Response, Status};
+use upl_microservice::auth::Authorized;
+use upl_microservice::inventory::InventoryMethods;
+use upl_microservice::metrics::{Metered, TimedMutex};
+use upl_microservice::migration::lock_lease;
 use upl_microservice::prelude::*;
+use upl_microservice::proto::health::v1::health_check_response::ServingStatus;
+use upl_microservice::proto::health::v1::health_server::*;
+use upl_microservice::proto::health::v1::*;
+use upl_microservice::proto::upl_ext::upl_ext_server::*;
+use upl_microservice::proto::upl_ext::*;
+use upl_microservice::reservation::{ReservationMethods, ReservedAmount};
 use upl_microservice::upl::{Location, UplMethods};
 use upl_microservice::*;
 
+// UplService is shared between the Upl and UplExt
+// gRPC servers, so its state is behind Arcs
+#[derive(Clone)]
 struct UplService {
   // Active UPLs
-  upls: Mutex<VecPack<upl::Upl>>,
+  upls: Arc<TimedMutex<store::UplStore>>,
   // Archived UPLs
-  archive: Mutex<VecPack<upl::Upl>>,
+  archive: Arc<TimedMutex<archive::ArchiveStore>>,
+  // Inventory sessions
+  inventories: Arc<TimedMutex<VecPack<inventory::InventorySession>>>,
+  // Registry of every issued UPL ID
+  index: Arc<TimedMutex<index::UplIndex>>,
+  // Cart reservations
+  reservations: Arc<TimedMutex<VecPack<reservation::Reservation>>>,
+  // UPL change feed
+  feed: feed::ChangeFeed,
+  // Service config
+  config: Arc<config::Config>,
+  // VAT rates of the gross prices, from the config
+  vat_rates: upl::VatRates,
+  // Time of the last persisted UPL change
+  clock: status::PersistClock,
+  // Error of the metrics endpoint if it has stopped
+  metrics_error: status::ServerError,
+  started_at: DateTime<Utc>,
 }
 
+// Health check and status RPCs give up waiting
+// for a store lock after this time
+const LOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
 impl UplService {
-  fn init(upls: VecPack<upl::Upl>, archive: VecPack<upl::Upl>) -> Self {
+  fn init(
+    upls: VecPack<upl::Upl>,
+    archive: archive::ArchiveStore,
+    inventories: VecPack<inventory::InventorySession>,
+    index: index::UplIndex,
+    reservations: VecPack<reservation::Reservation>,
+    log: Option<eventlog::EventLog>,
+    config: config::Config,
+  ) -> Self {
+    // UPL change feed
+    // It keeps the latest events for resuming subscribers
+    let feed = feed::ChangeFeed::new(config.feed_capacity);
+    let vat_rates = config.vat_rates().expect("Error while parsing VAT rates");
+    let clock = status::PersistClock::new();
+    let mut upls = store::UplStore::new(upls);
+    upls.set_feed(feed.clone());
+    upls.set_clock(clock.clone());
+    if let Some(log) = log {
+      upls.set_log(log);
+    }
     Self {
-      upls: Mutex::new(upls),
-      archive: Mutex::new(archive),
+      upls: Arc::new(TimedMutex::new("upls", upls)),
+      archive: Arc::new(TimedMutex::new("archive", archive)),
+      inventories: Arc::new(TimedMutex::new("inventories", inventories)),
+      index: Arc::new(TimedMutex::new("index", index)),
+      reservations: Arc::new(TimedMutex::new("reservations", reservations)),
+      feed,
+      config: Arc::new(config),
+      vat_rates,
+      clock,
+      metrics_error: status::ServerError::new(),
+      started_at: Utc::now(),
     }
   }
-  async fn create_new(&self, r: UplNew) -> ServiceResult<UplObj> {
+
+  // Check whether a new UPL ID can be issued
+  // It must be unknown for the UPL index and the archive as well
+  async fn check_new_upl_id(&self, upl_id: &str) -> ServiceResult<()> {
+    if self.index.lock().await.contains(upl_id) || self.archive.lock().await.contains(upl_id) {
+      return Err(ServiceError::already_exist(&format!(
+        "A megadott UPL ID már ki lett adva! {}",
+        upl_id
+      )));
+    }
+    Ok(())
+  }
+
+  // Register a new UPL ID in the UPL index
+  // and store the new UPL as an active one
+  async fn register_new_upl(&self, new_upl: upl::Upl) -> ServiceResult<()> {
+    let index = self.index.lock().await;
+
+    // Register UPL ID
+    index.add(&new_upl)?;
+
+    // Store new UPL
+    // If it fails, we remove its index as it was not issued
+    if let Err(e) = self.upls.lock().await.insert(new_upl.clone()) {
+      index.remove(new_upl.get_upl_id())?;
+      return Err(e.into());
+    }
+
+    Ok(())
+  }
+  // Build and validate a new UPL from a UplNew request
+  fn build_new_upl(&self, r: UplNew) -> ServiceResult<upl::Upl> {
     // Transform best_before object
     let best_before: Option<DateTime<Utc>> = match r.best_before.len() {
       x if x == 0 => None,
@@ -46,32 +141,152 @@ impl UplService {
       r.piece,
       r.sku_divisible_amount,
       r.sku_divisible,
-      r.sku_net_price,
+      r.sku_net_price.into(),
       upl::VAT::from_str(&r.sku_vat).map_err(|e| ServiceError::bad_request(&e))?,
       r.procurement_id,
-      r.procurement_net_price_sku,
+      r.procurement_net_price_sku.into(),
       upl::Location::Stock(r.stock_id),
       best_before,
       r.is_opened,
+      &self.vat_rates,
       r.created_by,
     )
     .map_err(|e| ServiceError::bad_request(&e))?;
 
-    // Store new UPL
-    self.upls.lock().await.insert(new_upl.clone())?;
+    Ok(new_upl)
+  }
+
+  async fn create_new(&self, r: UplNew) -> ServiceResult<UplObj> {
+    let new_upl = self.build_new_upl(r)?;
+
+    // Check if UPL ID was never issued
+    self.check_new_upl_id(new_upl.get_upl_id()).await?;
+
+    // Register and store new UPL
+    self.register_new_upl(new_upl.clone()).await?;
 
     // Return it as UplObj
-    Ok(new_upl.into())
+    Ok(upl_obj(new_upl, &self.vat_rates))
   }
 
-  async fn get_bulk(&self, r: BulkRequest) -> ServiceResult<Vec<UplObj>> {
+  async fn create_new_bulk_checked(
+    &self,
+    r: CreateNewBulkRequest,
+  ) -> ServiceResult<CreateNewBulkResponse> {
+    let new_result = |upl_id: String, res: ServiceResult<()>| CreateNewResult {
+      upl_id,
+      created: res.is_ok(),
+      error: res.err().map(|e| e.to_string()).unwrap_or_default(),
+    };
+
+    // Create UPLs one by one
+    // Invalid UPLs are reported and skipped
+    if !r.all_or_nothing {
+      let mut results = Vec::new();
+      for upl_new in r.upls {
+        let upl_id = upl_new.upl_id.clone();
+        let res = self.create_new(upl_new).await.map(|_| ());
+        results.push(new_result(upl_id, res));
+      }
+      let created = results.iter().filter(|res| res.created).count() as u32;
+      return Ok(CreateNewBulkResponse { results, created });
+    }
+
+    // All or nothing
+    // First validate every UPL
+    let mut checked: Vec<(String, ServiceResult<upl::Upl>)> = Vec::new();
+    for upl_new in r.upls {
+      let upl_id = upl_new.upl_id.clone();
+      let res = match self.build_new_upl(upl_new) {
+        Ok(_) if checked.iter().any(|(id, _)| id == &upl_id) => Err(ServiceError::bad_request(
+          &format!("A UPL ID többször szerepel a kérésben! {}", upl_id),
+        )),
+        Ok(new_upl) => self.check_new_upl_id(&upl_id).await.map(|_| new_upl),
+        Err(e) => Err(e),
+      };
+      checked.push((upl_id, res));
+    }
+
+    // If any of them is invalid, we create nothing
+    if checked.iter().any(|(_, res)| res.is_err()) {
+      let results = checked
+        .into_iter()
+        .map(|(upl_id, res)| {
+          let res = match res {
+            Ok(_) => Err(ServiceError::bad_request(
+              "A UPL nem jött létre, mert a kérés más tétele hibás!",
+            )),
+            Err(e) => Err(e),
+          };
+          new_result(upl_id, res)
+        })
+        .collect();
+      return Ok(CreateNewBulkResponse {
+        results,
+        created: 0,
+      });
+    }
+
+    // Register and store all the UPLs as one transaction
+    let new_upls = checked
+      .into_iter()
+      .filter_map(|(_, res)| res.ok())
+      .collect::<Vec<upl::Upl>>();
+    let index = self.index.lock().await;
+    let mut registered: Vec<String> = Vec::new();
+    let res = self.upls.lock().await.atomic(|tx| {
+      for new_upl in &new_upls {
+        tx.insert(new_upl.clone())?;
+        index.add(new_upl)?;
+        registered.push(new_upl.get_upl_id().to_string());
+      }
+      ServiceResult::Ok(())
+    });
+    if let Err(e) = res {
+      // Remove the indexes of the rolled back UPLs
+      for upl_id in registered {
+        index.remove(&upl_id)?;
+      }
+      return Err(e);
+    }
+
+    let results = new_upls
+      .iter()
+      .map(|new_upl| new_result(new_upl.get_upl_id().to_string(), Ok(())))
+      .collect::<Vec<CreateNewResult>>();
+    Ok(CreateNewBulkResponse {
+      created: results.len() as u32,
+      results,
+    })
+  }
+
+  /// Create UPLs from the streamed bulk request
+  /// Best effort: invalid UPLs are skipped,
+  /// it returns the IDs of the created ones
+  async fn create_new_bulk(&self, upls: Vec<UplNew>) -> ServiceResult<Vec<String>> {
     let res = self
-      .upls
-      .lock()
-      .await
+      .create_new_bulk_checked(CreateNewBulkRequest {
+        upls,
+        all_or_nothing: false,
+      })
+      .await?;
+    Ok(
+      res
+        .results
+        .into_iter()
+        .filter(|result| result.created)
+        .map(|result| result.upl_id)
+        .collect(),
+    )
+  }
+
+  async fn get_bulk(&self, r: BulkRequest) -> ServiceResult<Vec<UplObj>> {
+    let upls = self.upls.lock().await;
+    let res = r
+      .upl_ids
       .iter()
-      .filter(|upl| r.upl_ids.contains(&upl.unpack().id))
-      .map(|upl| upl.unpack().clone().into())
+      .filter_map(|upl_id| upls.find_id(upl_id).ok())
+      .map(|upl| upl_obj(upl.unpack().clone(), &self.vat_rates))
       .collect::<Vec<UplObj>>();
 
     Ok(res)
@@ -79,19 +294,12 @@ impl UplService {
 
   async fn get_by_id(&self, r: ByIdRequest) -> ServiceResult<UplObj> {
     let res = self.upls.lock().await.find_id(&r.upl_id)?.unpack().clone();
-    Ok(res.into())
+    Ok(upl_obj(res, &self.vat_rates))
   }
 
   async fn get_by_id_archive(&self, r: ByIdRequest) -> ServiceResult<UplObj> {
     // Looking for archived object
-    let mut res: UplObj = self
-      .archive
-      .lock()
-      .await
-      .find_id(&r.upl_id)?
-      .unpack()
-      .clone()
-      .into();
+    let mut res = upl_obj(self.archive.lock().await.get(&r.upl_id)?, &self.vat_rates);
     // Set UplObj to be archived
     res.is_archived = true;
     // Return UplObj
@@ -103,9 +311,8 @@ impl UplService {
       .upls
       .lock()
       .await
-      .iter()
-      .filter(|upl| upl.unpack().get_sku() == r.sku)
-      .map(|upl| upl.unpack().id.clone())
+      .find_by_sku(r.sku)
+      .map(|upl| upl.id.clone())
       .collect::<Vec<String>>();
 
     Ok(res)
@@ -116,9 +323,8 @@ impl UplService {
       .upls
       .lock()
       .await
-      .iter()
-      .filter(|upl| upl.unpack().product_id == r.product_id)
-      .map(|upl| upl.unpack().id.clone())
+      .find_by_product(r.product_id)
+      .map(|upl| upl.id.clone())
       .collect::<Vec<String>>();
 
     Ok(res)
@@ -142,12 +348,9 @@ impl UplService {
       .upls
       .lock()
       .await
-      .iter()
-      .filter(|upl| {
-        let _upl = upl.unpack();
-        _upl.get_sku() == r.sku && _upl.location == location
-      })
-      .map(|upl| upl.unpack().id.clone())
+      .find_by_location(&location)
+      .filter(|upl| upl.get_sku() == r.sku)
+      .map(|upl| upl.id.clone())
       .collect::<Vec<String>>();
 
     Ok(res)
@@ -168,12 +371,8 @@ impl UplService {
       .upls
       .lock()
       .await
-      .iter()
-      .filter(|upl| {
-        let _upl = upl.unpack();
-        _upl.location == location
-      })
-      .map(|upl| upl.unpack().id.clone())
+      .find_by_location(&location)
+      .map(|upl| upl.id.clone())
       .collect::<Vec<String>>();
 
     Ok(res)
@@ -190,110 +389,147 @@ impl UplService {
       ),
     };
 
-    let res = self
-      .upls
-      .lock()
-      .await
-      .find_id_mut(&r.upl)?
-      .as_mut()
-      .unpack()
-      .set_best_before(bbefore, r.created_by)
-      .clone();
+    let res = self.upls.lock().await.update(&r.upl, |_upl| {
+      ServiceResult::Ok(_upl.set_best_before(bbefore, r.created_by).clone())
+    })?;
 
-    Ok(res.into())
+    Ok(upl_obj(res, &self.vat_rates))
   }
 
   async fn split(&self, r: SplitRequest) -> ServiceResult<UplObj> {
-    let new_upl = self
-      .upls
-      .lock()
-      .await
-      .find_id_mut(&r.upl)?
-      .as_mut()
-      .unpack()
-      .split(r.new_upl, r.piece, r.created_by)
-      .map_err(|e| ServiceError::bad_request(&e))?;
+    // Check if new UPL ID was never issued
+    self.check_new_upl_id(&r.new_upl).await?;
 
-    // Insert the new UPL
-    self.upls.lock().await.insert(new_upl)?;
+    let index = self.index.lock().await;
 
-    // Select itself to send back as UplObj
-    let res = self.upls.lock().await.find_id(&r.upl)?.unpack().clone();
+    // Split, insert and register as one transaction
+    let res = self.upls.lock().await.atomic(|tx| {
+      let new_upl = tx
+        .find_id_mut(&r.upl)?
+        .as_mut()
+        .unpack()
+        .split(r.new_upl.clone(), r.piece, &self.vat_rates, r.created_by)
+        .map_err(|e| ServiceError::bad_request(&e))?;
 
-    Ok(res.into())
+      // Insert the new UPL
+      tx.insert(new_upl.clone())?;
+
+      // Register UPL ID as the last step,
+      // so we never need to remove it on rollback
+      index.add(&new_upl)?;
+
+      // Select itself to send back as UplObj
+      ServiceResult::Ok(tx.find_id(&r.upl)?.unpack().clone())
+    })?;
+
+    Ok(upl_obj(res, &self.vat_rates))
   }
 
   async fn divide(&self, r: DivideRequest) -> ServiceResult<UplObj> {
-    // Try to divide UPL
-    let new_upl = self
-      .upls
-      .lock()
-      .await
-      .find_id_mut(&r.upl)?
-      .as_mut()
-      .unpack()
-      .divide(r.new_upl, r.requested_amount, r.created_by)
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+    // Check if new UPL ID was never issued
+    self.check_new_upl_id(&r.new_upl).await?;
+
+    let index = self.index.lock().await;
+    let reservations = self.reservations.lock().await;
+
+    // Divide, insert and register as one transaction
+    let res = self.upls.lock().await.atomic(|tx| {
+      // DividedProduct reservations covered before the divide
+      // must stay covered after it
+      let product_id = tx.find_id(&r.upl)?.unpack().get_product_id();
+      let subject = reservation::Subject::DividedProduct(product_id);
+      let reserved =
+        ReservedAmount::for_subject(reservations.iter().map(|r| r.unpack()), &subject, None);
+      let covered_before = reserved.is_covered_by(&reservation::available(
+        tx.find_by_product(product_id),
+        &subject,
+      ));
+
+      // Try to divide UPL
+      let new_upl = tx
+        .find_id_mut(&r.upl)?
+        .as_mut()
+        .unpack()
+        .divide(
+          r.new_upl.clone(),
+          r.requested_amount,
+          &self.vat_rates,
+          r.created_by,
+        )
+        .map_err(|e| ServiceError::bad_request(&e))?
+        .clone();
+
+      // Insert the new UPL into the UPL db
+      tx.insert(new_upl.clone())?;
+
+      let available = reservation::available(tx.find_by_product(product_id), &subject);
+      if covered_before && !reserved.is_covered_by(&available) {
+        return Err(ServiceError::bad_request(
+          "A kimérés után a foglalások nem lennének teljesíthetők!",
+        ));
+      }
 
-    // Insert the new UPL into the UPL db
-    self.upls.lock().await.insert(new_upl)?;
+      // Register UPL ID as the last step,
+      // so we never need to remove it on rollback
+      index.add(&new_upl)?;
 
-    // Find self and return as UplObj
-    let res = self.upls.lock().await.find_id(&r.upl)?.unpack().clone();
+      // Find self and return as UplObj
+      ServiceResult::Ok(tx.find_id(&r.upl)?.unpack().clone())
+    })?;
 
-    Ok(res.into())
+    Ok(upl_obj(res, &self.vat_rates))
   }
 
   async fn set_depreciation(&self, r: DepreciationRequest) -> ServiceResult<UplObj> {
     // Try to find UPL and set depreciation
-    let res = self
-      .upls
-      .lock()
-      .await
-      .find_id_mut(&r.upl)?
-      .as_mut()
-      .unpack()
-      .set_depreciation(r.depreciation_id, r.depreciation_comment, r.created_by)
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+    let res = self.upls.lock().await.update(&r.upl, |_upl| {
+      ServiceResult::Ok(
+        _upl
+          .set_depreciation(
+            r.depreciation_id,
+            r.depreciation_comment.clone(),
+            upl::CreatedBy::Uid(r.created_by),
+          )
+          .map_err(|e| ServiceError::bad_request(&e))?
+          .clone(),
+      )
+    })?;
 
     // Return self as UplObj
-    Ok(res.into())
+    Ok(upl_obj(res, &self.vat_rates))
   }
 
   async fn remove_depreciation(&self, r: DepreciationRemoveRequest) -> ServiceResult<UplObj> {
     // Try find UPL and remove depreciation
-    let res = self
-      .upls
-      .lock()
-      .await
-      .find_id_mut(&r.upl)?
-      .as_mut()
-      .unpack()
-      .remove_deprecation(r.created_by)
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+    let res = self.upls.lock().await.update(&r.upl, |_upl| {
+      ServiceResult::Ok(
+        _upl
+          .remove_deprecation(r.created_by)
+          .map_err(|e| ServiceError::bad_request(&e))?
+          .clone(),
+      )
+    })?;
 
     // Returns self as UplObj
-    Ok(res.into())
+    Ok(upl_obj(res, &self.vat_rates))
   }
 
   async fn set_depreciation_price(&self, r: DepreciationPriceRequest) -> ServiceResult<UplObj> {
     // Try find UPL and set depreciation price
-    let res = self
-      .upls
-      .lock()
-      .await
-      .find_id_mut(&r.upl)?
-      .as_mut()
-      .unpack()
-      .set_depreciation_price(Some(r.depreciation_net_price), r.created_by)
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+    let res = self.upls.lock().await.update(&r.upl, |_upl| {
+      ServiceResult::Ok(
+        _upl
+          .set_depreciation_price(
+            Some(r.depreciation_net_price.into()),
+            upl::CreatedBy::Uid(r.created_by),
+          )
+          .map_err(|e| ServiceError::bad_request(&e))?
+          .clone(),
+      )
+    })?;
 
     // Return self as UplObj
-    Ok(res.into())
+    Ok(upl_obj(res, &self.vat_rates))
   }
 
   async fn remove_deprecation_price(
@@ -301,222 +537,1128 @@ impl UplService {
     r: RemoveDeprecationPriceRequest,
   ) -> ServiceResult<UplObj> {
     // Try find UPL and remove depreciation price
-    let res = self
-      .upls
-      .lock()
-      .await
-      .find_id_mut(&r.upl)?
-      .as_mut()
-      .unpack()
-      .set_depreciation_price(None, r.created_by)
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+    let res = self.upls.lock().await.update(&r.upl, |_upl| {
+      ServiceResult::Ok(
+        _upl
+          .set_depreciation_price(None, upl::CreatedBy::Uid(r.created_by))
+          .map_err(|e| ServiceError::bad_request(&e))?
+          .clone(),
+      )
+    })?;
 
     // Return self as UplObj
-    Ok(res.into())
+    Ok(upl_obj(res, &self.vat_rates))
   }
 
   async fn lock_to_cart(&self, r: CartLockRequest) -> ServiceResult<UplObj> {
-    // Try to find UPL and lock to Cart(ID)
-    let res = self
-      .upls
-      .lock()
-      .await
-      .find_id_mut(&r.upl)?
-      .as_mut()
-      .unpack()
-      .lock(upl::Lock::Cart(r.cart_id), r.created_by)
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+    let mut reservations = self.reservations.lock().await;
+    let mut upls = self.upls.lock().await;
+
+    // Check if the UPL is not reserved by other carts
+    check_cart_reservations(
+      &upls,
+      &reservations,
+      upls.find_id(&r.upl)?.unpack(),
+      &r.cart_id,
+    )?;
+
+    // Try to lock to Cart(ID)
+    let lock = upl::Lock::Cart(r.cart_id.clone());
+    let ttl_minutes = self.config.lock_timeouts.ttl_minutes(&lock);
+    let res = upls.update(&r.upl, |_upl| {
+      ServiceResult::Ok(_upl.lock_with_ttl(lock, ttl_minutes, r.created_by)?.clone())
+    })?;
+
+    // Consume the related cart reservations
+    take_reservation(&mut reservations, &r.cart_id, &res);
 
     // Returns self as UplObj
-    Ok(res.into())
+    Ok(upl_obj(res, &self.vat_rates))
   }
 
   async fn release_lock_from_cart(&self, r: CartUnlockRequest) -> ServiceResult<UplObj> {
     // Try to find UPL and unlock to Cart(ID)
-    let res = self
-      .upls
-      .lock()
-      .await
-      .find_id_mut(&r.upl)?
-      .as_mut()
-      .unpack()
-      .unlock(upl::Lock::Cart(r.cart_id), r.created_by)
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+    let res = self.upls.lock().await.update(&r.upl, |_upl| {
+      ServiceResult::Ok(
+        _upl
+          .unlock(upl::Lock::Cart(r.cart_id.clone()), r.created_by)?
+          .clone(),
+      )
+    })?;
+
+    // Give back the taken amount to the related cart reservation
+    give_back_reservation(&mut *self.reservations.lock().await, &r.cart_id, &res);
 
     // Returns self as UplObj
-    Ok(res.into())
+    Ok(upl_obj(res, &self.vat_rates))
   }
 
   async fn close_cart(&self, r: CloseCartRequest) -> ServiceResult<()> {
     // Try to find all the UPLs that have locked to
     // this given cart; and move them into that Cart Location.
     // This will automatically removes the lock::Cart(ID)
-    self
-      .upls
-      .lock()
-      .await
-      .as_vec_mut()
-      .into_iter()
-      .for_each(|upl| {
-        if upl.unpack().get_lock() == &upl::Lock::Cart(r.cart_id.clone()) {
-          // todo! manage if result is error?
-          let _ = upl
-            .as_mut()
-            .unpack()
-            .move_upl(upl::Location::Cart(r.cart_id.clone()), r.created_by);
-        }
-      });
+    // All or nothing: if any step fails, the UPL changes
+    // are rolled back. The archive records are committed with
+    // the removals, and the archive files are written after it.
+    let archive = self.archive.lock().await;
+    let mut upls = self.upls.lock().await;
+    let archived = upls.atomic(|tx| {
+      let locked_ids = tx
+        .find_by_lock(&upl::Lock::Cart(r.cart_id.clone()))
+        .map(|upl| upl.id.clone())
+        .collect::<Vec<String>>();
+      for upl_id in locked_ids {
+        tx.find_id_mut(&upl_id)?
+          .as_mut()
+          .unpack()
+          .move_upl(upl::Location::Cart(r.cart_id.clone()), r.created_by)?;
+      }
 
-    // Collect upls to archive
-    let upls_to_archive = self
-      .upls
-      .lock()
-      .await
-      .as_vec_mut()
-      .into_iter()
-      .filter(|upl| {
-        if upl.unpack().get_location() == &upl::Location::Cart(r.cart_id.clone()) {
-          return true;
+      // Collect upls to archive
+      let upls_to_archive = tx
+        .find_by_location(&upl::Location::Cart(r.cart_id.clone()))
+        .cloned()
+        .collect::<Vec<upl::Upl>>();
+
+      // Remove UPLs from the active db and archive them
+      let mut archived = Vec::new();
+      for upl_to_arch in upls_to_archive {
+        tx.remove_pack(upl_to_arch.get_id())?;
+        let archive_object = archive::ArchiveObject::archived(
+          upl_to_arch,
+          archive::ArchiveReason::Sold,
+          upl::CreatedBy::Uid(r.created_by),
+        );
+        tx.archive(&archive_object);
+        archived.push(archive_object);
+      }
+      ServiceResult::Ok(archived)
+    })?;
+    drop(upls);
+    for archive_object in &archived {
+      archive.put_committed(archive_object)?;
+    }
+    drop(archive);
+
+    // Release cart reservations
+    self.remove_reservations(&r.cart_id).await?;
+
+    Ok(())
+  }
+
+  async fn lock_to_delivery(&self, r: DeliveryLockRequest) -> ServiceResult<Vec<String>> {
+    let mut upls = self.upls.lock().await;
+
+    // First check all the UPLs
+    // We lock them only if all of them can be locked
+    for upl_id in &r.upl_ids {
+      let _upl = upls.find_id(upl_id)?.unpack();
+      match _upl.get_location() {
+        Location::Stock(_) => (),
+        _ => {
+          return Err(ServiceError::bad_request(&format!(
+            "A UPL nem raktárban van, így nem szállítható! {}",
+            upl_id
+          )))
         }
-        false
-      })
-      .map(|upl| upl.unpack().clone())
-      .collect::<Vec<upl::Upl>>();
+      }
+      if _upl.has_lock() {
+        return Err(ServiceError::bad_request(&format!(
+          "A UPL zárolva van, így nem szállítható! {}",
+          upl_id
+        )));
+      }
+    }
 
-    // Collect UPL IDs to remove them later
-    let upl_ids = upls_to_archive
-      .iter()
-      .map(|u| u.get_id().clone())
-      .collect::<Vec<String>>();
+    // Try to lock UPLs to Delivery(ID)
+    let lock = upl::Lock::Delivery(r.delivery_id);
+    let ttl_minutes = self.config.lock_timeouts.ttl_minutes(&lock);
+    upls.atomic(|tx| {
+      for upl_id in &r.upl_ids {
+        tx.find_id_mut(upl_id)?.as_mut().unpack().lock_with_ttl(
+          lock.clone(),
+          ttl_minutes,
+          r.created_by,
+        )?;
+      }
+      ServiceResult::Ok(())
+    })?;
+
+    Ok(r.upl_ids)
+  }
+
+  async fn release_lock_from_delivery(&self, r: DeliveryUnlockRequest) -> ServiceResult<UplObj> {
+    // Try to find UPL and unlock from Delivery(ID)
+    let res = self.upls.lock().await.update(&r.upl, |_upl| {
+      ServiceResult::Ok(
+        _upl
+          .unlock(upl::Lock::Delivery(r.delivery_id), r.created_by)?
+          .clone(),
+      )
+    })?;
+
+    // Returns self as UplObj
+    Ok(upl_obj(res, &self.vat_rates))
+  }
 
-    // Archive UPLs
-    for upl_to_arch in upls_to_archive {
-      let _ = self.archive.lock().await.insert(upl_to_arch);
+  async fn dispatch_delivery(&self, r: DispatchDeliveryRequest) -> ServiceResult<Vec<String>> {
+    // Move all the UPLs locked to this delivery
+    // into Location::Delivery(ID).
+    // This will automatically removes the Lock::Delivery(ID)
+    // All or nothing: if any move fails, none of them is moved
+    let res = self.upls.lock().await.atomic(|tx| {
+      let locked_ids = tx
+        .find_by_lock(&upl::Lock::Delivery(r.delivery_id))
+        .map(|upl| upl.id.clone())
+        .collect::<Vec<String>>();
+      for upl_id in &locked_ids {
+        tx.find_id_mut(upl_id)?
+          .as_mut()
+          .unpack()
+          .move_upl(Location::Delivery(r.delivery_id), r.created_by)?;
+      }
+      ServiceResult::Ok(locked_ids)
+    })?;
+
+    if res.is_empty() {
+      return Err(ServiceError::not_found(
+        "A megadott szállításhoz nincs zárolt UPL!",
+      ));
     }
 
-    // Remove UPLs from active db
-    for upl_id_to_remove in upl_ids {
-      let _ = self.upls.lock().await.remove_pack(&upl_id_to_remove);
+    Ok(res)
+  }
+
+  async fn receive_delivery(&self, r: ReceiveDeliveryRequest) -> ServiceResult<Vec<String>> {
+    // Move all the UPLs from Location::Delivery(ID)
+    // into the target stock
+    // All or nothing: if any move fails, none of them is moved
+    let res = self.upls.lock().await.atomic(|tx| {
+      let delivered_ids = tx
+        .find_by_location(&Location::Delivery(r.delivery_id))
+        .map(|upl| upl.id.clone())
+        .collect::<Vec<String>>();
+      for upl_id in &delivered_ids {
+        tx.find_id_mut(upl_id)?
+          .as_mut()
+          .unpack()
+          .move_upl(Location::Stock(r.stock_id), r.created_by)?;
+      }
+      ServiceResult::Ok(delivered_ids)
+    })?;
+
+    if res.is_empty() {
+      return Err(ServiceError::not_found(
+        "A megadott szállításban nincs UPL!",
+      ));
     }
 
-    Ok(())
+    Ok(res)
   }
 
-  async fn set_sku_price(&self, r: SetSkuPriceRequest) -> ServiceResult<()> {
-    // Try convert VAT
-    let vat = upl::VAT::from_str(&r.vat).map_err(|e| ServiceError::bad_request(&e))?;
-    // Check if prices valid
-    if (r.net_price * vat) != r.gross_price {
-      return Err(ServiceError::bad_request("A nettó * áfa != bruttó"));
+  async fn open_inventory(&self, r: OpenInventoryRequest) -> ServiceResult<InventoryObj> {
+    let mut inventories = self.inventories.lock().await;
+
+    // Check if there is an open session for this stock
+    if inventories
+      .iter()
+      .any(|i| i.unpack().get_stock_id() == r.stock_id && i.unpack().is_open())
+    {
+      return Err(ServiceError::already_exist(
+        "A raktárhoz már tartozik nyitott leltár!",
+      ));
     }
-    // Reprice related UPLs
-    self
+
+    // Collect the UPLs that are expected in this stock
+    let expected = self
       .upls
       .lock()
       .await
-      .as_vec_mut()
-      .into_iter()
-      .for_each(|upl| {
-        if upl.unpack().get_sku() == r.sku {
-          let _ = upl.as_mut().unpack().set_price(r.net_price, vat);
-          // TODO! LOG ERROR!
-        }
-      });
-    // Return nothing
-    Ok(())
+      .find_by_location(&Location::Stock(r.stock_id))
+      .map(|upl| upl.id.clone())
+      .collect::<Vec<String>>();
+
+    // Next inventory ID
+    let next_id = match inventories.iter().map(|i| *i.unpack().get_id()).max() {
+      Some(last_id) => last_id + 1,
+      None => 1,
+    };
+
+    let new_inventory =
+      inventory::InventorySession::new(next_id, r.stock_id, expected, r.created_by);
+
+    // Store new inventory session
+    inventories.insert(new_inventory.clone())?;
+
+    // Return it as InventoryObj
+    Ok(new_inventory.into())
   }
 
-  async fn set_sku_divisible(&self, r: SetSkuDivisibleRequest) -> ServiceResult<()> {
-    // Set related UPLs
-    self
-      .upls
-      .lock()
-      .await
-      .as_vec_mut()
-      .into_iter()
-      .for_each(|upl| {
-        if upl.unpack().get_sku() == r.sku {
-          let _ = upl.as_mut().unpack().set_divisible(r.divisible);
-          // TODO! ERROR LOG!
-        }
-      });
-    // Return nothing
-    Ok(())
+  async fn scan_inventory(&self, r: ScanInventoryRequest) -> ServiceResult<InventoryObj> {
+    let mut inventories = self.inventories.lock().await;
+    let mut inventory = inventories.find_id_mut(&r.inventory_id)?.as_mut();
+
+    // Register scanned UPL IDs
+    for upl_id in r.upl_ids {
+      inventory
+        .unpack()
+        .scan(upl_id)
+        .map_err(|e| ServiceError::bad_request(&e))?;
+    }
+
+    Ok(inventory.unpack().clone().into())
   }
 
-  // Try to open UPL
-  async fn open_upl(&self, r: OpenUplRequest) -> ServiceResult<UplObj> {
+  async fn get_inventory(&self, r: InventoryByIdRequest) -> ServiceResult<InventoryObj> {
     let res = self
-      .upls
+      .inventories
       .lock()
       .await
-      .find_id_mut(&r.upl_id)?
-      .as_mut()
+      .find_id(&r.inventory_id)?
       .unpack()
-      .open()
-      .map_err(|e| ServiceError::bad_request(&e))?
       .clone();
     Ok(res.into())
   }
 
-  // Try to close UPL
-  async fn close_upl(&self, r: CloseUplRequest) -> ServiceResult<UplObj> {
-    let res = self
-      .upls
-      .lock()
-      .await
-      .find_id_mut(&r.upl_id)?
+  async fn close_inventory(&self, r: CloseInventoryRequest) -> ServiceResult<InventoryObj> {
+    let mut inventories = self.inventories.lock().await;
+    let inventory = inventories.find_id_mut(&r.inventory_id)?;
+
+    if !inventory.unpack().is_open() {
+      return Err(ServiceError::bad_request("A leltár már le van zárva!"));
+    }
+
+    let stock = Location::Stock(inventory.unpack().get_stock_id());
+    let missing = inventory.unpack().get_missing();
+
+    // Lock missing UPLs with Lock::Inventory(ID)
+    // Only the ones that are still in the stock without any lock,
+    // the others have been moved or locked (e.g. sold) in the meantime.
+    // All or nothing: if any of them cannot be locked, none of them is locked
+    // and the session stays open
+    let locked = self.upls.lock().await.atomic(|tx| {
+      let to_lock = tx
+        .find_by_location(&stock)
+        .filter(|upl| missing.contains(&upl.id) && !upl.has_lock())
+        .map(|upl| upl.id.clone())
+        .collect::<Vec<String>>();
+      for upl_id in &to_lock {
+        tx.find_id_mut(upl_id)?
+          .as_mut()
+          .unpack()
+          .lock(upl::Lock::Inventory(r.inventory_id), r.created_by)?;
+      }
+      ServiceResult::Ok(to_lock)
+    })?;
+
+    // Close the session
+    let res = inventory
       .as_mut()
       .unpack()
-      .close()
+      .close(locked, r.created_by)
       .map_err(|e| ServiceError::bad_request(&e))?
       .clone();
+
     Ok(res.into())
   }
 
-  // Try to merge back UPL
-  async fn merge_back(&self, r: MergeRequest) -> ServiceResult<()> {
-    // First find child UPL to merge
-    let child_upl = self
-      .upls
+  async fn release_lock_from_inventory(&self, r: InventoryUnlockRequest) -> ServiceResult<UplObj> {
+    // Try to find UPL and unlock from Inventory(ID)
+    let res = self.upls.lock().await.update(&r.upl, |_upl| {
+      ServiceResult::Ok(
+        _upl
+          .unlock(upl::Lock::Inventory(r.inventory_id), r.created_by)?
+          .clone(),
+      )
+    })?;
+
+    // Returns self as UplObj
+    Ok(upl_obj(res, &self.vat_rates))
+  }
+
+  async fn discard_inventory_upl(&self, r: DiscardInventoryUplRequest) -> ServiceResult<UplObj> {
+    let res = self.upls.lock().await.update(&r.upl, |_upl| {
+      // Only UPLs locked by this inventory can be discarded here
+      if _upl.get_lock() != &upl::Lock::Inventory(r.inventory_id) {
+        return Err(ServiceError::bad_request(
+          "A UPL nem ehhez a leltárhoz van zárolva!",
+        ));
+      }
+
+      // Move UPL to Location::Discard(ID)
+      // This will automatically removes the Lock::Inventory(ID)
+      Ok(
+        _upl
+          .move_upl(Location::Discard(r.inventory_id), r.created_by)?
+          .clone(),
+      )
+    })?;
+
+    Ok(upl_obj(res, &self.vat_rates))
+  }
+
+  async fn restore_from_archive(&self, r: RestoreRequest) -> ServiceResult<UplObj> {
+    if r.reason.trim().is_empty() {
+      return Err(ServiceError::bad_request(
+        "A visszaállítás oka nem lehet üres!",
+      ));
+    }
+
+    let archive = self.archive.lock().await;
+    let mut reservations = self.reservations.lock().await;
+    let mut upls = self.upls.lock().await;
+
+    // Try to load archived UPL
+    let archive_object = archive.get_object(&r.upl_id)?;
+
+    // Merged UPLs live in their parent UPL
+    if archive_object.get_reason() == &archive::ArchiveReason::Merged {
+      return Err(ServiceError::bad_request(
+        "A UPL vissza lett téve a szülő termékbe, így nem állítható vissza!",
+      ));
+    }
+
+    let mut upl = archive_object.get_upl().clone();
+
+    // Check if the UPL ID is not in use
+    if !upls.check_id_available(&r.upl_id) {
+      return Err(ServiceError::already_exist("A megadott UPL ID már aktív!"));
+    }
+
+    // Set restore history event
+    upl.set_history(upl::UplHistoryItem::new(
+      upl::CreatedBy::Uid(r.created_by),
+      upl::UplHistoryEvent::Restored { reason: r.reason },
+    ));
+
+    // Set its new location or lock
+    let mut cart: Option<String> = None;
+    match r.target {
+      Some(restore_request::Target::Stock(stock_id)) => {
+        upl.move_upl(Location::Stock(stock_id), r.created_by)?;
+      }
+      Some(restore_request::Target::Cart(cart_id)) => {
+        if r.cart_stock == 0 {
+          return Err(ServiceError::bad_request(
+            "A kosárba visszaállításhoz meg kell adni a raktárat!",
+          ));
+        }
+        // It must be in stock to be found as a locked stock item
+        upl.move_upl(Location::Stock(r.cart_stock), r.created_by)?;
+        // Same as a cart lock, it cannot eat the reservations of other carts
+        check_cart_reservations(&upls, &reservations, &upl, &cart_id)?;
+        let lock = upl::Lock::Cart(cart_id.clone());
+        let ttl_minutes = self.config.lock_timeouts.ttl_minutes(&lock);
+        upl.lock_with_ttl(lock, ttl_minutes, r.created_by)?;
+        cart = Some(cart_id);
+      }
+      None => {
+        return Err(ServiceError::bad_request(
+          "A visszaállítás helye nincs megadva!",
+        ))
+      }
+    }
+
+    // Store it as an active UPL and remove it from the archive
+    // The archive file is removed after the commit
+    upls.atomic(|tx| {
+      tx.insert(upl.clone())?;
+      tx.unarchive(upl.get_id());
+      ServiceResult::Ok(())
+    })?;
+    archive.remove_committed(&r.upl_id)?;
+
+    // Consume the related cart reservations
+    if let Some(cart_id) = cart {
+      take_reservation(&mut reservations, &cart_id, &upl);
+    }
+
+    Ok(upl_obj(upl, &self.vat_rates))
+  }
+
+  async fn get_id_info(&self, r: ByIdRequest) -> ServiceResult<IdInfo> {
+    let index_object = match self.index.lock().await.get(&r.upl_id) {
+      Ok(index_object) => index_object,
+      Err(index::IndexError::NotFound) => {
+        return Ok(IdInfo {
+          upl_id: r.upl_id,
+          status: id_info::Status::Unknown as i32,
+          product_id: 0,
+          sku: 0,
+          created_at: "".to_string(),
+        })
+      }
+      Err(e) => return Err(e.into()),
+    };
+
+    // Determine where the UPL lives
+    let status = if !self.upls.lock().await.check_id_available(&r.upl_id) {
+      id_info::Status::Active
+    } else if self.archive.lock().await.contains(&r.upl_id) {
+      match self
+        .archive
+        .lock()
+        .await
+        .get_object(&r.upl_id)?
+        .get_reason()
+      {
+        archive::ArchiveReason::Merged => id_info::Status::Retired,
+        _ => id_info::Status::Archived,
+      }
+    } else {
+      id_info::Status::Retired
+    };
+
+    Ok(IdInfo {
+      upl_id: index_object.upl,
+      status: status as i32,
+      product_id: index_object.product,
+      sku: index_object.sku,
+      created_at: Utc
+        .timestamp_opt(index_object.created_at_unix_ts_utc, 0)
+        .single()
+        .map(|created_at| created_at.to_rfc3339())
+        .unwrap_or_default(),
+    })
+  }
+
+  async fn get_history(&self, r: HistoryRequest) -> ServiceResult<HistoryObj> {
+    // Check event kind filters
+    for kind in &r.event_kinds {
+      if !upl::UplHistoryEvent::KINDS.contains(&kind.as_str()) {
+        return Err(ServiceError::bad_request(&format!(
+          "Ismeretlen esemény típus: {}",
+          kind
+        )));
+      }
+    }
+
+    // Parse time range filters
+    let parse_date = |date: &str| -> ServiceResult<Option<DateTime<Utc>>> {
+      match date {
+        "" => Ok(None),
+        _ => Ok(Some(
+          DateTime::parse_from_rfc3339(date)
+            .map_err(|_| ServiceError::bad_request("Hibás dátum formátum!"))?
+            .with_timezone(&Utc),
+        )),
+      }
+    };
+    let from = parse_date(&r.from)?;
+    let till = parse_date(&r.till)?;
+
+    // Try to find UPL in the active store first
+    // then in the archive
+    let active = self
+      .upls
       .lock()
       .await
-      .find_id(&r.upl_to_merge_back)?
-      .unpack()
-      .clone();
+      .find_id(&r.upl_id)
+      .map(|upl| upl.unpack().clone());
+    let (upl, is_archived) = match active {
+      Ok(upl) => (upl, false),
+      Err(_) => (self.archive.lock().await.get(&r.upl_id)?, true),
+    };
 
-    // Check if UPL is a DerivedProduct
-    match &child_upl.kind {
-      upl::Kind::DerivedProduct {
-        derived_from,
-        derived_from_sku: _,
-        amount: _,
-      } => {
-        // Find parent UPL and try to put merge back UPL
-        let _ = self
-          .upls
-          .lock()
-          .await
-          .find_id_mut(&derived_from)?
-          .as_mut()
-          .unpack()
-          .merge(child_upl.clone(), r.created_by)
-          .map_err(|e| ServiceError::bad_request(&e))?
-          .clone();
+    let items = upl
+      .get_history()
+      .iter()
+      .filter(|item| {
+        r.event_kinds.is_empty()
+          || r
+            .event_kinds
+            .iter()
+            .any(|kind| kind == item.get_event().get_kind())
+      })
+      .filter(|item| match from {
+        Some(from) => item.get_created_at() >= from,
+        None => true,
+      })
+      .filter(|item| match till {
+        Some(till) => item.get_created_at() <= till,
+        None => true,
+      })
+      .map(|item| item.into())
+      .collect::<Vec<HistoryItemObj>>();
+
+    Ok(HistoryObj {
+      upl_id: upl.id,
+      is_archived,
+      items,
+    })
+  }
+
+  async fn suggest_pick(&self, r: PickRequest) -> ServiceResult<PickSuggestionObj> {
+    let quantity = match r.quantity {
+      Some(pick_request::Quantity::Pieces(pieces)) => pick::PickQuantity::Pieces(pieces),
+      Some(pick_request::Quantity::Amount(amount)) => pick::PickQuantity::Amount(amount),
+      None => return Err(ServiceError::bad_request("A kért mennyiség nincs megadva!")),
+    };
+    let options = pick::PickOptions {
+      include_depreciated: r.include_depreciated,
+      include_locked: r.include_locked,
+    };
+    let res = pick::suggest(
+      self.upls.lock().await.find_by_sku(r.sku),
+      r.sku,
+      r.stock_id,
+      quantity,
+      options,
+    );
+    Ok(res.into())
+  }
 
-        // Remove child UPL as its merged
-        self.upls.lock().await.remove_pack(child_upl.get_id())?;
+  async fn get_expiring_report(
+    &self,
+    r: ExpiringReportRequest,
+  ) -> ServiceResult<ExpiringReportObj> {
+    let res = report::expiring_report(
+      self.upls.lock().await.iter().map(|upl| upl.unpack()),
+      Utc::now(),
+      r.days,
+      &r.stock_ids,
+    );
+    Ok(res.into())
+  }
+
+  async fn get_stock_valuation(
+    &self,
+    r: StockValuationRequest,
+  ) -> ServiceResult<StockValuationReport> {
+    let res = report::valuation_report(
+      self.upls.lock().await.iter().map(|upl| upl.unpack()),
+      &r.stock_ids,
+      &self.vat_rates,
+    );
+    Ok(res.into())
+  }
+
+  // Take an event log snapshot
+  // The log is replayed and the snapshot is written in blocking tasks,
+  // only the missing archive files are written under the archive lock,
+  // so every archive write goes through the service archive.
+  async fn snapshot_event_log(&self, log: eventlog::EventLog) -> ServiceResult<u64> {
+    let replay_log = log.clone();
+    let state = tokio::task::spawn_blocking(move || replay_log.snapshot_state())
+      .await
+      .map_err(|e| ServiceError::internal_error(&e.to_string()))?
+      .map_err(|e| ServiceError::internal_error(&e.to_string()))?;
+
+    state
+      .write_recent_archive(&*self.archive.lock().await)
+      .map_err(|e| ServiceError::internal_error(&e.to_string()))?;
+
+    tokio::task::spawn_blocking(move || log.write_snapshot(&state))
+      .await
+      .map_err(|e| ServiceError::internal_error(&e.to_string()))?
+      .map_err(|e| ServiceError::internal_error(&e.to_string()))
+  }
+
+  // Create a backup file in the backups folder
+  async fn create_backup(&self) -> ServiceResult<BackupInfoObj> {
+    // Walking the archive is blocking IO,
+    // so the backup is taken in a blocking task
+    let service = self.clone();
+    let (path, info) = tokio::task::spawn_blocking(move || service.write_backup())
+      .await
+      .map_err(|e| ServiceError::internal_error(&e.to_string()))??;
+
+    Ok(BackupInfoObj {
+      path: path.to_string_lossy().to_string(),
+      version: info.version,
+      checksum: info.checksum,
+      created_at: info.created_at.to_rfc3339(),
+      active_upls: info.active_upls as u64,
+      archived_upls: info.archived_upls as u64,
+    })
+  }
+
+  // Collect and write a backup
+  // It blocks, so it must run in a blocking task
+  fn write_backup(&self) -> ServiceResult<(PathBuf, backup::BackupInfo)> {
+    let handle = tokio::runtime::Handle::current();
+    // Hold every store while they are copied, in the lock order,
+    // so the copies are consistent with each other. The index and
+    // the archive are held until the backup is collected, so no UPL
+    // can be created, archived or restored meanwhile.
+    let index = handle.block_on(self.index.lock());
+    let archive = handle.block_on(self.archive.lock());
+    let inventories = handle.block_on(self.inventories.lock());
+    let reservations = handle.block_on(self.reservations.lock());
+    let upls = handle.block_on(self.upls.lock());
+    let inventories_copy = inventories
+      .iter()
+      .map(|inventory| inventory.unpack().clone())
+      .collect();
+    let reservations_copy = reservations
+      .iter()
+      .map(|reservation| reservation.unpack().clone())
+      .collect();
+    let upls_copy = upls.iter().map(|upl| upl.unpack().clone()).collect();
+    drop(upls);
+    drop(reservations);
+    drop(inventories);
+    let backup = backup::Backup::collect(
+      upls_copy,
+      inventories_copy,
+      reservations_copy,
+      &archive,
+      &index,
+    )?;
+    drop(archive);
+    drop(index);
+
+    let path = self.config.paths.backups().join(format!(
+      "upl-{}.bak",
+      backup.created_at.format("%Y%m%d%H%M%S%.3f")
+    ));
+    let info = backup.write(&path)?;
+    Ok((path, info))
+  }
+
+  async fn get_status(&self) -> ServiceResult<ServiceStatusObj> {
+    let active_upls = match tokio::time::timeout(LOCK_TIMEOUT, self.upls.lock()).await {
+      Ok(upls) => upls.len() as u64,
+      Err(_) => {
+        return Err(ServiceError::internal_error(
+          "A UPL tároló foglalt, nem érhető el!",
+        ))
+      }
+    };
+    let archived_upls = match tokio::time::timeout(LOCK_TIMEOUT, self.archive.lock()).await {
+      Ok(archive) => archive.count() as u64,
+      Err(_) => {
+        return Err(ServiceError::internal_error(
+          "Az archívum foglalt, nem érhető el!",
+        ))
+      }
+    };
+
+    let paths = &self.config.paths;
+    let dirs = vec![
+      ("upls", paths.upls()),
+      ("archive", paths.archive()),
+      ("inventories", paths.inventories()),
+      ("upl_index", paths.upl_index()),
+      ("reservations", paths.reservations()),
+      ("upl_log", paths.upl_log()),
+      ("backups", paths.backups()),
+    ];
+    // Walking the folders is blocking IO
+    let data_dirs = tokio::task::spawn_blocking(move || {
+      dirs
+        .into_iter()
+        .map(|(name, path)| DataDirObj {
+          name: name.to_string(),
+          size_bytes: status::dir_size(&path),
+          path: path.to_string_lossy().to_string(),
+        })
+        .collect::<Vec<DataDirObj>>()
+    })
+    .await
+    .map_err(|e| ServiceError::internal_error(&e.to_string()))?;
+
+    let now = Utc::now();
+    Ok(ServiceStatusObj {
+      version: env!("CARGO_PKG_VERSION").to_string(),
+      started_at: self.started_at.to_rfc3339(),
+      uptime_seconds: (now - self.started_at).num_seconds().max(0) as u64,
+      active_upls,
+      archived_upls,
+      data_dirs,
+      last_persisted_at: self
+        .clock
+        .last()
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_default(),
+      auth_enabled: self.config.auth.enabled,
+      metrics_error: self.metrics_error.get().unwrap_or_default(),
+    })
+  }
+
+  async fn get_stale_locks(&self, r: StaleLocksRequest) -> ServiceResult<StaleLocksObj> {
+    let acquired_before = Utc::now() - chrono::Duration::minutes(r.older_than_minutes as i64);
+    let upls = self.upls.lock().await;
+    let holders = locks::stale(upls.iter().map(|upl| upl.unpack()), acquired_before);
+    Ok(StaleLocksObj {
+      holders: holders.iter().map(|holder| holder.into()).collect(),
+    })
+  }
+
+  // Release expired cart and delivery locks
+  // Cart locks give back their reserved amount as well
+  async fn reap_locks(&self) -> locks::ReapReport {
+    let mut report = locks::ReapReport::default();
+    let mut reservations = self.reservations.lock().await;
+    let mut upls = self.upls.lock().await;
+    let expired_ids = locks::expired(upls.iter().map(|upl| upl.unpack()), Utc::now());
+    for upl_id in expired_ids {
+      let res = upls.update(&upl_id, |_upl| {
+        let lock = _upl.get_lock().clone();
+        let acquired_at = _upl.get_lock_lease().map(|lease| lease.acquired_at);
+        _upl.expire_lock()?;
+        ServiceResult::Ok((lock, acquired_at, _upl.clone()))
+      });
+      let (lock, acquired_at, upl) = match res {
+        Ok(res) => res,
+        Err(e) => {
+          report.failed.push((upl_id, e.to_string()));
+          continue;
+        }
+      };
+      if let upl::Lock::Cart(cart_id) = &lock {
+        give_back_reservation(&mut reservations, cart_id, &upl);
+      }
+      metrics::global().lock_reaped(&lock);
+      report.released.push(locks::ReapedLock {
+        upl_id,
+        lock,
+        acquired_at: acquired_at.unwrap_or_else(Utc::now),
+      });
+    }
+    report
+  }
+
+  // Health status of the service
+  // It is serving if the UPL store can be locked in time,
+  // so a hung store is reported as not serving
+  async fn serving_status(&self) -> ServingStatus {
+    match tokio::time::timeout(LOCK_TIMEOUT, self.upls.lock()).await {
+      Ok(_) => ServingStatus::Serving,
+      Err(_) => ServingStatus::NotServing,
+    }
+  }
+
+  // Render metrics in Prometheus text format
+  // Stock gauges are skipped if the UPL store is busy
+  async fn render_metrics(&self) -> String {
+    let mut out = String::new();
+    metrics::global().render(&mut out);
+    let available = match tokio::time::timeout(LOCK_TIMEOUT, self.upls.lock()).await {
+      Ok(upls) => {
+        let today = Utc::now().date_naive();
+        metrics::StockGauges::collect(upls.iter().map(|upl| upl.unpack()), today).render(&mut out);
+        1
+      }
+      Err(_) => 0,
+    };
+    out.push_str("# HELP upl_store_available UPL store could be locked in time\n");
+    out.push_str("# TYPE upl_store_available gauge\n");
+    out.push_str(&format!("upl_store_available {}\n", available));
+    out
+  }
+
+  // Apply markdown rules to all the UPLs
+  // UPLs the rules cannot be applied to are reported as failed
+  async fn run_markdown(
+    &self,
+    markdown: &markdown::Markdown,
+  ) -> ServiceResult<markdown::MarkdownReport> {
+    let today = Utc::now().date_naive();
+    let mut report = markdown::MarkdownReport::default();
+    let mut upls = self.upls.lock().await;
+    // Apply on copies first, so we only save the changed UPLs
+    let mut marked_down = Vec::new();
+    for upl in upls.iter() {
+      let mut upl = upl.unpack().clone();
+      match markdown.apply(&mut upl, today) {
+        Ok(true) => marked_down.push(upl),
+        Ok(false) => (),
+        Err(e) => report.failed.push((upl.id, e)),
+      }
+    }
+    upls.atomic(|tx| {
+      for upl in marked_down {
+        let upl_id = upl.id.clone();
+        *tx.find_id_mut(&upl_id)?.as_mut().unpack() = upl;
+        report.updated.push(upl_id);
+      }
+      ServiceResult::Ok(())
+    })?;
+    Ok(report)
+  }
+
+  async fn reserve(&self, r: ReserveRequest) -> ServiceResult<ReservationObj> {
+    if r.amount == 0 {
+      return Err(ServiceError::bad_request(
+        "A foglalás mennyisége nem lehet 0!",
+      ));
+    }
+
+    let subject = match r.subject {
+      Some(reserve_request::Subject::Sku(sku)) => reservation::Subject::Sku(sku),
+      Some(reserve_request::Subject::DividedProduct(product_id)) => {
+        reservation::Subject::DividedProduct(product_id)
       }
-      _ => {
+      None => {
         return Err(ServiceError::bad_request(
-          "A kért UPL nem kimért termék, így nem tehető vissza!",
+          "A foglalás tárgya nincs megadva!",
+        ))
+      }
+    };
+
+    let scope = match r.scope {
+      Some(reserve_request::Scope::Local(stock_id)) => reservation::Scope::Local(stock_id),
+      Some(reserve_request::Scope::Global(_)) => reservation::Scope::Global,
+      None => {
+        return Err(ServiceError::bad_request(
+          "A foglalás hatóköre nincs megadva!",
         ))
       }
+    };
+
+    let mut reservations = self.reservations.lock().await;
+
+    // Collect available amount per stock
+    // Only UPLs in stock without any lock count
+    let upls = self.upls.lock().await;
+    let candidates: Box<dyn Iterator<Item = &upl::Upl>> = match &subject {
+      reservation::Subject::Sku(sku) => Box::new(upls.find_by_sku(*sku)),
+      reservation::Subject::DividedProduct(product_id) => {
+        Box::new(upls.find_by_product(*product_id))
+      }
+    };
+    let available = reservation::available(candidates, &subject);
+
+    // Check available amount net of the existing reservations
+    let reserved =
+      ReservedAmount::for_subject(reservations.iter().map(|r| r.unpack()), &subject, None);
+    let total_available = reservation::total_amount(&available);
+    if total_available < reserved.total() + u64::from(r.amount) {
+      return Err(ServiceError::bad_request(
+        "Nincs elegendő szabad mennyiség a foglaláshoz!",
+      ));
+    }
+    if let reservation::Scope::Local(stock_id) = &scope {
+      if !reserved.allows_taking(&available, *stock_id, r.amount) {
+        return Err(ServiceError::bad_request(
+          "Nincs elegendő szabad mennyiség a raktárban a foglaláshoz!",
+        ));
+      }
+    }
+
+    let new_reservation = reservation::Reservation::new(
+      nanoid::nanoid!(),
+      r.cart_id,
+      subject,
+      scope,
+      r.amount,
+      r.created_by,
+    );
+
+    // Store new reservation
+    reservations.insert(new_reservation.clone())?;
+
+    Ok(new_reservation.into())
+  }
+
+  async fn get_reservations(&self, r: CartReservationsRequest) -> ServiceResult<ReservationList> {
+    let res = self
+      .reservations
+      .lock()
+      .await
+      .iter()
+      .filter(|reservation| reservation.unpack().get_cart_id() == r.cart_id)
+      .map(|reservation| reservation.unpack().clone().into())
+      .collect::<Vec<ReservationObj>>();
+
+    Ok(ReservationList { reservations: res })
+  }
+
+  // Remove all the reservations of a cart
+  async fn remove_reservations(&self, cart_id: &str) -> ServiceResult<()> {
+    let mut reservations = self.reservations.lock().await;
+    let reservation_ids = reservations
+      .iter()
+      .filter(|reservation| reservation.unpack().get_cart_id() == cart_id)
+      .map(|reservation| reservation.unpack().get_reservation_id().to_string())
+      .collect::<Vec<String>>();
+    for reservation_id in reservation_ids {
+      reservations.remove_pack(&reservation_id)?;
+    }
+    Ok(())
+  }
+
+  // Decrease stock info quantities by the remaining reservations
+  // so the same item cannot be sold twice
+  // Sku reservations hold back their pieces, DividedProduct
+  // reservations the pieces of the SKU that cover their amount
+  async fn net_of_reservations(
+    &self,
+    location_info: &mut LocationInfoResponse,
+    divided: Option<reservation::DividedAvailability>,
+  ) {
+    let reservations = self.reservations.lock().await;
+    let reserved = ReservedAmount::for_subject(
+      reservations.iter().map(|r| r.unpack()),
+      &reservation::Subject::Sku(location_info.sku),
+      None,
+    );
+    let available = location_info
+      .stocks
+      .iter()
+      .map(|(stock_id, stock_info)| (*stock_id, stock_info.total))
+      .collect();
+    let mut held = reserved.distribute(&available);
+    if let Some(divided) = divided {
+      let reserved = ReservedAmount::for_subject(
+        reservations.iter().map(|r| r.unpack()),
+        &reservation::Subject::DividedProduct(divided.product_id),
+        None,
+      );
+      for (stock_id, pieces) in divided.reserved_pieces(&reserved) {
+        *held.entry(stock_id).or_insert(0) += pieces;
+      }
+    }
+    for (stock_id, amount) in held {
+      if let Some(stock_info) = location_info.stocks.get_mut(&stock_id) {
+        stock_info.total = stock_info.total.saturating_sub(amount);
+        stock_info.healthy = stock_info.healthy.saturating_sub(amount);
+      }
+    }
+  }
+
+  async fn set_sku_price(
+    &self,
+    r: SetSkuPriceRequest,
+    created_by: upl::CreatedBy,
+  ) -> ServiceResult<()> {
+    // Try convert VAT
+    let vat = upl::VAT::from_str(&r.vat).map_err(|e| ServiceError::bad_request(&e))?;
+    let net_price = upl::Money::from(r.net_price);
+    // Check if prices valid
+    if self.vat_rates.gross(net_price, vat) != upl::Money::from(r.gross_price) {
+      return Err(ServiceError::bad_request("A nettó * áfa != bruttó"));
     }
-    // Return nothing
+    // Reprice related UPLs
+    let mut upls = self.upls.lock().await;
+    let upl_ids = upls
+      .find_by_sku(r.sku)
+      .map(|upl| upl.id.clone())
+      .collect::<Vec<String>>();
+    upls.atomic(|tx| {
+      let mut repriced = Vec::new();
+      for upl_id in upl_ids {
+        let mut _upl = tx.find_id(&upl_id)?.unpack().clone();
+        _upl
+          .set_price(net_price, vat, &self.vat_rates, created_by.clone())
+          .map_err(|e| ServiceError::bad_request(&e))?;
+        repriced.push(_upl);
+      }
+      // Opened UPLs and their parts share their value by the divide rule
+      upl::allocate_opened_values(&mut repriced, &self.vat_rates);
+      for _upl in repriced {
+        let upl_id = _upl.id.clone();
+        *tx.find_id_mut(&upl_id)?.as_mut().unpack() = _upl;
+      }
+      // Return nothing
+      ServiceResult::Ok(())
+    })
+  }
+
+  async fn set_sku_divisible(
+    &self,
+    r: SetSkuDivisibleRequest,
+    created_by: upl::CreatedBy,
+  ) -> ServiceResult<()> {
+    // Set related UPLs
+    let mut upls = self.upls.lock().await;
+    let upl_ids = upls
+      .find_by_sku(r.sku)
+      .map(|upl| upl.id.clone())
+      .collect::<Vec<String>>();
+    upls.atomic(|tx| {
+      for upl_id in upl_ids {
+        tx.find_id_mut(&upl_id)?
+          .as_mut()
+          .unpack()
+          .set_divisible(r.divisible, created_by.clone());
+      }
+      // Return nothing
+      ServiceResult::Ok(())
+    })
+  }
+
+  // Try to open UPL
+  async fn open_upl(&self, r: OpenUplRequest, created_by: upl::CreatedBy) -> ServiceResult<UplObj> {
+    let res = self.upls.lock().await.update(&r.upl_id, |_upl| {
+      ServiceResult::Ok(
+        _upl
+          .open(created_by)
+          .map_err(|e| ServiceError::bad_request(&e))?
+          .clone(),
+      )
+    })?;
+    Ok(upl_obj(res, &self.vat_rates))
+  }
+
+  // Try to close UPL
+  async fn close_upl(
+    &self,
+    r: CloseUplRequest,
+    created_by: upl::CreatedBy,
+  ) -> ServiceResult<UplObj> {
+    let res = self.upls.lock().await.update(&r.upl_id, |_upl| {
+      ServiceResult::Ok(
+        _upl
+          .close(created_by)
+          .map_err(|e| ServiceError::bad_request(&e))?
+          .clone(),
+      )
+    })?;
+    Ok(upl_obj(res, &self.vat_rates))
+  }
+
+  // Try to merge back UPL
+  async fn merge_back(&self, r: MergeRequest) -> ServiceResult<()> {
+    let archive = self.archive.lock().await;
+
+    // Merge, remove and archive as one transaction
+    // The archive file is written after the commit
+    let archive_object = self.upls.lock().await.atomic(|tx| {
+      // First find child UPL to merge
+      let child_upl = tx.find_id(&r.upl_to_merge_back)?.unpack().clone();
+
+      // Check if UPL is a DerivedProduct
+      match &child_upl.kind {
+        upl::Kind::DerivedProduct {
+          derived_from,
+          derived_from_sku: _,
+          amount: _,
+        } => {
+          // Find parent UPL and try to put merge back UPL
+          let _ = tx
+            .find_id_mut(&derived_from)?
+            .as_mut()
+            .unpack()
+            .merge(child_upl.clone(), &self.vat_rates, r.created_by)
+            .map_err(|e| ServiceError::bad_request(&e))?
+            .clone();
+
+          // Remove child UPL as its merged
+          tx.remove_pack(child_upl.get_id())?;
+
+          // Archive child UPL as its merged
+          // so its history is kept
+          let mut merged_upl = child_upl.clone();
+          merged_upl.set_history(upl::UplHistoryItem::new(
+            upl::CreatedBy::Uid(r.created_by),
+            upl::UplHistoryEvent::MergedInto {
+              parent_upl_id: derived_from.to_string(),
+            },
+          ));
+          let archive_object =
+            archive::ArchiveObject::new(archive::ArchiveReason::Merged, merged_upl);
+          tx.archive(&archive_object);
+          Ok(archive_object)
+        }
+        _ => Err(ServiceError::bad_request(
+          "A kért UPL nem kimért termék, így nem tehető vissza!",
+        )),
+      }
+    })?;
+    archive.put_committed(&archive_object)?;
+
     Ok(())
   }
 
@@ -529,8 +1671,9 @@ impl UplService {
     };
 
     // Iterate over all the UPLs and collect stock info
-    self.upls.lock().await.iter().for_each(|upl| {
-      let _upl = upl.unpack();
+    // The UPL store guard must be released before the reservations are locked
+    let upls = self.upls.lock().await;
+    upls.find_by_sku(r.sku).for_each(|_upl| {
       // If UPL has the required SKU
       if _upl.get_sku() == r.sku {
         match _upl.get_location() {
@@ -575,6 +1718,12 @@ impl UplService {
       }
     });
 
+    let divided = divided_availability(&upls, r.sku);
+    drop(upls);
+
+    // Decrease quantities by the reservations
+    self.net_of_reservations(&mut res, divided).await;
+
     Ok(res)
   }
 
@@ -583,89 +1732,114 @@ impl UplService {
     // Create empty response
     let mut res: HashMap<u32, LocationInfoResponse> = HashMap::new();
 
-    // Iterate over all the UPLs and collect stock info
-    self.upls.lock().await.iter().for_each(|upl| {
-      let _upl = upl.unpack();
-      // If UPL is int the required SKU list
-      if r.contains(&_upl.get_sku()) {
-        match _upl.get_location() {
-          Location::Stock(stock_id) => {
-            // Get location info for SKU or init it
-            let location_info = res.entry(_upl.get_sku()).or_insert(LocationInfoResponse {
-              sku: _upl.get_sku(),
-              stocks: HashMap::new(),
-            });
-
-            // Get stock info or init it
-            let stock_info = location_info.stocks.entry(*stock_id).or_insert(StockInfo {
-              total: 0,
-              healthy: 0,
-              bulk: 0,
-              opened: 0,
-            });
-
-            // Increment total count
-            (*stock_info).total += _upl.get_upl_piece();
-
-            // If its healthy then increment healthy count
-            if _upl.is_available_healthy() {
-              (*stock_info).healthy += _upl.get_upl_piece();
-            }
+    // Requested SKUs without duplicates, so each SKU is counted once
+    let skus: BTreeSet<u32> = r.into_iter().collect();
 
-            // Check for bulk and opened product location info
-            match _upl.get_kind() {
-              // If single SKU, dont do anything
-              upl::Kind::Sku { sku: _ } => (),
-              // If bulk sku, then increase bulk by its pieces
-              upl::Kind::BulkSku {
-                sku: _,
-                upl_pieces: _,
-              } => (*stock_info).bulk += _upl.get_upl_piece(),
-              // If OpenedSku, then increase opened count by 1
-              upl::Kind::OpenedSku {
-                sku: _,
-                amount: _,
-                successors: _,
-              } => (*stock_info).opened += _upl.get_upl_piece(),
-              // If derived product, then increase opened count by 1
-              upl::Kind::DerivedProduct {
-                derived_from: _,
-                derived_from_sku: _,
-                amount: _,
-              } => (*stock_info).opened += _upl.get_upl_piece(),
+    // Iterate over all the UPLs and collect stock info
+    // The UPL store guard must be released before the reservations are locked
+    let upls = self.upls.lock().await;
+    skus
+      .iter()
+      .flat_map(|sku| upls.find_by_sku(*sku))
+      .for_each(|_upl| {
+        // If UPL is int the required SKU list
+        if skus.contains(&_upl.get_sku()) {
+          match _upl.get_location() {
+            Location::Stock(stock_id) => {
+              // Get location info for SKU or init it
+              let location_info = res.entry(_upl.get_sku()).or_insert(LocationInfoResponse {
+                sku: _upl.get_sku(),
+                stocks: HashMap::new(),
+              });
+
+              // Get stock info or init it
+              let stock_info = location_info.stocks.entry(*stock_id).or_insert(StockInfo {
+                total: 0,
+                healthy: 0,
+                bulk: 0,
+                opened: 0,
+              });
+
+              // Increment total count
+              (*stock_info).total += _upl.get_upl_piece();
+
+              // If its healthy then increment healthy count
+              if _upl.is_available_healthy() {
+                (*stock_info).healthy += _upl.get_upl_piece();
+              }
+
+              // Check for bulk and opened product location info
+              match _upl.get_kind() {
+                // If single SKU, dont do anything
+                upl::Kind::Sku { sku: _ } => (),
+                // If bulk sku, then increase bulk by its pieces
+                upl::Kind::BulkSku {
+                  sku: _,
+                  upl_pieces: _,
+                } => (*stock_info).bulk += _upl.get_upl_piece(),
+                // If OpenedSku, then increase opened count by 1
+                upl::Kind::OpenedSku {
+                  sku: _,
+                  amount: _,
+                  successors: _,
+                } => (*stock_info).opened += _upl.get_upl_piece(),
+                // If derived product, then increase opened count by 1
+                upl::Kind::DerivedProduct {
+                  derived_from: _,
+                  derived_from_sku: _,
+                  amount: _,
+                } => (*stock_info).opened += _upl.get_upl_piece(),
+              }
             }
+            _ => (),
           }
-          _ => (),
         }
-      }
-    });
+      });
+    let mut divided = skus
+      .iter()
+      .filter_map(|sku| Some((*sku, divided_availability(&upls, *sku)?)))
+      .collect::<HashMap<u32, reservation::DividedAvailability>>();
+    drop(upls);
+
+    // Decrease quantities by the reservations
+    for location_info in res.values_mut() {
+      let divided = divided.remove(&location_info.sku);
+      self.net_of_reservations(location_info, divided).await;
+    }
 
     // Transform response from HashMap to Vec
     Ok(res.into_iter().map(|(_k, v)| v).collect())
   }
 
   // Update product unit
-  async fn set_product_unit(&self, r: SetProductUnitRequest) -> ServiceResult<()> {
-    self
-      .upls
-      .lock()
-      .await
-      .as_vec_mut()
-      .into_iter()
-      .for_each(|upl| {
-        // Update all UPLs that related to the given PID
-        if upl.unpack().product_id == r.product_id {
-          let _ = upl.as_mut().unpack().set_product_unit(r.unit.clone());
-        }
-      });
-    Ok(())
+  async fn set_product_unit(
+    &self,
+    r: SetProductUnitRequest,
+    created_by: upl::CreatedBy,
+  ) -> ServiceResult<()> {
+    // Update all UPLs that related to the given PID
+    let mut upls = self.upls.lock().await;
+    let upl_ids = upls
+      .find_by_product(r.product_id)
+      .map(|upl| upl.id.clone())
+      .collect::<Vec<String>>();
+    upls.atomic(|tx| {
+      for upl_id in upl_ids {
+        let _ = tx
+          .find_id_mut(&upl_id)?
+          .as_mut()
+          .unpack()
+          .set_product_unit(r.unit.clone(), created_by.clone());
+      }
+      ServiceResult::Ok(())
+    })
   }
 }
 
 #[tonic::async_trait]
 impl gzlib::proto::upl::upl_server::Upl for UplService {
   async fn create_new(&self, request: Request<UplNew>) -> Result<Response<UplObj>, Status> {
-    let res = self.create_new(request.into_inner()).await?;
+    let res = self.create_new(auth::verified(request)).await?;
     Ok(Response::new(res))
   }
 
@@ -673,26 +1847,21 @@ impl gzlib::proto::upl::upl_server::Upl for UplService {
     &self,
     request: Request<tonic::Streaming<UplNew>>,
   ) -> Result<Response<UplIds>, Status> {
+    let caller_uid = auth::caller_uid(request.metadata());
     let mut stream = request.into_inner();
 
-    let s = stream! {
-        while let Some(new_upl) = stream.next().await {
-          if let Ok(upl) = new_upl {
-            if let Ok(res) = self.create_new(upl).await {
-              yield res.id;
-            }
-          }
-        }
-    };
-
-    pin_mut!(s);
-
-    let mut upl_ids: Vec<String> = Vec::new();
-
-    while let Some(value) = s.next().await {
-      upl_ids.push(value);
+    // Receive the whole stream first, a broken stream creates nothing
+    // Invalid UPLs of a complete stream are skipped
+    let mut upls: Vec<UplNew> = Vec::new();
+    while let Some(new_upl) = stream.next().await {
+      let mut upl = new_upl?;
+      if let Some(uid) = caller_uid {
+        upl.created_by = uid;
+      }
+      upls.push(upl);
     }
 
+    let upl_ids = self.create_new_bulk(upls).await?;
     Ok(Response::new(UplIds { upl_ids }))
   }
 
@@ -703,7 +1872,7 @@ impl gzlib::proto::upl::upl_server::Upl for UplService {
     request: Request<BulkRequest>,
   ) -> Result<Response<Self::GetBulkStream>, Status> {
     // Create channel for stream response
-    let (mut tx, rx) = tokio::sync::mpsc::channel(100);
+    let (mut tx, rx) = tokio::sync::mpsc::channel(self.config.stream_buffer);
 
     // Get resources as Vec<SourceObject>
     let res = self.get_bulk(request.into_inner()).await?;
@@ -765,17 +1934,17 @@ impl gzlib::proto::upl::upl_server::Upl for UplService {
     &self,
     request: Request<SetBestBeforeRequest>,
   ) -> Result<Response<UplObj>, Status> {
-    let res = self.set_best_before(request.into_inner()).await?;
+    let res = self.set_best_before(auth::verified(request)).await?;
     Ok(Response::new(res))
   }
 
   async fn split(&self, request: Request<SplitRequest>) -> Result<Response<UplObj>, Status> {
-    let res = self.split(request.into_inner()).await?;
+    let res = self.split(auth::verified(request)).await?;
     Ok(Response::new(res))
   }
 
   async fn divide(&self, request: Request<DivideRequest>) -> Result<Response<UplObj>, Status> {
-    let res = self.divide(request.into_inner()).await?;
+    let res = self.divide(auth::verified(request)).await?;
     Ok(Response::new(res))
   }
 
@@ -783,7 +1952,7 @@ impl gzlib::proto::upl::upl_server::Upl for UplService {
     &self,
     request: Request<DepreciationRequest>,
   ) -> Result<Response<UplObj>, Status> {
-    let res = self.set_depreciation(request.into_inner()).await?;
+    let res = self.set_depreciation(auth::verified(request)).await?;
     Ok(Response::new(res))
   }
 
@@ -791,7 +1960,7 @@ impl gzlib::proto::upl::upl_server::Upl for UplService {
     &self,
     request: Request<DepreciationRemoveRequest>,
   ) -> Result<Response<UplObj>, Status> {
-    let res = self.remove_depreciation(request.into_inner()).await?;
+    let res = self.remove_depreciation(auth::verified(request)).await?;
     Ok(Response::new(res))
   }
 
@@ -799,7 +1968,7 @@ impl gzlib::proto::upl::upl_server::Upl for UplService {
     &self,
     request: Request<DepreciationPriceRequest>,
   ) -> Result<Response<UplObj>, Status> {
-    let res = self.set_depreciation_price(request.into_inner()).await?;
+    let res = self.set_depreciation_price(auth::verified(request)).await?;
     Ok(Response::new(res))
   }
 
@@ -807,7 +1976,9 @@ impl gzlib::proto::upl::upl_server::Upl for UplService {
     &self,
     request: Request<RemoveDeprecationPriceRequest>,
   ) -> Result<Response<UplObj>, Status> {
-    let res = self.remove_deprecation_price(request.into_inner()).await?;
+    let res = self
+      .remove_deprecation_price(auth::verified(request))
+      .await?;
     Ok(Response::new(res))
   }
 
@@ -815,7 +1986,7 @@ impl gzlib::proto::upl::upl_server::Upl for UplService {
     &self,
     request: Request<CartLockRequest>,
   ) -> Result<Response<UplObj>, Status> {
-    let res = self.lock_to_cart(request.into_inner()).await?;
+    let res = self.lock_to_cart(auth::verified(request)).await?;
     Ok(Response::new(res))
   }
 
@@ -823,12 +1994,12 @@ impl gzlib::proto::upl::upl_server::Upl for UplService {
     &self,
     request: Request<CartUnlockRequest>,
   ) -> Result<Response<UplObj>, Status> {
-    let res = self.release_lock_from_cart(request.into_inner()).await?;
+    let res = self.release_lock_from_cart(auth::verified(request)).await?;
     Ok(Response::new(res))
   }
 
   async fn close_cart(&self, request: Request<CloseCartRequest>) -> Result<Response<()>, Status> {
-    let _ = self.close_cart(request.into_inner()).await?;
+    let _ = self.close_cart(auth::verified(request)).await?;
     Ok(Response::new(()))
   }
 
@@ -836,7 +2007,8 @@ impl gzlib::proto::upl::upl_server::Upl for UplService {
     &self,
     request: Request<SetSkuPriceRequest>,
   ) -> Result<Response<()>, Status> {
-    let _ = self.set_sku_price(request.into_inner()).await?;
+    let (r, created_by) = auth::with_caller(request);
+    let _ = self.set_sku_price(r, created_by).await?;
     Ok(Response::new(()))
   }
 
@@ -844,22 +2016,25 @@ impl gzlib::proto::upl::upl_server::Upl for UplService {
     &self,
     request: Request<SetSkuDivisibleRequest>,
   ) -> Result<Response<()>, Status> {
-    let _ = self.set_sku_divisible(request.into_inner()).await?;
+    let (r, created_by) = auth::with_caller(request);
+    let _ = self.set_sku_divisible(r, created_by).await?;
     Ok(Response::new(()))
   }
 
   async fn open_upl(&self, request: Request<OpenUplRequest>) -> Result<Response<UplObj>, Status> {
-    let res = self.open_upl(request.into_inner()).await?;
+    let (r, created_by) = auth::with_caller(request);
+    let res = self.open_upl(r, created_by).await?;
     Ok(Response::new(res))
   }
 
   async fn close_upl(&self, request: Request<CloseUplRequest>) -> Result<Response<UplObj>, Status> {
-    let res = self.close_upl(request.into_inner()).await?;
+    let (r, created_by) = auth::with_caller(request);
+    let res = self.close_upl(r, created_by).await?;
     Ok(Response::new(res))
   }
 
   async fn merge_back(&self, request: Request<MergeRequest>) -> Result<Response<()>, Status> {
-    let _ = self.merge_back(request.into_inner()).await?;
+    let _ = self.merge_back(auth::verified(request)).await?;
     Ok(Response::new(()))
   }
 
@@ -878,7 +2053,7 @@ impl gzlib::proto::upl::upl_server::Upl for UplService {
     request: Request<LocationInfoBulkRequest>,
   ) -> Result<Response<Self::GetLocationInfoBulkStream>, Status> {
     // Create channel for stream response
-    let (mut tx, rx) = tokio::sync::mpsc::channel(100);
+    let (mut tx, rx) = tokio::sync::mpsc::channel(self.config.stream_buffer);
 
     // Get resources as Vec<SourceObject>
     let res = self
@@ -900,27 +2075,726 @@ impl gzlib::proto::upl::upl_server::Upl for UplService {
     &self,
     request: Request<SetProductUnitRequest>,
   ) -> Result<Response<()>, Status> {
-    let _ = self.set_product_unit(request.into_inner()).await?;
+    let (r, created_by) = auth::with_caller(request);
+    let _ = self.set_product_unit(r, created_by).await?;
     Ok(Response::new(()))
   }
 }
 
+#[tonic::async_trait]
+impl UplExt for UplService {
+  async fn lock_to_delivery(
+    &self,
+    request: Request<DeliveryLockRequest>,
+  ) -> Result<Response<UplIds>, Status> {
+    let upl_ids = self.lock_to_delivery(auth::verified(request)).await?;
+    Ok(Response::new(UplIds { upl_ids }))
+  }
+
+  async fn release_lock_from_delivery(
+    &self,
+    request: Request<DeliveryUnlockRequest>,
+  ) -> Result<Response<UplObj>, Status> {
+    let res = self
+      .release_lock_from_delivery(auth::verified(request))
+      .await?;
+    Ok(Response::new(res))
+  }
+
+  async fn dispatch_delivery(
+    &self,
+    request: Request<DispatchDeliveryRequest>,
+  ) -> Result<Response<UplIds>, Status> {
+    let upl_ids = self.dispatch_delivery(auth::verified(request)).await?;
+    Ok(Response::new(UplIds { upl_ids }))
+  }
+
+  async fn receive_delivery(
+    &self,
+    request: Request<ReceiveDeliveryRequest>,
+  ) -> Result<Response<UplIds>, Status> {
+    let upl_ids = self.receive_delivery(auth::verified(request)).await?;
+    Ok(Response::new(UplIds { upl_ids }))
+  }
+
+  async fn open_inventory(
+    &self,
+    request: Request<OpenInventoryRequest>,
+  ) -> Result<Response<InventoryObj>, Status> {
+    let res = self.open_inventory(auth::verified(request)).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn scan_inventory(
+    &self,
+    request: Request<ScanInventoryRequest>,
+  ) -> Result<Response<InventoryObj>, Status> {
+    let res = self.scan_inventory(auth::verified(request)).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_inventory(
+    &self,
+    request: Request<InventoryByIdRequest>,
+  ) -> Result<Response<InventoryObj>, Status> {
+    let res = self.get_inventory(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn close_inventory(
+    &self,
+    request: Request<CloseInventoryRequest>,
+  ) -> Result<Response<InventoryObj>, Status> {
+    let res = self.close_inventory(auth::verified(request)).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn release_lock_from_inventory(
+    &self,
+    request: Request<InventoryUnlockRequest>,
+  ) -> Result<Response<UplObj>, Status> {
+    let res = self
+      .release_lock_from_inventory(auth::verified(request))
+      .await?;
+    Ok(Response::new(res))
+  }
+
+  async fn discard_inventory_upl(
+    &self,
+    request: Request<DiscardInventoryUplRequest>,
+  ) -> Result<Response<UplObj>, Status> {
+    let res = self.discard_inventory_upl(auth::verified(request)).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn restore_from_archive(
+    &self,
+    request: Request<RestoreRequest>,
+  ) -> Result<Response<UplObj>, Status> {
+    let res = self.restore_from_archive(auth::verified(request)).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_id_info(&self, request: Request<ByIdRequest>) -> Result<Response<IdInfo>, Status> {
+    let res = self.get_id_info(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn reserve(
+    &self,
+    request: Request<ReserveRequest>,
+  ) -> Result<Response<ReservationObj>, Status> {
+    let res = self.reserve(auth::verified(request)).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_reservations(
+    &self,
+    request: Request<CartReservationsRequest>,
+  ) -> Result<Response<ReservationList>, Status> {
+    let res = self.get_reservations(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn release_reservations(
+    &self,
+    request: Request<CartReservationsRequest>,
+  ) -> Result<Response<()>, Status> {
+    self
+      .remove_reservations(&request.into_inner().cart_id)
+      .await?;
+    Ok(Response::new(()))
+  }
+
+  async fn get_history(
+    &self,
+    request: Request<HistoryRequest>,
+  ) -> Result<Response<HistoryObj>, Status> {
+    let res = self.get_history(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn suggest_pick(
+    &self,
+    request: Request<PickRequest>,
+  ) -> Result<Response<PickSuggestionObj>, Status> {
+    let res = self.suggest_pick(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_expiring_report(
+    &self,
+    request: Request<ExpiringReportRequest>,
+  ) -> Result<Response<ExpiringReportObj>, Status> {
+    let res = self.get_expiring_report(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_stock_valuation(
+    &self,
+    request: Request<StockValuationRequest>,
+  ) -> Result<Response<StockValuationReport>, Status> {
+    let res = self.get_stock_valuation(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn create_new_bulk_checked(
+    &self,
+    request: Request<CreateNewBulkRequest>,
+  ) -> Result<Response<CreateNewBulkResponse>, Status> {
+    let res = self
+      .create_new_bulk_checked(auth::verified(request))
+      .await?;
+    Ok(Response::new(res))
+  }
+
+  type WatchStream = ReceiverStream<Result<ChangeEventObj, Status>>;
+
+  async fn watch(
+    &self,
+    request: Request<WatchRequest>,
+  ) -> Result<Response<Self::WatchStream>, Status> {
+    let r = request.into_inner();
+    let filter = feed::ChangeFilter {
+      stock_ids: r.stock_ids,
+      skus: r.skus,
+      event_kinds: r.event_kinds,
+    };
+    let after_seq = match r.start {
+      Some(watch_request::Start::AfterSeq(seq)) => Some(seq),
+      _ => None,
+    };
+
+    // Subscribe first, so we do not miss any event
+    let subscription = self
+      .feed
+      .subscribe(after_seq)
+      .map_err(|e| ServiceError::bad_request(&e))?;
+
+    // Create channel for stream response
+    let (tx, rx) = tokio::sync::mpsc::channel(self.config.stream_buffer);
+    let feed = self.feed.clone();
+    let vat_rates = self.vat_rates.clone();
+
+    tokio::spawn(async move {
+      let mut events = subscription.receiver;
+      // Last sequence number sent or filtered out
+      let mut last_seq = subscription.last_seq;
+      let mut pending = subscription.backlog;
+      loop {
+        for event in pending.drain(..) {
+          if event.seq <= last_seq {
+            continue;
+          }
+          last_seq = event.seq;
+          if filter.matches(&event)
+            && tx
+              .send(Ok(change_event_obj(event, &vat_rates)))
+              .await
+              .is_err()
+          {
+            // Subscriber is gone
+            return;
+          }
+        }
+        match events.recv().await {
+          Ok(event) => pending.push(event),
+          // Subscriber was too slow, we try to catch up
+          // from the events the feed keeps
+          Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => match feed.since(last_seq) {
+            Ok(events) => pending = events,
+            Err(e) => {
+              let _ = tx.send(Err(Status::data_loss(e))).await;
+              return;
+            }
+          },
+          Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+        }
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn create_backup(&self, _request: Request<()>) -> Result<Response<BackupInfoObj>, Status> {
+    let res = self.create_backup().await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_status(&self, _request: Request<()>) -> Result<Response<ServiceStatusObj>, Status> {
+    let res = self.get_status().await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_stale_locks(
+    &self,
+    request: Request<StaleLocksRequest>,
+  ) -> Result<Response<StaleLocksObj>, Status> {
+    let res = self.get_stale_locks(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+}
+
+// Standard gRPC health service
+// The server starts only after all the data is loaded,
+// so it is not reachable while the service is loading.
+#[derive(Clone)]
+struct HealthService {
+  upl_service: UplService,
+}
+
+impl HealthService {
+  // Known service names
+  // Empty name means the whole server
+  const SERVICES: [&'static str; 3] = ["", "upl.Upl", "upl_ext.UplExt"];
+
+  fn is_known(service: &str) -> bool {
+    Self::SERVICES.contains(&service)
+  }
+}
+
+#[tonic::async_trait]
+impl Health for HealthService {
+  async fn check(
+    &self,
+    request: Request<HealthCheckRequest>,
+  ) -> Result<Response<HealthCheckResponse>, Status> {
+    let service = request.into_inner().service;
+    if !Self::is_known(&service) {
+      return Err(Status::not_found(format!("Unknown service: {}", service)));
+    }
+    let status = self.upl_service.serving_status().await;
+    Ok(Response::new(HealthCheckResponse {
+      status: status as i32,
+    }))
+  }
+
+  type WatchStream = ReceiverStream<Result<HealthCheckResponse, Status>>;
+
+  async fn watch(
+    &self,
+    request: Request<HealthCheckRequest>,
+  ) -> Result<Response<Self::WatchStream>, Status> {
+    // Unknown services are reported in the stream
+    let known = Self::is_known(&request.into_inner().service);
+
+    // Create channel for stream response
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    let upl_service = self.upl_service.clone();
+
+    // Send the current status, then every change of it
+    tokio::spawn(async move {
+      let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
+      let mut last_status = None;
+      loop {
+        tokio::select! {
+          _ = interval.tick() => (),
+          // Subscriber is gone
+          _ = tx.closed() => return,
+        }
+        let status = match known {
+          true => upl_service.serving_status().await,
+          false => ServingStatus::ServiceUnknown,
+        };
+        if last_status == Some(status) {
+          continue;
+        }
+        last_status = Some(status);
+        let res = HealthCheckResponse {
+          status: status as i32,
+        };
+        if tx.send(Ok(res)).await.is_err() {
+          // Subscriber is gone
+          return;
+        }
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+}
+
+// Divisible availability of a SKU and its product
+// None if there is no UPL of the SKU
+fn divided_availability(
+  upls: &store::UplStore,
+  sku: u32,
+) -> Option<reservation::DividedAvailability> {
+  let upl = upls.find_by_sku(sku).next()?;
+  Some(reservation::DividedAvailability::collect(
+    sku,
+    upl.get_product_id(),
+    upl.get_sku_divisible_amount(),
+    upls.find_by_product(upl.get_product_id()),
+  ))
+}
+
+// Take the amount a UPL represents
+// from its cart reservations
+// The amount can be split over several reservations
+// Check whether a UPL in stock can be taken into a cart
+// without eating the reservations of other carts
+// The UPL can be a restored one, not yet in the store
+fn check_cart_reservations(
+  upls: &store::UplStore,
+  reservations: &VecPack<reservation::Reservation>,
+  upl: &upl::Upl,
+  cart_id: &str,
+) -> ServiceResult<()> {
+  if let Location::Stock(stock_id) = upl.get_location() {
+    let subjects = [
+      reservation::Subject::Sku(upl.get_sku()),
+      reservation::Subject::DividedProduct(upl.get_product_id()),
+    ];
+    for subject in &subjects {
+      let amount = reservation::available_amount(upl, subject);
+      if amount == 0 {
+        continue;
+      }
+      let candidates: Box<dyn Iterator<Item = &upl::Upl>> = match subject {
+        reservation::Subject::Sku(sku) => Box::new(upls.find_by_sku(*sku)),
+        reservation::Subject::DividedProduct(product_id) => {
+          Box::new(upls.find_by_product(*product_id))
+        }
+      };
+      let candidates = candidates
+        .filter(|candidate| candidate.id != upl.id)
+        .chain(std::iter::once(upl));
+      let available = reservation::available(candidates, subject);
+      let reserved = ReservedAmount::for_subject(
+        reservations.iter().map(|r| r.unpack()),
+        subject,
+        Some(cart_id),
+      );
+      if !reserved.allows_taking(&available, *stock_id, amount) {
+        return Err(ServiceError::bad_request(
+          "A UPL más kosár foglalása miatt nem zárolható!",
+        ));
+      }
+    }
+  }
+  Ok(())
+}
+
+fn take_reservation(
+  reservations: &mut VecPack<reservation::Reservation>,
+  cart_id: &str,
+  upl: &upl::Upl,
+) {
+  let mut remaining: Option<u32> = None;
+  for reservation in reservations.as_vec_mut() {
+    if reservation.unpack().get_cart_id() != cart_id {
+      continue;
+    }
+    if let Some(amount) = reservation.unpack().get_matching_amount(upl) {
+      let remaining = remaining.get_or_insert(amount);
+      if *remaining == 0 {
+        break;
+      }
+      if reservation.unpack().get_amount_remaining() > 0 {
+        *remaining -= reservation.as_mut().unpack().take(*remaining);
+      }
+    }
+  }
+}
+
+// Give back the amount a UPL has taken
+// from its cart reservations
+// The amount can be split over several reservations
+fn give_back_reservation(
+  reservations: &mut VecPack<reservation::Reservation>,
+  cart_id: &str,
+  upl: &upl::Upl,
+) {
+  let mut remaining: Option<u32> = None;
+  for reservation in reservations.as_vec_mut() {
+    if reservation.unpack().get_cart_id() != cart_id {
+      continue;
+    }
+    if let Some(amount) = reservation.unpack().get_matching_amount(upl) {
+      let remaining = remaining.get_or_insert(amount);
+      if *remaining == 0 {
+        break;
+      }
+      if *reservation.unpack().get_amount_taken() > 0 {
+        *remaining -= reservation.as_mut().unpack().give_back(*remaining);
+      }
+    }
+  }
+}
+
+// Metrics HTTP endpoint
+// Move UPLs from the legacy in-memory archive into the archive store
+// The legacy archive was written before the lock lease,
+// so its UPLs are read by the old scheme and migrated.
+// Archived UPLs keep their lock, but it never expires.
+fn move_legacy_archive(path: PathBuf, archive_db: &archive::ArchiveStore) -> ServiceResult<()> {
+  let mut legacy_archive_db: VecPack<lock_lease::Upl> =
+    VecPack::load_or_init(path).expect("Error while loading legacy UPL archive database");
+  let legacy_ids = legacy_archive_db
+    .iter()
+    .map(|upl| upl.unpack().id.clone())
+    .collect::<Vec<String>>();
+  for upl_id in legacy_ids {
+    // It can be moved already, if the previous run stopped
+    // before removing it from the legacy archive
+    if !archive_db.contains(&upl_id) {
+      let upl = legacy_archive_db
+        .find_id(&upl_id)?
+        .unpack()
+        .clone()
+        .migrate(None);
+      archive_db.add(upl, archive::ArchiveReason::Sold, upl::CreatedBy::Technical)?;
+    }
+    legacy_archive_db.remove_pack(&upl_id)?;
+  }
+  Ok(())
+}
+
+async fn metrics_endpoint(
+  upl_service: UplService,
+  req: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, hyper::http::Error> {
+  match (req.method(), req.uri().path()) {
+    (&hyper::Method::GET, "/metrics") => hyper::Response::builder()
+      .header("Content-Type", "text/plain; version=0.0.4")
+      .body(hyper::Body::from(upl_service.render_metrics().await)),
+    _ => hyper::Response::builder()
+      .status(hyper::StatusCode::NOT_FOUND)
+      .body(hyper::Body::empty()),
+  }
+}
+
 #[tokio::main]
 async fn main() -> prelude::ServiceResult<()> {
-  // Init UPL DB
-  let upl_db: VecPack<upl::Upl> =
-    VecPack::load_or_init(PathBuf::from("data/upls")).expect("Error while loading UPL database");
+  // Load config
+  // Invalid config stops the service before touching any data
+  let config = config::Config::load().unwrap_or_else(|e| panic!("{}", e));
+  let paths = config.paths.clone();
 
   // Init UPL DB
-  let archive_db: VecPack<upl::Upl> = VecPack::load_or_init(PathBuf::from("data/upl_archive"))
-    .expect("Error while loading UPL archive database");
+  let mut upl_db: VecPack<upl::Upl> =
+    VecPack::load_or_init(paths.upls()).expect("Error while loading UPL database");
+
+  // Init UPL archive store
+  let mut archive_db = archive::ArchiveStore::init(paths.archive());
+
+  // Init event log
+  // It is the source of truth: if it is new, we write all the existing
+  // active and archived UPLs into it as its baseline, otherwise
+  // the active UPLs and the archive are rebuilt from it.
+  let event_log = eventlog::EventLog::open(paths.upl_log()).expect("Error while opening event log");
+  if event_log.is_empty() {
+    for upl in upl_db.iter() {
+      event_log
+        .append(eventlog::LogEntry::Inserted {
+          upl: upl.unpack().clone(),
+        })
+        .expect("Error while writing active UPL into the event log");
+    }
+    for upl_id in archive_db
+      .get_upl_ids()
+      .expect("Error while reading UPL archive")
+    {
+      let archive_object = archive_db
+        .get_object(&upl_id)
+        .expect("Error while loading archived UPL");
+      event_log
+        .append(eventlog::LogEntry::Archived {
+          reason: archive_object.get_reason().clone(),
+          archived_at: archive_object.get_archived_at(),
+          upl: archive_object.get_upl().clone(),
+        })
+        .expect("Error while writing archived UPL into the event log");
+    }
+  } else {
+    let state = eventlog::replay(&paths.upl_log(), None).expect("Error while replaying event log");
+    // Remove UPLs the log does not know about
+    // e.g. changes saved to disk, but not committed into the log
+    let unknown_ids = upl_db
+      .iter()
+      .map(|upl| upl.unpack().id.clone())
+      .filter(|upl_id| !state.active.contains_key(upl_id))
+      .collect::<Vec<String>>();
+    for upl_id in unknown_ids {
+      upl_db.remove_pack(&upl_id)?;
+    }
+    // Save UPLs that differ from the log
+    let encode = |upl: &upl::Upl| bincode::serialize(upl).expect("Error while encoding UPL");
+    for (upl_id, upl) in state.active {
+      match upl_db.find_id_mut(&upl_id) {
+        Ok(current) => {
+          if encode(current.unpack()) != encode(&upl) {
+            *current.as_mut().unpack() = upl;
+          }
+        }
+        Err(_) => upl_db.insert(upl)?,
+      }
+    }
+    // The archive files are written after the log commit,
+    // so we write the missing ones from the replayed records
+    let archived_ids = archive_db
+      .get_upl_ids()
+      .expect("Error while reading UPL archive");
+    for upl_id in &archived_ids {
+      if !state.archived.contains(upl_id) {
+        archive_db.restore(upl_id)?;
+      }
+    }
+    for (upl_id, archive_object) in &state.recent_archive {
+      if !archive_db.contains(upl_id) {
+        archive_db.put(archive_object)?;
+      }
+    }
+    for upl_id in &state.archived {
+      if !archive_db.contains(upl_id) {
+        panic!("Archived UPL is missing from the archive: {}", upl_id);
+      }
+    }
+  }
+  archive_db.set_log(event_log.clone());
 
-  let upl_service = UplService::init(upl_db, archive_db);
+  // Move UPLs from the legacy in-memory archive
+  // into the archive store
+  if paths.legacy_archive().exists() {
+    move_legacy_archive(paths.legacy_archive(), &archive_db)?;
+  }
 
-  let addr = env::var("SERVICE_ADDR_UPL")
-    .unwrap_or("[::1]:50064".into())
-    .parse()
-    .unwrap();
+  // Init inventory session DB
+  let inventory_db: VecPack<inventory::InventorySession> =
+    VecPack::load_or_init(paths.inventories())
+      .expect("Error while loading inventory session database");
+
+  // Init UPL index
+  // If the index is new, we register all the existing
+  // active and archived UPL IDs
+  let index_is_new = !paths.upl_index().exists();
+  let index_db = index::UplIndex::init(paths.upl_index());
+  if index_is_new {
+    for upl in upl_db.iter() {
+      index_db
+        .add(upl.unpack())
+        .expect("Error while registering active UPL in the UPL index");
+    }
+    for upl_id in archive_db
+      .get_upl_ids()
+      .expect("Error while reading UPL archive")
+    {
+      let upl = archive_db
+        .get(&upl_id)
+        .expect("Error while loading archived UPL");
+      index_db
+        .add(&upl)
+        .expect("Error while registering archived UPL in the UPL index");
+    }
+  }
+
+  // Init reservation DB
+  let reservation_db: VecPack<reservation::Reservation> =
+    VecPack::load_or_init(paths.reservations()).expect("Error while loading reservation database");
+
+  let upl_service = UplService::init(
+    upl_db,
+    archive_db,
+    inventory_db,
+    index_db,
+    reservation_db,
+    Some(event_log.clone()),
+    config.clone(),
+  );
+
+  // Automatic markdown rules
+  // e.g. 30% off when 3 days remain, 50% on the last day
+  if config.features.markdown_job {
+    let markdown = markdown::Markdown::new(
+      config
+        .markdown_rules()
+        .expect("Error while parsing markdown rules"),
+      config.markdown.depreciation_id,
+    );
+    let run_at = config
+      .markdown_run_at()
+      .expect("Error while parsing markdown run time");
+
+    // Spawn the daily markdown job
+    // It runs at the configured local time
+    let markdown_service = upl_service.clone();
+    tokio::task::spawn(async move {
+      loop {
+        let now = Local::now();
+        let next = markdown::next_run(&now, run_at);
+        tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+        let res = markdown_service.run_markdown(&markdown).await;
+        if let Ok(report) = &res {
+          metrics::global().job_upls("markdown", "updated", report.updated.len());
+          metrics::global().job_upls("markdown", "failed", report.failed.len());
+        }
+        metrics::global().job_run("markdown", res.is_ok());
+      }
+    });
+  }
+
+  // Spawn the periodic event log snapshot job
+  // Snapshots keep the startup and the replay fast
+  let snapshot_service = upl_service.clone();
+  let snapshot_minutes = config.event_log.snapshot_minutes;
+  tokio::task::spawn(async move {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(snapshot_minutes * 60));
+    loop {
+      interval.tick().await;
+      let res = snapshot_service.snapshot_event_log(event_log.clone()).await;
+      metrics::global().job_run("event_log_snapshot", res.is_ok());
+    }
+  });
+
+  // Spawn the lock reaper job
+  // It releases locks expired by their own TTL
+  let reaper_service = upl_service.clone();
+  let reaper_minutes = config.lock_timeouts.reaper_minutes;
+  tokio::task::spawn(async move {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(reaper_minutes * 60));
+    loop {
+      interval.tick().await;
+      let report = reaper_service.reap_locks().await;
+      metrics::global().job_upls("lock_reaper", "released", report.released.len());
+      metrics::global().job_upls("lock_reaper", "failed", report.failed.len());
+      metrics::global().job_run("lock_reaper", report.failed.is_empty());
+    }
+  });
+
+  let addr = config.listen_addr().unwrap_or_else(|e| panic!("{}", e));
+
+  // Caller authentication
+  // The health service is left open for the orchestrator
+  // A disabled auth is reported by the GetStatus RPC
+  let auth = Arc::new(auth::Auth::new(&config.auth));
+
+  // Spawn the metrics endpoint
+  // Plain text Prometheus metrics at GET /metrics
+  if config.features.metrics {
+    let metrics_addr = config.metrics_addr().unwrap_or_else(|e| panic!("{}", e));
+    let metrics_service = upl_service.clone();
+    let make_service = hyper::service::make_service_fn(move |_| {
+      let metrics_service = metrics_service.clone();
+      async move {
+        Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req| {
+          metrics_endpoint(metrics_service.clone(), req)
+        }))
+      }
+    });
+    let metrics_server = hyper::Server::try_bind(&metrics_addr)
+      .expect("Error while binding metrics address")
+      .serve(make_service);
+    // Its error is reported by the GetStatus RPC
+    let metrics_error = upl_service.metrics_error.clone();
+    tokio::task::spawn(async move {
+      if let Err(e) = metrics_server.await {
+        metrics_error.set(e.to_string());
+      }
+    });
+  }
 
   // Create shutdown channel
   let (tx, rx) = oneshot::channel();
@@ -928,7 +2802,17 @@ async fn main() -> prelude::ServiceResult<()> {
   // Spawn the server into a runtime
   tokio::task::spawn(async move {
     Server::builder()
-      .add_service(UplServer::new(upl_service))
+      .add_service(Metered::new(Authorized::new(
+        UplServer::new(upl_service.clone()),
+        auth.clone(),
+      )))
+      .add_service(HealthServer::new(HealthService {
+        upl_service: upl_service.clone(),
+      }))
+      .add_service(Metered::new(Authorized::new(
+        UplExtServer::new(upl_service),
+        auth,
+      )))
       .serve_with_shutdown(addr, async { rx.await.unwrap() })
       .await
   });
@@ -942,3 +2826,799 @@ async fn main() -> prelude::ServiceResult<()> {
 
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use upl_microservice::upl::{Kind, Lock, Upl};
+
+  // Service with empty stores in a temp dir
+  fn service(dir: &tempfile::TempDir) -> UplService {
+    let path = |name: &str| dir.path().join(name);
+    UplService::init(
+      VecPack::load_or_init(path("upls")).unwrap(),
+      archive::ArchiveStore::init(path("archive")),
+      VecPack::load_or_init(path("inventories")).unwrap(),
+      index::UplIndex::init(path("upl_index")),
+      VecPack::load_or_init(path("reservations")).unwrap(),
+      None,
+      config::Config::default(),
+    )
+  }
+
+  fn upl(id: &str, sku: u32, location: Location) -> Upl {
+    Upl {
+      id: id.to_string(),
+      kind: Kind::Sku { sku },
+      location,
+      ..Upl::default()
+    }
+  }
+
+  async fn insert(service: &UplService, upl: Upl) {
+    service.upls.lock().await.insert(upl).unwrap();
+  }
+
+  async fn get(service: &UplService, upl_id: &str) -> Upl {
+    service
+      .upls
+      .lock()
+      .await
+      .find_id(upl_id)
+      .unwrap()
+      .unpack()
+      .clone()
+  }
+
+  #[tokio::test]
+  async fn test_dispatch_and_receive_delivery() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = service(&dir);
+    insert(&service, upl("a", 1, Location::Stock(1))).await;
+    insert(&service, upl("b", 1, Location::Stock(1))).await;
+
+    // Nothing to dispatch
+    let dispatch = DispatchDeliveryRequest {
+      delivery_id: 7,
+      created_by: 1,
+    };
+    assert!(service.dispatch_delivery(dispatch.clone()).await.is_err());
+
+    service
+      .lock_to_delivery(DeliveryLockRequest {
+        upl_ids: vec!["a".to_string(), "b".to_string()],
+        delivery_id: 7,
+        created_by: 1,
+      })
+      .await
+      .unwrap();
+    let mut dispatched = service.dispatch_delivery(dispatch).await.unwrap();
+    dispatched.sort();
+    assert_eq!(dispatched, vec!["a", "b"]);
+    let a = get(&service, "a").await;
+    assert_eq!(a.location, Location::Delivery(7));
+    assert_eq!(a.lock, Lock::None);
+
+    // Cart lock while in transit
+    let res = service
+      .lock_to_cart(CartLockRequest {
+        upl: "a".to_string(),
+        cart_id: "x".to_string(),
+        created_by: 1,
+      })
+      .await;
+    assert!(res.is_err());
+    assert_eq!(get(&service, "a").await.lock, Lock::None);
+
+    let mut received = service
+      .receive_delivery(ReceiveDeliveryRequest {
+        delivery_id: 7,
+        stock_id: 2,
+        created_by: 1,
+      })
+      .await
+      .unwrap();
+    received.sort();
+    assert_eq!(received, vec!["a", "b"]);
+    assert_eq!(get(&service, "b").await.location, Location::Stock(2));
+  }
+
+  #[tokio::test]
+  async fn test_receive_delivery_all_or_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = service(&dir);
+    insert(&service, upl("a", 1, Location::Delivery(7))).await;
+    insert(
+      &service,
+      Upl {
+        lock: Lock::Cart("x".to_string()),
+        ..upl("b", 1, Location::Delivery(7))
+      },
+    )
+    .await;
+
+    // b cannot leave the delivery, so a stays there as well
+    let res = service
+      .receive_delivery(ReceiveDeliveryRequest {
+        delivery_id: 7,
+        stock_id: 2,
+        created_by: 1,
+      })
+      .await;
+    assert!(res.is_err());
+    assert_eq!(get(&service, "a").await.location, Location::Delivery(7));
+    assert_eq!(get(&service, "b").await.location, Location::Delivery(7));
+
+    // Empty delivery
+    let res = service
+      .receive_delivery(ReceiveDeliveryRequest {
+        delivery_id: 8,
+        stock_id: 2,
+        created_by: 1,
+      })
+      .await;
+    assert!(res.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_close_inventory() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = service(&dir);
+    insert(&service, upl("a", 1, Location::Stock(1))).await;
+    insert(&service, upl("b", 1, Location::Stock(1))).await;
+    insert(&service, upl("c", 1, Location::Stock(1))).await;
+
+    let inventory = service
+      .open_inventory(OpenInventoryRequest {
+        stock_id: 1,
+        created_by: 1,
+      })
+      .await
+      .unwrap();
+    service
+      .scan_inventory(ScanInventoryRequest {
+        inventory_id: inventory.inventory_id,
+        upl_ids: vec!["a".to_string()],
+        created_by: 1,
+      })
+      .await
+      .unwrap();
+    // c is sold meanwhile, so it is not locked
+    service
+      .upls
+      .lock()
+      .await
+      .update("c", |upl| {
+        ServiceResult::Ok(upl.lock(Lock::Cart("x".to_string()), 1)?.clone())
+      })
+      .unwrap();
+
+    let close = CloseInventoryRequest {
+      inventory_id: inventory.inventory_id,
+      created_by: 1,
+    };
+    service.close_inventory(close.clone()).await.unwrap();
+    assert_eq!(get(&service, "a").await.lock, Lock::None);
+    assert_eq!(
+      get(&service, "b").await.lock,
+      Lock::Inventory(inventory.inventory_id)
+    );
+    assert_eq!(get(&service, "c").await.lock, Lock::Cart("x".to_string()));
+
+    // Closed session cannot be closed again
+    assert!(service.close_inventory(close).await.is_err());
+  }
+
+  fn luhn_id(base: u64) -> String {
+    use gzlib::id::{generate_id, HexHelper, IdKind};
+    generate_id(base, IdKind::LuhnTwo).to_hex()
+  }
+
+  async fn archive(service: &UplService, upl: Upl, reason: archive::ArchiveReason) {
+    service
+      .archive
+      .lock()
+      .await
+      .add(upl, reason, upl::CreatedBy::Technical)
+      .unwrap();
+  }
+
+  fn restore_request(upl_id: &str, target: restore_request::Target) -> RestoreRequest {
+    RestoreRequest {
+      upl_id: upl_id.to_string(),
+      target: Some(target),
+      reason: "Visszahozta".to_string(),
+      created_by: 1,
+      cart_stock: 3,
+    }
+  }
+
+  #[tokio::test]
+  async fn test_restore_from_archive() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = service(&dir);
+    let (a, b) = (luhn_id(1), luhn_id(2));
+    let sold = |id: &str| upl(id, 1, Location::Cart("old".to_string()));
+    archive(&service, sold(&a), archive::ArchiveReason::Sold).await;
+    archive(&service, sold(&b), archive::ArchiveReason::Sold).await;
+
+    // Into a stock
+    let res = service
+      .restore_from_archive(restore_request(&a, restore_request::Target::Stock(2)))
+      .await
+      .unwrap();
+    assert!(!res.is_archived);
+    let restored = get(&service, &a).await;
+    assert_eq!(restored.location, Location::Stock(2));
+    assert_eq!(restored.lock, Lock::None);
+    assert!(!service.archive.lock().await.contains(&a));
+
+    // Into a new cart, through the cart stock
+    service
+      .restore_from_archive(restore_request(
+        &b,
+        restore_request::Target::Cart("new".to_string()),
+      ))
+      .await
+      .unwrap();
+    let restored = get(&service, &b).await;
+    assert_eq!(restored.location, Location::Stock(3));
+    assert_eq!(restored.lock, Lock::Cart("new".to_string()));
+    assert_eq!(
+      service
+        .upls
+        .lock()
+        .await
+        .find_by_lock(&Lock::Cart("new".to_string()))
+        .count(),
+      1
+    );
+  }
+
+  #[tokio::test]
+  async fn test_restore_from_archive_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = service(&dir);
+    let (a, b) = (luhn_id(1), luhn_id(2));
+    archive(
+      &service,
+      upl(&a, 1, Location::Stock(1)),
+      archive::ArchiveReason::Merged,
+    )
+    .await;
+    archive(
+      &service,
+      upl(&b, 1, Location::Stock(1)),
+      archive::ArchiveReason::Sold,
+    )
+    .await;
+    insert(&service, upl(&b, 1, Location::Stock(1))).await;
+
+    // Empty reason
+    let mut r = restore_request(&b, restore_request::Target::Stock(2));
+    r.reason = " ".to_string();
+    assert!(service.restore_from_archive(r).await.is_err());
+    // Missing target
+    let mut r = restore_request(&b, restore_request::Target::Stock(2));
+    r.target = None;
+    assert!(service.restore_from_archive(r).await.is_err());
+    // Merged UPLs live in their parent
+    let r = restore_request(&a, restore_request::Target::Stock(2));
+    assert!(service.restore_from_archive(r).await.is_err());
+    // UPL ID is active
+    let r = restore_request(&b, restore_request::Target::Stock(2));
+    assert!(service.restore_from_archive(r).await.is_err());
+    assert!(service.archive.lock().await.contains(&b));
+    // Unknown UPL
+    let r = restore_request(&luhn_id(3), restore_request::Target::Stock(2));
+    assert!(service.restore_from_archive(r).await.is_err());
+    // Cart restore without its stock
+    let mut r = restore_request(&b, restore_request::Target::Cart("x".to_string()));
+    r.cart_stock = 0;
+    assert!(service.restore_from_archive(r).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_restore_into_cart_reservations() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = service(&dir);
+    let (a, b) = (luhn_id(1), luhn_id(2));
+    archive(
+      &service,
+      upl(&a, 1, Location::Cart("old".to_string())),
+      archive::ArchiveReason::Sold,
+    )
+    .await;
+    insert(&service, upl(&b, 1, Location::Stock(3))).await;
+    service
+      .reserve(reserve_request("x", 1, reserve_request::Scope::Local(3)))
+      .await
+      .unwrap();
+    // The reserved UPL leaves the stock
+    service
+      .upls
+      .lock()
+      .await
+      .update(&b, |upl| {
+        ServiceResult::Ok(upl.move_upl(Location::Stock(4), 1)?.clone())
+      })
+      .unwrap();
+
+    // Cart y cannot take the restored UPL, cart x needs it
+    let r = restore_request(&a, restore_request::Target::Cart("y".to_string()));
+    assert!(service.restore_from_archive(r).await.is_err());
+    assert!(service.archive.lock().await.contains(&a));
+
+    // Cart x takes it and consumes its reservation
+    let r = restore_request(&a, restore_request::Target::Cart("x".to_string()));
+    service.restore_from_archive(r).await.unwrap();
+    assert_eq!(get(&service, &a).await.lock, Lock::Cart("x".to_string()));
+    assert_eq!(taken(&service, "x").await, vec![1]);
+  }
+
+  fn reserve_request(cart_id: &str, sku: u32, scope: reserve_request::Scope) -> ReserveRequest {
+    ReserveRequest {
+      cart_id: cart_id.to_string(),
+      subject: Some(reserve_request::Subject::Sku(sku)),
+      scope: Some(scope),
+      amount: 1,
+      created_by: 1,
+    }
+  }
+
+  async fn taken(service: &UplService, cart_id: &str) -> Vec<u32> {
+    service
+      .reservations
+      .lock()
+      .await
+      .iter()
+      .filter(|r| r.unpack().get_cart_id() == cart_id)
+      .map(|r| *r.unpack().get_amount_taken())
+      .collect()
+  }
+
+  #[tokio::test]
+  async fn test_lock_to_cart_reservations() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = service(&dir);
+    insert(&service, upl("a", 1, Location::Stock(1))).await;
+    insert(
+      &service,
+      Upl {
+        kind: Kind::BulkSku {
+          sku: 1,
+          upl_pieces: 2,
+        },
+        ..upl("b", 1, Location::Stock(1))
+      },
+    )
+    .await;
+
+    // Cart x reserves 2 of the 3 pieces, in two reservations
+    service
+      .reserve(reserve_request("x", 1, reserve_request::Scope::Local(1)))
+      .await
+      .unwrap();
+    service
+      .reserve(reserve_request("x", 1, reserve_request::Scope::Global(())))
+      .await
+      .unwrap();
+
+    // Cart y cannot take the bulk UPL, as it would eat cart x reservations
+    let lock = |upl: &str, cart_id: &str| CartLockRequest {
+      upl: upl.to_string(),
+      cart_id: cart_id.to_string(),
+      created_by: 1,
+    };
+    assert!(service.lock_to_cart(lock("b", "y")).await.is_err());
+    assert_eq!(get(&service, "b").await.lock, Lock::None);
+
+    // Cart x takes it from both of its reservations
+    service.lock_to_cart(lock("b", "x")).await.unwrap();
+    assert_eq!(taken(&service, "x").await, vec![1, 1]);
+    // The last free piece can go to cart y
+    service.lock_to_cart(lock("a", "y")).await.unwrap();
+
+    // Released UPL gives back both
+    service
+      .release_lock_from_cart(CartUnlockRequest {
+        upl: "b".to_string(),
+        cart_id: "x".to_string(),
+        created_by: 1,
+      })
+      .await
+      .unwrap();
+    assert_eq!(taken(&service, "x").await, vec![0, 0]);
+  }
+
+  #[tokio::test]
+  async fn test_divided_product_reservations() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = service(&dir);
+    let opened = luhn_id(1);
+    insert(
+      &service,
+      Upl {
+        product_id: 5,
+        kind: Kind::OpenedSku {
+          sku: 2,
+          amount: 10,
+          successors: Vec::new(),
+        },
+        sku_divisible: true,
+        sku_divisible_amount: 10,
+        ..upl(&opened, 2, Location::Stock(1))
+      },
+    )
+    .await;
+
+    // Cart x reserves 8 of the 10 units
+    service
+      .reserve(ReserveRequest {
+        cart_id: "x".to_string(),
+        subject: Some(reserve_request::Subject::DividedProduct(5)),
+        scope: Some(reserve_request::Scope::Local(1)),
+        amount: 8,
+        created_by: 1,
+      })
+      .await
+      .unwrap();
+
+    // Dividing keeps the product amount, so it is allowed
+    let derived = luhn_id(2);
+    service
+      .divide(DivideRequest {
+        upl: opened.clone(),
+        new_upl: derived.clone(),
+        requested_amount: 4,
+        created_by: 1,
+      })
+      .await
+      .unwrap();
+
+    // Cart y cannot take the divided part, as it would eat cart x reservation
+    let lock = |cart_id: &str| CartLockRequest {
+      upl: derived.clone(),
+      cart_id: cart_id.to_string(),
+      created_by: 1,
+    };
+    assert!(service.lock_to_cart(lock("y")).await.is_err());
+    service.lock_to_cart(lock("x")).await.unwrap();
+    assert_eq!(taken(&service, "x").await, vec![4]);
+
+    // The remaining 4 reserved units hold back the opened piece
+    let info = service
+      .get_location_info(LocationInfoRequest { sku: 2 })
+      .await
+      .unwrap();
+    assert_eq!(info.stocks[&1].total, 1);
+  }
+
+  fn history_request(upl_id: &str, event_kinds: &[&str]) -> HistoryRequest {
+    HistoryRequest {
+      upl_id: upl_id.to_string(),
+      event_kinds: event_kinds.iter().map(|kind| kind.to_string()).collect(),
+      from: String::new(),
+      till: String::new(),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_get_history() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = service(&dir);
+    let (a, b) = (luhn_id(1), luhn_id(2));
+    let mut active = upl(&a, 1, Location::Stock(1));
+    active.set_history(upl::UplHistoryItem::new(
+      upl::CreatedBy::Technical,
+      upl::UplHistoryEvent::Created,
+    ));
+    active.move_upl(Location::Stock(2), 1).unwrap();
+    active.lock(Lock::Cart("x".to_string()), 1).unwrap();
+    insert(&service, active).await;
+    archive(
+      &service,
+      upl(&b, 1, Location::Cart("x".to_string())),
+      archive::ArchiveReason::Sold,
+    )
+    .await;
+
+    let res = service.get_history(history_request(&a, &[])).await.unwrap();
+    assert!(!res.is_archived);
+    let kinds = res
+      .items
+      .iter()
+      .map(|item| item.event_kind.as_str())
+      .collect::<Vec<&str>>();
+    assert_eq!(kinds, vec!["created", "moved", "locked"]);
+
+    // Kind filter
+    let res = service
+      .get_history(history_request(&a, &["moved", "locked"]))
+      .await
+      .unwrap();
+    assert_eq!(res.items.len(), 2);
+
+    // Time range filter
+    let mut r = history_request(&a, &[]);
+    r.from = (Utc::now() + chrono::Duration::minutes(1)).to_rfc3339();
+    assert!(service.get_history(r).await.unwrap().items.is_empty());
+    let mut r = history_request(&a, &[]);
+    r.till = (Utc::now() + chrono::Duration::minutes(1)).to_rfc3339();
+    assert_eq!(service.get_history(r).await.unwrap().items.len(), 3);
+
+    // Archived UPL
+    let res = service.get_history(history_request(&b, &[])).await.unwrap();
+    assert!(res.is_archived);
+    assert_eq!(res.items.last().unwrap().event_kind, "archived");
+
+    // Errors
+    assert!(service
+      .get_history(history_request(&a, &["not_a_kind"]))
+      .await
+      .is_err());
+    let mut r = history_request(&a, &[]);
+    r.from = "yesterday".to_string();
+    assert!(service.get_history(r).await.is_err());
+    assert!(service
+      .get_history(history_request(&luhn_id(3), &[]))
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn test_split_and_divide_creation_events() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = service(&dir);
+    let (bulk, opened) = (luhn_id(1), luhn_id(2));
+    insert(
+      &service,
+      Upl {
+        kind: Kind::BulkSku {
+          sku: 1,
+          upl_pieces: 3,
+        },
+        ..upl(&bulk, 1, Location::Stock(1))
+      },
+    )
+    .await;
+    insert(
+      &service,
+      Upl {
+        kind: Kind::OpenedSku {
+          sku: 2,
+          amount: 10,
+          successors: Vec::new(),
+        },
+        sku_divisible_amount: 10,
+        ..upl(&opened, 2, Location::Stock(1))
+      },
+    )
+    .await;
+    let seq = service.feed.last_seq();
+
+    let split_id = luhn_id(3);
+    service
+      .split(SplitRequest {
+        upl: bulk.clone(),
+        new_upl: split_id.clone(),
+        piece: 1,
+        created_by: 1,
+      })
+      .await
+      .unwrap();
+    let divided_id = luhn_id(4);
+    service
+      .divide(DivideRequest {
+        upl: opened.clone(),
+        new_upl: divided_id.clone(),
+        requested_amount: 4,
+        created_by: 1,
+      })
+      .await
+      .unwrap();
+
+    let last_event = |upl: Upl| upl.get_history().last().unwrap().get_event().clone();
+    match last_event(get(&service, &split_id).await) {
+      upl::UplHistoryEvent::SplitFrom { parent_upl_id } => assert_eq!(parent_upl_id, bulk),
+      event => panic!("Unexpected event: {:?}", event),
+    }
+    match last_event(get(&service, &divided_id).await) {
+      upl::UplHistoryEvent::DividedFrom {
+        parent_upl_id,
+        amount,
+      } => {
+        assert_eq!(parent_upl_id, opened);
+        assert_eq!(amount, 4);
+      }
+      event => panic!("Unexpected event: {:?}", event),
+    }
+
+    // The feed publishes the creation events of the new UPLs
+    let kinds = service
+      .feed
+      .since(seq)
+      .unwrap()
+      .into_iter()
+      .filter(|event| event.upl_id == split_id || event.upl_id == divided_id)
+      .map(|event| event.event.get_event().get_kind())
+      .collect::<Vec<&str>>();
+    assert_eq!(kinds, vec!["split_from", "divided_from"]);
+
+    // Already issued UPL ID
+    let res = service
+      .split(SplitRequest {
+        upl: bulk.clone(),
+        new_upl: split_id,
+        piece: 1,
+        created_by: 1,
+      })
+      .await;
+    assert!(res.is_err());
+  }
+
+  fn upl_new(upl_id: &str) -> UplNew {
+    UplNew {
+      upl_id: upl_id.to_string(),
+      product_id: 1,
+      product_unit: "db".to_string(),
+      sku: 1,
+      piece: 1,
+      sku_divisible_amount: 1,
+      sku_net_price: 100,
+      sku_vat: "27".to_string(),
+      stock_id: 1,
+      created_by: 1,
+      ..UplNew::default()
+    }
+  }
+
+  fn bulk_request(upls: Vec<UplNew>, all_or_nothing: bool) -> CreateNewBulkRequest {
+    CreateNewBulkRequest {
+      upls,
+      all_or_nothing,
+    }
+  }
+
+  #[tokio::test]
+  async fn test_create_new_bulk_checked() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = service(&dir);
+    let (a, b, c) = (luhn_id(1), luhn_id(2), luhn_id(3));
+
+    let res = service
+      .create_new_bulk_checked(bulk_request(vec![upl_new(&a), upl_new(&b)], true))
+      .await
+      .unwrap();
+    assert_eq!(res.created, 2);
+    assert!(res.results.iter().all(|r| r.created && r.error.is_empty()));
+    assert_eq!(get(&service, &a).await.location, Location::Stock(1));
+    assert!(service.index.lock().await.contains(&b));
+
+    // Already issued ID, so one by one only c is created
+    let res = service
+      .create_new_bulk_checked(bulk_request(vec![upl_new(&a), upl_new(&c)], false))
+      .await
+      .unwrap();
+    assert_eq!(res.created, 1);
+    assert!(!res.results[0].created && !res.results[0].error.is_empty());
+    assert!(res.results[1].created);
+    assert!(service.index.lock().await.contains(&c));
+  }
+
+  #[tokio::test]
+  async fn test_create_new_bulk() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = service(&dir);
+    let (a, b) = (luhn_id(1), luhn_id(2));
+
+    // Invalid item is skipped, the others are created
+    let upl_ids = service
+      .create_new_bulk(vec![upl_new(&a), upl_new("invalid"), upl_new(&b)])
+      .await
+      .unwrap();
+    assert_eq!(upl_ids, vec![a.clone(), b.clone()]);
+    assert_eq!(get(&service, &b).await.location, Location::Stock(1));
+
+    // Already created ones are skipped as well
+    let upl_ids = service.create_new_bulk(vec![upl_new(&a)]).await.unwrap();
+    assert!(upl_ids.is_empty());
+    assert_eq!(service.upls.lock().await.len(), 2);
+  }
+
+  #[tokio::test]
+  async fn test_create_new_bulk_checked_all_or_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = service(&dir);
+    let (a, b) = (luhn_id(1), luhn_id(2));
+
+    // Invalid ID, duplicated ID and wrong VAT: nothing is created
+    let res = service
+      .create_new_bulk_checked(bulk_request(
+        vec![
+          upl_new(&a),
+          upl_new("invalid"),
+          upl_new(&a),
+          UplNew {
+            sku_vat: "x".to_string(),
+            ..upl_new(&b)
+          },
+        ],
+        true,
+      ))
+      .await
+      .unwrap();
+    assert_eq!(res.created, 0);
+    assert_eq!(res.results.len(), 4);
+    assert!(res
+      .results
+      .iter()
+      .all(|r| !r.created && !r.error.is_empty()));
+    assert!(service.upls.lock().await.is_empty());
+    assert!(!service.index.lock().await.contains(&a));
+
+    // Already issued ID in the archive
+    archive(
+      &service,
+      upl(&b, 1, Location::Stock(1)),
+      archive::ArchiveReason::Sold,
+    )
+    .await;
+    let res = service
+      .create_new_bulk_checked(bulk_request(vec![upl_new(&a), upl_new(&b)], true))
+      .await
+      .unwrap();
+    assert_eq!(res.created, 0);
+    assert!(res.results[1].error.contains(&b));
+    assert!(service.upls.lock().await.is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_create_backup() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut service = service(&dir);
+    let mut config = config::Config::default();
+    config.paths.backups = Some(dir.path().join("backups"));
+    service.config = Arc::new(config);
+    insert(&service, upl(&luhn_id(1), 1, Location::Stock(1))).await;
+    archive(
+      &service,
+      upl(&luhn_id(2), 1, Location::Stock(1)),
+      archive::ArchiveReason::Sold,
+    )
+    .await;
+
+    let info = service.create_backup().await.unwrap();
+    assert_eq!(info.active_upls, 1);
+    assert_eq!(info.archived_upls, 1);
+    assert!(std::path::Path::new(&info.path).starts_with(dir.path().join("backups")));
+    assert!(std::path::Path::new(&info.path).exists());
+  }
+
+  #[test]
+  fn test_move_legacy_archive() {
+    let dir = tempfile::tempdir().unwrap();
+    let archive_db = archive::ArchiveStore::init(dir.path().join("archive"));
+    let upl_id = luhn_id(1);
+
+    // Legacy archive written before the lock lease
+    let mut legacy_archive_db: VecPack<lock_lease::Upl> =
+      VecPack::load_or_init(dir.path().join("upl_archive")).unwrap();
+    legacy_archive_db
+      .insert(lock_lease::Upl {
+        id: upl_id.clone(),
+        lock: Lock::Cart("a".to_string()),
+        ..lock_lease::Upl::default()
+      })
+      .unwrap();
+    drop(legacy_archive_db);
+
+    move_legacy_archive(dir.path().join("upl_archive"), &archive_db).unwrap();
+
+    let upl = archive_db.get(&upl_id).unwrap();
+    assert_eq!(upl.lock, Lock::Cart("a".to_string()));
+    let legacy_archive_db: VecPack<lock_lease::Upl> =
+      VecPack::load_or_init(dir.path().join("upl_archive")).unwrap();
+    assert!(legacy_archive_db.is_empty());
+  }
+}
@@ -0,0 +1,211 @@
+use crate::upl::*;
+use chrono::prelude::*;
+
+/// Markdown rule
+/// Apply percent_off when at most days_left days remain
+/// until best_before. 0 days left means the last day.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MarkdownRule {
+  pub days_left: i64,
+  pub percent_off: u32,
+}
+
+impl MarkdownRule {
+  /// Parse rules from a list like "3:30,0:50"
+  /// (days left:percent off)
+  pub fn parse_list(rules: &str) -> Result<Vec<MarkdownRule>, String> {
+    rules
+      .split(',')
+      .map(|rule| rule.trim())
+      .filter(|rule| !rule.is_empty())
+      .map(|rule| {
+        let mut parts = rule.split(':');
+        let days_left = parts.next().and_then(|p| p.trim().parse::<i64>().ok());
+        let percent_off = parts.next().and_then(|p| p.trim().parse::<u32>().ok());
+        match (days_left, percent_off, parts.next()) {
+          (Some(days_left), Some(percent_off), None) if days_left >= 0 && percent_off < 100 => {
+            Ok(MarkdownRule {
+              days_left,
+              percent_off,
+            })
+          }
+          _ => Err(format!("Hibás leárazási szabály: {}", rule)),
+        }
+      })
+      .collect()
+  }
+}
+
+/// Next run of a daily job at a local time
+/// Today if the time has not passed yet, otherwise tomorrow.
+/// A day the time does not exist on, e.g. by a DST change, is skipped.
+pub fn next_run<Tz: TimeZone>(now: &DateTime<Tz>, at: NaiveTime) -> DateTime<Tz> {
+  let mut date = now.date_naive();
+  loop {
+    if let Some(next) = now
+      .timezone()
+      .from_local_datetime(&date.and_time(at))
+      .earliest()
+    {
+      if next > *now {
+        return next;
+      }
+    }
+    date = date.succ_opt().expect("Error while getting the next day");
+  }
+}
+
+/// Result of a markdown run
+#[derive(Debug, Default)]
+pub struct MarkdownReport {
+  // Marked down UPL IDs
+  pub updated: Vec<String>,
+  // UPL ID and error message
+  pub failed: Vec<(String, String)>,
+}
+
+/// Markdown config
+pub struct Markdown {
+  rules: Vec<MarkdownRule>,
+  // Depreciation ID we use for the automatic markdowns
+  // UPLs depreciated with a different ID are left alone
+  depreciation_id: u32,
+}
+
+impl Markdown {
+  pub fn new(rules: Vec<MarkdownRule>, depreciation_id: u32) -> Self {
+    Self {
+      rules,
+      depreciation_id,
+    }
+  }
+
+  /// Get percent off for a UPL if any rule applies
+  /// Expired UPLs and UPLs without best_before get None
+  pub fn get_percent_off(&self, upl: &Upl, today: NaiveDate) -> Option<u32> {
+    let days_left = (upl.get_best_before()?.date_naive() - today).num_days();
+    if days_left < 0 {
+      return None;
+    }
+    self
+      .rules
+      .iter()
+      .filter(|rule| days_left <= rule.days_left)
+      .map(|rule| rule.percent_off)
+      .max()
+  }
+
+  /// Apply markdown to a UPL
+  /// Only UPLs in stock without any lock
+  /// Returns true if the UPL has changed
+  pub fn apply(&self, upl: &mut Upl, today: NaiveDate) -> Result<bool, String> {
+    if !matches!(upl.get_location(), Location::Stock(_)) || upl.has_lock() {
+      return Ok(false);
+    }
+
+    let percent_off = match self.get_percent_off(upl, today) {
+      Some(percent_off) => percent_off,
+      None => return Ok(false),
+    };
+
+    // Calculate the new net retail price based on the regular price
+    let price = upl.price_net.percent(100 - percent_off);
+
+    match upl.get_depreciation_id() {
+      // Manually depreciated UPL, we do not touch it
+      Some(depreciation_id) if depreciation_id != self.depreciation_id => return Ok(false),
+      // Already marked down with the same or a lower price
+      Some(_) => {
+        if let Some(current) = upl.get_depreciation_price() {
+          if current <= price {
+            return Ok(false);
+          }
+        }
+      }
+      None => {
+        upl.set_depreciation(
+          self.depreciation_id,
+          format!("Automatikus leárazás {}%", percent_off),
+          CreatedBy::Technical,
+        )?;
+      }
+    }
+
+    upl.set_depreciation_price(Some(price), CreatedBy::Technical)?;
+
+    Ok(true)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::Duration;
+
+  fn upl(best_before: NaiveDate) -> Upl {
+    Upl {
+      best_before: Some(Utc.from_utc_datetime(&best_before.and_hms_opt(12, 0, 0).unwrap())),
+      price_net: Money::new(1000),
+      ..Upl::default()
+    }
+  }
+
+  #[test]
+  fn test_parse_list() {
+    assert_eq!(
+      MarkdownRule::parse_list("3:30, 0:50").unwrap(),
+      vec![
+        MarkdownRule {
+          days_left: 3,
+          percent_off: 30
+        },
+        MarkdownRule {
+          days_left: 0,
+          percent_off: 50
+        }
+      ]
+    );
+    assert!(MarkdownRule::parse_list("3:130").is_err());
+    assert!(MarkdownRule::parse_list("x").is_err());
+  }
+
+  #[test]
+  fn test_apply() {
+    let markdown = Markdown::new(MarkdownRule::parse_list("3:30,0:50").unwrap(), 9);
+    let today = Utc::now().date_naive();
+
+    // Too early
+    let mut u = upl(today + Duration::days(4));
+    assert_eq!(markdown.apply(&mut u, today), Ok(false));
+
+    // 30% off
+    let mut u = upl(today + Duration::days(3));
+    assert_eq!(markdown.apply(&mut u, today), Ok(true));
+    assert_eq!(u.get_depreciation_id(), Some(9));
+    assert_eq!(u.get_depreciation_price(), Some(Money::new(700)));
+    // Running again changes nothing
+    assert_eq!(markdown.apply(&mut u, today), Ok(false));
+
+    // Last day 50% off
+    assert_eq!(markdown.apply(&mut u, today + Duration::days(3)), Ok(true));
+    assert_eq!(u.get_depreciation_price(), Some(Money::new(500)));
+
+    // Expired
+    assert_eq!(markdown.apply(&mut u, today + Duration::days(4)), Ok(false));
+  }
+
+  #[test]
+  fn test_next_run() {
+    let at = NaiveTime::from_hms_opt(2, 0, 0).unwrap();
+    let now = Utc.with_ymd_and_hms(2021, 3, 1, 1, 0, 0).unwrap();
+    assert_eq!(
+      next_run(&now, at),
+      Utc.with_ymd_and_hms(2021, 3, 1, 2, 0, 0).unwrap()
+    );
+    let now = Utc.with_ymd_and_hms(2021, 3, 1, 2, 0, 0).unwrap();
+    assert_eq!(
+      next_run(&now, at),
+      Utc.with_ymd_and_hms(2021, 3, 2, 2, 0, 0).unwrap()
+    );
+  }
+}
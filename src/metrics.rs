@@ -0,0 +1,487 @@
+use crate::upl::*;
+use chrono::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tonic::transport::NamedService;
+
+/// Latency histogram buckets in seconds
+pub const BUCKETS: [f64; 11] = [
+  0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+  // Count of observations per bucket, not cumulative
+  buckets: [u64; BUCKETS.len()],
+  sum: f64,
+  count: u64,
+}
+
+impl Histogram {
+  fn observe(&mut self, seconds: f64) {
+    if let Some(i) = BUCKETS.iter().position(|le| seconds <= *le) {
+      self.buckets[i] += 1;
+    }
+    self.sum += seconds;
+    self.count += 1;
+  }
+
+  fn render(&self, out: &mut String, name: &str, labels: &str) {
+    let mut cumulative = 0;
+    for (le, count) in BUCKETS.iter().zip(self.buckets.iter()) {
+      cumulative += count;
+      let _ = writeln!(
+        out,
+        "{}_bucket{{{},le=\"{}\"}} {}",
+        name, labels, le, cumulative
+      );
+    }
+    let _ = writeln!(
+      out,
+      "{}_bucket{{{},le=\"+Inf\"}} {}",
+      name, labels, self.count
+    );
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+  }
+}
+
+#[derive(Default)]
+struct Registry {
+  // RPC path -> request count
+  rpc_requests: BTreeMap<String, u64>,
+  // (RPC path, gRPC status code) -> error count
+  rpc_errors: BTreeMap<(String, String), u64>,
+  // RPC path -> latency
+  rpc_latency: BTreeMap<String, Histogram>,
+  // ServiceError variant -> error count
+  service_errors: BTreeMap<&'static str, u64>,
+  // Mutex name -> wait time
+  lock_wait: BTreeMap<&'static str, Histogram>,
+  // Lock kind -> expired locks released
+  locks_reaped: BTreeMap<&'static str, u64>,
+  // (Job name, ok or error) -> job runs
+  job_runs: BTreeMap<(&'static str, &'static str), u64>,
+  // (Job name, result) -> UPLs processed by the job
+  job_upls: BTreeMap<(&'static str, &'static str), u64>,
+}
+
+/// Service metrics
+/// Counters and histograms in memory,
+/// rendered in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+  registry: Mutex<Registry>,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Get the service wide metrics
+pub fn global() -> &'static Metrics {
+  METRICS.get_or_init(Metrics::default)
+}
+
+impl Metrics {
+  fn registry(&self) -> std::sync::MutexGuard<'_, Registry> {
+    // Metrics must not stop the service,
+    // so we use them even after a panic
+    self
+      .registry
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  /// Observe a finished RPC call
+  /// Error code is None for successful calls
+  pub fn observe_rpc(&self, path: &str, error_code: Option<&str>, seconds: f64) {
+    let mut registry = self.registry();
+    *registry.rpc_requests.entry(path.to_string()).or_insert(0) += 1;
+    if let Some(code) = error_code {
+      *registry
+        .rpc_errors
+        .entry((path.to_string(), code.to_string()))
+        .or_insert(0) += 1;
+    }
+    registry
+      .rpc_latency
+      .entry(path.to_string())
+      .or_default()
+      .observe(seconds);
+  }
+
+  /// Count a ServiceError by its variant
+  pub fn service_error(&self, variant: &'static str) {
+    *self.registry().service_errors.entry(variant).or_insert(0) += 1;
+  }
+
+  /// Observe mutex wait time
+  pub fn observe_lock_wait(&self, name: &'static str, seconds: f64) {
+    self
+      .registry()
+      .lock_wait
+      .entry(name)
+      .or_default()
+      .observe(seconds);
+  }
+
+  /// Count a lock released by the lock reaper
+  pub fn lock_reaped(&self, lock: &Lock) {
+    *self
+      .registry()
+      .locks_reaped
+      .entry(lock_kind(lock))
+      .or_insert(0) += 1;
+  }
+
+  /// Count a finished background job run
+  pub fn job_run(&self, job: &'static str, ok: bool) {
+    let result = if ok { "ok" } else { "error" };
+    *self.registry().job_runs.entry((job, result)).or_insert(0) += 1;
+  }
+
+  /// Count UPLs processed by a background job
+  /// e.g. updated or failed UPLs of the markdown job
+  pub fn job_upls(&self, job: &'static str, result: &'static str, count: usize) {
+    *self.registry().job_upls.entry((job, result)).or_insert(0) += count as u64;
+  }
+
+  /// Render metrics in Prometheus text format
+  pub fn render(&self, out: &mut String) {
+    let registry = self.registry();
+
+    header(out, "upl_rpc_requests_total", "counter", "RPC requests");
+    for (path, count) in &registry.rpc_requests {
+      let _ = writeln!(
+        out,
+        "upl_rpc_requests_total{{method=\"{}\"}} {}",
+        path, count
+      );
+    }
+
+    header(
+      out,
+      "upl_rpc_errors_total",
+      "counter",
+      "RPC errors by gRPC status code",
+    );
+    for ((path, code), count) in &registry.rpc_errors {
+      let _ = writeln!(
+        out,
+        "upl_rpc_errors_total{{method=\"{}\",code=\"{}\"}} {}",
+        path, code, count
+      );
+    }
+
+    header(
+      out,
+      "upl_rpc_duration_seconds",
+      "histogram",
+      "RPC latency until the response headers",
+    );
+    for (path, histogram) in &registry.rpc_latency {
+      histogram.render(
+        out,
+        "upl_rpc_duration_seconds",
+        &format!("method=\"{}\"", path),
+      );
+    }
+
+    header(
+      out,
+      "upl_service_errors_total",
+      "counter",
+      "Service errors by ServiceError variant",
+    );
+    for (variant, count) in &registry.service_errors {
+      let _ = writeln!(
+        out,
+        "upl_service_errors_total{{variant=\"{}\"}} {}",
+        variant, count
+      );
+    }
+
+    header(
+      out,
+      "upl_lock_wait_seconds",
+      "histogram",
+      "Time spent waiting for a store mutex",
+    );
+    for (name, histogram) in &registry.lock_wait {
+      histogram.render(out, "upl_lock_wait_seconds", &format!("mutex=\"{}\"", name));
+    }
+
+    header(
+      out,
+      "upl_locks_reaped_total",
+      "counter",
+      "Expired locks released by the lock reaper",
+    );
+    for (lock, count) in &registry.locks_reaped {
+      let _ = writeln!(out, "upl_locks_reaped_total{{lock=\"{}\"}} {}", lock, count);
+    }
+
+    header(out, "upl_job_runs_total", "counter", "Background job runs");
+    for ((job, result), count) in &registry.job_runs {
+      let _ = writeln!(
+        out,
+        "upl_job_runs_total{{job=\"{}\",result=\"{}\"}} {}",
+        job, result, count
+      );
+    }
+
+    header(
+      out,
+      "upl_job_upls_total",
+      "counter",
+      "UPLs processed by background jobs by result",
+    );
+    for ((job, result), count) in &registry.job_upls {
+      let _ = writeln!(
+        out,
+        "upl_job_upls_total{{job=\"{}\",result=\"{}\"}} {}",
+        job, result, count
+      );
+    }
+  }
+}
+
+// Lock kind label
+fn lock_kind(lock: &Lock) -> &'static str {
+  match lock {
+    Lock::Cart(_) => "cart",
+    Lock::Delivery(_) => "delivery",
+    Lock::Inventory(_) => "inventory",
+    Lock::None => "none",
+  }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+  let _ = writeln!(out, "# HELP {} {}", name, help);
+  let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Stock gauges
+/// Computed from the active UPLs when metrics are scraped
+#[derive(Debug, Default, PartialEq)]
+pub struct StockGauges {
+  // Kind -> UPL count
+  pub by_kind: BTreeMap<&'static str, u64>,
+  // Lock variant -> UPL count
+  pub locked: BTreeMap<&'static str, u64>,
+  // Stock ID -> expired UPL count
+  pub expired: BTreeMap<u32, u64>,
+  // Stock ID -> depreciated UPL count
+  pub depreciated: BTreeMap<u32, u64>,
+}
+
+impl StockGauges {
+  /// Collect gauges from the active UPLs
+  pub fn collect<'a>(upls: impl Iterator<Item = &'a Upl>, today: NaiveDate) -> Self {
+    let mut gauges = Self::default();
+    for upl in upls {
+      let kind = match upl.get_kind() {
+        Kind::Sku { .. } => "sku",
+        Kind::BulkSku { .. } => "bulk_sku",
+        Kind::OpenedSku { .. } => "opened_sku",
+        Kind::DerivedProduct { .. } => "derived_product",
+      };
+      *gauges.by_kind.entry(kind).or_insert(0) += 1;
+      if upl.has_lock() {
+        *gauges.locked.entry(lock_kind(upl.get_lock())).or_insert(0) += 1;
+      }
+      if let Location::Stock(stock_id) = upl.get_location() {
+        let expired = match upl.get_best_before() {
+          Some(best_before) => best_before.date_naive() < today,
+          None => false,
+        };
+        if expired {
+          *gauges.expired.entry(*stock_id).or_insert(0) += 1;
+        }
+        if upl.is_depreciated() {
+          *gauges.depreciated.entry(*stock_id).or_insert(0) += 1;
+        }
+      }
+    }
+    gauges
+  }
+
+  /// Render gauges in Prometheus text format
+  pub fn render(&self, out: &mut String) {
+    header(out, "upl_active_upls", "gauge", "Active UPLs by kind");
+    for (kind, count) in &self.by_kind {
+      let _ = writeln!(out, "upl_active_upls{{kind=\"{}\"}} {}", kind, count);
+    }
+    header(out, "upl_locked_upls", "gauge", "Locked UPLs by lock");
+    for (lock, count) in &self.locked {
+      let _ = writeln!(out, "upl_locked_upls{{lock=\"{}\"}} {}", lock, count);
+    }
+    header(
+      out,
+      "upl_expired_upls",
+      "gauge",
+      "Expired UPLs in stock by stock ID",
+    );
+    for (stock_id, count) in &self.expired {
+      let _ = writeln!(
+        out,
+        "upl_expired_upls{{stock_id=\"{}\"}} {}",
+        stock_id, count
+      );
+    }
+    header(
+      out,
+      "upl_depreciated_upls",
+      "gauge",
+      "Depreciated UPLs in stock by stock ID",
+    );
+    for (stock_id, count) in &self.depreciated {
+      let _ = writeln!(
+        out,
+        "upl_depreciated_upls{{stock_id=\"{}\"}} {}",
+        stock_id, count
+      );
+    }
+  }
+}
+
+/// Tokio Mutex that records its wait time
+pub struct TimedMutex<T> {
+  name: &'static str,
+  inner: tokio::sync::Mutex<T>,
+}
+
+impl<T> TimedMutex<T> {
+  /// Create mutex
+  /// Its name is the metrics label
+  pub fn new(name: &'static str, value: T) -> Self {
+    Self {
+      name,
+      inner: tokio::sync::Mutex::new(value),
+    }
+  }
+
+  /// Lock mutex
+  pub async fn lock(&self) -> tokio::sync::MutexGuard<'_, T> {
+    let start = Instant::now();
+    let guard = self.inner.lock().await;
+    global().observe_lock_wait(self.name, start.elapsed().as_secs_f64());
+    guard
+  }
+}
+
+/// Metered gRPC service
+/// Wraps a tonic service and records the request count,
+/// latency and error code of every call.
+/// Latency is measured until the response headers,
+/// so for streaming RPCs it is the time to open the stream.
+#[derive(Debug, Clone)]
+pub struct Metered<S> {
+  inner: S,
+}
+
+impl<S> Metered<S> {
+  pub fn new(inner: S) -> Self {
+    Self { inner }
+  }
+}
+
+impl<S: NamedService> NamedService for Metered<S> {
+  const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> tower_service::Service<http::Request<hyper::Body>> for Metered<S>
+where
+  S:
+    tower_service::Service<http::Request<hyper::Body>, Response = http::Response<B>> + NamedService,
+  S::Future: Send + 'static,
+{
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = std::pin::Pin<
+    Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>,
+  >;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, req: http::Request<hyper::Body>) -> Self::Future {
+    // Only the methods of this service, so unknown
+    // paths cannot flood the metrics
+    let path = req.uri().path().to_string();
+    let known = path.starts_with(&format!("/{}/", S::NAME));
+    let start = Instant::now();
+    let future = self.inner.call(req);
+    Box::pin(async move {
+      let res = future.await;
+      if known {
+        let seconds = start.elapsed().as_secs_f64();
+        let error_code = match &res {
+          // Errors are sent in the headers (trailers only response),
+          // successful calls have their status in the trailers
+          Ok(response) => response
+            .headers()
+            .get("grpc-status")
+            .and_then(|code| code.to_str().ok())
+            .and_then(|code| code.parse::<i32>().ok())
+            .filter(|code| *code != 0)
+            .map(|code| format!("{:?}", tonic::Code::from_i32(code))),
+          Err(_) => Some("Transport".to_string()),
+        };
+        global().observe_rpc(&path, error_code.as_deref(), seconds);
+      }
+      res
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render() {
+    let metrics = Metrics::default();
+    metrics.observe_rpc("/upl.Upl/GetById", None, 0.002);
+    metrics.observe_rpc("/upl.Upl/GetById", Some("NotFound"), 2.0);
+    metrics.service_error("NotFound");
+    metrics.job_run("markdown", false);
+    metrics.job_upls("markdown", "failed", 2);
+    let mut out = String::new();
+    metrics.render(&mut out);
+    assert!(out.contains("upl_rpc_requests_total{method=\"/upl.Upl/GetById\"} 2"));
+    assert!(out.contains("upl_rpc_errors_total{method=\"/upl.Upl/GetById\",code=\"NotFound\"} 1"));
+    assert!(
+      out.contains("upl_rpc_duration_seconds_bucket{method=\"/upl.Upl/GetById\",le=\"0.0025\"} 1")
+    );
+    assert!(
+      out.contains("upl_rpc_duration_seconds_bucket{method=\"/upl.Upl/GetById\",le=\"+Inf\"} 2")
+    );
+    assert!(out.contains("upl_service_errors_total{variant=\"NotFound\"} 1"));
+    assert!(out.contains("upl_job_runs_total{job=\"markdown\",result=\"error\"} 1"));
+    assert!(out.contains("upl_job_upls_total{job=\"markdown\",result=\"failed\"} 2"));
+  }
+
+  #[test]
+  fn test_stock_gauges() {
+    let today = NaiveDate::from_ymd_opt(2021, 6, 10).unwrap();
+    let expired = Upl {
+      location: Location::Stock(1),
+      best_before: Some(Utc.with_ymd_and_hms(2021, 6, 9, 12, 0, 0).unwrap()),
+      ..Upl::default()
+    };
+    let locked = Upl {
+      location: Location::Stock(2),
+      lock: Lock::Delivery(3),
+      ..Upl::default()
+    };
+    let gauges = StockGauges::collect([expired, locked].iter(), today);
+    assert_eq!(gauges.by_kind.get("sku"), Some(&2));
+    assert_eq!(gauges.locked.get("delivery"), Some(&1));
+    assert_eq!(gauges.expired.get(&1), Some(&1));
+    assert!(!gauges.expired.contains_key(&2));
+  }
+}
@@ -0,0 +1,355 @@
+use crate::archive::{self, ArchiveReason};
+use crate::upl::{
+  self as current, CreatedBy, Depreciation, Kind, Location, Lock, LockLease, Money, VAT,
+};
+use chrono::prelude::*;
+use packman::VecPackMember;
+use serde::{Deserialize, Serialize};
+
+// History event before the new events were added
+// Frozen copy, so the variant indexes of the stored events do not change
+// when the current UplHistoryEvent grows.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum UplHistoryEvent {
+  Created,
+  Archived,
+  Moved {
+    from: Location,
+    to: Location,
+  },
+  BestBeforeUpdated {
+    to: Option<DateTime<Utc>>,
+  },
+  Locked {
+    to: Lock,
+  },
+  Unlocked,
+  SetDeprecated {
+    depreciation_id: u32,
+    comment: String,
+  },
+  DeprecationRemoved,
+  SetDepreciatedPrice {
+    retail_net_price: Option<u32>,
+  },
+  Split {
+    new_upl_id: String,
+  },
+  Divided {
+    new_upl_id: String,
+    requested_amount: u32,
+  },
+  None,
+}
+
+impl UplHistoryEvent {
+  /// Convert into the current history event
+  pub fn migrate(self) -> current::UplHistoryEvent {
+    match self {
+      UplHistoryEvent::Created => current::UplHistoryEvent::Created,
+      UplHistoryEvent::Archived => current::UplHistoryEvent::Archived,
+      UplHistoryEvent::Moved { from, to } => current::UplHistoryEvent::Moved { from, to },
+      UplHistoryEvent::BestBeforeUpdated { to } => {
+        current::UplHistoryEvent::BestBeforeUpdated { to }
+      }
+      UplHistoryEvent::Locked { to } => current::UplHistoryEvent::Locked { to },
+      UplHistoryEvent::Unlocked => current::UplHistoryEvent::Unlocked,
+      UplHistoryEvent::SetDeprecated {
+        depreciation_id,
+        comment,
+      } => current::UplHistoryEvent::SetDeprecated {
+        depreciation_id,
+        comment,
+      },
+      UplHistoryEvent::DeprecationRemoved => current::UplHistoryEvent::DeprecationRemoved,
+      UplHistoryEvent::SetDepreciatedPrice { retail_net_price } => {
+        current::UplHistoryEvent::SetDepreciatedPrice {
+          retail_net_price: retail_net_price.map(Money::new),
+        }
+      }
+      UplHistoryEvent::Split { new_upl_id } => current::UplHistoryEvent::Split { new_upl_id },
+      UplHistoryEvent::Divided {
+        new_upl_id,
+        requested_amount,
+      } => current::UplHistoryEvent::Divided {
+        new_upl_id,
+        requested_amount,
+      },
+      UplHistoryEvent::None => current::UplHistoryEvent::None,
+    }
+  }
+}
+
+// History item with the old history event
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UplHistoryItem {
+  pub event: UplHistoryEvent,
+  pub created_at: DateTime<Utc>,
+  pub created_by: CreatedBy,
+}
+
+impl UplHistoryItem {
+  /// Convert into the current history item
+  pub fn migrate(self) -> current::UplHistoryItem {
+    current::UplHistoryItem::new_at(self.created_by, self.event.migrate(), self.created_at)
+  }
+}
+
+// UPL scheme before the lock lease
+// Same fields in the same order as the current UPL without its lock_lease,
+// so bincode stored UPLs of that time can be read.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Upl {
+  pub id: String,
+  pub product_id: u32,
+  pub sku_divisible_amount: u32,
+  pub product_unit: String,
+  pub kind: Kind,
+  pub procurement_id: u32,
+  pub procurement_net_price: Money,
+  pub procurement_net_price_sku: Money,
+  pub margin_net: i32,
+  pub location: Location,
+  pub depreciation: Option<Depreciation>,
+  pub best_before: Option<DateTime<Utc>>,
+  pub sku_divisible: bool,
+  pub sku_price_net: Money,
+  pub price_net: Money,
+  pub vat: VAT,
+  pub price_gross: Money,
+  pub lock: Lock,
+  pub history: Vec<UplHistoryItem>,
+  pub created_at: DateTime<Utc>,
+  pub created_by: u32,
+}
+
+impl VecPackMember for Upl {
+  type Out = String;
+
+  fn get_id(&self) -> &Self::Out {
+    &self.id
+  }
+}
+
+impl Upl {
+  /// Convert into the current UPL scheme
+  /// A held lock gets its lease with the given TTL. It was acquired at the
+  /// time of the last lock event, or at the UPL creation if there is none.
+  pub fn migrate(self, ttl_minutes: Option<u64>) -> crate::upl::Upl {
+    let lock_lease = match self.lock {
+      Lock::None => None,
+      _ => {
+        let acquired_at = self
+          .history
+          .iter()
+          .rev()
+          .find(|item| matches!(item.event, UplHistoryEvent::Locked { .. }))
+          .map(|item| item.created_at)
+          .unwrap_or(self.created_at);
+        Some(LockLease::new(acquired_at, ttl_minutes))
+      }
+    };
+    crate::upl::Upl {
+      id: self.id,
+      product_id: self.product_id,
+      sku_divisible_amount: self.sku_divisible_amount,
+      product_unit: self.product_unit,
+      kind: self.kind,
+      procurement_id: self.procurement_id,
+      procurement_net_price: self.procurement_net_price,
+      procurement_net_price_sku: self.procurement_net_price_sku,
+      margin_net: self.margin_net,
+      location: self.location,
+      depreciation: self.depreciation,
+      best_before: self.best_before,
+      sku_divisible: self.sku_divisible,
+      sku_price_net: self.sku_price_net,
+      price_net: self.price_net,
+      vat: self.vat,
+      price_gross: self.price_gross,
+      lock: self.lock,
+      lock_lease,
+      history: self
+        .history
+        .into_iter()
+        .map(UplHistoryItem::migrate)
+        .collect(),
+      created_at: self.created_at,
+      created_by: self.created_by,
+    }
+  }
+}
+
+// Archive object before the lock lease
+// Only bincode backups need it, the archive files are YAML
+// and read the missing lease as None.
+#[derive(Serialize, Deserialize)]
+pub struct ArchiveObject {
+  reason: ArchiveReason,
+  archived_at: DateTime<Utc>,
+  upl: Upl,
+}
+
+impl ArchiveObject {
+  /// Convert into the current archive object
+  /// Archived UPLs keep their lock, but it never expires
+  pub fn migrate(self) -> archive::ArchiveObject {
+    archive::ArchiveObject::new_at(self.reason, self.upl.migrate(None), self.archived_at)
+  }
+}
+
+// Old UPL of a current one, to test the migration
+#[cfg(test)]
+impl From<crate::upl::Upl> for Upl {
+  fn from(upl: crate::upl::Upl) -> Self {
+    Self {
+      id: upl.id,
+      product_id: upl.product_id,
+      sku_divisible_amount: upl.sku_divisible_amount,
+      product_unit: upl.product_unit,
+      kind: upl.kind,
+      procurement_id: upl.procurement_id,
+      procurement_net_price: upl.procurement_net_price,
+      procurement_net_price_sku: upl.procurement_net_price_sku,
+      margin_net: upl.margin_net,
+      location: upl.location,
+      depreciation: upl.depreciation,
+      best_before: upl.best_before,
+      sku_divisible: upl.sku_divisible,
+      sku_price_net: upl.sku_price_net,
+      price_net: upl.price_net,
+      vat: upl.vat,
+      price_gross: upl.price_gross,
+      lock: upl.lock,
+      history: upl
+        .history
+        .iter()
+        .filter_map(|item| {
+          Some(UplHistoryItem {
+            event: legacy_event(item.get_event())?,
+            created_at: item.get_created_at(),
+            created_by: item.get_created_by().clone(),
+          })
+        })
+        .collect(),
+      created_at: upl.created_at,
+      created_by: upl.created_by,
+    }
+  }
+}
+
+// Old history event of a current one, to test the migration
+// None if the event has no old variant
+#[cfg(test)]
+fn legacy_event(event: &current::UplHistoryEvent) -> Option<UplHistoryEvent> {
+  let event = match event.clone() {
+    current::UplHistoryEvent::Created => UplHistoryEvent::Created,
+    current::UplHistoryEvent::Archived => UplHistoryEvent::Archived,
+    current::UplHistoryEvent::Moved { from, to } => UplHistoryEvent::Moved { from, to },
+    current::UplHistoryEvent::BestBeforeUpdated { to } => UplHistoryEvent::BestBeforeUpdated { to },
+    current::UplHistoryEvent::Locked { to } => UplHistoryEvent::Locked { to },
+    current::UplHistoryEvent::Unlocked => UplHistoryEvent::Unlocked,
+    current::UplHistoryEvent::SetDeprecated {
+      depreciation_id,
+      comment,
+    } => UplHistoryEvent::SetDeprecated {
+      depreciation_id,
+      comment,
+    },
+    current::UplHistoryEvent::DeprecationRemoved => UplHistoryEvent::DeprecationRemoved,
+    current::UplHistoryEvent::SetDepreciatedPrice { retail_net_price } => {
+      UplHistoryEvent::SetDepreciatedPrice {
+        retail_net_price: retail_net_price.map(u32::from),
+      }
+    }
+    current::UplHistoryEvent::Split { new_upl_id } => UplHistoryEvent::Split { new_upl_id },
+    current::UplHistoryEvent::Divided {
+      new_upl_id,
+      requested_amount,
+    } => UplHistoryEvent::Divided {
+      new_upl_id,
+      requested_amount,
+    },
+    current::UplHistoryEvent::None => UplHistoryEvent::None,
+    _ => return None,
+  };
+  Some(event)
+}
+
+// Old archive object of a current one, to test the migration
+#[cfg(test)]
+impl From<&archive::ArchiveObject> for ArchiveObject {
+  fn from(archive_object: &archive::ArchiveObject) -> Self {
+    Self {
+      reason: archive_object.get_reason().clone(),
+      archived_at: archive_object.get_archived_at(),
+      upl: archive_object.get_upl().clone().into(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::upl::UplMethods;
+
+  #[test]
+  fn test_migrate_lock_lease() {
+    let created_at = Utc.with_ymd_and_hms(2021, 6, 10, 12, 0, 0).unwrap();
+    let old: Upl = crate::upl::Upl {
+      id: "a".to_string(),
+      lock: Lock::Cart("c1".to_string()),
+      created_at,
+      ..crate::upl::Upl::default()
+    }
+    .into();
+    let bytes = bincode::serialize(&old).unwrap();
+
+    // Lock without a lock event was acquired at the creation
+    let upl = bincode::deserialize::<Upl>(&bytes)
+      .unwrap()
+      .migrate(Some(30));
+    assert_eq!(upl.lock, Lock::Cart("c1".to_string()));
+    assert_eq!(upl.lock_lease, Some(LockLease::new(created_at, Some(30))));
+
+    // Lock event time wins over the creation time
+    let mut locked = old.clone();
+    let locked_at = Utc.with_ymd_and_hms(2021, 6, 11, 8, 0, 0).unwrap();
+    locked.history = vec![
+      UplHistoryItem {
+        event: UplHistoryEvent::Locked {
+          to: Lock::Cart("c1".to_string()),
+        },
+        created_at: locked_at,
+        created_by: CreatedBy::Uid(1),
+      },
+      UplHistoryItem {
+        event: UplHistoryEvent::None,
+        created_at,
+        created_by: CreatedBy::Technical,
+      },
+    ];
+    let bytes = bincode::serialize(&locked).unwrap();
+    let migrated = bincode::deserialize::<Upl>(&bytes).unwrap().migrate(None);
+    let lease = migrated.lock_lease.clone().unwrap();
+    assert_eq!(lease.acquired_at, locked_at);
+    assert_eq!(lease.ttl_minutes, None);
+
+    // Old events keep their meaning and time
+    let history = migrated.get_history();
+    assert!(matches!(
+      history[0].get_event(),
+      current::UplHistoryEvent::Locked { .. }
+    ));
+    assert_eq!(history[0].get_created_at(), locked_at);
+    assert!(matches!(
+      history[1].get_event(),
+      current::UplHistoryEvent::None
+    ));
+
+    // No lease without a lock
+    let mut unlocked = old;
+    unlocked.lock = Lock::None;
+    assert_eq!(unlocked.migrate(Some(30)).lock_lease, None);
+  }
+}
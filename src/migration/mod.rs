@@ -1,3 +1,4 @@
+pub mod lock_lease;
 pub mod price;
 pub mod product;
 pub mod quantity;
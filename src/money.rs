@@ -0,0 +1,194 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+
+/// Money amount in the smallest currency unit
+/// Fixed-point, with no fraction below the smallest unit,
+/// so every calculation rounds explicitly:
+///  - gross prices and percentages round half up
+///  - shares round down, and split keeps the remainder in the rest
+///
+/// Add and Sub must stay within the bounds, which is asserted in debug builds,
+/// release builds saturate. Use checked_add and checked_sub where it is not known,
+/// e.g. on values coming from requests or stored UPLs.
+/// Other calculations saturate at the bounds instead of overflowing.
+/// Serialized as a plain u32, so stored UPLs keep their format.
+#[derive(
+  Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(transparent)]
+pub struct Money(u32);
+
+impl Money {
+  pub const ZERO: Money = Money(0);
+
+  pub fn new(value: u32) -> Self {
+    Self(value)
+  }
+
+  /// Amount in the smallest currency unit
+  pub fn value(&self) -> u32 {
+    self.0
+  }
+
+  /// Sum of the amounts, None if it overflows
+  pub fn checked_add(self, rhs: Money) -> Option<Money> {
+    self.0.checked_add(rhs.0).map(Money)
+  }
+
+  /// Difference of the amounts, None if it would be negative
+  pub fn checked_sub(self, rhs: Money) -> Option<Money> {
+    self.0.checked_sub(rhs.0).map(Money)
+  }
+
+  /// Share of part / whole, rounded down
+  /// whole 0 means there is nothing to share
+  pub fn share(self, part: u32, whole: u32) -> Money {
+    if whole == 0 {
+      return Money::ZERO;
+    }
+    Money((self.0 as u64 * part.min(whole) as u64 / whole as u64) as u32)
+  }
+
+  /// Split into the share of part / whole and the rest
+  /// The share is rounded down, the remainder is always kept by the rest,
+  /// so share + rest == self. The share is never more than self,
+  /// so the rest cannot saturate.
+  pub fn split(self, part: u32, whole: u32) -> (Money, Money) {
+    let share = self.share(part, whole);
+    (share, self - share)
+  }
+
+  /// Percent of the amount, rounded half up
+  /// Saturates at the largest amount
+  pub fn percent(self, percent: u32) -> Money {
+    let value = (self.0 as u64 * percent as u64 + 50) / 100;
+    Money(u32::try_from(value).unwrap_or(u32::MAX))
+  }
+
+  /// Amount increased by percent, e.g. by a VAT rate
+  /// Rounded half up
+  pub fn add_percent(self, percent: u32) -> Money {
+    self.percent(percent.saturating_add(100))
+  }
+
+  /// Net margin of a retail price over a procurement cost
+  /// Saturates at the bounds of i32
+  pub fn margin(self, cost: Money) -> i32 {
+    let margin = self.0 as i64 - cost.0 as i64;
+    margin.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+  }
+}
+
+impl From<u32> for Money {
+  fn from(value: u32) -> Self {
+    Money(value)
+  }
+}
+
+impl From<Money> for u32 {
+  fn from(money: Money) -> Self {
+    money.0
+  }
+}
+
+impl std::fmt::Display for Money {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl Add for Money {
+  type Output = Money;
+
+  fn add(self, rhs: Money) -> Self::Output {
+    debug_assert!(self.checked_add(rhs).is_some(), "Money overflow");
+    Money(self.0.saturating_add(rhs.0))
+  }
+}
+
+impl AddAssign for Money {
+  fn add_assign(&mut self, rhs: Money) {
+    *self = *self + rhs;
+  }
+}
+
+impl Sub for Money {
+  type Output = Money;
+
+  fn sub(self, rhs: Money) -> Self::Output {
+    debug_assert!(self.checked_sub(rhs).is_some(), "Money underflow");
+    Money(self.0.saturating_sub(rhs.0))
+  }
+}
+
+impl SubAssign for Money {
+  fn sub_assign(&mut self, rhs: Money) {
+    *self = *self - rhs;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_split_conserves_value() {
+    for total in &[0, 1, 7, 10, 999, 1001, u32::MAX] {
+      let total = Money::new(*total);
+      for whole in 1..=12 {
+        let mut rest = total;
+        let mut parts = Vec::new();
+        // Divide the whole amount piece by piece
+        for amount in (1..=whole).rev() {
+          let (share, remaining) = rest.split(1, amount);
+          parts.push(share);
+          rest = remaining;
+        }
+        assert_eq!(rest, Money::ZERO);
+        assert_eq!(
+          parts.iter().map(|p| p.value() as u64).sum::<u64>(),
+          total.value() as u64
+        );
+      }
+    }
+    // Remainder stays with the rest
+    assert_eq!(Money::new(10).split(1, 3), (Money::new(3), Money::new(7)));
+    assert_eq!(Money::new(10).split(0, 0), (Money::ZERO, Money::new(10)));
+  }
+
+  #[test]
+  fn test_percent() {
+    assert_eq!(Money::new(1000).percent(127), Money::new(1270));
+    assert_eq!(Money::new(1).percent(150), Money::new(2));
+    assert_eq!(Money::new(1).percent(149), Money::new(1));
+    assert_eq!(Money::new(999).percent(70), Money::new(699));
+    assert_eq!(Money::new(999).add_percent(27), Money::new(1269));
+    assert_eq!(Money::new(50).margin(Money::new(60)), -10);
+  }
+
+  #[test]
+  fn test_saturation() {
+    let max = Money::new(u32::MAX);
+    assert_eq!(max.checked_add(Money::new(1)), None);
+    assert_eq!(Money::new(1).checked_sub(Money::new(2)), None);
+    assert_eq!(max.checked_sub(max), Some(Money::ZERO));
+    let mut money = Money::new(1);
+    money += Money::new(1);
+    assert_eq!(money, Money::new(2));
+    money -= Money::new(2);
+    assert_eq!(money, Money::ZERO);
+    assert_eq!(max.percent(127), max);
+    assert_eq!(max.add_percent(u32::MAX), max);
+    assert_eq!(max.percent(100), max);
+    assert_eq!(max.margin(Money::ZERO), i32::MAX);
+    assert_eq!(Money::ZERO.margin(max), i32::MIN);
+  }
+
+  #[test]
+  #[cfg(debug_assertions)]
+  #[should_panic(expected = "Money overflow")]
+  fn test_add_overflow_asserts() {
+    let _ = Money::new(u32::MAX) + Money::new(1);
+  }
+}
@@ -0,0 +1,237 @@
+use crate::upl::*;
+use std::cmp::Ordering;
+
+/// Requested quantity to pick
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PickQuantity {
+  // Number of SKU pieces
+  // Only Sku and BulkSku UPLs can be picked
+  Pieces(u32),
+  // Divisible amount in product unit
+  // Only OpenedSku and divisible Sku UPLs can be picked
+  Amount(u32),
+}
+
+/// Pick options
+/// By default depreciated and locked UPLs are excluded
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PickOptions {
+  pub include_depreciated: bool,
+  pub include_locked: bool,
+}
+
+/// Suggested UPL to pick
+#[derive(Debug, Clone, PartialEq)]
+pub struct PickItem {
+  // UPL ID to take
+  pub upl_id: String,
+  // Pieces or amount to take from this UPL
+  pub take: u32,
+  // Pieces or amount this UPL has
+  // If take < available, the UPL needs to be split or divided
+  pub available: u32,
+}
+
+/// Pick suggestion
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PickSuggestion {
+  // UPLs to take in order
+  pub items: Vec<PickItem>,
+  // Requested quantity we could not cover
+  pub missing: u32,
+}
+
+// Pieces or amount a UPL can give
+// None if the UPL cannot be picked for the requested quantity
+fn pickable(upl: &Upl, quantity: &PickQuantity) -> Option<u32> {
+  match (quantity, upl.get_kind()) {
+    (PickQuantity::Pieces(_), Kind::Sku { .. })
+    | (PickQuantity::Pieces(_), Kind::BulkSku { .. }) => Some(upl.get_upl_piece()),
+    (PickQuantity::Amount(_), Kind::Sku { .. })
+    | (PickQuantity::Amount(_), Kind::OpenedSku { .. })
+      if upl.is_divisible() =>
+    {
+      upl.get_divisible_amount()
+    }
+    _ => None,
+  }
+}
+
+/// Pick order
+///   1. Opened packages (OpenedSku) first
+///   2. Earliest best_before first, UPLs without best_before last
+///   3. Oldest created_at first
+pub fn pick_order(a: &Upl, b: &Upl) -> Ordering {
+  let is_opened = |upl: &Upl| matches!(upl.get_kind(), Kind::OpenedSku { .. });
+  is_opened(b)
+    .cmp(&is_opened(a))
+    .then_with(|| match (a.get_best_before(), b.get_best_before()) {
+      (Some(a), Some(b)) => a.cmp(&b),
+      (Some(_), None) => Ordering::Less,
+      (None, Some(_)) => Ordering::Greater,
+      (None, None) => Ordering::Equal,
+    })
+    .then_with(|| a.get_created_at().cmp(&b.get_created_at()))
+}
+
+/// Suggest UPLs to pick of a SKU from a stock
+/// FEFO (first expired first out), then FIFO order
+pub fn suggest<'a>(
+  upls: impl Iterator<Item = &'a Upl>,
+  sku: u32,
+  stock_id: u32,
+  quantity: PickQuantity,
+  options: PickOptions,
+) -> PickSuggestion {
+  let mut candidates = upls
+    .filter(|upl| upl.get_sku() == sku)
+    .filter(|upl| upl.get_location() == &Location::Stock(stock_id))
+    .filter(|upl| options.include_depreciated || !upl.is_depreciated())
+    .filter(|upl| options.include_locked || !upl.has_lock())
+    .filter_map(|upl| pickable(upl, &quantity).map(|available| (upl, available)))
+    .filter(|(_, available)| *available > 0)
+    .collect::<Vec<(&Upl, u32)>>();
+
+  candidates.sort_by(|(a, _), (b, _)| pick_order(a, b));
+
+  let mut missing = match quantity {
+    PickQuantity::Pieces(pieces) => pieces,
+    PickQuantity::Amount(amount) => amount,
+  };
+
+  let mut items = Vec::new();
+  for (upl, available) in candidates {
+    if missing == 0 {
+      break;
+    }
+    let take = available.min(missing);
+    missing -= take;
+    items.push(PickItem {
+      upl_id: upl.get_upl_id().to_string(),
+      take,
+      available,
+    });
+  }
+
+  PickSuggestion { items, missing }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::prelude::*;
+
+  fn upl(id: &str, kind: Kind, best_before: Option<i64>, created_at: i64) -> Upl {
+    Upl {
+      id: id.to_string(),
+      kind,
+      sku_divisible: true,
+      sku_divisible_amount: 10,
+      location: Location::Stock(1),
+      best_before: best_before.map(|ts| Utc.timestamp_opt(ts, 0).unwrap()),
+      created_at: Utc.timestamp_opt(created_at, 0).unwrap(),
+      ..Upl::default()
+    }
+  }
+
+  #[test]
+  fn test_pieces_order() {
+    let upls = [
+      upl("a", Kind::Sku { sku: 1 }, None, 1),
+      upl("b", Kind::Sku { sku: 1 }, Some(200), 3),
+      upl(
+        "c",
+        Kind::BulkSku {
+          sku: 1,
+          upl_pieces: 3,
+        },
+        Some(100),
+        2,
+      ),
+      upl("d", Kind::Sku { sku: 1 }, Some(200), 2),
+    ];
+    let res = suggest(
+      upls.iter(),
+      1,
+      1,
+      PickQuantity::Pieces(5),
+      PickOptions::default(),
+    );
+    let ids = res
+      .items
+      .iter()
+      .map(|i| i.upl_id.as_str())
+      .collect::<Vec<&str>>();
+    assert_eq!(ids, vec!["c", "d", "b"]);
+    assert_eq!(res.items[0].take, 3);
+    assert_eq!(res.missing, 0);
+  }
+
+  #[test]
+  fn test_amount_opened_first() {
+    let upls = [
+      upl("a", Kind::Sku { sku: 1 }, Some(100), 1),
+      upl(
+        "b",
+        Kind::OpenedSku {
+          sku: 1,
+          amount: 4,
+          successors: Vec::new(),
+        },
+        Some(300),
+        2,
+      ),
+    ];
+    let res = suggest(
+      upls.iter(),
+      1,
+      1,
+      PickQuantity::Amount(20),
+      PickOptions::default(),
+    );
+    assert_eq!(
+      res.items,
+      vec![
+        PickItem {
+          upl_id: "b".to_string(),
+          take: 4,
+          available: 4
+        },
+        PickItem {
+          upl_id: "a".to_string(),
+          take: 10,
+          available: 10
+        }
+      ]
+    );
+    assert_eq!(res.missing, 6);
+  }
+
+  #[test]
+  fn test_exclude_locked_and_depreciated() {
+    let mut locked = upl("a", Kind::Sku { sku: 1 }, None, 1);
+    locked.lock(Lock::Delivery(1), 0).unwrap();
+    let mut depreciated = upl("b", Kind::Sku { sku: 1 }, None, 2);
+    depreciated
+      .set_depreciation(1, "sérült".to_string(), CreatedBy::Technical)
+      .unwrap();
+    let upls = [locked, depreciated];
+
+    let res = suggest(
+      upls.iter(),
+      1,
+      1,
+      PickQuantity::Pieces(2),
+      PickOptions::default(),
+    );
+    assert!(res.items.is_empty());
+    assert_eq!(res.missing, 2);
+
+    let options = PickOptions {
+      include_depreciated: true,
+      include_locked: true,
+    };
+    let res = suggest(upls.iter(), 1, 1, PickQuantity::Pieces(2), options);
+    assert_eq!(res.items.len(), 2);
+  }
+}
@@ -1,4 +1,14 @@
+use crate::archive::ArchiveError;
+use crate::backup::BackupError;
+use crate::feed::*;
+use crate::index::IndexError;
+use crate::inventory::*;
+use crate::locks::*;
+use crate::pick::*;
+use crate::report::*;
+use crate::reservation::*;
 use crate::upl::*;
+use std::collections::HashMap;
 
 pub enum ServiceError {
   InternalError(String),
@@ -20,6 +30,15 @@ impl ServiceError {
   pub fn bad_request(msg: &str) -> Self {
     ServiceError::BadRequest(msg.to_string())
   }
+  /// Variant name, used as metrics label
+  pub fn variant(&self) -> &'static str {
+    match self {
+      ServiceError::InternalError(_) => "InternalError",
+      ServiceError::NotFound(_) => "NotFound",
+      ServiceError::AlreadyExists(_) => "AlreadyExists",
+      ServiceError::BadRequest(_) => "BadRequest",
+    }
+  }
 }
 
 impl std::fmt::Display for ServiceError {
@@ -44,6 +63,7 @@ impl std::fmt::Debug for ServiceError {
 
 impl From<ServiceError> for ::tonic::Status {
   fn from(error: ServiceError) -> Self {
+    crate::metrics::global().service_error(error.variant());
     match error {
       ServiceError::InternalError(msg) => ::tonic::Status::internal(msg),
       ServiceError::NotFound(msg) => ::tonic::Status::not_found(msg),
@@ -62,6 +82,43 @@ impl From<::packman::PackError> for ServiceError {
   }
 }
 
+impl From<ArchiveError> for ServiceError {
+  fn from(error: ArchiveError) -> Self {
+    match error {
+      ArchiveError::NotFound(msg) => ServiceError::not_found(&msg),
+      ArchiveError::AlreadyExist(msg) => ServiceError::already_exist(&msg),
+      ArchiveError::WrongId(msg) => ServiceError::bad_request(&msg),
+      ArchiveError::InternalError(msg) => ServiceError::internal_error(&msg),
+    }
+  }
+}
+
+impl From<IndexError> for ServiceError {
+  fn from(error: IndexError) -> Self {
+    match error {
+      IndexError::NotFound => ServiceError::not_found(&error.to_string()),
+      IndexError::WrongId => ServiceError::bad_request(&error.to_string()),
+      IndexError::AlreadyExist => ServiceError::already_exist(&error.to_string()),
+      _ => ServiceError::internal_error(&error.to_string()),
+    }
+  }
+}
+
+impl From<BackupError> for ServiceError {
+  fn from(error: BackupError) -> Self {
+    match error {
+      BackupError::AlreadyExist(msg) => ServiceError::already_exist(&msg),
+      _ => ServiceError::internal_error(&error.to_string()),
+    }
+  }
+}
+
+impl From<LockError> for ServiceError {
+  fn from(error: LockError) -> Self {
+    ServiceError::bad_request(&error.to_string())
+  }
+}
+
 pub type ServiceResult<T> = Result<T, ServiceError>;
 
 impl From<std::env::VarError> for ServiceError {
@@ -120,51 +177,339 @@ impl From<Location> for upl_obj::Location {
   }
 }
 
-impl From<Upl> for gzlib::proto::upl::UplObj {
-  fn from(upl: Upl) -> Self {
+/// UPL proto object
+/// Its gross prices are calculated by the service VAT rates
+pub fn upl_obj(upl: Upl, vat_rates: &VatRates) -> gzlib::proto::upl::UplObj {
+  gzlib::proto::upl::UplObj {
+    id: upl.id.clone(),
+    product_id: upl.product_id,
+    sku_id: upl.get_sku(),
+    product_unit: upl.product_unit.clone(),
+    upl_piece: upl.get_upl_piece(),
+    is_healty: upl.is_available_healthy(),
+    best_before: match upl.best_before {
+      Some(bbefore) => bbefore.to_rfc3339(),
+      None => "".to_string(),
+    },
+    depreciation: upl.depreciation.as_ref().map(|dp| upl_obj::Depreciation {
+      depreciation_id: dp.depreciation_id,
+      depreciation_comment: dp.comment.clone(),
+    }),
+    procurement_id: upl.procurement_id,
+    procurement_net_price: upl.procurement_net_price.into(),
+    procurement_net_price_sku: upl.procurement_net_price_sku.into(),
+    is_divisible: upl.is_divisible(),
+    sku_divisible_amount: upl.get_sku_divisible_amount(),
+    kind: Some(upl.kind.clone().into()),
+    lock: Some(upl.lock.clone().into()),
+    location: Some(upl.location.clone().into()),
+    has_special_price: upl.get_upl_has_special_price(),
+    price_net: upl.get_upl_net_price().into(),
+    vat: upl.vat.to_string(),
+    price_gross: upl.get_upl_gross_price(vat_rates).into(),
+    margin_net: match upl.get_upl_special_price_margin() {
+      Some(sm) => sm,
+      None => upl.margin_net,
+    },
+    is_archived: false,
+    created_by: upl.created_by,
+    created_at: upl.created_at.to_rfc3339(),
+  }
+}
+
+impl From<InventorySession> for crate::proto::upl_ext::InventoryObj {
+  fn from(s: InventorySession) -> Self {
     Self {
-      id: upl.id.clone(),
-      product_id: upl.product_id,
-      sku_id: upl.get_sku(),
-      product_unit: upl.product_unit.clone(),
-      upl_piece: upl.get_upl_piece(),
-      is_healty: upl.is_available_healthy(),
-      best_before: match upl.best_before {
-        Some(bbefore) => bbefore.to_rfc3339(),
+      inventory_id: s.get_inventory_id(),
+      stock_id: s.get_stock_id(),
+      expected: s.get_expected().iter().cloned().collect(),
+      scanned: s.get_scanned().iter().cloned().collect(),
+      missing: s.get_missing(),
+      unexpected: s.get_unexpected(),
+      locked: s.get_locked().clone(),
+      is_closed: !s.is_open(),
+      closed_at: match s.get_closed_at() {
+        Some(closed_at) => closed_at.to_rfc3339(),
         None => "".to_string(),
       },
-      depreciation: match &upl.depreciation {
-        Some(dp) => Some(upl_obj::Depreciation {
-          depreciation_id: dp.depreciation_id,
-          depreciation_comment: dp.comment.clone(),
-        }),
-        None => None,
-      },
-      procurement_id: upl.procurement_id,
-      procurement_net_price: upl.procurement_net_price,
-      procurement_net_price_sku: upl.procurement_net_price_sku,
-      is_divisible: upl.is_divisible(),
-      sku_divisible_amount: upl.get_sku_divisible_amount(),
-      kind: Some(upl.kind.clone().into()),
-      lock: Some(upl.lock.clone().into()),
-      location: Some(upl.location.clone().into()),
-      has_special_price: upl.get_upl_has_special_price(),
-      price_net: match upl.get_upl_special_price_net() {
-        Some(spn) => spn,
-        None => upl.price_net,
-      },
-      vat: upl.vat.to_string(),
-      price_gross: match upl.get_upl_special_price_net() {
-        Some(spn) => spn * upl.vat,
-        None => upl.price_gross,
-      },
-      margin_net: match upl.get_upl_special_price_margin() {
-        Some(sm) => sm,
-        None => upl.margin_net,
-      },
-      is_archived: false,
-      created_by: upl.created_by,
-      created_at: upl.created_at.to_rfc3339(),
+      closed_by: s.get_closed_by().unwrap_or(0),
+      created_by: s.get_created_by(),
+      created_at: s.get_created_at().to_rfc3339(),
+    }
+  }
+}
+
+impl From<Reservation> for crate::proto::upl_ext::ReservationObj {
+  fn from(r: Reservation) -> Self {
+    use crate::proto::upl_ext::reservation_obj;
+    Self {
+      reservation_id: r.get_reservation_id().to_string(),
+      cart_id: r.get_cart_id().to_string(),
+      subject: Some(match r.get_subject() {
+        Subject::Sku(sku) => reservation_obj::Subject::Sku(*sku),
+        Subject::DividedProduct(product_id) => {
+          reservation_obj::Subject::DividedProduct(*product_id)
+        }
+      }),
+      scope: Some(match r.get_scope() {
+        Scope::Local(stock_id) => reservation_obj::Scope::Local(*stock_id),
+        Scope::Global => reservation_obj::Scope::Global(()),
+      }),
+      amount_reserved: *r.get_amount_reserved(),
+      amount_taken: *r.get_amount_taken(),
+      amount_remaining: r.get_amount_remaining(),
+      created_by: r.get_created_by(),
+      created_at: r.get_created_at().to_rfc3339(),
+    }
+  }
+}
+
+// Location as a history detail value
+// e.g. stock:1, cart:abc
+fn location_detail(location: &Location) -> String {
+  match location {
+    Location::Stock(stock_id) => format!("stock:{}", stock_id),
+    Location::Delivery(delivery_id) => format!("delivery:{}", delivery_id),
+    Location::Cart(cart_id) => format!("cart:{}", cart_id),
+    Location::Discard(discard_id) => format!("discard:{}", discard_id),
+  }
+}
+
+// Lock as a history detail value
+fn lock_detail(lock: &Lock) -> String {
+  match lock {
+    Lock::Cart(cart_id) => format!("cart:{}", cart_id),
+    Lock::Delivery(delivery_id) => format!("delivery:{}", delivery_id),
+    Lock::Inventory(inventory_id) => format!("inventory:{}", inventory_id),
+    Lock::None => "none".to_string(),
+  }
+}
+
+// History event details as key value pairs
+fn history_details(event: &UplHistoryEvent) -> HashMap<String, String> {
+  let mut details = HashMap::new();
+  let mut add = |key: &str, value: String| {
+    details.insert(key.to_string(), value);
+  };
+  match event {
+    UplHistoryEvent::Restored { reason } => add("reason", reason.clone()),
+    UplHistoryEvent::Moved { from, to } => {
+      add("from", location_detail(from));
+      add("to", location_detail(to));
+    }
+    UplHistoryEvent::BestBeforeUpdated { to } => add(
+      "to",
+      to.map(|best_before| best_before.to_rfc3339())
+        .unwrap_or_default(),
+    ),
+    UplHistoryEvent::Locked { to } => add("to", lock_detail(to)),
+    UplHistoryEvent::SetDeprecated {
+      depreciation_id,
+      comment,
+    } => {
+      add("depreciation_id", depreciation_id.to_string());
+      add("comment", comment.clone());
+    }
+    UplHistoryEvent::SetDepreciatedPrice { retail_net_price } => add(
+      "retail_net_price",
+      retail_net_price
+        .map(|price| price.to_string())
+        .unwrap_or_default(),
+    ),
+    UplHistoryEvent::Split { new_upl_id } => add("new_upl_id", new_upl_id.clone()),
+    UplHistoryEvent::Divided {
+      new_upl_id,
+      requested_amount,
+    } => {
+      add("new_upl_id", new_upl_id.clone());
+      add("requested_amount", requested_amount.to_string());
+    }
+    UplHistoryEvent::Merged {
+      merged_upl_id,
+      amount,
+    } => {
+      add("merged_upl_id", merged_upl_id.clone());
+      add("amount", amount.to_string());
+    }
+    UplHistoryEvent::MergedInto { parent_upl_id } => add("parent_upl_id", parent_upl_id.clone()),
+    UplHistoryEvent::PriceChanged {
+      old_net_price,
+      new_net_price,
+      old_vat,
+      new_vat,
+    } => {
+      add("old_net_price", old_net_price.to_string());
+      add("new_net_price", new_net_price.to_string());
+      add("old_vat", old_vat.to_string());
+      add("new_vat", new_vat.to_string());
+    }
+    UplHistoryEvent::DivisibleChanged { from, to } => {
+      add("from", from.to_string());
+      add("to", to.to_string());
+    }
+    UplHistoryEvent::ProductUnitChanged { from, to } => {
+      add("from", from.clone());
+      add("to", to.clone());
+    }
+    UplHistoryEvent::LockExpired { lock, acquired_at } => {
+      add("lock", lock_detail(lock));
+      add("acquired_at", acquired_at.to_rfc3339());
+    }
+    UplHistoryEvent::SplitFrom { parent_upl_id } => add("parent_upl_id", parent_upl_id.clone()),
+    UplHistoryEvent::DividedFrom {
+      parent_upl_id,
+      amount,
+    } => {
+      add("parent_upl_id", parent_upl_id.clone());
+      add("amount", amount.to_string());
+    }
+    UplHistoryEvent::Created
+    | UplHistoryEvent::Archived
+    | UplHistoryEvent::Unlocked
+    | UplHistoryEvent::DeprecationRemoved
+    | UplHistoryEvent::Opened
+    | UplHistoryEvent::Closed
+    | UplHistoryEvent::None => (),
+  }
+  details
+}
+
+impl From<&UplHistoryItem> for crate::proto::upl_ext::HistoryItemObj {
+  fn from(item: &UplHistoryItem) -> Self {
+    use crate::proto::upl_ext::history_item_obj;
+    Self {
+      event_kind: item.get_event().get_kind().to_string(),
+      details: history_details(item.get_event()),
+      created_at: item.get_created_at().to_rfc3339(),
+      created_by: Some(match item.get_created_by() {
+        CreatedBy::Uid(uid) => history_item_obj::CreatedBy::Uid(*uid),
+        CreatedBy::Technical => history_item_obj::CreatedBy::Technical(()),
+      }),
+    }
+  }
+}
+
+impl From<PickSuggestion> for crate::proto::upl_ext::PickSuggestionObj {
+  fn from(s: PickSuggestion) -> Self {
+    Self {
+      items: s
+        .items
+        .into_iter()
+        .map(|item| crate::proto::upl_ext::PickItemObj {
+          upl_id: item.upl_id,
+          take: item.take,
+          available: item.available,
+        })
+        .collect(),
+      missing: s.missing,
+    }
+  }
+}
+
+impl From<ExpiringLine> for crate::proto::upl_ext::ExpiringLineObj {
+  fn from(l: ExpiringLine) -> Self {
+    Self {
+      stock_id: l.stock_id,
+      sku: l.sku,
+      pieces: l.pieces,
+      retail_value_gross: l.retail_value_gross,
+      procurement_value_net: l.procurement_value_net,
+      earliest_best_before: l
+        .earliest_best_before
+        .map(|best_before| best_before.to_rfc3339())
+        .unwrap_or_default(),
+      upl_ids: l.upl_ids,
+    }
+  }
+}
+
+impl From<ExpiringReport> for crate::proto::upl_ext::ExpiringReportObj {
+  fn from(r: ExpiringReport) -> Self {
+    Self {
+      expiring: r.expiring.into_iter().map(|l| l.into()).collect(),
+      expired: r.expired.into_iter().map(|l| l.into()).collect(),
+    }
+  }
+}
+
+impl From<ValuationValues> for crate::proto::upl_ext::ValuationValuesObj {
+  fn from(v: ValuationValues) -> Self {
+    Self {
+      pieces: v.pieces,
+      procurement_value_net: v.procurement_value_net,
+      retail_value_net: v.retail_value_net,
+      retail_value_gross: v.retail_value_gross,
+      margin_net: v.margin_net,
+      depreciated_pieces: v.depreciated_pieces,
+      depreciated_procurement_value_net: v.depreciated_procurement_value_net,
+      depreciated_retail_value_net: v.depreciated_retail_value_net,
+    }
+  }
+}
+
+impl From<StockValuation> for crate::proto::upl_ext::StockValuationObj {
+  fn from(s: StockValuation) -> Self {
+    use crate::proto::upl_ext::{ProductValuationObj, SkuValuationObj};
+    Self {
+      stock_id: s.stock_id,
+      total: Some(s.total.into()),
+      skus: s
+        .skus
+        .into_iter()
+        .map(|(product_id, sku, values)| SkuValuationObj {
+          product_id,
+          sku,
+          values: Some(values.into()),
+        })
+        .collect(),
+      products: s
+        .products
+        .into_iter()
+        .map(|(product_id, values)| ProductValuationObj {
+          product_id,
+          values: Some(values.into()),
+        })
+        .collect(),
+    }
+  }
+}
+
+impl From<ValuationReport> for crate::proto::upl_ext::StockValuationReport {
+  fn from(r: ValuationReport) -> Self {
+    Self {
+      stocks: r.stocks.into_iter().map(|s| s.into()).collect(),
+      total: Some(r.total.into()),
+    }
+  }
+}
+
+/// Change event proto object
+/// Its UPL gross prices are calculated by the service VAT rates
+pub fn change_event_obj(
+  e: ChangeEvent,
+  vat_rates: &VatRates,
+) -> crate::proto::upl_ext::ChangeEventObj {
+  crate::proto::upl_ext::ChangeEventObj {
+    seq: e.seq,
+    event: Some((&e.event).into()),
+    upl_id: e.upl_id,
+    upl: Some(upl_obj(e.upl, vat_rates)),
+  }
+}
+
+impl From<&LockHolder> for crate::proto::upl_ext::LockHolderObj {
+  fn from(holder: &LockHolder) -> Self {
+    let (kind, holder_id) = match &holder.lock {
+      Lock::Cart(cart_id) => ("cart", cart_id.clone()),
+      Lock::Delivery(delivery_id) => ("delivery", delivery_id.to_string()),
+      Lock::Inventory(inventory_id) => ("inventory", inventory_id.to_string()),
+      Lock::None => ("none", String::new()),
+    };
+    Self {
+      kind: kind.to_string(),
+      holder_id,
+      upl_ids: holder.upl_ids.clone(),
+      oldest_acquired_at: holder.oldest_acquired_at.to_rfc3339(),
     }
   }
 }
@@ -0,0 +1,294 @@
+use crate::upl::*;
+use chrono::prelude::*;
+use chrono::Duration;
+use std::collections::BTreeMap;
+
+/// Expiring stock line
+/// UPLs of a SKU in a stock
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExpiringLine {
+  pub stock_id: u32,
+  pub sku: u32,
+  // Total SKU pieces
+  pub pieces: u32,
+  // Total retail value, based on price_gross
+  pub retail_value_gross: u64,
+  // Total procurement value
+  pub procurement_value_net: u64,
+  // Earliest best_before in this line
+  pub earliest_best_before: Option<DateTime<Utc>>,
+  // Related UPL IDs
+  pub upl_ids: Vec<String>,
+}
+
+impl ExpiringLine {
+  fn add(&mut self, upl: &Upl) {
+    let pieces = upl.get_upl_piece();
+    self.pieces += pieces;
+    self.retail_value_gross += upl.price_gross.value() as u64 * pieces as u64;
+    self.procurement_value_net += upl.get_procurement_net_price().value() as u64 * pieces as u64;
+    self.earliest_best_before = match (self.earliest_best_before, upl.get_best_before()) {
+      (Some(a), Some(b)) => Some(a.min(b)),
+      (a, b) => a.or(b),
+    };
+    self.upl_ids.push(upl.get_upl_id().to_string());
+  }
+}
+
+/// Expiring stock report
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExpiringReport {
+  // best_before within the horizon
+  pub expiring: Vec<ExpiringLine>,
+  // best_before already passed
+  pub expired: Vec<ExpiringLine>,
+}
+
+/// Collect UPLs in stock whose best_before falls
+/// within the next horizon_days, grouped by stock and SKU.
+/// Empty stock_ids means all the stocks.
+pub fn expiring_report<'a>(
+  upls: impl Iterator<Item = &'a Upl>,
+  now: DateTime<Utc>,
+  horizon_days: u32,
+  stock_ids: &[u32],
+) -> ExpiringReport {
+  let horizon = now + Duration::days(horizon_days as i64);
+  let mut expiring: BTreeMap<(u32, u32), ExpiringLine> = BTreeMap::new();
+  let mut expired: BTreeMap<(u32, u32), ExpiringLine> = BTreeMap::new();
+
+  for upl in upls {
+    let stock_id = match upl.get_location() {
+      Location::Stock(stock_id) => *stock_id,
+      _ => continue,
+    };
+    if !stock_ids.is_empty() && !stock_ids.contains(&stock_id) {
+      continue;
+    }
+    let group = match upl.get_best_before() {
+      Some(best_before) if best_before < now => &mut expired,
+      Some(best_before) if best_before <= horizon => &mut expiring,
+      _ => continue,
+    };
+    group
+      .entry((stock_id, upl.get_sku()))
+      .or_insert_with(|| ExpiringLine {
+        stock_id,
+        sku: upl.get_sku(),
+        ..ExpiringLine::default()
+      })
+      .add(upl);
+  }
+
+  ExpiringReport {
+    expiring: expiring.into_values().collect(),
+    expired: expired.into_values().collect(),
+  }
+}
+
+/// Valuation values
+/// Prices and margins are the effective ones,
+/// so the depreciation price and margin when set.
+/// Depreciated values are also counted in the totals.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ValuationValues {
+  // Total SKU pieces
+  pub pieces: u64,
+  pub procurement_value_net: u64,
+  pub retail_value_net: u64,
+  pub retail_value_gross: u64,
+  // Expected margin
+  pub margin_net: i64,
+  // Depreciated part
+  pub depreciated_pieces: u64,
+  pub depreciated_procurement_value_net: u64,
+  pub depreciated_retail_value_net: u64,
+}
+
+impl ValuationValues {
+  fn add(&mut self, upl: &Upl, vat_rates: &VatRates) {
+    let pieces = upl.get_upl_piece() as u64;
+    let procurement_value_net = upl.get_procurement_net_price().value() as u64 * pieces;
+    let retail_value_net = upl.get_upl_net_price().value() as u64 * pieces;
+    let margin_net =
+      upl.get_upl_special_price_margin().unwrap_or(upl.margin_net) as i64 * pieces as i64;
+    self.pieces += pieces;
+    self.procurement_value_net += procurement_value_net;
+    self.retail_value_net += retail_value_net;
+    self.retail_value_gross += upl.get_upl_gross_price(vat_rates).value() as u64 * pieces;
+    self.margin_net += margin_net;
+    if upl.is_depreciated() {
+      self.depreciated_pieces += pieces;
+      self.depreciated_procurement_value_net += procurement_value_net;
+      self.depreciated_retail_value_net += retail_value_net;
+    }
+  }
+}
+
+/// Stock valuation
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StockValuation {
+  pub stock_id: u32,
+  pub total: ValuationValues,
+  // (product_id, sku, values)
+  pub skus: Vec<(u32, u32, ValuationValues)>,
+  // (product_id, values)
+  pub products: Vec<(u32, ValuationValues)>,
+}
+
+/// Stock valuation report
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValuationReport {
+  pub stocks: Vec<StockValuation>,
+  pub total: ValuationValues,
+}
+
+/// Aggregate active UPLs in stock into inventory value
+/// per stock, SKU and product.
+/// Empty stock_ids means all the stocks.
+pub fn valuation_report<'a>(
+  upls: impl Iterator<Item = &'a Upl>,
+  stock_ids: &[u32],
+  vat_rates: &VatRates,
+) -> ValuationReport {
+  let mut skus: BTreeMap<(u32, u32, u32), ValuationValues> = BTreeMap::new();
+  let mut products: BTreeMap<(u32, u32), ValuationValues> = BTreeMap::new();
+  let mut stocks: BTreeMap<u32, ValuationValues> = BTreeMap::new();
+  let mut total = ValuationValues::default();
+
+  for upl in upls {
+    let stock_id = match upl.get_location() {
+      Location::Stock(stock_id) => *stock_id,
+      _ => continue,
+    };
+    if !stock_ids.is_empty() && !stock_ids.contains(&stock_id) {
+      continue;
+    }
+    skus
+      .entry((stock_id, upl.get_product_id(), upl.get_sku()))
+      .or_default()
+      .add(upl, vat_rates);
+    products
+      .entry((stock_id, upl.get_product_id()))
+      .or_default()
+      .add(upl, vat_rates);
+    stocks.entry(stock_id).or_default().add(upl, vat_rates);
+    total.add(upl, vat_rates);
+  }
+
+  ValuationReport {
+    stocks: stocks
+      .into_iter()
+      .map(|(stock_id, stock_total)| StockValuation {
+        stock_id,
+        total: stock_total,
+        skus: skus
+          .range((stock_id, 0, 0)..=(stock_id, u32::MAX, u32::MAX))
+          .map(|((_, product_id, sku), values)| (*product_id, *sku, *values))
+          .collect(),
+        products: products
+          .range((stock_id, 0)..=(stock_id, u32::MAX))
+          .map(|((_, product_id), values)| (*product_id, *values))
+          .collect(),
+      })
+      .collect(),
+    total,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn upl(id: &str, stock_id: u32, best_before: Option<DateTime<Utc>>) -> Upl {
+    Upl {
+      id: id.to_string(),
+      kind: Kind::BulkSku {
+        sku: 1,
+        upl_pieces: 2,
+      },
+      location: Location::Stock(stock_id),
+      best_before,
+      price_gross: Money::new(100),
+      procurement_net_price: Money::new(50),
+      ..Upl::default()
+    }
+  }
+
+  #[test]
+  fn test_expiring_report() {
+    let now = Utc::now();
+    let upls = [
+      upl("a", 1, Some(now - Duration::days(1))),
+      upl("b", 1, Some(now + Duration::days(2))),
+      upl("c", 1, Some(now + Duration::days(3))),
+      upl("d", 2, Some(now + Duration::days(3))),
+      upl("e", 1, Some(now + Duration::days(30))),
+      upl("f", 1, None),
+    ];
+
+    let report = expiring_report(upls.iter(), now, 7, &[]);
+    assert_eq!(report.expired.len(), 1);
+    assert_eq!(report.expired[0].upl_ids, vec!["a".to_string()]);
+    assert_eq!(report.expiring.len(), 2);
+    assert_eq!(report.expiring[0].stock_id, 1);
+    assert_eq!(report.expiring[0].pieces, 4);
+    assert_eq!(report.expiring[0].retail_value_gross, 400);
+    assert_eq!(report.expiring[0].procurement_value_net, 200);
+    assert_eq!(
+      report.expiring[0].earliest_best_before,
+      Some(now + Duration::days(2))
+    );
+
+    let report = expiring_report(upls.iter(), now, 7, &[2]);
+    assert!(report.expired.is_empty());
+    assert_eq!(report.expiring.len(), 1);
+  }
+
+  #[test]
+  fn test_valuation_report() {
+    let mut depreciated = Upl {
+      id: "c".to_string(),
+      product_id: 2,
+      kind: Kind::Sku { sku: 3 },
+      location: Location::Stock(1),
+      price_net: Money::new(100),
+      price_gross: Money::new(127),
+      procurement_net_price: Money::new(60),
+      margin_net: 40,
+      ..Upl::default()
+    };
+    depreciated
+      .set_depreciation(1, "sérült".to_string(), CreatedBy::Technical)
+      .unwrap();
+    depreciated
+      .set_depreciation_price(Some(Money::new(50)), CreatedBy::Technical)
+      .unwrap();
+    let upls = [
+      // 2 pieces, sku 1
+      upl("a", 1, None),
+      upl("b", 2, None),
+      depreciated,
+    ];
+
+    let report = valuation_report(upls.iter(), &[], &VatRates::default());
+    assert_eq!(report.stocks.len(), 2);
+    assert_eq!(report.total.pieces, 5);
+
+    let stock = &report.stocks[0];
+    assert_eq!(stock.stock_id, 1);
+    assert_eq!(stock.skus.len(), 2);
+    assert_eq!(stock.products.len(), 2);
+    assert_eq!(stock.total.pieces, 3);
+    assert_eq!(stock.total.procurement_value_net, 2 * 50 + 60);
+    assert_eq!(stock.total.retail_value_net, 50);
+    assert_eq!(stock.total.retail_value_gross, 2 * 100 + 64);
+    assert_eq!(stock.total.margin_net, -10);
+    assert_eq!(stock.total.depreciated_pieces, 1);
+    assert_eq!(stock.total.depreciated_retail_value_net, 50);
+
+    let report = valuation_report(upls.iter(), &[2], &VatRates::default());
+    assert_eq!(report.stocks.len(), 1);
+    assert_eq!(report.total.pieces, 2);
+  }
+}
@@ -15,18 +15,29 @@
 // You should have received a copy of the GNU General Public License
 // along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
 
+use crate::upl::*;
 use chrono::prelude::*;
 use packman::VecPackMember;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 pub trait ReservationMethods
 where
   Self: Sized,
 {
   /// Create new reservation object
-  fn new(cart_id: u32, subject: Subject, scope: Scope, reserved_amount: u32) -> Self;
+  fn new(
+    id: String,
+    cart_id: String,
+    subject: Subject,
+    scope: Scope,
+    reserved_amount: u32,
+    created_by: u32,
+  ) -> Self;
+  /// Get reservation id ref
+  fn get_reservation_id(&self) -> &str;
   /// Get cart id ref
-  fn get_cart_id(&self) -> &u32;
+  fn get_cart_id(&self) -> &str;
   /// Get subject ref
   fn get_subject(&self) -> &Subject;
   /// Get scope ref
@@ -35,31 +46,42 @@ where
   fn get_amount_reserved(&self) -> &u32;
   /// Get amount already taken ref
   fn get_amount_taken(&self) -> &u32;
+  /// Get amount still reserved but not yet taken
+  fn get_amount_remaining(&self) -> u32;
   /// Set amount reserved
   fn set_amount_reserved(&mut self, amount: u32) -> &Self;
   /// Set amount taken
   fn set_amount_taken(&mut self, amount: u32) -> &Self;
+  /// Returns the amount the given UPL represents
+  /// for this reservation, if the UPL matches its subject and scope
+  fn get_matching_amount(&self, upl: &Upl) -> Option<u32>;
+  /// Take amount from the reservation
+  /// Returns the amount that was actually taken
+  fn take(&mut self, amount: u32) -> u32;
+  /// Give back amount into the reservation
+  /// e.g. when a UPL is removed from the cart
+  /// Returns the amount that was actually given back
+  fn give_back(&mut self, amount: u32) -> u32;
+  /// Get reservation creation time
+  fn get_created_at(&self) -> DateTime<Utc>;
+  /// Get reservation created by (user id)
+  fn get_created_by(&self) -> u32;
 }
 
 // Reservation storage
 // Itt tároljuk a
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub enum Scope {
   // Local(StoreId)
   // Local means a stock reservation
   Local(u32),
   // Global scope means
   // we have a reservation over all the locations
+  #[default]
   Global,
 }
 
-impl Default for Scope {
-  fn default() -> Self {
-    Self::Global
-  }
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum Subject {
   // We have a reservation to an exact SKU
   Sku(u32),
@@ -76,8 +98,10 @@ impl Default for Subject {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Reservation {
+  // Reservation ID
+  id: String,
   // Cart ID that owns this reservation
-  cart_id: u32,
+  cart_id: String,
   // Sku or DividedProduct
   subject: Subject,
   // Local or global scope
@@ -92,32 +116,53 @@ pub struct Reservation {
   // Remaining amount will be calculated
   // by reserved_amount - already_taken
   already_taken: u32,
+  // Reservation creation time
+  created_at: DateTime<Utc>,
+  // Reservation created by (user id)
+  created_by: u32,
 }
 
 impl Default for Reservation {
   fn default() -> Self {
     Self {
-      cart_id: 0,
+      id: String::default(),
+      cart_id: String::default(),
       subject: Subject::default(),
       scope: Scope::default(),
       reserved_amount: 0,
       already_taken: 0,
+      created_at: Utc::now(),
+      created_by: 0,
     }
   }
 }
 
 impl ReservationMethods for Reservation {
-  fn new(cart_id: u32, subject: Subject, scope: Scope, reserved_amount: u32) -> Self {
+  fn new(
+    id: String,
+    cart_id: String,
+    subject: Subject,
+    scope: Scope,
+    reserved_amount: u32,
+    created_by: u32,
+  ) -> Self {
     Self {
+      id,
       cart_id,
       subject,
       scope,
       reserved_amount,
       already_taken: 0,
+      created_at: Utc::now(),
+      created_by,
     }
   }
 
-  fn get_cart_id(&self) -> &u32 {
+  fn get_reservation_id(&self) -> &str {
+    &self.id
+  }
+
+  fn get_cart_id(&self) -> &str {
     &self.cart_id
   }
 
@@ -137,6 +182,10 @@ impl ReservationMethods for Reservation {
     &self.already_taken
   }
 
+  fn get_amount_remaining(&self) -> u32 {
+    self.reserved_amount.saturating_sub(self.already_taken)
+  }
+
   fn set_amount_reserved(&mut self, amount: u32) -> &Self {
     self.reserved_amount = amount;
     self
@@ -146,4 +195,402 @@ impl ReservationMethods for Reservation {
     self.already_taken = amount;
     self
   }
+
+  fn get_matching_amount(&self, upl: &Upl) -> Option<u32> {
+    // Check scope first
+    match (&self.scope, upl.get_location()) {
+      (Scope::Global, _) => (),
+      (Scope::Local(stock_id), Location::Stock(upl_stock_id)) if stock_id == upl_stock_id => (),
+      _ => return None,
+    }
+    // Then check subject
+    match subject_amount(upl, &self.subject) {
+      0 => None,
+      amount => Some(amount),
+    }
+  }
+
+  fn take(&mut self, amount: u32) -> u32 {
+    let taken = amount.min(self.get_amount_remaining());
+    self.already_taken += taken;
+    taken
+  }
+
+  fn give_back(&mut self, amount: u32) -> u32 {
+    let given_back = amount.min(self.already_taken);
+    self.already_taken -= given_back;
+    given_back
+  }
+
+  fn get_created_at(&self) -> DateTime<Utc> {
+    self.created_at
+  }
+
+  fn get_created_by(&self) -> u32 {
+    self.created_by
+  }
+}
+
+impl VecPackMember for Reservation {
+  type Out = String;
+
+  fn get_id(&self) -> &Self::Out {
+    &self.id
+  }
+}
+
+/// Amount of a subject a UPL holds
+///  - Sku: the pieces of an unopened Sku or BulkSku UPL
+///  - DividedProduct: the divisible amount of a divisible Sku, the
+///    remaining amount of an opened UPL, or the amount of a UPL divided
+///    from it, so dividing never changes the amount of the product
+///
+/// Reservations match UPLs and count availability by it,
+/// so a reservation covers the same amount it is checked against.
+pub fn subject_amount(upl: &Upl, subject: &Subject) -> u32 {
+  match (subject, upl.get_kind()) {
+    (Subject::Sku(sku), Kind::Sku { .. }) | (Subject::Sku(sku), Kind::BulkSku { .. })
+      if *sku == upl.get_sku() =>
+    {
+      upl.get_upl_piece()
+    }
+    (Subject::DividedProduct(product_id), Kind::OpenedSku { amount, .. })
+    | (Subject::DividedProduct(product_id), Kind::DerivedProduct { amount, .. })
+      if *product_id == upl.get_product_id() =>
+    {
+      *amount
+    }
+    (Subject::DividedProduct(product_id), Kind::Sku { .. })
+      if *product_id == upl.get_product_id() && upl.is_divisible() =>
+    {
+      upl.get_sku_divisible_amount()
+    }
+    _ => 0,
+  }
+}
+
+/// Amount of a UPL that is available for a subject
+/// Only UPLs in stock without any lock count
+pub fn available_amount(upl: &Upl, subject: &Subject) -> u32 {
+  match (upl.get_location(), upl.has_lock()) {
+    (Location::Stock(_), false) => subject_amount(upl, subject),
+    _ => 0,
+  }
+}
+
+/// Available amount of a subject by stock ID
+pub fn available<'a>(upls: impl Iterator<Item = &'a Upl>, subject: &Subject) -> BTreeMap<u32, u32> {
+  let mut res: BTreeMap<u32, u32> = BTreeMap::new();
+  for upl in upls {
+    if let Location::Stock(stock_id) = upl.get_location() {
+      let amount = available_amount(upl, subject);
+      if amount > 0 {
+        let entry = res.entry(*stock_id).or_insert(0);
+        *entry = entry.saturating_add(amount);
+      }
+    }
+  }
+  res
+}
+
+/// Total amount of the stocks
+pub fn total_amount(amounts: &BTreeMap<u32, u32>) -> u64 {
+  amounts.values().map(|amount| u64::from(*amount)).sum()
+}
+
+/// Remaining reserved amounts of a subject
+/// Local reservations by stock ID, and the global reserved amount
+/// Amounts are summed as u64, so client supplied amounts cannot overflow them
+#[derive(Debug, Default, PartialEq)]
+pub struct ReservedAmount {
+  pub local: BTreeMap<u32, u64>,
+  pub global: u64,
+}
+
+impl ReservedAmount {
+  /// Collect the remaining reserved amounts for a subject
+  /// optionally skipping the reservations of a given cart
+  pub fn for_subject<'a>(
+    reservations: impl Iterator<Item = &'a Reservation>,
+    subject: &Subject,
+    skip_cart: Option<&str>,
+  ) -> Self {
+    let mut res = Self::default();
+    for r in reservations {
+      if r.get_subject() != subject || Some(r.get_cart_id()) == skip_cart {
+        continue;
+      }
+      match r.get_scope() {
+        Scope::Local(stock_id) => {
+          *res.local.entry(*stock_id).or_insert(0) += u64::from(r.get_amount_remaining())
+        }
+        Scope::Global => res.global += u64::from(r.get_amount_remaining()),
+      }
+    }
+    res
+  }
+
+  /// Total remaining reserved amount
+  pub fn total(&self) -> u64 {
+    self.local.values().sum::<u64>() + self.global
+  }
+
+  /// Check whether amount can be taken from a stock
+  /// and the available amounts still cover these reservations
+  pub fn allows_taking(&self, available: &BTreeMap<u32, u32>, stock_id: u32, amount: u32) -> bool {
+    let total = total_amount(available);
+    let stock = u64::from(available.get(&stock_id).cloned().unwrap_or(0));
+    let local = self.local.get(&stock_id).cloned().unwrap_or(0);
+    let amount = u64::from(amount);
+    total >= self.total() + amount && stock >= local + amount
+  }
+
+  /// Check whether the available amounts cover these reservations
+  pub fn is_covered_by(&self, available: &BTreeMap<u32, u32>) -> bool {
+    self
+      .local
+      .keys()
+      .all(|stock_id| self.allows_taking(available, *stock_id, 0))
+      && total_amount(available) >= self.total()
+  }
+
+  /// Distribute the reserved amounts over the stock amounts
+  /// Local reservations are taken from their own stock,
+  /// global reservations are taken from the stocks in ascending
+  /// stock ID order. Returns the reserved amount per stock.
+  pub fn distribute(&self, available: &BTreeMap<u32, u32>) -> BTreeMap<u32, u32> {
+    let mut res: BTreeMap<u32, u32> = BTreeMap::new();
+    // Local reservations first
+    for (stock_id, amount) in available {
+      let local = self.local.get(stock_id).cloned().unwrap_or(0);
+      res.insert(*stock_id, local.min(u64::from(*amount)) as u32);
+    }
+    // Then the global ones
+    let mut global = self.global;
+    for (stock_id, amount) in available {
+      if global == 0 {
+        break;
+      }
+      let reserved = res.entry(*stock_id).or_insert(0);
+      let taken = global.min(u64::from(amount - *reserved)) as u32;
+      *reserved += taken;
+      global -= u64::from(taken);
+    }
+    res
+  }
+}
+
+/// Divisible availability of a SKU and of its product by stock
+/// to net the DividedProduct reservations from the SKU stock info
+#[derive(Debug, Default, PartialEq)]
+pub struct DividedAvailability {
+  pub product_id: u32,
+  pub sku_divisible_amount: u32,
+  // Available DividedProduct amount of the product
+  pub product: BTreeMap<u32, u32>,
+  // Available DividedProduct amount of the SKU UPLs
+  pub sku: BTreeMap<u32, u32>,
+}
+
+impl DividedAvailability {
+  /// Collect from the UPLs of the product
+  pub fn collect<'a>(
+    sku: u32,
+    product_id: u32,
+    sku_divisible_amount: u32,
+    product_upls: impl Iterator<Item = &'a Upl>,
+  ) -> Self {
+    let subject = Subject::DividedProduct(product_id);
+    let mut res = Self {
+      product_id,
+      sku_divisible_amount,
+      ..Self::default()
+    };
+    for upl in product_upls {
+      if let Location::Stock(stock_id) = upl.get_location() {
+        let amount = available_amount(upl, &subject);
+        if amount == 0 {
+          continue;
+        }
+        *res.product.entry(*stock_id).or_insert(0) += amount;
+        if upl.get_sku() == sku {
+          *res.sku.entry(*stock_id).or_insert(0) += amount;
+        }
+      }
+    }
+    res
+  }
+
+  /// SKU pieces held back by the DividedProduct reservations by stock
+  /// The reserved amount of a stock is taken from this SKU up to its
+  /// available amount there, and rounded up to whole pieces.
+  /// Other SKUs of the product may cover the same amount, so it rather
+  /// holds back too much than offers a reserved amount.
+  pub fn reserved_pieces(&self, reserved: &ReservedAmount) -> BTreeMap<u32, u32> {
+    let mut res = BTreeMap::new();
+    if self.sku_divisible_amount == 0 {
+      return res;
+    }
+    for (stock_id, amount) in reserved.distribute(&self.product) {
+      let amount = amount.min(self.sku.get(&stock_id).cloned().unwrap_or(0));
+      if amount > 0 {
+        let pieces = amount.div_ceil(self.sku_divisible_amount);
+        res.insert(stock_id, pieces);
+      }
+    }
+    res
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use gzlib::id::{generate_id, HexHelper, IdKind};
+
+  fn upl(sku: u32, stock_id: u32, piece: u32) -> Upl {
+    Upl {
+      kind: match piece {
+        1 => Kind::Sku { sku },
+        _ => Kind::BulkSku {
+          sku,
+          upl_pieces: piece,
+        },
+      },
+      location: Location::Stock(stock_id),
+      ..Upl::default()
+    }
+  }
+
+  fn reservation(cart_id: &str, sku: u32, scope: Scope, amount: u32) -> Reservation {
+    Reservation::new(
+      cart_id.to_string(),
+      cart_id.to_string(),
+      Subject::Sku(sku),
+      scope,
+      amount,
+      0,
+    )
+  }
+
+  #[test]
+  fn test_matching_amount() {
+    let local = reservation("a", 1, Scope::Local(2), 5);
+    let global = reservation("a", 1, Scope::Global, 5);
+    assert_eq!(local.get_matching_amount(&upl(1, 2, 3)), Some(3));
+    assert_eq!(local.get_matching_amount(&upl(1, 3, 3)), None);
+    assert_eq!(local.get_matching_amount(&upl(2, 2, 1)), None);
+    assert_eq!(global.get_matching_amount(&upl(1, 3, 1)), Some(1));
+  }
+
+  #[test]
+  fn test_take_and_give_back() {
+    let mut r = reservation("a", 1, Scope::Global, 5);
+    assert_eq!(r.take(3), 3);
+    assert_eq!(r.take(3), 2);
+    assert_eq!(r.get_amount_remaining(), 0);
+    assert_eq!(r.give_back(4), 4);
+    assert_eq!(r.give_back(4), 1);
+    assert_eq!(r.get_amount_remaining(), 5);
+  }
+
+  #[test]
+  fn test_available_and_allows_taking() {
+    let mut locked = upl(1, 1, 1);
+    locked.lock = Lock::Cart("a".to_string());
+    let upls = [upl(1, 1, 2), upl(1, 2, 1), upl(2, 1, 1), locked];
+    let available = available(upls.iter(), &Subject::Sku(1));
+    assert_eq!(available.get(&1), Some(&2));
+    assert_eq!(available.get(&2), Some(&1));
+
+    let reservations = [
+      reservation("a", 1, Scope::Local(1), 1),
+      reservation("b", 1, Scope::Global, 1),
+    ];
+    let reserved = ReservedAmount::for_subject(reservations.iter(), &Subject::Sku(1), None);
+    assert!(reserved.allows_taking(&available, 2, 1));
+    assert!(reserved.allows_taking(&available, 1, 1));
+    assert!(!reserved.allows_taking(&available, 1, 2));
+    let others = ReservedAmount::for_subject(reservations.iter(), &Subject::Sku(1), Some("b"));
+    assert!(others.allows_taking(&available, 1, 1));
+    assert!(!others.allows_taking(&available, 1, 2));
+  }
+
+  #[test]
+  fn test_divided_product_amount() {
+    let mut parent = Upl {
+      product_id: 7,
+      kind: Kind::OpenedSku {
+        sku: 1,
+        amount: 5,
+        successors: Vec::new(),
+      },
+      location: Location::Stock(1),
+      ..Upl::default()
+    };
+    let subject = Subject::DividedProduct(7);
+    let reservations = [Reservation::new(
+      "r".to_string(),
+      "a".to_string(),
+      subject.clone(),
+      Scope::Local(1),
+      5,
+      0,
+    )];
+    let reserved = ReservedAmount::for_subject(reservations.iter(), &subject, None);
+    assert!(reserved.is_covered_by(&available([parent.clone()].iter(), &subject)));
+
+    // Dividing keeps the amount of the product,
+    // even if the parent is left with a single unit
+    let derived = parent
+      .divide(
+        generate_id(10159851, IdKind::LuhnTwo).to_hex(),
+        4,
+        &VatRates::default(),
+        0,
+      )
+      .unwrap();
+    assert_eq!(subject_amount(&parent, &subject), 1);
+    assert_eq!(subject_amount(&derived, &subject), 4);
+    let upls = [parent, derived.clone()];
+    assert!(reserved.is_covered_by(&available(upls.iter(), &subject)));
+
+    // The derived UPL matches the same reservation by the same amount
+    assert_eq!(reservations[0].get_matching_amount(&derived), Some(4));
+    assert!(!reserved.allows_taking(&available(upls.iter(), &subject), 1, 4));
+  }
+
+  #[test]
+  fn test_distribute() {
+    let reservations = [
+      reservation("a", 1, Scope::Local(2), 3),
+      reservation("b", 1, Scope::Global, 4),
+      reservation("c", 2, Scope::Global, 10),
+    ];
+    let reserved = ReservedAmount::for_subject(reservations.iter(), &Subject::Sku(1), None);
+    assert_eq!(reserved.total(), 7);
+    assert_eq!(
+      ReservedAmount::for_subject(reservations.iter(), &Subject::Sku(1), Some("b")).total(),
+      3
+    );
+
+    let available: BTreeMap<u32, u32> = vec![(1, 2), (2, 5)].into_iter().collect();
+    let distributed = reserved.distribute(&available);
+    // Stock 1: 2 from global; stock 2: 3 local + 2 global
+    assert_eq!(distributed.get(&1), Some(&2));
+    assert_eq!(distributed.get(&2), Some(&5));
+  }
+
+  #[test]
+  fn test_reserved_amount_does_not_overflow() {
+    let reservations = [
+      reservation("a", 1, Scope::Local(1), u32::MAX),
+      reservation("b", 1, Scope::Global, u32::MAX),
+    ];
+    let reserved = ReservedAmount::for_subject(reservations.iter(), &Subject::Sku(1), None);
+    assert_eq!(reserved.total(), 2 * u64::from(u32::MAX));
+
+    let available: BTreeMap<u32, u32> = vec![(1, u32::MAX), (2, u32::MAX)].into_iter().collect();
+    assert!(!reserved.allows_taking(&available, 1, u32::MAX));
+    assert!(reserved.is_covered_by(&available));
+  }
 }
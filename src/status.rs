@@ -0,0 +1,103 @@
+use chrono::prelude::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Persistence clock
+/// Time of the last persisted UPL change.
+/// It is shared between the UPL store and the status RPC,
+/// so reading it never waits for the store lock.
+#[derive(Clone, Default)]
+pub struct PersistClock {
+  // Unix timestamp in milliseconds, 0 if nothing has been persisted yet
+  millis: Arc<AtomicI64>,
+}
+
+impl PersistClock {
+  /// Create a new clock
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Set the last persistence time to now
+  pub fn touch(&self) {
+    self
+      .millis
+      .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+  }
+
+  /// Get the last persistence time
+  pub fn last(&self) -> Option<DateTime<Utc>> {
+    match self.millis.load(Ordering::Relaxed) {
+      0 => None,
+      millis => Utc.timestamp_millis_opt(millis).single(),
+    }
+  }
+}
+
+/// Error of a background server
+/// Set by the failing server and reported by the status RPC.
+#[derive(Clone, Default)]
+pub struct ServerError {
+  error: Arc<Mutex<Option<String>>>,
+}
+
+impl ServerError {
+  /// Create a new, empty error
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Record the error of the server
+  pub fn set(&self, error: String) {
+    *self.error.lock().unwrap() = Some(error);
+  }
+
+  /// Get the recorded error
+  pub fn get(&self) -> Option<String> {
+    self.error.lock().unwrap().clone()
+  }
+}
+
+/// Get the total size of the files in a folder in bytes
+/// Missing folders are 0 bytes
+pub fn dir_size(path: &Path) -> u64 {
+  let entries = match std::fs::read_dir(path) {
+    Ok(entries) => entries,
+    Err(_) => return 0,
+  };
+  entries
+    .filter_map(|entry| entry.ok())
+    .map(|entry| match entry.metadata() {
+      Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+      Ok(meta) => meta.len(),
+      Err(_) => 0,
+    })
+    .sum()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_clock_and_dir_size() {
+    let clock = PersistClock::new();
+    assert!(clock.last().is_none());
+    clock.clone().touch();
+    assert!(clock.last().is_some());
+
+    let error = ServerError::new();
+    assert!(error.get().is_none());
+    error.clone().set("bind".to_string());
+    assert_eq!(error.get(), Some("bind".to_string()));
+
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("a"), [0u8; 10]).unwrap();
+    std::fs::write(dir.join("sub/b"), [0u8; 5]).unwrap();
+    assert_eq!(dir_size(dir), 15);
+    assert_eq!(dir_size(&dir.join("missing")), 0);
+  }
+}
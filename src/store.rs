@@ -0,0 +1,809 @@
+use crate::archive::ArchiveObject;
+use crate::eventlog::{EventLog, EventLogError, LogEntry};
+use crate::feed::ChangeFeed;
+use crate::status::PersistClock;
+use crate::upl::*;
+use packman::{Pack, PackError, PackGuard, VecPack};
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+use std::path::PathBuf;
+
+// File path of a UPL in a VecPack
+// packman names a pack file by its ID in the VecPack folder, see
+// VecPack::insert, but it has no API to remove a pack by its position.
+// test_pack_file_path fails if packman changes its file layout.
+fn pack_file_path(upls: &VecPack<Upl>, upl_id: &str) -> PathBuf {
+  upls.get_path().join(upl_id)
+}
+
+// Secondary index keys of a UPL
+#[derive(Clone, PartialEq)]
+struct IndexKeys {
+  sku: u32,
+  product: u32,
+  location: Location,
+  lock: Lock,
+}
+
+impl IndexKeys {
+  fn of(upl: &Upl) -> Self {
+    Self {
+      sku: upl.get_sku(),
+      product: upl.get_product_id(),
+      location: upl.get_location().clone(),
+      lock: upl.get_lock().clone(),
+    }
+  }
+}
+
+// Secondary indexes
+// Key -> UPL IDs
+// We store IDs instead of VecPack positions, so removing a UPL
+// does not need to shift every index entry after it.
+#[derive(Default)]
+struct Indexes {
+  by_sku: HashMap<u32, BTreeSet<String>>,
+  by_product: HashMap<u32, BTreeSet<String>>,
+  by_location: HashMap<Location, BTreeSet<String>>,
+  by_lock: HashMap<Lock, BTreeSet<String>>,
+}
+
+fn index_add<K: Hash + Eq>(index: &mut HashMap<K, BTreeSet<String>>, key: K, upl_id: &str) {
+  index.entry(key).or_default().insert(upl_id.to_string());
+}
+
+fn index_remove<K: Hash + Eq>(index: &mut HashMap<K, BTreeSet<String>>, key: &K, upl_id: &str) {
+  if let Some(upl_ids) = index.get_mut(key) {
+    upl_ids.remove(upl_id);
+    if upl_ids.is_empty() {
+      index.remove(key);
+    }
+  }
+}
+
+impl Indexes {
+  fn add(&mut self, upl_id: &str, keys: IndexKeys) {
+    index_add(&mut self.by_sku, keys.sku, upl_id);
+    index_add(&mut self.by_product, keys.product, upl_id);
+    index_add(&mut self.by_location, keys.location, upl_id);
+    // We do not index UPLs without lock
+    if keys.lock != Lock::None {
+      index_add(&mut self.by_lock, keys.lock, upl_id);
+    }
+  }
+
+  fn remove(&mut self, upl_id: &str, keys: &IndexKeys) {
+    index_remove(&mut self.by_sku, &keys.sku, upl_id);
+    index_remove(&mut self.by_product, &keys.product, upl_id);
+    index_remove(&mut self.by_location, &keys.location, upl_id);
+    index_remove(&mut self.by_lock, &keys.lock, upl_id);
+  }
+}
+
+// UPL change in the store
+enum Change {
+  Inserted(Upl),
+  // New history event or None if the UPL
+  // has changed without any history event
+  Changed(Option<UplHistoryItem>, Upl),
+  Removed(Upl),
+  // Removed UPL is archived
+  // Only logged, the feed gets the removal
+  Archived(ArchiveObject),
+  // Archived UPL is restored
+  Unarchived(String),
+}
+
+// Change publisher
+// Writes changes into the event log and publishes them
+// to the change feed, and records the time of the last persisted change
+#[derive(Default)]
+struct Changes {
+  feed: Option<ChangeFeed>,
+  log: Option<EventLog>,
+  clock: Option<PersistClock>,
+  // Changes of the running transaction
+  // They are published only if the transaction succeeds
+  pending: Vec<Change>,
+}
+
+impl Changes {
+  fn is_enabled(&self) -> bool {
+    self.feed.is_some() || self.log.is_some()
+  }
+
+  fn persisted(&self) {
+    if let Some(clock) = &self.clock {
+      clock.touch();
+    }
+  }
+
+  fn record(&mut self, change: Change) {
+    self.pending.push(change);
+  }
+
+  fn publish(&self, change: Change) {
+    if let Some(feed) = &self.feed {
+      match change {
+        Change::Inserted(upl) => feed.publish(inserted_event(&upl), upl),
+        Change::Changed(Some(event), upl) => feed.publish(event, upl),
+        Change::Changed(None, _) | Change::Archived(_) | Change::Unarchived(_) => (),
+        // The last history event of the UPL is the cause of its removal,
+        // e.g. Archived or MergedInto
+        Change::Removed(upl) => feed.publish(removed_event(&upl), upl),
+      }
+    }
+  }
+
+  fn begin(&mut self) {
+    self.pending.clear();
+  }
+
+  // Write the changes of the transaction into the event log,
+  // and publish them only if they are written
+  fn commit(&mut self) -> Result<(), EventLogError> {
+    let pending = std::mem::take(&mut self.pending);
+    if let Some(log) = self.log.as_ref().filter(|_| !pending.is_empty()) {
+      let entries = pending
+        .iter()
+        .map(|change| match change {
+          Change::Inserted(upl) => LogEntry::Inserted { upl: upl.clone() },
+          Change::Changed(event, upl) => LogEntry::changed(event.clone(), upl),
+          Change::Removed(upl) => LogEntry::Removed {
+            upl_id: upl.id.clone(),
+          },
+          Change::Archived(archive_object) => LogEntry::Archived {
+            reason: archive_object.get_reason().clone(),
+            archived_at: archive_object.get_archived_at(),
+            upl: archive_object.get_upl().clone(),
+          },
+          Change::Unarchived(upl_id) => LogEntry::Unarchived {
+            upl_id: upl_id.clone(),
+          },
+        })
+        .collect();
+      log.append_all(entries)?;
+    }
+    for change in pending {
+      self.publish(change);
+    }
+    Ok(())
+  }
+
+  fn discard(&mut self) {
+    self.pending.clear();
+  }
+}
+
+// Change feed event of an inserted UPL
+// It is the latest creation event of the UPL:
+// Created, Restored, SplitFrom or DividedFrom.
+// UPLs without any of them get a Created event.
+fn inserted_event(upl: &Upl) -> UplHistoryItem {
+  upl
+    .get_history()
+    .iter()
+    .rev()
+    .find(|item| {
+      matches!(
+        item.get_event(),
+        UplHistoryEvent::Created
+          | UplHistoryEvent::Restored { .. }
+          | UplHistoryEvent::SplitFrom { .. }
+          | UplHistoryEvent::DividedFrom { .. }
+      )
+    })
+    .cloned()
+    .unwrap_or_else(|| {
+      UplHistoryItem::new(CreatedBy::Uid(upl.created_by), UplHistoryEvent::Created)
+    })
+}
+
+// Change feed event of a removed UPL
+// It is the last history event of the UPL.
+// UPLs without history get an Archived event.
+fn removed_event(upl: &Upl) -> UplHistoryItem {
+  upl
+    .get_history()
+    .last()
+    .cloned()
+    .unwrap_or_else(|| UplHistoryItem::new(CreatedBy::Technical, UplHistoryEvent::Archived))
+}
+
+/// UPL Store
+/// VecPack<Upl> with an ID index and secondary indexes
+/// by SKU, product, location and lock.
+/// Every insert, mutation and removal goes through the store,
+/// so the indexes are always consistent with the UPLs.
+/// Every change is made in a transaction, which is written
+/// into the event log before it is committed.
+pub struct UplStore {
+  upls: VecPack<Upl>,
+  // UPL ID -> position in the VecPack
+  positions: HashMap<String, usize>,
+  indexes: Indexes,
+  changes: Changes,
+}
+
+/// Mutable UPL Pack
+/// Works like &mut Pack<Upl>, but when it is dropped
+/// it updates the store indexes by the new UPL state,
+/// and publishes its changes
+pub struct UplPackMut<'a> {
+  pack: &'a mut Pack<Upl>,
+  indexes: &'a mut Indexes,
+  changes: &'a mut Changes,
+  keys: IndexKeys,
+  history_len: usize,
+  // as_mut was called
+  touched: bool,
+}
+
+impl<'a> UplPackMut<'a> {
+  /// Get UPL ref
+  pub fn unpack(&self) -> &Upl {
+    self.pack.unpack()
+  }
+  /// Get mutable guard
+  /// It saves the UPL when it is dropped
+  pub fn as_mut(&mut self) -> PackGuard<'_, Upl> {
+    self.touched = true;
+    self.pack.as_mut()
+  }
+}
+
+impl<'a> Drop for UplPackMut<'a> {
+  fn drop(&mut self) {
+    let keys = IndexKeys::of(self.pack.unpack());
+    if keys != self.keys {
+      let upl_id = &self.pack.unpack().id;
+      self.indexes.remove(upl_id, &self.keys);
+      self.indexes.add(upl_id, keys);
+    }
+    if !self.touched {
+      return;
+    }
+    self.changes.persisted();
+    if !self.changes.is_enabled() {
+      return;
+    }
+    let upl = self.pack.unpack();
+    match upl.get_history().get(self.history_len..) {
+      Some(events) if !events.is_empty() => {
+        for event in events {
+          self
+            .changes
+            .record(Change::Changed(Some(event.clone()), upl.clone()));
+        }
+      }
+      _ => self.changes.record(Change::Changed(None, upl.clone())),
+    }
+  }
+}
+
+impl UplStore {
+  /// Create store and build its indexes
+  pub fn new(upls: VecPack<Upl>) -> Self {
+    let mut store = Self {
+      upls,
+      positions: HashMap::new(),
+      indexes: Indexes::default(),
+      changes: Changes::default(),
+    };
+    for (position, upl) in store.upls.iter().enumerate() {
+      let upl = upl.unpack();
+      store.positions.insert(upl.id.clone(), position);
+      store.indexes.add(&upl.id, IndexKeys::of(upl));
+    }
+    store
+  }
+
+  /// Publish UPL changes to a change feed
+  pub fn set_feed(&mut self, feed: ChangeFeed) {
+    self.changes.feed = Some(feed);
+  }
+
+  /// Write UPL changes into an event log
+  pub fn set_log(&mut self, log: EventLog) {
+    self.changes.log = Some(log);
+  }
+
+  /// Record the time of the last persisted change
+  pub fn set_clock(&mut self, clock: PersistClock) {
+    self.changes.clock = Some(clock);
+  }
+
+  /// Iterate over all the UPLs
+  pub fn iter(&self) -> std::slice::Iter<'_, Pack<Upl>> {
+    self.upls.iter()
+  }
+
+  /// Number of UPLs
+  pub fn len(&self) -> usize {
+    self.upls.len()
+  }
+
+  /// Returns true if there is no UPL
+  pub fn is_empty(&self) -> bool {
+    self.upls.is_empty()
+  }
+
+  /// Check ID is available
+  /// If ID is taken, returns false,
+  /// otherwise returns true
+  pub fn check_id_available(&self, upl_id: &str) -> bool {
+    !self.positions.contains_key(upl_id)
+  }
+
+  /// Find UPL by ID
+  pub fn find_id(&self, upl_id: &str) -> Result<&Pack<Upl>, PackError> {
+    match self.positions.get(upl_id) {
+      Some(position) => Ok(&self.upls[*position]),
+      None => Err(PackError::ObjectNotFound),
+    }
+  }
+
+  // Find UPL by ID as mutable
+  // Only through a transaction, so the change is logged
+  fn find_id_mut(&mut self, upl_id: &str) -> Result<UplPackMut<'_>, PackError> {
+    let position = *self
+      .positions
+      .get(upl_id)
+      .ok_or(PackError::ObjectNotFound)?;
+    let pack = &mut self.upls.as_vec_mut()[position];
+    let keys = IndexKeys::of(pack.unpack());
+    let history_len = pack.unpack().get_history().len();
+    Ok(UplPackMut {
+      pack,
+      indexes: &mut self.indexes,
+      changes: &mut self.changes,
+      keys,
+      history_len,
+      touched: false,
+    })
+  }
+
+  /// Insert a new UPL
+  /// Only if ID is not taken
+  pub fn insert(&mut self, upl: Upl) -> Result<(), PackError> {
+    self.atomic(|tx| tx.insert(upl))
+  }
+
+  /// Remove UPL by ID
+  pub fn remove_pack(&mut self, upl_id: &str) -> Result<Upl, PackError> {
+    self.atomic(|tx| tx.remove_pack(upl_id))
+  }
+
+  /// Update a UPL by ID as a single transaction
+  pub fn update<T, E, F>(&mut self, upl_id: &str, f: F) -> Result<T, E>
+  where
+    E: From<PackError>,
+    F: FnOnce(&mut Upl) -> Result<T, E>,
+  {
+    self.atomic(|tx| f(tx.find_id_mut(upl_id)?.as_mut().unpack()))
+  }
+
+  fn insert_upl(&mut self, upl: Upl) -> Result<(), PackError> {
+    let upl_id = upl.id.clone();
+    let keys = IndexKeys::of(&upl);
+    self.upls.insert(upl)?;
+    self.changes.persisted();
+    let position = self.upls.len() - 1;
+    if self.changes.is_enabled() {
+      let upl = self.upls[position].unpack().clone();
+      self.changes.record(Change::Inserted(upl));
+    }
+    self.indexes.add(&upl_id, keys);
+    self.positions.insert(upl_id, position);
+    Ok(())
+  }
+
+  fn remove_upl(&mut self, upl_id: &str) -> Result<Upl, PackError> {
+    let position = *self
+      .positions
+      .get(upl_id)
+      .ok_or(PackError::ObjectNotFound)?;
+    // Remove the UPL file the same way as VecPack::remove_pack,
+    // but we know the position, so we neither search nor shift the UPLs
+    std::fs::remove_file(pack_file_path(&self.upls, upl_id))?;
+    let upl = self.upls.as_vec_mut().swap_remove(position).into_inner();
+    self.changes.persisted();
+    self.positions.remove(upl_id);
+    self.indexes.remove(upl_id, &IndexKeys::of(&upl));
+    // The last UPL is moved into the place of the removed one
+    if let Some(moved) = self.upls.get(position) {
+      self.positions.insert(moved.unpack().id.clone(), position);
+    }
+    if self.changes.is_enabled() {
+      self.changes.record(Change::Removed(upl.clone()));
+    }
+    Ok(upl)
+  }
+
+  // Get UPLs by indexed IDs
+  fn get_all<'a>(&'a self, upl_ids: Option<&'a BTreeSet<String>>) -> impl Iterator<Item = &'a Upl> {
+    upl_ids
+      .into_iter()
+      .flatten()
+      .map(move |upl_id| self.upls[self.positions[upl_id]].unpack())
+  }
+
+  /// Get UPLs by SKU
+  pub fn find_by_sku(&self, sku: u32) -> impl Iterator<Item = &Upl> {
+    self.get_all(self.indexes.by_sku.get(&sku))
+  }
+
+  /// Get UPLs by product ID
+  pub fn find_by_product(&self, product_id: u32) -> impl Iterator<Item = &Upl> {
+    self.get_all(self.indexes.by_product.get(&product_id))
+  }
+
+  /// Get UPLs by location
+  pub fn find_by_location(&self, location: &Location) -> impl Iterator<Item = &Upl> {
+    self.get_all(self.indexes.by_location.get(location))
+  }
+
+  /// Get UPLs by lock
+  /// Lock::None is not indexed, so it returns nothing
+  pub fn find_by_lock(&self, lock: &Lock) -> impl Iterator<Item = &Upl> {
+    self.get_all(self.indexes.by_lock.get(lock))
+  }
+}
+
+impl UplStore {
+  /// Run f as a single all-or-nothing transaction
+  /// If f returns an error, every UPL change made through
+  /// the transaction is rolled back, and the error is returned.
+  /// Steps outside the store that cannot be rolled back
+  /// should be the last ones in f.
+  ///
+  /// Caveat: it is not crash safe. Every step is saved to disk
+  /// immediately, and the rollback is done in memory, so a crash
+  /// in the middle of a transaction leaves its partial state on disk.
+  /// With an event log the committed changes are written into the log
+  /// before they are published, and the startup state is rebuilt
+  /// from the log, so such partial state does not survive a restart.
+  /// If the log cannot be written, the transaction is rolled back.
+  pub fn atomic<T, E, F>(&mut self, f: F) -> Result<T, E>
+  where
+    E: From<PackError>,
+    F: FnOnce(&mut UplTransaction<'_>) -> Result<T, E>,
+  {
+    self.changes.begin();
+    let mut tx = UplTransaction {
+      store: self,
+      originals: Vec::new(),
+    };
+    let res = f(&mut tx).and_then(|res| match tx.store.changes.commit() {
+      Ok(_) => Ok(res),
+      Err(e) => Err(PackError::InternalError(e.to_string()).into()),
+    });
+    if res.is_err() {
+      // If the rollback itself fails, its error is the more important one
+      let rollback = tx.rollback();
+      self.changes.discard();
+      rollback?;
+    }
+    res
+  }
+}
+
+/// UPL store transaction
+/// Keeps the original state of every UPL it touches,
+/// so all of its changes can be rolled back
+pub struct UplTransaction<'a> {
+  store: &'a mut UplStore,
+  // (UPL ID, original UPL)
+  // None if the UPL was inserted in this transaction
+  originals: Vec<(String, Option<Upl>)>,
+}
+
+impl<'a> UplTransaction<'a> {
+  // Save the original state of a UPL
+  // only before its first change
+  fn keep_original(&mut self, upl_id: &str) {
+    if self.originals.iter().any(|(id, _)| id == upl_id) {
+      return;
+    }
+    let original = self
+      .store
+      .find_id(upl_id)
+      .ok()
+      .map(|upl| upl.unpack().clone());
+    self.originals.push((upl_id.to_string(), original));
+  }
+
+  /// Find UPL by ID
+  pub fn find_id(&self, upl_id: &str) -> Result<&Pack<Upl>, PackError> {
+    self.store.find_id(upl_id)
+  }
+
+  /// Get UPLs by product ID
+  pub fn find_by_product(&self, product_id: u32) -> impl Iterator<Item = &Upl> {
+    self.store.find_by_product(product_id)
+  }
+
+  /// Get UPLs by location
+  pub fn find_by_location(&self, location: &Location) -> impl Iterator<Item = &Upl> {
+    self.store.find_by_location(location)
+  }
+
+  /// Get UPLs by lock
+  pub fn find_by_lock(&self, lock: &Lock) -> impl Iterator<Item = &Upl> {
+    self.store.find_by_lock(lock)
+  }
+
+  /// Find UPL by ID as mutable
+  pub fn find_id_mut(&mut self, upl_id: &str) -> Result<UplPackMut<'_>, PackError> {
+    self.keep_original(upl_id);
+    self.store.find_id_mut(upl_id)
+  }
+
+  /// Insert a new UPL
+  pub fn insert(&mut self, upl: Upl) -> Result<(), PackError> {
+    self.keep_original(&upl.id.clone());
+    self.store.insert_upl(upl)
+  }
+
+  /// Remove UPL by ID
+  pub fn remove_pack(&mut self, upl_id: &str) -> Result<Upl, PackError> {
+    self.keep_original(upl_id);
+    self.store.remove_upl(upl_id)
+  }
+
+  /// Archive a UPL removed in this transaction
+  /// Its Archived log record is committed together with the removal,
+  /// the caller writes its archive file only after the commit.
+  /// The removal is published with the archived UPL and its last history event.
+  pub fn archive(&mut self, archive_object: &ArchiveObject) {
+    if self.store.changes.is_enabled() {
+      let archived_upl = archive_object.get_upl();
+      for change in self.store.changes.pending.iter_mut() {
+        if let Change::Removed(upl) = change {
+          if upl.id == archived_upl.id {
+            *upl = archived_upl.clone();
+          }
+        }
+      }
+      self
+        .store
+        .changes
+        .record(Change::Archived(archive_object.clone()));
+    }
+  }
+
+  /// Restore an archived UPL inserted in this transaction
+  /// Its Unarchived log record is committed together with the insert,
+  /// the caller removes its archive file only after the commit.
+  pub fn unarchive(&mut self, upl_id: &str) {
+    if self.store.changes.is_enabled() {
+      self
+        .store
+        .changes
+        .record(Change::Unarchived(upl_id.to_string()));
+    }
+  }
+
+  // Restore every touched UPL into its original state
+  // in reverse order
+  fn rollback(&mut self) -> Result<(), PackError> {
+    while let Some((upl_id, original)) = self.originals.pop() {
+      let exists = !self.store.check_id_available(&upl_id);
+      match original {
+        Some(original) if exists => {
+          *self.store.find_id_mut(&upl_id)?.as_mut().unpack() = original;
+        }
+        Some(original) => self.store.insert_upl(original)?,
+        None if exists => {
+          self.store.remove_upl(&upl_id)?;
+        }
+        None => (),
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::archive::ArchiveReason;
+
+  fn load(dir: &tempfile::TempDir) -> UplStore {
+    UplStore::new(VecPack::load_or_init(dir.path().to_path_buf()).unwrap())
+  }
+
+  fn upl(id: &str, sku: u32, stock_id: u32) -> Upl {
+    Upl {
+      id: id.to_string(),
+      kind: Kind::Sku { sku },
+      location: Location::Stock(stock_id),
+      ..Upl::default()
+    }
+  }
+
+  fn ids<'a>(upls: impl Iterator<Item = &'a Upl>) -> Vec<String> {
+    upls.map(|upl| upl.id.clone()).collect()
+  }
+
+  #[test]
+  fn test_indexes() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = load(&dir);
+    store.insert(upl("a", 1, 1)).unwrap();
+    store.insert(upl("b", 1, 2)).unwrap();
+    store.insert(upl("c", 2, 1)).unwrap();
+    assert!(store.insert(upl("c", 2, 1)).is_err());
+
+    assert_eq!(ids(store.find_by_sku(1)), vec!["a", "b"]);
+    assert_eq!(
+      ids(store.find_by_location(&Location::Stock(1))),
+      vec!["a", "c"]
+    );
+
+    // Mutation updates the indexes
+    store
+      .find_id_mut("a")
+      .unwrap()
+      .as_mut()
+      .unpack()
+      .move_upl(Location::Stock(2), 0)
+      .unwrap();
+    store
+      .find_id_mut("b")
+      .unwrap()
+      .as_mut()
+      .unpack()
+      .lock(Lock::Cart("x".to_string()), 0)
+      .unwrap();
+    assert_eq!(ids(store.find_by_location(&Location::Stock(1))), vec!["c"]);
+    assert_eq!(
+      ids(store.find_by_location(&Location::Stock(2))),
+      vec!["a", "b"]
+    );
+    assert_eq!(
+      ids(store.find_by_lock(&Lock::Cart("x".to_string()))),
+      vec!["b"]
+    );
+
+    // Removal updates the indexes and the positions
+    // The last UPL is moved into the place of the removed one
+    store.remove_pack("a").unwrap();
+    assert_eq!(ids(store.find_by_sku(1)), vec!["b"]);
+    assert_eq!(store.find_id("c").unwrap().unpack().id, "c");
+    assert_eq!(store.find_id("b").unwrap().unpack().id, "b");
+    assert_eq!(ids(store.find_by_sku(2)), vec!["c"]);
+    assert!(store.check_id_available("a"));
+    assert_eq!(store.len(), 2);
+
+    // Rebuilt indexes are the same
+    let store = load(&dir);
+    assert_eq!(ids(store.find_by_sku(1)), vec!["b"]);
+    assert_eq!(ids(store.find_by_location(&Location::Stock(1))), vec!["c"]);
+  }
+
+  #[test]
+  fn test_pack_file_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = load(&dir);
+    store.insert(upl("a", 1, 1)).unwrap();
+    store.insert(upl("b", 1, 1)).unwrap();
+    assert!(pack_file_path(&store.upls, "a").is_file());
+
+    // Removed UPL is not loaded again
+    store.remove_pack("a").unwrap();
+    assert!(!pack_file_path(&store.upls, "a").exists());
+    let store = load(&dir);
+    assert!(store.check_id_available("a"));
+    assert_eq!(store.len(), 1);
+  }
+
+  #[test]
+  fn test_atomic_rollback() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = load(&dir);
+    let feed = ChangeFeed::new(10);
+    store.set_feed(feed.clone());
+    let log_dir = tempfile::tempdir().unwrap();
+    store.set_log(EventLog::open(log_dir.path().to_path_buf()).unwrap());
+    store.insert(upl("a", 1, 1)).unwrap();
+    store.insert(upl("b", 1, 1)).unwrap();
+    assert_eq!(feed.last_seq(), 2);
+
+    // Move a, insert c, remove b, then fail
+    let res: Result<(), PackError> = store.atomic(|tx| {
+      tx.find_id_mut("a")?
+        .as_mut()
+        .unpack()
+        .move_upl(Location::Stock(2), 0)
+        .unwrap();
+      tx.insert(upl("c", 1, 1))?;
+      let removed = tx.remove_pack("b")?;
+      tx.archive(&ArchiveObject::new(ArchiveReason::Sold, removed));
+      tx.insert(upl("a", 1, 1))?;
+      Ok(())
+    });
+    assert!(res.is_err());
+    // Changes of a rolled back transaction are not published nor logged
+    assert_eq!(feed.last_seq(), 2);
+    let state = crate::eventlog::replay(log_dir.path(), None).unwrap();
+    assert_eq!(state.seq, 2);
+    assert_eq!(state.active.keys().collect::<Vec<_>>(), vec!["a", "b"]);
+    assert!(state.archived.is_empty());
+    assert!(store.check_id_available("c"));
+    assert_eq!(
+      ids(store.find_by_location(&Location::Stock(1))),
+      vec!["a", "b"]
+    );
+
+    // Rolled back state is saved as well
+    let store = load(&dir);
+    let mut reloaded = ids(store.find_by_location(&Location::Stock(1)));
+    reloaded.sort();
+    assert_eq!(reloaded, vec!["a", "b"]);
+    assert!(store.find_by_location(&Location::Stock(2)).next().is_none());
+
+    // Removal and its archive record are committed together
+    let mut store = store;
+    store.set_log(EventLog::open(log_dir.path().to_path_buf()).unwrap());
+    store
+      .atomic(|tx| {
+        let removed = tx.remove_pack("b")?;
+        tx.archive(&ArchiveObject::new(ArchiveReason::Sold, removed));
+        Result::<(), PackError>::Ok(())
+      })
+      .unwrap();
+    let state = crate::eventlog::replay(log_dir.path(), None).unwrap();
+    assert_eq!(state.seq, 4);
+    assert!(!state.active.contains_key("b"));
+    assert!(state.archived.contains("b"));
+  }
+
+  #[test]
+  fn test_removed_event() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = load(&dir);
+    let feed = ChangeFeed::new(10);
+    store.set_feed(feed.clone());
+    store.insert(upl("a", 1, 1)).unwrap();
+    store.insert(upl("b", 1, 1)).unwrap();
+
+    // Removal without archive publishes the last history event
+    store
+      .find_id_mut("a")
+      .unwrap()
+      .as_mut()
+      .unpack()
+      .move_upl(Location::Stock(2), 0)
+      .unwrap();
+    store.remove_pack("a").unwrap();
+
+    // Archived removal publishes the last history event of the archived UPL
+    store
+      .atomic(|tx| {
+        let mut removed = tx.remove_pack("b")?;
+        removed.set_history(UplHistoryItem::new(
+          CreatedBy::Technical,
+          UplHistoryEvent::MergedInto {
+            parent_upl_id: "c".to_string(),
+          },
+        ));
+        tx.archive(&ArchiveObject::new(ArchiveReason::Merged, removed));
+        Result::<(), PackError>::Ok(())
+      })
+      .unwrap();
+
+    let events = feed.since(2).unwrap();
+    assert_eq!(events.len(), 2);
+    assert!(matches!(
+      events[0].event.get_event(),
+      UplHistoryEvent::Moved { .. }
+    ));
+    assert_eq!(events[0].upl_id, "a");
+    assert!(matches!(
+      events[1].event.get_event(),
+      UplHistoryEvent::MergedInto { .. }
+    ));
+    assert_eq!(events[1].upl_id, "b");
+  }
+}
@@ -1,5 +1,9 @@
-use std::ops::Mul;
+use std::collections::HashMap;
 
+use crate::locks::{
+  check_acquire, check_lock_at, check_move, check_move_from, check_release, LockError,
+};
+pub use crate::money::Money;
 use chrono::prelude::*;
 use gzlib::id::LuhnCheck;
 use packman::VecPackMember;
@@ -22,13 +26,14 @@ where
     piece: u32,
     sku_divisible_amount: u32,
     sku_divisible: bool,
-    sku_net_price: u32,
+    sku_net_price: Money,
     sku_vat: VAT,
     procurement_id: u32,
-    sku_procurement_net_price: u32,
+    sku_procurement_net_price: Money,
     location: Location,
     best_before: Option<DateTime<Utc>>,
     is_opened: bool,
+    vat_rates: &VatRates,
     created_by: u32,
   ) -> Result<Self, String>;
   /// Get UPL ID ref
@@ -42,7 +47,7 @@ where
   /// Get UPL procurement ID ref
   fn get_procurement_id(&self) -> u32;
   /// Get UPL procurement net price ref
-  fn get_procurement_net_price(&self) -> u32;
+  fn get_procurement_net_price(&self) -> Money;
   /// Check whether UPL can move to a different location
   /// depends on its acquired Lock kind
   fn can_move(&self, to: &Location) -> bool;
@@ -56,28 +61,50 @@ where
   /// Get current location ref
   fn get_location(&self) -> &Location;
   /// Try move UPL to location B
-  fn move_upl(&mut self, to: Location, created_by: u32) -> Result<&Self, String>;
+  /// The move releases the lock of the target holder
+  fn move_upl(&mut self, to: Location, created_by: u32) -> Result<&Self, LockError>;
   /// Check whether UPL has a lock or none
   fn has_lock(&self) -> bool;
   /// Get UPL lock ref
   fn get_lock(&self) -> &Lock;
-  /// Check whether it can be locked to a &Lock
+  /// Check whether it can be locked
+  /// Only an unlocked UPL can be locked
   fn can_lock(&self) -> bool;
   /// Try to lock UPL by a given Lock
-  fn lock(&mut self, lock: Lock, created_by: u32) -> Result<&Self, String>;
-  /// Try to unlock UPL
-  fn unlock(&mut self, lock: Lock, created_by: u32) -> Result<&Self, String>;
+  /// The lock never expires
+  fn lock(&mut self, lock: Lock, created_by: u32) -> Result<&Self, LockError>;
+  /// Try to lock UPL by a given Lock for ttl_minutes
+  /// None means the lock never expires
+  fn lock_with_ttl(
+    &mut self,
+    lock: Lock,
+    ttl_minutes: Option<u64>,
+    created_by: u32,
+  ) -> Result<&Self, LockError>;
+  /// Try to unlock UPL by its lock holder
+  fn unlock(&mut self, lock: Lock, created_by: u32) -> Result<&Self, LockError>;
   /// Unlock UPL anyway
   fn unlock_forced(&mut self) -> &Self;
+  /// Get the lease of the current lock
+  fn get_lock_lease(&self) -> Option<&LockLease>;
+  /// Release an expired lock
+  /// Only for the lock reaper
+  fn expire_lock(&mut self) -> Result<&Self, LockError>;
   /// Try to set new price to UPL
-  fn set_price(&mut self, sku_net_price: u32, sku_vat: VAT) -> Result<&Self, String>;
+  fn set_price(
+    &mut self,
+    sku_net_price: Money,
+    sku_vat: VAT,
+    vat_rates: &VatRates,
+    created_by: CreatedBy,
+  ) -> Result<&Self, String>;
   /// Set depreciation
-  /// Should be limited to the inventory service
+  /// Limited to callers with the depreciate permission
   fn set_depreciation(
     &mut self,
     deprecation_id: u32,
     comment: String,
-    created_by: u32,
+    created_by: CreatedBy,
   ) -> Result<&Self, String>;
   /// Remove deprecation
   fn remove_deprecation(&mut self, created_by: u32) -> Result<&Self, String>;
@@ -85,8 +112,8 @@ where
   /// there is room for validation if needed
   fn set_depreciation_price(
     &mut self,
-    net_depreciated_price: Option<u32>,
-    created_by: u32,
+    net_depreciated_price: Option<Money>,
+    created_by: CreatedBy,
   ) -> Result<&Self, String>;
   /// Check if the UPL is depreciated
   /// This can mean a damaged package, or anything the might
@@ -97,12 +124,12 @@ where
   /// Get depreciation comment if there is any
   fn get_depreciation_comment(&self) -> Option<&String>;
   /// Get depreciation price if there is any
-  fn get_depreciation_price(&self) -> Option<u32>;
+  fn get_depreciation_price(&self) -> Option<Money>;
   /// Get best before date if there is any
   fn get_best_before(&self) -> Option<DateTime<Utc>>;
   /// Update UPL best_before date
   /// for any reason
-  /// Should be private and used only from the inventory service
+  /// Limited to callers with the depreciate permission
   fn set_best_before(&mut self, best_before: Option<DateTime<Utc>>, created_by: u32) -> &Self;
   /// Check whether the UPL is an un-opened original one or not
   fn is_original(&self) -> bool;
@@ -119,14 +146,25 @@ where
   /// ----------
   /// in a higher lever you must save the split UPL in the UPL store
   /// ID must be validated
-  fn split(&mut self, new_upl_id: String, piece: u32, created_by: u32) -> Result<Upl, String>;
+  fn split(
+    &mut self,
+    new_upl_id: String,
+    piece: u32,
+    vat_rates: &VatRates,
+    created_by: u32,
+  ) -> Result<Upl, String>;
   /// Split multiple UPLs from the bulk ones
   /// ----------
   /// IMPORTANT!
   /// ----------
   /// in a higher lever you must save the split UPL in the UPL store
   /// IDs must be validated
-  fn split_bulk(&mut self, new_upl_ids: Vec<String>, created_by: u32) -> Result<Vec<Upl>, String>;
+  fn split_bulk(
+    &mut self,
+    new_upl_ids: Vec<String>,
+    vat_rates: &VatRates,
+    created_by: u32,
+  ) -> Result<Vec<Upl>, String>;
   /// Divide a divisible UPL into two UPLs
   /// If the UPL is a divisible Sku, then it will become an OpenedSku
   /// and the resulted new Upl will be a DerivedProduct
@@ -139,12 +177,18 @@ where
     &mut self,
     new_upl_id: String,
     requested_amount: u32,
+    vat_rates: &VatRates,
     created_by: u32,
   ) -> Result<Upl, String>;
   /// Try to merge a source and a derived UPL into together
   /// When for any reason we want to put back a derived UPL into
   /// its ancestor
-  fn merge(&mut self, upl_to_destroy: Upl, created_by: u32) -> Result<&Upl, String>;
+  fn merge(
+    &mut self,
+    upl_to_destroy: Upl,
+    vat_rates: &VatRates,
+    created_by: u32,
+  ) -> Result<&Upl, String>;
 
   /// Check whether this UPL is divisible or not
   fn is_divisible(&self) -> bool;
@@ -162,27 +206,29 @@ where
   /// Get UPL object created by value (user id)
   fn get_created_by(&self) -> u32;
   /// Get UPL net_price
-  fn get_upl_net_price(&self) -> u32;
-  /// Get UPL vat
-  fn get_upl_gross_price(&self) -> u32;
+  fn get_upl_net_price(&self) -> Money;
   /// Get UPL gross price
+  fn get_upl_gross_price(&self, vat_rates: &VatRates) -> Money;
+  /// Get UPL vat
   fn get_upl_vat(&self) -> VAT;
   /// Get UPL has special price
   fn get_upl_has_special_price(&self) -> bool;
   /// Get net special price if there is any
-  fn get_upl_special_price_net(&self) -> Option<u32>;
+  fn get_upl_special_price_net(&self) -> Option<Money>;
   /// Get net special margin if there is any
   fn get_upl_special_price_margin(&self) -> Option<i32>;
   /// Recalculate retail prices, procurement value and net margin
-  fn recalculate_prices(&mut self);
+  /// of this UPL alone, the parts of an opened UPL are allocated
+  /// together by allocate_opened_values
+  fn recalculate_prices(&mut self, vat_rates: &VatRates);
   /// Try to open Kind Sku
-  fn open(&mut self) -> Result<&Upl, String>;
+  fn open(&mut self, created_by: CreatedBy) -> Result<&Upl, String>;
   /// Try to close Kind OpenedSku
-  fn close(&mut self) -> Result<&Upl, String>;
+  fn close(&mut self, created_by: CreatedBy) -> Result<&Upl, String>;
   /// Set UPL to be divisible based on its SKU
-  fn set_divisible(&mut self, divisible: bool) -> &Self;
+  fn set_divisible(&mut self, divisible: bool, created_by: CreatedBy) -> &Self;
   /// Set Product unit
-  fn set_product_unit(&mut self, unit: String) -> &Self;
+  fn set_product_unit(&mut self, unit: String, created_by: CreatedBy) -> &Self;
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -207,13 +253,30 @@ pub struct UplHistoryItem {
 }
 
 impl UplHistoryItem {
-  fn new(created_by: CreatedBy, event: UplHistoryEvent) -> Self {
+  pub fn new(created_by: CreatedBy, event: UplHistoryEvent) -> Self {
+    Self::new_at(created_by, event, Utc::now())
+  }
+  /// History item with a given event time
+  /// e.g. when it is migrated from an older scheme
+  pub fn new_at(created_by: CreatedBy, event: UplHistoryEvent, created_at: DateTime<Utc>) -> Self {
     Self {
       event,
-      created_at: Utc::now(),
+      created_at,
       created_by,
     }
   }
+  /// Get history event ref
+  pub fn get_event(&self) -> &UplHistoryEvent {
+    &self.event
+  }
+  /// Get event time
+  pub fn get_created_at(&self) -> DateTime<Utc> {
+    self.created_at
+  }
+  /// Get event created by ref
+  pub fn get_created_by(&self) -> &CreatedBy {
+    &self.created_by
+  }
 }
 
 impl Default for UplHistoryItem {
@@ -256,7 +319,7 @@ pub enum UplHistoryEvent {
   DeprecationRemoved,
   // When UPL has set a special depreciation retail price
   SetDepreciatedPrice {
-    retail_net_price: Option<u32>,
+    retail_net_price: Option<Money>,
   },
   Split {
     new_upl_id: String,
@@ -268,6 +331,56 @@ pub enum UplHistoryEvent {
   },
   // Default event
   None,
+  // New events go below, stored UPLs decode events by variant index
+  // When an archived UPL is restored
+  // e.g. a sold UPL is brought back by the customer
+  Restored {
+    reason: String,
+  },
+  // When a Sku UPL is opened to be divisible
+  Opened,
+  // When an OpenedSku UPL is closed back to Sku
+  Closed,
+  // When a derived UPL is merged back into this UPL
+  Merged {
+    merged_upl_id: String,
+    amount: u32,
+  },
+  // When this derived UPL is merged back into its parent
+  MergedInto {
+    parent_upl_id: String,
+  },
+  // When SKU price or VAT has changed
+  PriceChanged {
+    old_net_price: Money,
+    new_net_price: Money,
+    old_vat: VAT,
+    new_vat: VAT,
+  },
+  // When SKU divisible flag has changed
+  DivisibleChanged {
+    from: bool,
+    to: bool,
+  },
+  // When product unit has changed
+  ProductUnitChanged {
+    from: String,
+    to: String,
+  },
+  // When an expired lock is released by the lock reaper
+  LockExpired {
+    lock: Lock,
+    acquired_at: DateTime<Utc>,
+  },
+  // When this UPL is created by splitting a bulk UPL
+  SplitFrom {
+    parent_upl_id: String,
+  },
+  // When this UPL is created by dividing an opened UPL
+  DividedFrom {
+    parent_upl_id: String,
+    amount: u32,
+  },
 }
 
 impl Default for UplHistoryEvent {
@@ -276,7 +389,52 @@ impl Default for UplHistoryEvent {
   }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+// Event kind names
+// KINDS and get_kind are generated from the same list
+macro_rules! event_kinds {
+  ($($event:pat => $kind:literal,)*) => {
+    /// All the event kind names
+    /// we can filter the history by
+    pub const KINDS: &'static [&'static str] = &[$($kind),*];
+
+    /// Get event kind name
+    pub fn get_kind(&self) -> &'static str {
+      match self {
+        $($event => $kind,)*
+      }
+    }
+  };
+}
+
+impl UplHistoryEvent {
+  event_kinds! {
+    UplHistoryEvent::Created => "created",
+    UplHistoryEvent::Archived => "archived",
+    UplHistoryEvent::Moved { .. } => "moved",
+    UplHistoryEvent::BestBeforeUpdated { .. } => "best_before_updated",
+    UplHistoryEvent::Locked { .. } => "locked",
+    UplHistoryEvent::Unlocked => "unlocked",
+    UplHistoryEvent::SetDeprecated { .. } => "set_deprecated",
+    UplHistoryEvent::DeprecationRemoved => "deprecation_removed",
+    UplHistoryEvent::SetDepreciatedPrice { .. } => "set_depreciated_price",
+    UplHistoryEvent::Split { .. } => "split",
+    UplHistoryEvent::Divided { .. } => "divided",
+    UplHistoryEvent::None => "none",
+    UplHistoryEvent::Restored { .. } => "restored",
+    UplHistoryEvent::Opened => "opened",
+    UplHistoryEvent::Closed => "closed",
+    UplHistoryEvent::Merged { .. } => "merged",
+    UplHistoryEvent::MergedInto { .. } => "merged_into",
+    UplHistoryEvent::PriceChanged { .. } => "price_changed",
+    UplHistoryEvent::DivisibleChanged { .. } => "divisible_changed",
+    UplHistoryEvent::ProductUnitChanged { .. } => "product_unit_changed",
+    UplHistoryEvent::LockExpired { .. } => "lock_expired",
+    UplHistoryEvent::SplitFrom { .. } => "split_from",
+    UplHistoryEvent::DividedFrom { .. } => "divided_from",
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Location {
   // Upl is in stock
   Stock(u32),
@@ -349,7 +507,7 @@ impl Default for Kind {
 
 /// Lock kinds
 /// None means there is no lock, so the UPL can be moved away.
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Lock {
   // Cart lock means the given UPL is locked to a specific Cart
   // so it cannot move away, as its under a sales process.
@@ -383,10 +541,7 @@ impl Default for Lock {
 impl Lock {
   // Behaves like Option<T>
   pub fn is_none(&self) -> bool {
-    match self {
-      Lock::None => true,
-      _ => true,
-    }
+    matches!(self, Lock::None)
   }
   // Behaves like Option<T>
   // pub fn is_some(&self) -> bool {
@@ -394,6 +549,32 @@ impl Lock {
   // }
 }
 
+/// Lock lease
+/// When the current lock was acquired and how long it can be held
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LockLease {
+  pub acquired_at: DateTime<Utc>,
+  // TTL in minutes
+  // None if the lock never expires
+  pub ttl_minutes: Option<u64>,
+}
+
+impl LockLease {
+  pub fn new(acquired_at: DateTime<Utc>, ttl_minutes: Option<u64>) -> Self {
+    Self {
+      acquired_at,
+      ttl_minutes,
+    }
+  }
+  /// Time the lock expires at
+  /// None if it never expires
+  pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+    self
+      .ttl_minutes
+      .map(|minutes| self.acquired_at + chrono::Duration::minutes(minutes as i64))
+  }
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Depreciation {
   // If the product is injured
@@ -403,7 +584,7 @@ pub struct Depreciation {
   // Related scrap price
   // if there any.
   // Can set if there is related depreciation_id
-  pub net_retail_price: Option<u32>,
+  pub net_retail_price: Option<Money>,
   // UPL net margin when we have special price
   pub margin_net: Option<i32>,
   // Related depreciation comment
@@ -424,13 +605,13 @@ impl Depreciation {
     }
   }
   /// Set depreciation price
-  pub fn set_price(&mut self, net_retail_price: Option<u32>, margin_net: Option<i32>) {
+  pub fn set_price(&mut self, net_retail_price: Option<Money>, margin_net: Option<i32>) {
     self.net_retail_price = net_retail_price;
     self.margin_net = margin_net;
   }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Copy)]
+#[derive(Serialize, Deserialize, Clone, Debug, Copy, PartialEq, Eq, Hash)]
 pub enum VAT {
   AAM,
   FAD,
@@ -447,6 +628,21 @@ impl Default for VAT {
 }
 
 impl VAT {
+  /// All the VAT codes
+  pub const ALL: [VAT; 6] = [VAT::AAM, VAT::FAD, VAT::TAM, VAT::_5, VAT::_18, VAT::_27];
+
+  /// Default VAT rate in percent
+  pub fn default_rate(&self) -> u32 {
+    match self {
+      VAT::AAM => 0,
+      VAT::FAD => 0,
+      VAT::TAM => 0,
+      VAT::_5 => 5,
+      VAT::_18 => 18,
+      VAT::_27 => 27,
+    }
+  }
+
   pub fn from_str(str: &str) -> Result<VAT, String> {
     match str {
       "AAM" => Ok(VAT::AAM),
@@ -476,19 +672,32 @@ impl ToString for VAT {
   }
 }
 
-impl Mul<VAT> for u32 {
-  type Output = u32;
-
-  fn mul(self, rhs: VAT) -> Self::Output {
-    let res = match rhs {
-      VAT::AAM => self as f32 * 1.0,
-      VAT::FAD => self as f32 * 1.0,
-      VAT::TAM => self as f32 * 1.0,
-      VAT::_5 => self as f32 * 1.05,
-      VAT::_18 => self as f32 * 1.18,
-      VAT::_27 => self as f32 * 1.27,
-    };
-    res.round() as u32
+/// VAT rates table in percent
+/// Built from the service config,
+/// missing VAT codes keep their default rate.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VatRates {
+  rates: HashMap<VAT, u32>,
+}
+
+impl VatRates {
+  pub fn new(rates: HashMap<VAT, u32>) -> Self {
+    Self { rates }
+  }
+
+  /// VAT rate in percent
+  pub fn rate(&self, vat: VAT) -> u32 {
+    self
+      .rates
+      .get(&vat)
+      .copied()
+      .unwrap_or_else(|| vat.default_rate())
+  }
+
+  /// Gross price of a net price
+  /// Rounded half up to the smallest currency unit
+  pub fn gross(&self, net: Money, vat: VAT) -> Money {
+    net.add_percent(self.rate(vat))
   }
 }
 
@@ -513,9 +722,9 @@ pub struct Upl {
   pub procurement_id: u32,
   // Net wholesale price in which
   // this item was purchased by us
-  pub procurement_net_price: u32,
+  pub procurement_net_price: Money,
   // SKU original procurement price
-  pub procurement_net_price_sku: u32,
+  pub procurement_net_price_sku: Money,
   // Total net margin for this UPL
   pub margin_net: i32,
   // Current UPL location
@@ -532,18 +741,22 @@ pub struct Upl {
   // SKU is divisible or not
   pub sku_divisible: bool,
   // Stored sku net price
-  pub sku_price_net: u32,
+  pub sku_price_net: Money,
   // Net retail price
-  pub price_net: u32,
+  pub price_net: Money,
   // SKU VAT
   pub vat: VAT,
   // Gross retail price
-  pub price_gross: u32,
+  pub price_gross: Money,
   // Lock enum
   // When a UPL is locked by any reason,
   // that UPL cannot be updated.
   // ~ Only ~ the lock owner can unlock
   pub lock: Lock,
+  // Lease of the current lock
+  // None when there is no lock
+  #[serde(default)]
+  pub lock_lease: Option<LockLease>,
   // UPL event history
   // We store all the major UPL event here
   pub history: Vec<UplHistoryItem>,
@@ -553,6 +766,68 @@ pub struct Upl {
   pub created_by: u32,
 }
 
+impl Upl {
+  // Set net retail price and procurement value
+  // with the related gross price and margin
+  fn set_values(&mut self, price_net: Money, procurement_net_price: Money, vat_rates: &VatRates) {
+    self.price_net = price_net;
+    self.price_gross = vat_rates.gross(price_net, self.vat);
+    self.procurement_net_price = procurement_net_price;
+    self.margin_net = price_net.margin(procurement_net_price);
+  }
+}
+
+/// Allocate the SKU values between opened UPLs and their divided parts
+/// It follows the divide rule: the family of an opened UPL is worth
+/// the value of its amount, its parts take their rounded down share
+/// one by one in divide order, and the parent keeps the remainder.
+/// So an unchanged family gets back the exact values of its divisions.
+/// Parts of a parent not in the slice keep their own share.
+pub fn allocate_opened_values(upls: &mut [Upl], vat_rates: &VatRates) {
+  let positions = upls
+    .iter()
+    .enumerate()
+    .map(|(position, upl)| (upl.id.clone(), position))
+    .collect::<HashMap<String, usize>>();
+  for parent in 0..upls.len() {
+    let (amount, successors) = match &upls[parent].kind {
+      Kind::OpenedSku {
+        amount, successors, ..
+      } => (*amount, successors.clone()),
+      _ => continue,
+    };
+    // Active parts in divide order
+    let parts = successors
+      .iter()
+      .filter_map(|upl_id| positions.get(upl_id).copied())
+      .filter_map(|position| match &upls[position].kind {
+        Kind::DerivedProduct {
+          derived_from,
+          amount,
+          ..
+        } if derived_from == &upls[parent].id => Some((position, *amount)),
+        _ => None,
+      })
+      .collect::<Vec<(usize, u32)>>();
+    // Value of the family amount, the rest belongs to the parts gone
+    let whole = upls[parent].sku_divisible_amount;
+    let mut rest_amount = amount + parts.iter().map(|(_, amount)| amount).sum::<u32>();
+    let gone = whole.saturating_sub(rest_amount);
+    let mut price_net = upls[parent].sku_price_net.split(gone, whole).1;
+    let mut procurement_net_price = upls[parent].procurement_net_price_sku.split(gone, whole).1;
+    for (position, part_amount) in parts {
+      let (part_price_net, rest_price_net) = price_net.split(part_amount, rest_amount);
+      let (part_procurement_net_price, rest_procurement_net_price) =
+        procurement_net_price.split(part_amount, rest_amount);
+      upls[position].set_values(part_price_net, part_procurement_net_price, vat_rates);
+      price_net = rest_price_net;
+      procurement_net_price = rest_procurement_net_price;
+      rest_amount -= part_amount;
+    }
+    upls[parent].set_values(price_net, procurement_net_price, vat_rates);
+  }
+}
+
 impl UplMethods for Upl {
   fn new(
     upl_id: String,
@@ -562,13 +837,14 @@ impl UplMethods for Upl {
     piece: u32,
     sku_divisible_amount: u32,
     sku_divisible: bool,
-    sku_price_net: u32,
+    sku_price_net: Money,
     sku_vat: VAT,
     procurement_id: u32,
-    procurement_net_price_sku: u32,
+    procurement_net_price_sku: Money,
     location: Location,
     best_before: Option<DateTime<Utc>>,
     is_opened: bool,
+    vat_rates: &VatRates,
     created_by: u32,
   ) -> Result<Self, String> {
     // Create new UPL
@@ -594,12 +870,13 @@ impl UplMethods for Upl {
       },
       product_unit,
       procurement_id,
-      procurement_net_price: 0,
+      procurement_net_price: Money::ZERO,
       procurement_net_price_sku,
       location,
       depreciation: None,
       best_before,
       lock: Lock::None,
+      lock_lease: None,
       // Init history vector with UplHistoryEvent::Created
       history: vec![UplHistoryItem::new(
         CreatedBy::Uid(created_by.clone()),
@@ -611,13 +888,13 @@ impl UplMethods for Upl {
       margin_net: 0,
       sku_divisible,
       sku_price_net,
-      price_net: 0,
+      price_net: Money::ZERO,
       vat: sku_vat,
-      price_gross: 0,
+      price_gross: Money::ZERO,
     };
 
     // Set prices
-    upl.recalculate_prices();
+    upl.recalculate_prices(vat_rates);
 
     // Return new UPL
     Ok(upl)
@@ -652,55 +929,22 @@ impl UplMethods for Upl {
     self.procurement_id
   }
 
-  fn get_procurement_net_price(&self) -> u32 {
+  fn get_procurement_net_price(&self) -> Money {
     self.procurement_net_price
   }
 
   fn can_move(&self, to: &Location) -> bool {
-    match to {
-      Location::Stock(_) => match self.lock {
-        Lock::Cart(_) => false,
-        Lock::Delivery(_) => false,
-        Lock::Inventory(_) => false,
-        // Or if it has no lock at all
-        Lock::None => true,
-      },
-      Location::Delivery(id) => match self.lock {
-        Lock::Cart(_) => false,
-        // If it has already a delivery lock,
-        // then only if it has its own delivery lock
-        Lock::Delivery(_id) => *id == _id,
-        Lock::Inventory(_) => false,
-        // Or if it has no lock at all
-        Lock::None => true,
-      },
-      Location::Cart(id) => match &self.lock {
-        // Only if it has its own cart lock
-        Lock::Cart(_id) => id == _id,
-        Lock::Delivery(_) => false,
-        Lock::Inventory(_) => false,
-        // Or if it has no lock at all
-        Lock::None => true,
-      },
-      Location::Discard(_) => match self.lock {
-        Lock::Cart(_) => false,
-        Lock::Delivery(_) => false,
-        // Only inventory locked UPL can be moved to Discard
-        Lock::Inventory(_) => true,
-        Lock::None => false,
-      },
-    }
+    check_move(&self.lock, to).is_ok()
   }
 
   fn get_location(&self) -> &Location {
     &self.location
   }
 
-  fn move_upl(&mut self, to: Location, created_by: u32) -> Result<&Self, String> {
+  fn move_upl(&mut self, to: Location, created_by: u32) -> Result<&Self, LockError> {
     // Check whether it can move to the target location or not
-    if !self.can_move(&to) {
-      return Err("Cannot move to target location".into());
-    }
+    check_move(&self.lock, &to)?;
+    check_move_from(&self.location, &to)?;
     // Preserve from_location to save later into history
     let from = self.location.clone();
     // If it can move
@@ -711,8 +955,10 @@ impl UplMethods for Upl {
       CreatedBy::Uid(created_by),
       UplHistoryEvent::Moved { from, to },
     ));
-    // Should clear lock after move
-    self.unlock_forced();
+    // Should clear the own lock after move
+    if self.has_lock() {
+      self.unlock_forced();
+    }
     Ok(self)
   }
 
@@ -734,13 +980,22 @@ impl UplMethods for Upl {
     self.get_lock().is_none()
   }
 
-  fn lock(&mut self, lock: Lock, created_by: u32) -> Result<&Self, String> {
+  fn lock(&mut self, lock: Lock, created_by: u32) -> Result<&Self, LockError> {
+    self.lock_with_ttl(lock, None, created_by)
+  }
+
+  fn lock_with_ttl(
+    &mut self,
+    lock: Lock,
+    ttl_minutes: Option<u64>,
+    created_by: u32,
+  ) -> Result<&Self, LockError> {
     // Check if wheter we can lock it or not
-    if !self.can_lock() {
-      return Err("Cannot lock! Already locked!".into());
-    }
-    // Set the new lock
+    check_acquire(&self.lock, &lock)?;
+    check_lock_at(&self.location)?;
+    // Set the new lock with its lease
     self.lock = lock.clone();
+    self.lock_lease = Some(LockLease::new(Utc::now(), ttl_minutes));
     // Set lock history event
     self.set_history(UplHistoryItem::new(
       CreatedBy::Uid(created_by),
@@ -750,26 +1005,48 @@ impl UplMethods for Upl {
     Ok(self)
   }
 
-  fn unlock(&mut self, lock: Lock, created_by: u32) -> Result<&Self, String> {
-    match self.lock == lock {
-      true => {
-        // Just release the lock
-        self.lock = Lock::None;
-        // Set UPL history
-        self.set_history(UplHistoryItem::new(
-          CreatedBy::Uid(created_by),
-          UplHistoryEvent::Unlocked,
-        ));
-        // Return self ref
-        Ok(self)
-      }
-      false => Err("A kért UPL zárolása nem fololdható. Nem megfelelő a forrás zárlat!".into()),
+  fn unlock(&mut self, lock: Lock, created_by: u32) -> Result<&Self, LockError> {
+    // Only the lock holder can release it
+    check_release(&self.lock, &lock)?;
+    // Just release the lock
+    self.lock = Lock::None;
+    self.lock_lease = None;
+    // Set UPL history
+    self.set_history(UplHistoryItem::new(
+      CreatedBy::Uid(created_by),
+      UplHistoryEvent::Unlocked,
+    ));
+    // Return self ref
+    Ok(self)
+  }
+
+  fn get_lock_lease(&self) -> Option<&LockLease> {
+    self.lock_lease.as_ref()
+  }
+
+  fn expire_lock(&mut self) -> Result<&Self, LockError> {
+    if !self.has_lock() {
+      return Err(LockError::NotLocked);
     }
+    let acquired_at = match self.lock_lease.take() {
+      Some(lease) => lease.acquired_at,
+      // Lock without a known lease
+      None => Utc::now(),
+    };
+    let lock = std::mem::take(&mut self.lock);
+    // Set UPL history
+    self.set_history(UplHistoryItem::new(
+      CreatedBy::Technical,
+      UplHistoryEvent::LockExpired { lock, acquired_at },
+    ));
+    // Return self ref
+    Ok(self)
   }
 
   fn unlock_forced(&mut self) -> &Self {
     // Just release the lock
     self.lock = Lock::None;
+    self.lock_lease = None;
     // Set UPL history
     self.set_history(UplHistoryItem::new(
       CreatedBy::Technical,
@@ -783,7 +1060,7 @@ impl UplMethods for Upl {
     &mut self,
     depreciation_id: u32,
     comment: String,
-    created_by: u32,
+    created_by: CreatedBy,
   ) -> Result<&Self, String> {
     // Check whether already depreciated
     if self.depreciation.is_some() {
@@ -795,7 +1072,7 @@ impl UplMethods for Upl {
 
     // Set UPL history
     self.set_history(UplHistoryItem::new(
-      CreatedBy::Uid(created_by),
+      created_by,
       UplHistoryEvent::SetDeprecated {
         depreciation_id,
         comment,
@@ -819,14 +1096,14 @@ impl UplMethods for Upl {
 
   fn set_depreciation_price(
     &mut self,
-    net_retail_price: Option<u32>,
-    created_by: u32,
+    net_retail_price: Option<Money>,
+    created_by: CreatedBy,
   ) -> Result<&Self, String> {
     // Set depreciation price if there is deprecation already set
     if let Some(dep) = &mut self.depreciation {
       // Calculate margin for the given depreciation price
       let margin: Option<i32> = match net_retail_price {
-        Some(discounted_price) => Some(discounted_price as i32 - self.procurement_net_price as i32),
+        Some(discounted_price) => Some(discounted_price.margin(self.procurement_net_price)),
         None => None,
       };
       // Set depreciation price
@@ -837,7 +1114,7 @@ impl UplMethods for Upl {
 
     // Set UPL history
     self.set_history(UplHistoryItem::new(
-      CreatedBy::Uid(created_by),
+      created_by,
       UplHistoryEvent::SetDepreciatedPrice {
         retail_net_price: net_retail_price,
       },
@@ -865,7 +1142,7 @@ impl UplMethods for Upl {
     None
   }
 
-  fn get_depreciation_price(&self) -> Option<u32> {
+  fn get_depreciation_price(&self) -> Option<Money> {
     if let Some(dep) = &self.depreciation {
       return dep.net_retail_price;
     }
@@ -916,7 +1193,13 @@ impl UplMethods for Upl {
     }
   }
 
-  fn split(&mut self, new_upl_id: String, piece: u32, created_by: u32) -> Result<Upl, String> {
+  fn split(
+    &mut self,
+    new_upl_id: String,
+    piece: u32,
+    vat_rates: &VatRates,
+    created_by: u32,
+  ) -> Result<Upl, String> {
     // Check piece
     if piece == 0 {
       return Err("Az új UPL mennyiség nem lehet 0!".to_string());
@@ -955,6 +1238,13 @@ impl UplMethods for Upl {
                 upl_pieces: piece,
               },
             };
+            // Set the creation event of the new UPL
+            new_upl.set_history(UplHistoryItem::new(
+              CreatedBy::Uid(created_by),
+              UplHistoryEvent::SplitFrom {
+                parent_upl_id: self.id.clone(),
+              },
+            ));
             // Set UPL history
             self.set_history(UplHistoryItem::new(
               CreatedBy::Uid(created_by),
@@ -962,10 +1252,10 @@ impl UplMethods for Upl {
             ));
 
             // Recalculate parent prices
-            self.recalculate_prices();
+            self.recalculate_prices(vat_rates);
 
             // Recalculate child prices
-            new_upl.recalculate_prices();
+            new_upl.recalculate_prices(vat_rates);
 
             // Return the new UPL
             Ok(new_upl)
@@ -977,7 +1267,12 @@ impl UplMethods for Upl {
     }
   }
 
-  fn split_bulk(&mut self, new_upl_ids: Vec<String>, created_by: u32) -> Result<Vec<Upl>, String> {
+  fn split_bulk(
+    &mut self,
+    new_upl_ids: Vec<String>,
+    vat_rates: &VatRates,
+    created_by: u32,
+  ) -> Result<Vec<Upl>, String> {
     // Check all the new UPL IDs to ensure all of them valid Luhn ids
     for id in &new_upl_ids {
       if id.luhn_check_ref().is_err() {
@@ -998,7 +1293,7 @@ impl UplMethods for Upl {
         new_upl_ids.into_iter().for_each(|id| {
           // We can use unwrap, as we already checked its a Bulk UPL
           // and only this can cause error
-          if let Ok(upl) = self.split(id, 1, created_by.clone()) {
+          if let Ok(upl) = self.split(id, 1, vat_rates, created_by) {
             result.push(upl);
           }
         });
@@ -1008,7 +1303,7 @@ impl UplMethods for Upl {
     }
   }
 
-  fn open(&mut self) -> Result<&Upl, String> {
+  fn open(&mut self, created_by: CreatedBy) -> Result<&Upl, String> {
     if !self.is_divisible() {
       return Err(
         "A kért UPL nem mérhető ki, így nem bontható meg!
@@ -1036,6 +1331,9 @@ impl UplMethods for Upl {
           successors: Vec::new(),
         };
 
+        // Set UPL history
+        self.set_history(UplHistoryItem::new(created_by, UplHistoryEvent::Opened));
+
         // Return self ref
         Ok(self)
       }
@@ -1043,7 +1341,7 @@ impl UplMethods for Upl {
     }
   }
 
-  fn close(&mut self) -> Result<&Upl, String> {
+  fn close(&mut self, created_by: CreatedBy) -> Result<&Upl, String> {
     if self.has_lock() {
       return Err("A terméket nem tudjuk lezárni, mivel zárolva van!".to_string());
     }
@@ -1061,6 +1359,9 @@ impl UplMethods for Upl {
         // Set Kind::Sku again
         self.kind = Kind::Sku { sku: *sku };
 
+        // Set UPL history
+        self.set_history(UplHistoryItem::new(created_by, UplHistoryEvent::Closed));
+
         // Return self ref
         Ok(self)
       }
@@ -1072,6 +1373,7 @@ impl UplMethods for Upl {
     &mut self,
     new_upl_id: String,
     requested_amount: u32,
+    vat_rates: &VatRates,
     created_by: u32,
   ) -> Result<Upl, String> {
     // Check piece
@@ -1099,6 +1401,9 @@ impl UplMethods for Upl {
           return Err("A kért termék túl kicsi a kívánt mértékhez!".into());
         }
 
+        // Preserve the amount to split the value by
+        let amount_before = *amount;
+
         // Decrease its amount
         *amount -= requested_amount;
 
@@ -1124,11 +1429,32 @@ impl UplMethods for Upl {
           amount: requested_amount,
         };
 
-        // Recalculate parent prices
-        self.recalculate_prices();
+        // Set the creation event of the new UPL
+        new_upl.set_history(UplHistoryItem::new(
+          CreatedBy::Uid(created_by),
+          UplHistoryEvent::DividedFrom {
+            parent_upl_id: self.id.clone(),
+            amount: requested_amount,
+          },
+        ));
 
-        // Recalculate child prices
-        new_upl.recalculate_prices();
+        // Set UPL history
+        self.set_history(UplHistoryItem::new(
+          CreatedBy::Uid(created_by),
+          UplHistoryEvent::Divided {
+            new_upl_id,
+            requested_amount,
+          },
+        ));
+
+        // Split the current value between the new UPL and the parent
+        // The rounding remainder stays with the parent, so no value is lost
+        let (price_net, parent_price_net) = self.price_net.split(requested_amount, amount_before);
+        let (procurement_net_price, parent_procurement_net_price) = self
+          .procurement_net_price
+          .split(requested_amount, amount_before);
+        self.set_values(parent_price_net, parent_procurement_net_price, vat_rates);
+        new_upl.set_values(price_net, procurement_net_price, vat_rates);
 
         // Return the new UPL
         Ok(new_upl)
@@ -1138,7 +1464,12 @@ impl UplMethods for Upl {
     }
   }
 
-  fn merge(&mut self, upl_to_merge: Upl, _by: u32) -> Result<&Upl, String> {
+  fn merge(
+    &mut self,
+    upl_to_merge: Upl,
+    vat_rates: &VatRates,
+    created_by: u32,
+  ) -> Result<&Upl, String> {
     if self.is_depreciated() {
       return Err(
         "A szülő UPL selejtezett. Selejtezett termékbe nem tudunk vissza tenni".to_string(),
@@ -1169,10 +1500,25 @@ impl UplMethods for Upl {
           if &self.id != derived_from {
             return Err("A kért UPL nem tehető vissza másik szülőbe!".to_string());
           }
+          // Take back the value of the merged UPL, margin + procurement net value
+          let value_error = || "A visszatett UPL értéke túl nagy!".to_string();
+          let price_net = self
+            .price_net
+            .checked_add(upl_to_merge.price_net)
+            .ok_or_else(value_error)?;
+          let procurement_net_price = self
+            .procurement_net_price
+            .checked_add(upl_to_merge.procurement_net_price)
+            .ok_or_else(value_error)?;
           // Put back the required amount
           *amount_parent = *amount_parent + *child_amount;
-          // Recalculate prices, margin + procurement net value
-          self.recalculate_prices();
+          // Set UPL history
+          let event = UplHistoryEvent::Merged {
+            merged_upl_id: upl_to_merge.id.clone(),
+            amount: *child_amount,
+          };
+          self.set_history(UplHistoryItem::new(CreatedBy::Uid(created_by), event));
+          self.set_values(price_net, procurement_net_price, vat_rates);
           // Return self as ref
           return Ok(self);
         }
@@ -1283,18 +1629,36 @@ impl UplMethods for Upl {
     &self.id
   }
 
-  fn set_price(&mut self, sku_net_price: u32, sku_vat: VAT) -> Result<&Self, String> {
+  fn set_price(
+    &mut self,
+    sku_net_price: Money,
+    sku_vat: VAT,
+    vat_rates: &VatRates,
+    created_by: CreatedBy,
+  ) -> Result<&Self, String> {
+    // Set UPL history if anything has changed
+    if self.sku_price_net != sku_net_price || self.vat != sku_vat {
+      self.set_history(UplHistoryItem::new(
+        created_by,
+        UplHistoryEvent::PriceChanged {
+          old_net_price: self.sku_price_net,
+          new_net_price: sku_net_price,
+          old_vat: self.vat,
+          new_vat: sku_vat,
+        },
+      ));
+    }
     // Store SKU net price
     self.sku_price_net = sku_net_price;
     // Store new VAT
     self.vat = sku_vat;
     // Recalculate prices
-    self.recalculate_prices();
+    self.recalculate_prices(vat_rates);
     // Return self as ref
     Ok(self)
   }
 
-  fn get_upl_net_price(&self) -> u32 {
+  fn get_upl_net_price(&self) -> Money {
     match &self.depreciation {
       Some(d) => match d.net_retail_price {
         Some(dp) => dp,
@@ -1304,10 +1668,10 @@ impl UplMethods for Upl {
     }
   }
 
-  fn get_upl_gross_price(&self) -> u32 {
+  fn get_upl_gross_price(&self, vat_rates: &VatRates) -> Money {
     match &self.depreciation {
       Some(d) => match d.net_retail_price {
-        Some(dp) => dp * self.vat,
+        Some(dp) => vat_rates.gross(dp, self.vat),
         None => self.price_gross,
       },
       None => self.price_gross,
@@ -1325,14 +1689,14 @@ impl UplMethods for Upl {
     }
   }
 
-  fn recalculate_prices(&mut self) {
+  fn recalculate_prices(&mut self, vat_rates: &VatRates) {
     match self.kind {
       // Set price for a normal SKU UPL
       Kind::Sku { sku: _ } => {
         // Set net retail price
         self.price_net = self.sku_price_net;
         // Set gross retail price
-        self.price_gross = self.sku_price_net * self.vat;
+        self.price_gross = vat_rates.gross(self.sku_price_net, self.vat);
         // Set procurement price
         self.procurement_net_price = self.procurement_net_price_sku;
       }
@@ -1344,61 +1708,79 @@ impl UplMethods for Upl {
         // Set net retail price
         self.price_net = self.sku_price_net;
         // Set gross retail price
-        self.price_gross = self.sku_price_net * self.vat;
+        self.price_gross = vat_rates.gross(self.sku_price_net, self.vat);
         // Set procurement price
         self.procurement_net_price = self.procurement_net_price_sku;
       }
       // Set price for an opened SKU
+      // It keeps the remainder of the divided amount, like in divide
       Kind::OpenedSku {
         sku: _,
         amount,
         successors: _,
       } => {
-        // Calculate unit net price
-        let unit_net_price = self.sku_price_net as f32 / self.sku_divisible_amount as f32;
+        let divided = self.sku_divisible_amount.saturating_sub(amount);
         // Reset UPL retail net price based on its amount
-        self.price_net = (amount as f32 * unit_net_price).round() as u32;
+        self.price_net = self
+          .sku_price_net
+          .split(divided, self.sku_divisible_amount)
+          .1;
         // Reset UPL retail gross price based on its amount
-        self.price_gross = self.price_net * self.vat;
-        // Calculate unit procurement value
-        let unit_procurement_value =
-          self.procurement_net_price_sku as f32 / self.sku_divisible_amount as f32;
+        self.price_gross = vat_rates.gross(self.price_net, self.vat);
         // Set new procurement value
-        self.procurement_net_price = (amount as f32 * unit_procurement_value).round() as u32;
+        self.procurement_net_price = self
+          .procurement_net_price_sku
+          .split(divided, self.sku_divisible_amount)
+          .1;
       }
       Kind::DerivedProduct {
         derived_from: _,
         derived_from_sku: _,
         amount,
       } => {
-        // Calculate unit net price
-        let unit_net_price = self.sku_price_net as f32 / self.sku_divisible_amount as f32;
         // Reset UPL retail net price based on its amount
-        self.price_net = (amount as f32 * unit_net_price).round() as u32;
+        self.price_net = self.sku_price_net.share(amount, self.sku_divisible_amount);
         // Reset UPL retail gross price based on its amount
-        self.price_gross = self.price_net * self.vat;
-        // Calculate unit procurement value
-        let unit_procurement_value =
-          self.procurement_net_price_sku as f32 / self.sku_divisible_amount as f32;
+        self.price_gross = vat_rates.gross(self.price_net, self.vat);
         // Set new procurement value
-        self.procurement_net_price = (amount as f32 * unit_procurement_value).round() as u32;
+        self.procurement_net_price = self
+          .procurement_net_price_sku
+          .share(amount, self.sku_divisible_amount);
       }
     }
     // Set margin
-    self.margin_net = self.price_net as i32 - self.procurement_net_price as i32;
+    self.margin_net = self.price_net.margin(self.procurement_net_price);
   }
 
-  fn set_divisible(&mut self, divisible: bool) -> &Self {
+  fn set_divisible(&mut self, divisible: bool, created_by: CreatedBy) -> &Self {
+    if self.sku_divisible != divisible {
+      self.set_history(UplHistoryItem::new(
+        created_by,
+        UplHistoryEvent::DivisibleChanged {
+          from: self.sku_divisible,
+          to: divisible,
+        },
+      ));
+    }
     self.sku_divisible = divisible;
     self
   }
 
-  fn set_product_unit(&mut self, unit: String) -> &Self {
+  fn set_product_unit(&mut self, unit: String, created_by: CreatedBy) -> &Self {
+    if self.product_unit != unit {
+      self.set_history(UplHistoryItem::new(
+        created_by,
+        UplHistoryEvent::ProductUnitChanged {
+          from: self.product_unit.clone(),
+          to: unit.clone(),
+        },
+      ));
+    }
     self.product_unit = unit;
     self
   }
 
-  fn get_upl_special_price_net(&self) -> Option<u32> {
+  fn get_upl_special_price_net(&self) -> Option<Money> {
     match &self.depreciation {
       Some(d) => d.net_retail_price,
       None => None,
@@ -1421,22 +1803,23 @@ impl Default for Upl {
       product_unit: String::default(),
       kind: Kind::default(),
       procurement_id: 0,
-      procurement_net_price: 0,
+      procurement_net_price: Money::ZERO,
       location: Location::default(),
       depreciation: None,
       best_before: None,
       sku_divisible_amount: 1,
       lock: Lock::default(),
+      lock_lease: None,
       history: Vec::new(),
       created_at: Utc::now(),
       created_by: 0,
-      procurement_net_price_sku: 0,
+      procurement_net_price_sku: Money::ZERO,
       margin_net: 0,
-      price_net: 0,
+      price_net: Money::ZERO,
       vat: VAT::default(),
-      price_gross: 0,
+      price_gross: Money::ZERO,
       sku_divisible: false,
-      sku_price_net: 0,
+      sku_price_net: Money::ZERO,
     }
   }
 }
@@ -1448,3 +1831,139 @@ impl VecPackMember for Upl {
     &self.id
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Bincode variant index of a history event
+  fn variant_index(event: &UplHistoryEvent) -> u32 {
+    let bytes = bincode::serialize(event).unwrap();
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+  }
+
+  #[test]
+  fn test_history_event_layout() {
+    // Stored UPLs decode history events by variant index,
+    // so the existing variants must keep their place
+    assert_eq!(variant_index(&UplHistoryEvent::Created), 0);
+    assert_eq!(variant_index(&UplHistoryEvent::Archived), 1);
+    assert_eq!(
+      variant_index(&UplHistoryEvent::Moved {
+        from: Location::Stock(1),
+        to: Location::Stock(2),
+      }),
+      2
+    );
+    assert_eq!(
+      variant_index(&UplHistoryEvent::Locked { to: Lock::None }),
+      4
+    );
+    assert_eq!(variant_index(&UplHistoryEvent::Unlocked), 5);
+    assert_eq!(variant_index(&UplHistoryEvent::None), 11);
+    assert_eq!(
+      variant_index(&UplHistoryEvent::Restored {
+        reason: String::new(),
+      }),
+      12
+    );
+    assert_eq!(variant_index(&UplHistoryEvent::Opened), 13);
+  }
+
+  #[test]
+  fn test_divide_conserves_value() {
+    use gzlib::id::{generate_id, HexHelper, IdKind};
+    let id = |base: u64| generate_id(base, IdKind::LuhnTwo).to_hex();
+    let rates = VatRates::default();
+    let mut parent = Upl::new(
+      id(1),
+      1,
+      "ml".to_string(),
+      1,
+      1,
+      3,
+      true,
+      Money::new(1000),
+      VAT::_27,
+      1,
+      Money::new(700),
+      Location::Stock(1),
+      None,
+      false,
+      &rates,
+      0,
+    )
+    .unwrap();
+    parent.open(CreatedBy::Technical).unwrap();
+    assert_eq!(parent.price_net, Money::new(1000));
+
+    let first = parent.divide(id(2), 1, &rates, 0).unwrap();
+    let second = parent.divide(id(3), 1, &rates, 0).unwrap();
+    // Remainder stays with the parent
+    assert_eq!(first.price_net, Money::new(333));
+    assert_eq!(second.price_net, Money::new(333));
+    assert_eq!(parent.price_net, Money::new(334));
+    assert_eq!(
+      parent.procurement_net_price + first.procurement_net_price + second.procurement_net_price,
+      Money::new(700)
+    );
+    assert_eq!(
+      parent.margin_net + first.margin_net + second.margin_net,
+      300
+    );
+
+    // Repricing allocates by the same rule as divide
+    let reprice = |upls: &[&Upl], price: u32| {
+      let mut upls = upls.iter().map(|upl| (*upl).clone()).collect::<Vec<Upl>>();
+      for upl in upls.iter_mut() {
+        upl
+          .set_price(Money::new(price), VAT::_27, &rates, CreatedBy::Technical)
+          .unwrap();
+      }
+      allocate_opened_values(&mut upls, &rates);
+      upls
+    };
+    let family = reprice(&[&second, &parent, &first], 1000);
+    assert_eq!(family[0].price_net, second.price_net);
+    assert_eq!(family[1].price_net, parent.price_net);
+    assert_eq!(family[2].price_net, first.price_net);
+    let family = reprice(&[&parent, &first, &second], 1001);
+    assert_eq!(
+      family
+        .iter()
+        .map(|upl| upl.price_net)
+        .collect::<Vec<Money>>(),
+      vec![Money::new(334), Money::new(333), Money::new(334)]
+    );
+    assert_eq!(
+      family
+        .iter()
+        .map(|upl| upl.procurement_net_price.value())
+        .sum::<u32>(),
+      700
+    );
+    // A sold part takes its share of the SKU value away
+    let family = reprice(&[&parent, &second], 1001);
+    assert_eq!(family[0].price_net + family[1].price_net, Money::new(668));
+
+    // Merging back restores the original value
+    parent.merge(second, &rates, 0).unwrap();
+    parent.merge(first, &rates, 0).unwrap();
+    assert_eq!(parent.price_net, Money::new(1000));
+    assert_eq!(parent.procurement_net_price, Money::new(700));
+    assert_eq!(parent.price_gross, Money::new(1270));
+    assert_eq!(parent.margin_net, 300);
+  }
+
+  #[test]
+  fn test_vat_rates() {
+    let rates = VatRates::new([(VAT::_27, 25)].iter().cloned().collect());
+    assert_eq!(rates.rate(VAT::_27), 25);
+    assert_eq!(rates.rate(VAT::_5), 5);
+    assert_eq!(rates.gross(Money::new(1000), VAT::_27), Money::new(1250));
+    assert_eq!(
+      VatRates::default().gross(Money::new(999), VAT::_27),
+      Money::new(1269)
+    );
+  }
+}